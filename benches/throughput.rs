@@ -0,0 +1,193 @@
+//! Timings of the read and write paths over a socket pair, run with `cargo bench`,
+//! or `cargo bench -- <group>` for the groups whose name contains it
+use std::io::{IoSlice, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Instant;
+use rust_sfp::{ChecksumKind, Connection, FrameReader, FrameWriter, FlushPolicy, GenericConnection};
+
+/// Runs `f` `iterations` times, printing the median time of an iteration, which other processes
+/// and the scheduling of the two threads disturb less than the mean
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()){
+    f();
+    let mut times: Vec<_> = (0..iterations).map(|_| {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }).collect();
+    times.sort_unstable();
+    println!("{:<52} {:>12?}/iter", name, times[times.len() / 2]);
+}
+
+/// Sends `count` frames of `len` bytes from another thread and reads them all
+fn round_trip(count: usize, len: usize, setup: impl Fn(&mut Connection, &mut Connection)){
+    let (mut writer, mut reader) = Connection::pair().unwrap();
+    setup(&mut writer, &mut reader);
+    let sender = thread::spawn(move || {
+        let frame = vec![0xA5u8; len];
+        for _ in 0..count {
+            writer.write_frame(&frame).unwrap();
+        }
+        writer.flush().unwrap();
+    });
+    let mut buf = Vec::new();
+    for _ in 0..count {
+        reader.read_frame_into(&mut buf).unwrap();
+    }
+    sender.join().unwrap();
+}
+
+fn small_frames(){
+    for capacity in [0, rust_sfp::DEFAULT_READ_BUFFER_CAPACITY] {
+        let name = format!("100k 16 byte frames, read buffer {}", capacity);
+        bench(&name, 5, || {
+            round_trip(100_000, 16, |writer, reader| {
+                writer.set_flush_policy(FlushPolicy::explicit().max_bytes(64 * 1024));
+                reader.set_read_buffer_capacity(capacity);
+            });
+        });
+    }
+}
+
+/// Sends `count` frames of `len` bytes from another thread, then runs `read` for each of them
+fn large_round_trip(count: usize, len: usize, mut read: impl FnMut(&mut Connection, &mut Vec<u8>)){
+    let (mut writer, mut reader) = Connection::pair().unwrap();
+    reader.set_max_frame_len(len);
+    let frame = vec![0xA5u8; len];
+    let sender = thread::spawn(move || {
+        for _ in 0..count {
+            writer.write_frame(&frame).unwrap();
+        }
+    });
+    let mut buf = Vec::new();
+    for _ in 0..count {
+        read(&mut reader, &mut buf);
+    }
+    sender.join().unwrap();
+}
+
+/// Frames of 16 and 64 MB read by `read_frame` and into a reused buffer, against reading into
+/// a zeroed buffer as before
+fn large_frames(){
+    for len in [16 * 1024 * 1024, 64 * 1024 * 1024] {
+        let mb = len >> 20;
+        bench(&format!("4 {} MB frames, read_frame", mb), 15, || large_round_trip(4, len, |reader, _| {
+            reader.read_frame().unwrap();
+        }));
+        bench(&format!("4 {} MB frames, zeroed buffer and read_exact", mb), 15, || large_round_trip(4, len, |reader, _| {
+            let mut stream = reader.get_ref();
+            let mut header = [0u8; 4];
+            stream.read_exact(&mut header).unwrap();
+            let mut frame = vec![0u8; u32::from_be_bytes(header) as usize];
+            stream.read_exact(&mut frame).unwrap();
+        }));
+        bench(&format!("4 {} MB frames, read_frame_into a reused buffer", mb), 15, || large_round_trip(4, len, |reader, buf| {
+            reader.read_frame_into(buf).unwrap();
+        }));
+        bench(&format!("4 {} MB frames, reused zeroed buffer and read_exact", mb), 15, || large_round_trip(4, len, |reader, buf| {
+            let mut stream = reader.get_ref();
+            let mut header = [0u8; 4];
+            stream.read_exact(&mut header).unwrap();
+            buf.clear();
+            buf.resize(u32::from_be_bytes(header) as usize, 0);
+            stream.read_exact(buf).unwrap();
+        }));
+    }
+}
+
+/// 1 KB and 1 MB frames with each checksum of the build, against none
+fn checksums(){
+    let kinds = [ChecksumKind::None, ChecksumKind::Crc32, ChecksumKind::Crc32c, ChecksumKind::XxHash64];
+    for (count, len, size) in [(10_000, 1024, "1 KB"), (100, 1024 * 1024, "1 MB")] {
+        for kind in kinds.iter().copied().filter(|kind| kind.is_available()) {
+            bench(&format!("{} {} frames, checksum {:?}", count, size, kind), 5, || {
+                round_trip(count, len, |writer, reader| {
+                    writer.set_checksum(kind).unwrap();
+                    reader.set_checksum(kind).unwrap();
+                });
+            });
+        }
+    }
+}
+
+/// Stream counting the write calls made to it, each one a syscall
+struct CountedWrites{
+    stream: TcpStream,
+    writes: usize,
+}
+
+impl Read for CountedWrites{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for CountedWrites{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes += 1;
+        self.stream.write(buf)
+    }
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        self.writes += 1;
+        self.stream.write_vectored(bufs)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+const VECTORED_FRAMES: usize = 100_000;
+
+type CountedWrite = fn(&mut GenericConnection<CountedWrites>, &[u8]);
+
+/// Writes 100k 32 byte frames with `write` over loopback TCP without Nagle, the peer drains
+/// the bytes without decoding them. Returns the write calls per frame
+fn write_only(write: CountedWrite) -> f64{
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut writer = GenericConnection::new(CountedWrites{stream, writes: 0});
+    let (mut reader, _) = listener.accept().unwrap();
+    let drain = thread::spawn(move || std::io::copy(&mut reader, &mut std::io::sink()).unwrap());
+    let frame = [0xA5u8; 32];
+    for _ in 0..VECTORED_FRAMES {
+        write(&mut writer, &frame);
+    }
+    let writes = writer.get_ref().writes;
+    drop(writer);
+    drain.join().unwrap();
+    writes as f64 / VECTORED_FRAMES as f64
+}
+
+/// 32 byte frames, header and payload in one vectored write of `write_frame`,
+/// against a write for each of them
+fn vectored_writes(){
+    let cases: [(&str, CountedWrite); 2] = [
+        ("write_frame", |writer, frame| writer.write_frame(frame).unwrap()),
+        ("header and payload apart", |writer, frame| {
+            let stream = writer.get_mut();
+            stream.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(frame).unwrap();
+        }),
+    ];
+    for (name, write) in cases {
+        let mut writes = 0.0;
+        bench(&format!("100k 32 byte frames, {}", name), 5, || writes = write_only(write));
+        println!("{:<48} {:>12} writes/frame", "", writes);
+    }
+}
+
+fn main(){
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let groups: [(&str, fn()); 4] = [
+        ("small_frames", small_frames),
+        ("large_frames", large_frames),
+        ("checksums", checksums),
+        ("vectored_writes", vectored_writes),
+    ];
+    for (name, group) in groups {
+        if filter.as_deref().is_none_or(|filter| name.contains(filter)) {
+            group();
+        }
+    }
+}
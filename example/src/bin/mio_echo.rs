@@ -0,0 +1,96 @@
+//! Echo server driven by a single mio loop, `mio_echo [ADDR]` sends every frame back to its client
+#[cfg(unix)]
+use std::collections::{HashMap, VecDeque};
+#[cfg(unix)]
+use rust_sfp::{self as sfp, ConnectionController, FrameWriter, WriteErr, WriteProgress};
+
+#[cfg(unix)]
+const SERVER: mio::Token = mio::Token(0);
+
+/// A client and the frames it is owed while its socket is full
+#[cfg(unix)]
+struct Client{
+    connection: sfp::Connection,
+    backlog: VecDeque<Vec<u8>>,
+}
+
+#[cfg(unix)]
+impl Client{
+    /// Echoes what arrived, `false` once the client is gone
+    fn readable(&mut self) -> bool{
+        loop {
+            match self.connection.try_read_frame() {
+                Ok(Some(frame)) => self.backlog.push_back(frame),
+                // Spurious readiness lands here too
+                Ok(None) => return self.writable(),
+                Err(_) => return false,
+            }
+        }
+    }
+    fn writable(&mut self) -> bool{
+        match self.connection.resume_write() {
+            Ok(WriteProgress::Complete) => {}
+            Ok(WriteProgress::Pending) => return true,
+            Err(_) => return false,
+        }
+        while let Some(frame) = self.backlog.pop_front() {
+            match self.connection.write_frame(&frame) {
+                Ok(()) => {}
+                // The frame was taken, the rest goes out on the next writable event
+                Err(WriteErr::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(unix)]
+fn main() -> std::io::Result<()> {
+    use mio::{Events, Interest, Poll, Token};
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "unix:/tmp/rust_sfp_example.sock".to_string());
+    let _ = std::fs::remove_file(addr.trim_start_matches("unix:"));
+    let mut server = sfp::Server::bind(&addr.parse().unwrap())?;
+    server.set_nonblocking(true)?;
+    let mut poll = Poll::new()?;
+    poll.registry().register(&mut server, SERVER, Interest::READABLE)?;
+    let mut clients = HashMap::new();
+    let mut next_token = 1;
+    let mut events = Events::with_capacity(128);
+    loop {
+        poll.poll(&mut events, None)?;
+        for event in &events {
+            if event.token() == SERVER {
+                loop {
+                    let mut connection = match server.accept() {
+                        Ok((connection, _)) => connection,
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    };
+                    connection.set_nonblocking(true)?;
+                    let token = Token(next_token);
+                    next_token += 1;
+                    poll.registry().register(&mut connection, token, Interest::READABLE | Interest::WRITABLE)?;
+                    clients.insert(token, Client{connection, backlog: VecDeque::new()});
+                    println!("Client {} connected", token.0);
+                }
+                continue
+            }
+            let alive = match clients.get_mut(&event.token()) {
+                Some(client) => (!event.is_readable() || client.readable()) && (!event.is_writable() || client.writable()),
+                None => continue,
+            };
+            if !alive {
+                if let Some(mut client) = clients.remove(&event.token()) {
+                    poll.registry().deregister(&mut client.connection)?;
+                    println!("Client {} closed", event.token().0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    println!("The mio feature of rust_sfp is only available on Unix");
+}
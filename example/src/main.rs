@@ -12,8 +12,11 @@ const ADDR: &str = "unix:/tmp/rust_sfp_example.sock";
 
 type ID = u128;
 
+/// Frames queued for a client before the oldest are dropped
+const CLIENT_QUEUE: usize = 64;
+
 struct Clients{
-    clients: HashMap<ID, sfp::ConnectionWriter>,
+    clients: HashMap<ID, sfp::QueuedWriter>,
     next_id: ID,
 }
 
@@ -24,16 +27,17 @@ impl Clients{
     pub fn add(&mut self, writer: sfp::ConnectionWriter) -> ID{
         let id = self.next_id;
         self.next_id += 1;
-        self.clients.insert(id, writer);
+        // A slow client only delays its own queue
+        self.clients.insert(id, sfp::QueuedWriter::new(writer, CLIENT_QUEUE, sfp::OverflowPolicy::DropOldest));
         id
     }
     pub fn remove(&mut self, id: ID){
         self.clients.remove(&id);
     }
-    pub fn send(&mut self, mut frame: Vec<u8>){
+    pub fn send(&mut self, frame: &[u8]){
         let mut to_remove: Vec<ID> = Vec::new();
         for (id, writer) in self.clients.iter_mut(){
-            if let Err(_) = writer.write_frame(&mut frame){
+            if let Err(_) = writer.try_send(frame.to_vec()){
                 to_remove.push(*id)
             } else {
                 println!("Sent frame to {}", id);
@@ -49,7 +53,7 @@ fn server(){
     let server = sfp::Server::bind(&ADDR.parse().unwrap()).unwrap();
     let clients = Arc::new(Mutex::new(Clients::new()));
     for (connection, addr) in server{
-        let (reader, writer) = connection.separate().unwrap();
+        let (mut reader, writer) = connection.separate().unwrap();
         let id = {
             let mut cl = clients.lock().unwrap();
             cl.add(writer)
@@ -57,10 +61,17 @@ fn server(){
         println!("New connection from {} with id {}", addr, id);
         let clients = clients.clone();
         thread::spawn(move || {
-            for frame in reader{
-                println!("Recv frame from {}", id);
-                let mut cl = clients.lock().unwrap();
-                cl.send(frame);
+            for frame in reader.frames(){
+                match frame {
+                    Ok(frame) => {
+                        println!("Recv frame from {}", id);
+                        let mut cl = clients.lock().unwrap();
+                        cl.send(&frame);
+                    }
+                    Err(err) => {
+                        println!("Connection {} failed: {}", id, err);
+                    }
+                }
             }
             let mut cl = clients.lock().unwrap();
             cl.remove(id);
@@ -70,7 +81,6 @@ fn server(){
 }
 
 fn client(msg: String){
-    let msg_frame = msg.into_bytes();
     let connection = sfp::Connection::connect(&ADDR.parse().unwrap()).unwrap();
     println!("Connected");
     let (reader, mut writer) = connection.separate().unwrap();
@@ -80,7 +90,7 @@ fn client(msg: String){
         }
     });
     loop {
-        if let Err(_) = writer.write_frame(&mut msg_frame.clone()){break}
+        if let Err(_) = writer.send(&msg){break}
         if let Err(_) = writer.flush(){break}
         println!("Sent frame to server");
         thread::sleep(time::Duration::from_secs(1));
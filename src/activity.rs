@@ -0,0 +1,116 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Times are kept as nanoseconds since this, plus one so that 0 means never
+fn epoch() -> Instant{
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Counters of a connection, its clones and its halves together, see `Connection::stats`.
+/// Bytes are those of payloads, padding included. Counters wrap around on overflow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectionStats{
+    /// Frames received, keepalives and skipped frames included
+    pub frames_read: u64,
+    /// Frames taken for sending, those still buffered included
+    pub frames_written: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Frames the decoder rejected: too long, failing their checksum, out of sequence, with unknown flags
+    /// or not frames at all
+    pub read_errors: u64,
+    /// Frames a failed send did not get out, in full or at all
+    pub write_errors: u64,
+}
+
+/// What a connection read and wrote and when, shared by its clones and halves
+#[derive(Debug)]
+pub(crate) struct Activity{
+    created: Instant,
+    last_read: AtomicU64,
+    last_write: AtomicU64,
+    count_keepalives: AtomicBool,
+    frames_read: AtomicU64,
+    frames_written: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+}
+
+impl Activity{
+    pub(crate) fn new() -> Self{
+        epoch();
+        Self{
+            created: Instant::now(),
+            last_read: AtomicU64::new(0),
+            last_write: AtomicU64::new(0),
+            count_keepalives: AtomicBool::new(true),
+            frames_read: AtomicU64::new(0),
+            frames_written: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            read_errors: AtomicU64::new(0),
+            write_errors: AtomicU64::new(0),
+        }
+    }
+    pub(crate) fn read(&self, length: usize){
+        self.frames_read.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(length as u64, Ordering::Relaxed);
+        self.record(&self.last_read, length == 0)
+    }
+    /// `bytes` of payload in `frames` frames
+    pub(crate) fn wrote(&self, frames: usize, bytes: u64){
+        self.frames_written.fetch_add(frames as u64, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+        self.record(&self.last_write, bytes == 0)
+    }
+    pub(crate) fn read_error(&self){
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn write_error(&self){
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn stats(&self) -> ConnectionStats{
+        ConnectionStats{
+            frames_read: self.frames_read.load(Ordering::Relaxed),
+            frames_written: self.frames_written.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            read_errors: self.read_errors.load(Ordering::Relaxed),
+            write_errors: self.write_errors.load(Ordering::Relaxed),
+        }
+    }
+    fn record(&self, slot: &AtomicU64, keepalive: bool){
+        if keepalive && !self.count_keepalives.load(Ordering::Relaxed) {
+            return
+        }
+        let nanos = epoch().elapsed().as_nanos().min(u64::MAX as u128 - 1) as u64;
+        slot.store(nanos + 1, Ordering::Relaxed);
+    }
+    pub(crate) fn last_read(&self) -> Option<Instant>{
+        Self::at(&self.last_read)
+    }
+    pub(crate) fn last_write(&self) -> Option<Instant>{
+        Self::at(&self.last_write)
+    }
+    fn at(slot: &AtomicU64) -> Option<Instant>{
+        match slot.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => epoch().checked_add(Duration::from_nanos(nanos - 1)),
+        }
+    }
+    /// Since the last frame either way, or since the connection was made
+    pub(crate) fn idle_for(&self) -> Duration{
+        let last = self.last_read().max(self.last_write()).unwrap_or(self.created);
+        last.elapsed()
+    }
+    pub(crate) fn set_count_keepalives(&self, count: bool){
+        self.count_keepalives.store(count, Ordering::Relaxed)
+    }
+    pub(crate) fn count_keepalives(&self) -> bool{
+        self.count_keepalives.load(Ordering::Relaxed)
+    }
+}
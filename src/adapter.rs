@@ -0,0 +1,457 @@
+use std::io;
+use std::io::{Read, Write};
+use crate::{FrameReader, FrameWriter, FrameDecoder, Frame, FrameBuf, FrameFlags, Framing, FramingConfig, HeaderWidth, ChecksumKind,
+            FlushPolicy, ReadErr, WriteErr, FrameEncoder, WriteState, WritePath, read_source};
+use crate::source::ReadUninit;
+
+/// Any `Read`, zeroing memory before reading into it
+#[derive(Debug)]
+struct Plain<R>(R);
+
+impl<R: Read> Read for Plain<R>{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> ReadUninit for Plain<R>{}
+
+/// Reads frames from any `Read`, a file or a `Cursor` in tests, in the wire format of `Connection`.
+/// Reads ahead: bytes past the last frame returned are buffered here, `into_inner` drops them
+#[derive(Debug)]
+pub struct SfpReader<R: Read>{
+    inner: Plain<R>,
+    decoder: FrameDecoder,
+}
+
+impl<R: Read> SfpReader<R>{
+    pub fn new(inner: R) -> Self{
+        Self{inner: Plain(inner), decoder: FrameDecoder::new()}
+    }
+    pub fn get_ref(&self) -> &R{
+        &self.inner.0
+    }
+    /// Reading from it directly corrupts the frame stream
+    pub fn get_mut(&mut self) -> &mut R{
+        &mut self.inner.0
+    }
+    pub fn into_inner(self) -> R{
+        self.inner.0
+    }
+    /// See `Connection::set_max_frame_len`
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize){
+        self.decoder.set_max_frame_len(max_frame_len)
+    }
+    pub fn max_frame_len(&self) -> usize{
+        self.decoder.max_frame_len()
+    }
+    /// See `Connection::set_magic_prefix`
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.decoder.set_magic_prefix(magic)
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.decoder.magic_prefix()
+    }
+    /// See `Connection::set_framing_config`
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.decoder.set_framing_config(config)
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.decoder.framing_config()
+    }
+    pub fn set_framing(&mut self, framing: Framing){
+        self.decoder.set_framing(framing)
+    }
+    pub fn framing(&self) -> Framing{
+        self.decoder.framing()
+    }
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.decoder.set_header_width(width)
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.decoder.header_width()
+    }
+    /// See `Connection::set_sequence_numbers`
+    pub fn set_sequence_numbers(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.sequence_numbers = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn sequence_numbers(&self) -> bool{
+        self.decoder.extensions().sequence_numbers
+    }
+    /// See `Connection::set_checksum`, fails the same way
+    pub fn set_checksum(&mut self, kind: ChecksumKind) -> io::Result<()>{
+        kind.check_available()?;
+        let mut extensions = self.decoder.extensions();
+        extensions.checksum = kind;
+        self.decoder.set_extensions(extensions);
+        Ok(())
+    }
+    pub fn checksum(&self) -> ChecksumKind{
+        self.decoder.extensions().checksum
+    }
+    /// See `Connection::set_frame_flags`
+    pub fn set_frame_flags(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.flags = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn frame_flags(&self) -> bool{
+        self.decoder.extensions().flags
+    }
+    /// See `Connection::set_strip_padding`
+    pub fn set_strip_padding(&mut self, strip: bool){
+        self.decoder.set_strip_padding(strip)
+    }
+    pub fn strip_padding(&self) -> bool{
+        self.decoder.strip_padding()
+    }
+    pub fn read_frame_with_flags(&mut self) -> io::Result<(Vec<u8>, FrameFlags)>{
+        let frame = self.read_frame()?;
+        Ok((frame, self.decoder.flags()))
+    }
+    pub fn read_frame_checked(&mut self) -> Result<Vec<u8>, ReadErr>{
+        self.read_frame().map_err(|err| self.decoder.read_err(err))
+    }
+}
+
+impl<R: Read> FrameReader for SfpReader<R>{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        self.decoder.read_frame_into(&mut self.inner, buf)
+    }
+    fn skip_frame(&mut self) -> io::Result<usize>{
+        self.decoder.skip_frame(&mut self.inner)
+    }
+    fn read_frame_meta(&mut self) -> io::Result<Frame>{
+        let frame = self.read_frame()?;
+        Ok(Frame::new(frame, self.decoder.meta()))
+    }
+}
+
+/// Writing counterpart of `SfpReader`
+pub type SfpWriter<W> = FrameEncoder<W>;
+
+/// Frames in both directions over any `Read + Write` transport, a TLS stream or an SSH channel,
+/// in the wire format of `Connection`. It has no socket, so there is no `ConnectionController`:
+/// timeouts and shutdown are the transport's own
+#[derive(Debug)]
+pub struct GenericConnection<S: Read + Write>{
+    inner: Plain<S>,
+    decoder: FrameDecoder,
+    state: WriteState,
+}
+
+impl<S: Read + Write> GenericConnection<S>{
+    pub fn new(inner: S) -> Self{
+        Self{inner: Plain(inner), decoder: FrameDecoder::new(), state: WriteState::default()}
+    }
+    pub fn get_ref(&self) -> &S{
+        &self.inner.0
+    }
+    /// Reading from or writing to it directly corrupts the frame stream
+    pub fn get_mut(&mut self) -> &mut S{
+        &mut self.inner.0
+    }
+    /// Bytes read ahead and frames still buffered are dropped, `flush` first
+    pub fn into_inner(self) -> S{
+        self.inner.0
+    }
+    /// Halves over two handles of the transport, for transports whose clones read and write the same stream
+    /// (`&TcpStream` is one). The reader keeps bytes read ahead, the writer the buffered frames.
+    /// TLS streams are never `Clone`: both directions share one session state, so a TLS connection
+    /// stays in one piece and reads and writes take turns on it
+    pub fn separate(self) -> (SfpReader<S>, FrameEncoder<S>) where S: Clone{
+        let writer = FrameEncoder::resume(self.inner.0.clone(), self.decoder.fresh(), self.state);
+        (SfpReader{inner: self.inner, decoder: self.decoder}, writer)
+    }
+    /// See `Connection::set_max_frame_len`
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize){
+        self.decoder.set_max_frame_len(max_frame_len)
+    }
+    pub fn max_frame_len(&self) -> usize{
+        self.decoder.max_frame_len()
+    }
+    /// See `Connection::set_magic_prefix`
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.decoder.set_magic_prefix(magic)
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.decoder.magic_prefix()
+    }
+    /// See `Connection::set_framing_config`
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.decoder.set_framing_config(config)
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.decoder.framing_config()
+    }
+    /// See `Connection::set_sequence_numbers`
+    pub fn set_sequence_numbers(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.sequence_numbers = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn sequence_numbers(&self) -> bool{
+        self.decoder.extensions().sequence_numbers
+    }
+    /// See `Connection::set_checksum`, fails the same way
+    pub fn set_checksum(&mut self, kind: ChecksumKind) -> io::Result<()>{
+        kind.check_available()?;
+        let mut extensions = self.decoder.extensions();
+        extensions.checksum = kind;
+        self.decoder.set_extensions(extensions);
+        Ok(())
+    }
+    pub fn checksum(&self) -> ChecksumKind{
+        self.decoder.extensions().checksum
+    }
+    /// See `Connection::set_frame_flags`
+    pub fn set_frame_flags(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.flags = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn frame_flags(&self) -> bool{
+        self.decoder.extensions().flags
+    }
+    /// See `Connection::set_flush_policy`
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy){
+        self.state.flush_policy = policy;
+    }
+    pub fn flush_policy(&self) -> FlushPolicy{
+        self.state.flush_policy
+    }
+    pub fn read_frame_with_flags(&mut self) -> io::Result<(Vec<u8>, FrameFlags)>{
+        let frame = self.read_frame()?;
+        Ok((frame, self.decoder.flags()))
+    }
+    pub fn read_frame_checked(&mut self) -> Result<Vec<u8>, ReadErr>{
+        self.read_frame().map_err(|err| self.decoder.read_err(err))
+    }
+    pub fn write_frame_with_flags(&mut self, frame: &[u8], flags: FrameFlags) -> Result<(), WriteErr>{
+        self.path().send_frame(frame, flags, true, false)
+    }
+    fn path(&mut self) -> WritePath<'_, &mut S>{
+        WritePath{stream: &mut self.inner.0, decoder: &self.decoder, state: &mut self.state, turn: None}
+    }
+}
+
+impl<S: Read + Write> FrameReader for GenericConnection<S>{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        self.decoder.read_frame_into(&mut self.inner, buf)
+    }
+    fn skip_frame(&mut self) -> io::Result<usize>{
+        self.decoder.skip_frame(&mut self.inner)
+    }
+    fn read_frame_meta(&mut self) -> io::Result<Frame>{
+        let frame = self.read_frame()?;
+        Ok(Frame::new(frame, self.decoder.meta()))
+    }
+}
+
+impl<S: Read + Write> FrameWriter for GenericConnection<S>{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame, FrameFlags::empty())
+    }
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame.payload(), frame.meta.flags)
+    }
+    /// Streams the payload unless a checksum is on, as `FrameEncoder` does
+    fn write_frame_from_reader(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr>{
+        if self.checksum() == ChecksumKind::None {
+            return self.path().write_streamed(src, len, 0, false)
+        }
+        let frame = read_source(src, len, false)?;
+        self.write_frame(&frame)
+    }
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr>{
+        self.path().send_vectored(parts)
+    }
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr>{
+        self.path().send_framebuf(buf)
+    }
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr>{
+        let frames: Vec<&[u8]> = frames.into_iter().collect();
+        self.path().write_frames(&frames)
+    }
+    /// Sends the buffered frames, then flushes the transport
+    fn flush(&mut self) -> io::Result<()>{
+        self.path().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Cursor;
+    use super::*;
+
+    const FRAMES: [&[u8]; 5] = [b"", b"a", &[0u8; 127], &[1u8; 128], &[2u8; 70_000]];
+
+    /// Writes `FRAMES` with `writer` settings, reads them back with `reader` settings, then expects a clean end
+    fn round_trip(writer: impl FnOnce(&mut SfpWriter<Vec<u8>>), reader: impl FnOnce(&mut SfpReader<Cursor<Vec<u8>>>)){
+        let mut encoder = SfpWriter::new(Vec::new());
+        writer(&mut encoder);
+        for frame in FRAMES {
+            encoder.write_frame(frame).unwrap();
+        }
+        encoder.flush().unwrap();
+        let mut decoder = SfpReader::new(Cursor::new(encoder.into_inner()));
+        reader(&mut decoder);
+        for frame in FRAMES {
+            assert_eq!(decoder.read_frame().unwrap(), frame);
+        }
+        assert!(matches!(decoder.read_frame_checked(), Err(ReadErr::Disconnected)));
+    }
+
+    #[test]
+    fn frames_round_trip_through_a_cursor(){
+        round_trip(|_| {}, |_| {});
+        round_trip(|w| w.set_framing(Framing::Varint), |r| r.set_framing(Framing::Varint));
+        round_trip(|w| w.set_header_width(HeaderWidth::U64), |r| r.set_header_width(HeaderWidth::U64));
+        round_trip(|w| w.set_magic_prefix(true), |r| r.set_magic_prefix(true));
+        round_trip(
+            |w| {
+                w.set_sequence_numbers(true);
+                w.set_checksum(ChecksumKind::Crc32).unwrap();
+                w.set_frame_flags(true);
+            },
+            |r| {
+                r.set_sequence_numbers(true);
+                r.set_checksum(ChecksumKind::Crc32).unwrap();
+                r.set_frame_flags(true);
+            },
+        );
+    }
+
+    #[test]
+    fn buffered_frames_round_trip_once_flushed(){
+        round_trip(|w| w.set_flush_policy(FlushPolicy::explicit()), |_| {});
+    }
+
+    #[test]
+    fn frames_past_the_max_frame_len_are_rejected(){
+        let mut encoder = SfpWriter::new(Vec::new());
+        for frame in [&[1u8; 100][..], &[2u8; 101], b"after"] {
+            encoder.write_frame(frame).unwrap();
+        }
+        let mut reader = SfpReader::new(Cursor::new(encoder.into_inner()));
+        reader.set_max_frame_len(100);
+        assert_eq!(reader.max_frame_len(), 100);
+        assert_eq!(reader.read_frame().unwrap(), [1u8; 100]);
+        for _ in 0..2 {
+            match reader.read_frame_checked() {
+                Err(ReadErr::TooLongFrame{length, max_frame_len}) => assert_eq!((length, max_frame_len), (101, 100)),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn stream_cut_inside_a_frame_is_truncated(){
+        let mut encoder = SfpWriter::new(Vec::new());
+        encoder.write_frame(b"whole").unwrap();
+        encoder.write_frame(b"cut short").unwrap();
+        let mut bytes = encoder.into_inner();
+        bytes.truncate(bytes.len() - 3);
+        let mut reader = SfpReader::new(Cursor::new(bytes));
+        assert_eq!(reader.read_frame().unwrap(), b"whole");
+        assert!(matches!(reader.read_frame_checked(), Err(ReadErr::TruncatedFrame{expected: 9, got: 6})));
+    }
+
+    /// One end of an in-memory transport: reads what the other end wrote, in the order written
+    #[derive(Debug, Default)]
+    struct CursorEnd{
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
+
+    impl Read for CursorEnd{
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for CursorEnd{
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Moves what `from` wrote so far to the end of what `to` reads
+    fn deliver(from: &mut GenericConnection<CursorEnd>, to: &mut GenericConnection<CursorEnd>){
+        let written = std::mem::take(&mut from.get_mut().outgoing);
+        to.get_mut().incoming.get_mut().extend_from_slice(&written);
+    }
+
+    fn generic_pair() -> (GenericConnection<CursorEnd>, GenericConnection<CursorEnd>){
+        (GenericConnection::new(CursorEnd::default()), GenericConnection::new(CursorEnd::default()))
+    }
+
+    #[test]
+    fn generic_connections_round_trip_both_ways(){
+        let (mut a, mut b) = generic_pair();
+        for connection in [&mut a, &mut b] {
+            connection.set_sequence_numbers(true);
+            connection.set_checksum(ChecksumKind::Crc32).unwrap();
+            connection.set_frame_flags(true);
+        }
+        a.write_frame(b"to b").unwrap();
+        a.write_frame_vectored(&[io::IoSlice::new(b"vec"), io::IoSlice::new(b""), io::IoSlice::new(b"tored")]).unwrap();
+        a.write_frame_with_flags(b"flagged", FrameFlags::from(crate::FrameFlag::Application)).unwrap();
+        let mut buf = FrameBuf::new();
+        buf.extend_from_slice(b"framebuf");
+        a.write_framebuf(&mut buf).unwrap();
+        b.write_frame(b"to a").unwrap();
+        deliver(&mut a, &mut b);
+        deliver(&mut b, &mut a);
+        assert_eq!(b.read_frame().unwrap(), b"to b");
+        assert_eq!(b.read_frame().unwrap(), b"vectored");
+        assert_eq!(b.read_frame_with_flags().unwrap(), (b"flagged".to_vec(), FrameFlags::from(crate::FrameFlag::Application)));
+        assert_eq!(b.read_frame().unwrap(), b"framebuf");
+        assert_eq!(a.read_frame().unwrap(), b"to a");
+        assert!(matches!(b.read_frame_checked(), Err(ReadErr::Disconnected)));
+    }
+
+    #[test]
+    fn generic_connection_matches_the_sfp_writer_on_the_wire(){
+        let (mut a, _b) = generic_pair();
+        let mut writer = SfpWriter::new(Vec::new());
+        for frame in FRAMES {
+            a.write_frame(frame).unwrap();
+            writer.write_frame(frame).unwrap();
+        }
+        assert_eq!(a.get_ref().outgoing, writer.into_inner());
+    }
+
+    #[test]
+    fn generic_connection_holds_buffered_frames_until_flushed(){
+        let (mut a, mut b) = generic_pair();
+        a.set_flush_policy(FlushPolicy::explicit());
+        a.write_frame(b"one").unwrap();
+        a.write_frame(b"two").unwrap();
+        assert!(a.get_ref().outgoing.is_empty());
+        a.flush().unwrap();
+        deliver(&mut a, &mut b);
+        assert_eq!(b.read_frame().unwrap(), b"one");
+        assert_eq!(b.read_frame().unwrap(), b"two");
+    }
+
+    #[test]
+    fn generic_connection_reports_truncated_and_too_long_frames(){
+        let (mut a, mut b) = generic_pair();
+        a.write_frame(&[5u8; 1000]).unwrap();
+        let bytes = std::mem::take(&mut a.get_mut().outgoing);
+        b.get_mut().incoming.get_mut().extend_from_slice(&bytes[..300]);
+        assert!(matches!(b.read_frame_checked(), Err(ReadErr::TruncatedFrame{expected: 1000, got: 296})));
+        let (mut a, mut b) = generic_pair();
+        a.set_max_frame_len(10);
+        b.set_max_frame_len(10);
+        a.write_frame(&[1u8; 11]).unwrap();
+        deliver(&mut a, &mut b);
+        assert!(matches!(b.read_frame_checked(), Err(ReadErr::TooLongFrame{length: 11, max_frame_len: 10})));
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use unisocket::Stream;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+/// Error (wrapped into an `io::Error`) returned by reads of a connection whose `CancelToken` was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Cancelled{
+    fn error() -> io::Error{
+        io::Error::other(Cancelled)
+    }
+    pub fn is_cancelled(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+    }
+}
+
+impl fmt::Display for Cancelled{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Read was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled{}
+
+#[derive(Debug)]
+struct CancelState{
+    cancelled: AtomicBool,
+    /// Self-pipe: becomes readable once cancelled, waking up a blocked reader
+    #[cfg(unix)]
+    pipe: [RawFd; 2],
+}
+
+impl Drop for CancelState{
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::close(self.pipe[0]);
+            libc::close(self.pipe[1]);
+        }
+    }
+}
+
+/// Stops reads of a connection from any thread without closing it, see `Connection::cancel_token`.
+/// Cancellation is permanent: every following read that needs the socket fails with `Cancelled`,
+/// frames already received are still delivered.
+/// Where self-pipes are unavailable a blocked read only notices the cancellation
+/// when it returns on its own
+#[derive(Debug, Clone)]
+pub struct CancelToken{
+    state: Arc<CancelState>,
+}
+
+impl CancelToken{
+    pub(crate) fn new() -> io::Result<Self>{
+        Ok(Self{state: Arc::new(CancelState{
+            cancelled: AtomicBool::new(false),
+            #[cfg(unix)]
+            pipe: self_pipe()?,
+        })})
+    }
+    pub fn cancel(&self){
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        #[cfg(unix)]
+        unsafe {
+            // The pipe is never drained, one byte keeps it readable for good
+            libc::write(self.state.pipe[1], [1u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+    pub fn is_cancelled(&self) -> bool{
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+    /// Waits until `stream` is readable, as long as its read timeout allows
+    #[cfg(unix)]
+    pub(crate) fn wait_readable(&self, stream: &Stream) -> io::Result<()>{
+        if self.is_cancelled() {
+            return Err(Cancelled::error())
+        }
+        let fd = crate::stream_fd(stream);
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        if flags & libc::O_NONBLOCK != 0 {
+            return Ok(())
+        }
+        let timeout = match crate::stream_read_timeout(stream)? {
+            Some(t) => t.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        let mut fds = [
+            libc::pollfd{fd, events: libc::POLLIN, revents: 0},
+            libc::pollfd{fd: self.state.pipe[0], events: libc::POLLIN, revents: 0},
+        ];
+        match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            0 => Err(io::ErrorKind::WouldBlock.into()),
+            _ if fds[1].revents != 0 => Err(Cancelled::error()),
+            _ => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    pub(crate) fn wait_readable(&self, _stream: &Stream) -> io::Result<()>{
+        if self.is_cancelled() {
+            return Err(Cancelled::error())
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn self_pipe() -> io::Result<[RawFd; 2]>{
+    let mut pipe = [0 as RawFd; 2];
+    if unsafe { libc::pipe(pipe.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    for fd in pipe.iter() {
+        unsafe {
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+        }
+    }
+    Ok(pipe)
+}
+
+#[cfg(all(test, unix))]
+mod tests{
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::{Connection, FrameReader, FrameWriter, Cancelled, ReadErr};
+
+    #[test]
+    fn blocked_read_wakes_up_and_the_connection_still_writes(){
+        let (mut a, mut b) = Connection::pair().unwrap();
+        let token = b.cancel_token().unwrap();
+        let reader = thread::spawn(move || {
+            let result = b.read_frame();
+            (b, result, Instant::now())
+        });
+        thread::sleep(Duration::from_millis(100));
+        let cancelled_at = Instant::now();
+        token.cancel();
+        let (mut b, result, woke_at) = reader.join().unwrap();
+        assert!(Cancelled::is_cancelled(&result.unwrap_err()));
+        assert!(woke_at.duration_since(cancelled_at) < Duration::from_millis(500));
+        assert!(matches!(b.read_frame_checked(), Err(ReadErr::Cancelled)));
+        b.write_frame(b"still writing").unwrap();
+        assert_eq!(a.read_frame().unwrap(), b"still writing");
+    }
+
+    #[test]
+    fn frames_received_before_the_cancellation_are_delivered(){
+        let (mut a, mut b) = Connection::pair().unwrap();
+        a.write_frames([&b"one"[..], b"two"]).unwrap();
+        assert_eq!(b.read_frame().unwrap(), b"one");
+        b.cancel_token().unwrap().cancel();
+        assert_eq!(b.read_frame().unwrap(), b"two");
+        assert!(Cancelled::is_cancelled(&b.read_frame().unwrap_err()));
+    }
+}
@@ -0,0 +1,468 @@
+use std::io;
+
+/// Checksum algorithm of the frames of a connection, see `Connection::set_checksum`.
+/// `Crc32c` and `XxHash64` are only available with the cargo features of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumKind{
+    #[default]
+    None,
+    /// CRC-32 (IEEE 802.3, as used by zlib and Ethernet)
+    Crc32,
+    /// CRC-32C (Castagnoli), hardware accelerated on x86_64 with SSE 4.2
+    Crc32c,
+    /// 64 bit xxHash with seed 0
+    XxHash64,
+}
+
+impl ChecksumKind{
+    /// Whether this build has the algorithm
+    pub fn is_available(self) -> bool{
+        match self {
+            ChecksumKind::None | ChecksumKind::Crc32 => true,
+            ChecksumKind::Crc32c => cfg!(feature = "crc32c"),
+            ChecksumKind::XxHash64 => cfg!(feature = "xxhash64"),
+        }
+    }
+    /// `Unsupported` unless the algorithm is available
+    pub(crate) fn check_available(self) -> io::Result<()>{
+        if !self.is_available() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("checksum {:?} needs its cargo feature", self)))
+        }
+        Ok(())
+    }
+    /// Bytes of the digest sent in every header
+    pub fn digest_len(self) -> usize{
+        match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32 | ChecksumKind::Crc32c => Crc32::DIGEST_LEN,
+            ChecksumKind::XxHash64 => 8,
+        }
+    }
+    pub(crate) fn code(self) -> u8{
+        match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32 => 1,
+            ChecksumKind::Crc32c => 2,
+            ChecksumKind::XxHash64 => 3,
+        }
+    }
+    pub(crate) fn from_code(code: u8) -> Option<Self>{
+        match code {
+            0 => Some(ChecksumKind::None),
+            1 => Some(ChecksumKind::Crc32),
+            2 => Some(ChecksumKind::Crc32c),
+            3 => Some(ChecksumKind::XxHash64),
+            _ => None,
+        }
+    }
+}
+
+/// Incremental digest of a payload
+pub trait FrameHasher{
+    /// Bytes of the digest, at most 8
+    const DIGEST_LEN: usize;
+    fn init() -> Self;
+    fn update(&mut self, bytes: &[u8]);
+    /// Sent as the `DIGEST_LEN` low bytes, big-endian
+    fn finalize(self) -> u64;
+}
+
+/// Hasher of any available kind
+#[derive(Debug, Clone)]
+pub(crate) enum Hasher{
+    Crc32(Crc32),
+    #[cfg(feature = "crc32c")]
+    Crc32c(Crc32c),
+    #[cfg(feature = "xxhash64")]
+    XxHash64(XxHash64),
+}
+
+impl Hasher{
+    pub(crate) fn new(kind: ChecksumKind) -> Option<Self>{
+        match kind {
+            ChecksumKind::Crc32 => Some(Hasher::Crc32(Crc32::init())),
+            #[cfg(feature = "crc32c")]
+            ChecksumKind::Crc32c => Some(Hasher::Crc32c(Crc32c::init())),
+            #[cfg(feature = "xxhash64")]
+            ChecksumKind::XxHash64 => Some(Hasher::XxHash64(XxHash64::init())),
+            _ => None,
+        }
+    }
+    pub(crate) fn update(&mut self, bytes: &[u8]){
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(bytes),
+            #[cfg(feature = "crc32c")]
+            Hasher::Crc32c(hasher) => hasher.update(bytes),
+            #[cfg(feature = "xxhash64")]
+            Hasher::XxHash64(hasher) => hasher.update(bytes),
+        }
+    }
+    pub(crate) fn finalize(self) -> u64{
+        match self {
+            Hasher::Crc32(hasher) => hasher.finalize(),
+            #[cfg(feature = "crc32c")]
+            Hasher::Crc32c(hasher) => hasher.finalize(),
+            #[cfg(feature = "xxhash64")]
+            Hasher::XxHash64(hasher) => hasher.finalize(),
+        }
+    }
+}
+
+/// Digest of `bytes` with `kind`, which must be available
+pub(crate) fn digest_kind(kind: ChecksumKind, bytes: &[u8]) -> u64{
+    let mut hasher = Hasher::new(kind).expect("checksum kind not available");
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+/// Slicing-by-8 tables of a reflected CRC-32 polynomial
+#[cfg(any(feature = "crc32c", not(feature = "crc32fast")))]
+const fn crc_tables(polynomial: u32) -> [[u32; 256]; 8]{
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ polynomial } else { crc >> 1 };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut t = 1;
+    while t < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let previous = tables[t - 1][i];
+            tables[t][i] = (previous >> 8) ^ tables[0][(previous & 0xFF) as usize];
+            i += 1;
+        }
+        t += 1;
+    }
+    tables
+}
+
+#[cfg(any(feature = "crc32c", not(feature = "crc32fast")))]
+fn crc_update(tables: &[[u32; 256]; 8], mut crc: u32, bytes: &[u8]) -> u32{
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let low = crc ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        crc = tables[7][(low & 0xFF) as usize]
+            ^ tables[6][((low >> 8) & 0xFF) as usize]
+            ^ tables[5][((low >> 16) & 0xFF) as usize]
+            ^ tables[4][(low >> 24) as usize]
+            ^ tables[3][chunk[4] as usize]
+            ^ tables[2][chunk[5] as usize]
+            ^ tables[1][chunk[6] as usize]
+            ^ tables[0][chunk[7] as usize];
+    }
+    for byte in chunks.remainder() {
+        crc = (crc >> 8) ^ tables[0][((crc ^ *byte as u32) & 0xFF) as usize];
+    }
+    crc
+}
+
+#[cfg(not(feature = "crc32fast"))]
+static CRC32_TABLES: [[u32; 256]; 8] = crc_tables(0xEDB8_8320);
+
+/// Computed by `crc32fast` with the default feature of the same name, which folds with carry-less
+/// multiplication where the CPU has it. Without it by slicing-by-8, several times slower
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl FrameHasher for Crc32{
+    const DIGEST_LEN: usize = 4;
+    fn init() -> Self{
+        Crc32(!0)
+    }
+    fn update(&mut self, bytes: &[u8]){
+        #[cfg(feature = "crc32fast")]
+        {
+            // crc32fast keeps the finalized value
+            let mut hasher = crc32fast::Hasher::new_with_initial(!self.0);
+            hasher.update(bytes);
+            self.0 = !hasher.finalize();
+        }
+        #[cfg(not(feature = "crc32fast"))]
+        {
+            self.0 = crc_update(&CRC32_TABLES, self.0, bytes);
+        }
+    }
+    fn finalize(self) -> u64{
+        !self.0 as u64
+    }
+}
+
+#[cfg(feature = "crc32c")]
+static CRC32C_TABLES: [[u32; 256]; 8] = crc_tables(0x82F6_3B78);
+
+#[cfg(feature = "crc32c")]
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32c(u32);
+
+#[cfg(feature = "crc32c")]
+impl FrameHasher for Crc32c{
+    const DIGEST_LEN: usize = 4;
+    fn init() -> Self{
+        Crc32c(!0)
+    }
+    fn update(&mut self, bytes: &[u8]){
+        #[cfg(target_arch = "x86_64")]
+        {
+            if std::is_x86_feature_detected!("sse4.2") {
+                // Checked just above
+                self.0 = unsafe { crc32c_sse42(self.0, bytes) };
+                return
+            }
+        }
+        self.0 = crc_update(&CRC32C_TABLES, self.0, bytes);
+    }
+    fn finalize(self) -> u64{
+        !self.0 as u64
+    }
+}
+
+#[cfg(all(feature = "crc32c", target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(crc: u32, bytes: &[u8]) -> u32{
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    let mut crc = crc as u64;
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word));
+    }
+    let mut crc = crc as u32;
+    for byte in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, *byte);
+    }
+    crc
+}
+
+#[cfg(feature = "xxhash64")]
+const PRIME64: [u64; 5] = [
+    0x9E37_79B1_85EB_CA87,
+    0xC2B2_AE3D_27D4_EB4F,
+    0x1656_67B1_9E37_79F9,
+    0x85EB_CA77_C2B2_AE63,
+    0x27D4_EB2F_1656_67C5,
+];
+
+#[cfg(feature = "xxhash64")]
+#[derive(Debug, Clone)]
+pub struct XxHash64{
+    accumulators: [u64; 4],
+    /// Bytes short of a 32 byte stripe
+    pending: [u8; 32],
+    pending_len: usize,
+    total_len: u64,
+}
+
+#[cfg(feature = "xxhash64")]
+fn xxh64_round(accumulator: u64, lane: u64) -> u64{
+    accumulator.wrapping_add(lane.wrapping_mul(PRIME64[1])).rotate_left(31).wrapping_mul(PRIME64[0])
+}
+
+#[cfg(feature = "xxhash64")]
+fn xxh64_lane(bytes: &[u8]) -> u64{
+    let mut lane = [0u8; 8];
+    lane.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(lane)
+}
+
+#[cfg(feature = "xxhash64")]
+impl XxHash64{
+    fn stripe(&mut self, stripe: &[u8]){
+        for (i, accumulator) in self.accumulators.iter_mut().enumerate() {
+            *accumulator = xxh64_round(*accumulator, xxh64_lane(&stripe[i * 8..]));
+        }
+    }
+}
+
+#[cfg(feature = "xxhash64")]
+impl FrameHasher for XxHash64{
+    const DIGEST_LEN: usize = 8;
+    fn init() -> Self{
+        XxHash64{
+            accumulators: [
+                PRIME64[0].wrapping_add(PRIME64[1]),
+                PRIME64[1],
+                0,
+                PRIME64[0].wrapping_neg(),
+            ],
+            pending: [0u8; 32],
+            pending_len: 0,
+            total_len: 0,
+        }
+    }
+    fn update(&mut self, mut bytes: &[u8]){
+        self.total_len += bytes.len() as u64;
+        if self.pending_len > 0 {
+            let n = bytes.len().min(32 - self.pending_len);
+            self.pending[self.pending_len..self.pending_len + n].copy_from_slice(&bytes[..n]);
+            self.pending_len += n;
+            bytes = &bytes[n..];
+            if self.pending_len < 32 {
+                return
+            }
+            let pending = self.pending;
+            self.stripe(&pending);
+            self.pending_len = 0;
+        }
+        let mut stripes = bytes.chunks_exact(32);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.pending[..rest.len()].copy_from_slice(rest);
+        self.pending_len = rest.len();
+    }
+    fn finalize(self) -> u64{
+        let [v1, v2, v3, v4] = self.accumulators;
+        let mut hash = if self.total_len >= 32 {
+            let mut hash = v1.rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            for accumulator in self.accumulators.iter() {
+                hash = (hash ^ xxh64_round(0, *accumulator)).wrapping_mul(PRIME64[0]).wrapping_add(PRIME64[3]);
+            }
+            hash
+        } else {
+            PRIME64[4]
+        };
+        hash = hash.wrapping_add(self.total_len);
+        let mut rest = &self.pending[..self.pending_len];
+        while rest.len() >= 8 {
+            hash ^= xxh64_round(0, xxh64_lane(rest));
+            hash = hash.rotate_left(27).wrapping_mul(PRIME64[0]).wrapping_add(PRIME64[3]);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            let lane = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as u64;
+            hash ^= lane.wrapping_mul(PRIME64[0]);
+            hash = hash.rotate_left(23).wrapping_mul(PRIME64[1]).wrapping_add(PRIME64[2]);
+            rest = &rest[4..];
+        }
+        for byte in rest {
+            hash ^= (*byte as u64).wrapping_mul(PRIME64[4]);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64[0]);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64[1]);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64[2]);
+        hash ^ (hash >> 32)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::{ChecksumMismatch, FrameEncoder, FrameReader, FrameWriter, SfpReader};
+
+    const KINDS: [ChecksumKind; 3] = [ChecksumKind::Crc32, ChecksumKind::Crc32c, ChecksumKind::XxHash64];
+
+    fn available() -> impl Iterator<Item = ChecksumKind>{
+        KINDS.iter().copied().filter(|kind| kind.is_available())
+    }
+
+    /// Payloads of every length around the 8 and 32 byte strides
+    fn payloads() -> Vec<Vec<u8>>{
+        let mut state = 0x9E37_79B9u32;
+        (0..200).chain([1000, 4096, 65_537]).map(|len| {
+            (0..len).map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            }).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn known_answers(){
+        assert_eq!(digest_kind(ChecksumKind::Crc32, b"123456789"), 0xCBF4_3926);
+        assert_eq!(digest_kind(ChecksumKind::Crc32, b""), 0);
+        #[cfg(feature = "crc32c")]
+        assert_eq!(digest_kind(ChecksumKind::Crc32c, b"123456789"), 0xE306_9283);
+        #[cfg(feature = "xxhash64")]
+        {
+            assert_eq!(digest_kind(ChecksumKind::XxHash64, b""), 0xEF46_DB37_51D8_E999);
+            assert_eq!(digest_kind(ChecksumKind::XxHash64, b"a"), 0xD24E_C4F1_A98C_6E5B);
+            assert_eq!(digest_kind(ChecksumKind::XxHash64, b"abc"), 0x44BC_2CF5_AD77_0999);
+        }
+    }
+
+    #[test]
+    fn digests_match_the_reference_implementations(){
+        for payload in payloads() {
+            assert_eq!(digest_kind(ChecksumKind::Crc32, &payload), crc32fast::hash(&payload) as u64);
+            #[cfg(feature = "crc32c")]
+            assert_eq!(digest_kind(ChecksumKind::Crc32c, &payload), crc32c::crc32c(&payload) as u64);
+            #[cfg(feature = "xxhash64")]
+            assert_eq!(digest_kind(ChecksumKind::XxHash64, &payload), xxhash_rust::xxh64::xxh64(&payload, 0));
+        }
+    }
+
+    #[test]
+    fn split_updates_match_one_update(){
+        let payload = payloads().pop().unwrap();
+        for kind in available() {
+            let whole = digest_kind(kind, &payload);
+            for split in [1, 7, 8, 31, 32, 33, 4095] {
+                let mut hasher = Hasher::new(kind).unwrap();
+                for piece in payload.chunks(split) {
+                    hasher.update(piece);
+                }
+                assert_eq!(hasher.finalize(), whole, "{:?} in pieces of {}", kind, split);
+            }
+        }
+    }
+
+    #[test]
+    fn every_single_bit_flip_changes_the_digest(){
+        let payload = payloads()[64].clone();
+        for kind in available() {
+            let digest = digest_kind(kind, &payload);
+            for bit in 0..payload.len() * 8 {
+                let mut flipped = payload.clone();
+                flipped[bit / 8] ^= 1 << (bit % 8);
+                assert_ne!(digest_kind(kind, &flipped), digest, "{:?} bit {}", kind, bit);
+            }
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_and_flipped_payloads_are_caught(){
+        for kind in available() {
+            let payload = payloads()[100].clone();
+            let mut encoder = FrameEncoder::new(Vec::new());
+            encoder.set_checksum(kind).unwrap();
+            encoder.write_frame(&payload).unwrap();
+            let bytes = encoder.into_inner();
+
+            let mut reader = SfpReader::new(&bytes[..]);
+            reader.set_checksum(kind).unwrap();
+            assert_eq!(reader.read_frame().unwrap(), payload);
+
+            // The payload ends the frame
+            for at in bytes.len() - payload.len()..bytes.len() {
+                let mut corrupted = bytes.clone();
+                corrupted[at] ^= 0x01;
+                let mut reader = SfpReader::new(&corrupted[..]);
+                reader.set_checksum(kind).unwrap();
+                assert!(ChecksumMismatch::is_checksum_mismatch(&reader.read_frame().unwrap_err()), "{:?} byte {}", kind, at);
+            }
+        }
+    }
+
+    #[test]
+    fn unavailable_kinds_are_unsupported(){
+        for kind in KINDS.iter().copied().filter(|kind| !kind.is_available()) {
+            let mut encoder = FrameEncoder::new(Vec::new());
+            assert_eq!(encoder.set_checksum(kind).unwrap_err().kind(), io::ErrorKind::Unsupported);
+            assert_eq!(encoder.checksum(), ChecksumKind::None);
+        }
+    }
+}
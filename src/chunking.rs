@@ -0,0 +1,127 @@
+use std::io;
+use std::io::{Read, BufRead, Write};
+use crate::{ConnectionWriter, ConnectionReader, FrameWriter, FrameReader};
+
+/// Sends a byte stream as frames of `chunk_size` bytes, see `ConnectionWriter::into_chunking_writer`.
+/// `flush` sends what is left as a shorter frame. Bytes not sent yet are lost when it is dropped,
+/// use `flush` or `finish`
+pub struct ChunkingWriter{
+    writer: ConnectionWriter,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl ChunkingWriter{
+    pub(crate) fn new(writer: ConnectionWriter, chunk_size: usize) -> Self{
+        let chunk_size = chunk_size.max(1);
+        Self{writer, chunk_size, buffer: Vec::with_capacity(chunk_size)}
+    }
+    pub fn chunk_size(&self) -> usize{
+        self.chunk_size
+    }
+    /// Sends what is left, then a zero-length frame if `terminator` so that the peer sees the stream end,
+    /// and gives the writer back
+    pub fn finish(mut self, terminator: bool) -> io::Result<ConnectionWriter>{
+        self.send_buffer()?;
+        if terminator {
+            self.writer.write_keepalive()?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+    fn send_buffer(&mut self) -> io::Result<()>{
+        if self.buffer.is_empty() {
+            return Ok(())
+        }
+        let pending = self.writer.is_write_pending();
+        let result = self.writer.write_frame(&self.buffer);
+        // A frame that counts as written, kept for `resume_write` or cut short, must not be sent again
+        if result.is_ok() || (!pending && self.writer.is_write_pending()) || self.writer.is_desynced() {
+            self.buffer.clear();
+        }
+        Ok(result?)
+    }
+}
+
+/// A chunk that fills up is sent at once. If that fails the bytes are still taken,
+/// the next `write` or `flush` retries the chunk and reports the error
+impl Write for ChunkingWriter{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() == self.chunk_size {
+            self.send_buffer()?;
+        }
+        // Whole chunks skip the buffer
+        if self.buffer.is_empty() && buf.len() >= self.chunk_size {
+            let pending = self.writer.is_write_pending();
+            return match self.writer.write_frame(&buf[..self.chunk_size]) {
+                Ok(()) => Ok(self.chunk_size),
+                // Kept for `resume_write`, so taken
+                Err(_) if !pending && self.writer.is_write_pending() => Ok(self.chunk_size),
+                Err(err) => Err(err.into()),
+            }
+        }
+        let n = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.chunk_size {
+            let _ = self.send_buffer();
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()?;
+        self.writer.flush()
+    }
+}
+
+/// Reads the payloads of incoming frames as one byte stream, see `ConnectionReader::into_byte_reader`.
+/// Read errors are returned as they happen, timeouts can be retried
+pub struct FrameByteReader{
+    reader: ConnectionReader,
+    frame: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl FrameByteReader{
+    pub(crate) fn new(reader: ConnectionReader) -> Self{
+        Self{reader, frame: Vec::new(), pos: 0, done: false}
+    }
+    /// True once the zero-length frame or the end of the connection was read
+    pub fn is_finished(&self) -> bool{
+        self.done
+    }
+    /// Gives the reader back, bytes of the current frame not read yet are dropped
+    pub fn into_inner(self) -> ConnectionReader{
+        self.reader
+    }
+}
+
+impl Read for FrameByteReader{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for FrameByteReader{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.frame.len() && !self.done {
+            self.pos = 0;
+            self.frame.clear();
+            match self.reader.read_frame_into(&mut self.frame) {
+                Ok(0) => self.done = true,
+                Ok(_) => {}
+                // A close between frames ends the stream, one inside a frame is an error
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !self.reader.connection.decoder.is_mid_frame() => {
+                    self.done = true
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(&self.frame[self.pos..])
+    }
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.frame.len());
+    }
+}
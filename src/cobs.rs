@@ -0,0 +1,325 @@
+//! COBS framing: every frame is COBS encoded and ends with a zero byte,
+//! so a reader recovers at the next zero whatever came before it
+use std::io;
+use std::io::{Read, Write};
+use std::fmt;
+use std::fmt::Formatter;
+use crate::{FrameReader, FrameWriter, WriteErr, FrameTooLong, DEFAULT_MAX_FRAME_LEN};
+use crate::decoder::eof_error;
+use crate::source::{ChunkBuf, CHUNK_BUF_SIZE};
+
+const DELIMITER: u8 = 0;
+/// Longest run of non-zero bytes a single COBS block carries
+const MAX_RUN: usize = 254;
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by `CobsConnection` reads when a delimiter
+/// cuts a block short. The damaged frame is dropped, the next read starts after the delimiter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptFrame{
+    /// Bytes of the frame decoded before the delimiter
+    pub decoded: usize,
+}
+
+impl CorruptFrame{
+    pub fn is_corrupt_frame(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<CorruptFrame>())
+    }
+}
+
+impl fmt::Display for CorruptFrame{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "COBS frame cut short by a delimiter after {} bytes", self.decoded)
+    }
+}
+
+impl std::error::Error for CorruptFrame{}
+
+/// Appends the COBS encoding of `frame` followed by the delimiter to `out`
+pub fn encode(frame: &[u8], out: &mut Vec<u8>){
+    out.reserve(frame.len() + frame.len() / MAX_RUN + 2);
+    let mut code_at = out.len();
+    out.push(1);
+    for (i, &byte) in frame.iter().enumerate() {
+        if byte == DELIMITER {
+            code_at = out.len();
+            out.push(1);
+            continue
+        }
+        out.push(byte);
+        out[code_at] += 1;
+        // A full run at the very end needs no empty block after it
+        if out[code_at] as usize == MAX_RUN + 1 && i + 1 < frame.len() {
+            code_at = out.len();
+            out.push(1);
+        }
+    }
+    out.push(DELIMITER);
+}
+
+/// Incremental COBS decoder, fed any slices of the encoded stream
+#[derive(Debug, Clone)]
+struct Decoder{
+    frame: Vec<u8>,
+    /// Bytes of the current block still to come, or 0 at a code byte
+    block_left: u8,
+    /// The block just finished stands for a zero unless the frame ends there
+    zero_pending: bool,
+    /// Whether a code byte of the current frame was seen
+    started: bool,
+    /// Decoded length of an overlong frame being skipped
+    overlong: Option<usize>,
+}
+
+impl Decoder{
+    fn new() -> Self{
+        Self{frame: Vec::new(), block_left: 0, zero_pending: false, started: false, overlong: None}
+    }
+    fn reset(&mut self){
+        self.frame.clear();
+        self.block_left = 0;
+        self.zero_pending = false;
+        self.started = false;
+        self.overlong = None;
+    }
+    fn push(&mut self, byte: u8, max_frame_len: usize){
+        match &mut self.overlong {
+            Some(length) => *length += 1,
+            None if self.frame.len() == max_frame_len => self.overlong = Some(max_frame_len + 1),
+            None => self.frame.push(byte),
+        }
+    }
+    /// Consumes bytes of `input` up to the end of a frame, returning how many.
+    /// `Some` once the frame in `self.frame` is complete or has failed
+    fn feed(&mut self, input: &[u8], max_frame_len: usize) -> (usize, Option<io::Result<()>>){
+        for (i, &byte) in input.iter().enumerate() {
+            if byte == DELIMITER {
+                if !self.started {
+                    // Empty delimiters carry no frame, they only resynchronize
+                    continue
+                }
+                let result = if self.block_left != 0 {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, CorruptFrame{decoded: self.frame.len()}))
+                } else if let Some(length) = self.overlong {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, FrameTooLong{length, max_frame_len}))
+                } else {
+                    Ok(())
+                };
+                return (i + 1, Some(result))
+            }
+            self.started = true;
+            if self.block_left == 0 {
+                if self.zero_pending {
+                    self.push(0, max_frame_len);
+                }
+                self.block_left = byte - 1;
+                self.zero_pending = byte as usize != MAX_RUN + 1;
+            } else {
+                self.push(byte, max_frame_len);
+                self.block_left -= 1;
+            }
+        }
+        (input.len(), None)
+    }
+}
+
+/// Frames over any byte stream with COBS encoding and zero delimiters instead of length prefixes.
+/// A damaged frame costs only itself: reads resume at the next delimiter
+#[derive(Debug)]
+pub struct CobsConnection<S: Read + Write>{
+    stream: S,
+    decoder: Decoder,
+    max_frame_len: usize,
+    read_buf: ChunkBuf,
+    write_buf: Vec<u8>,
+}
+
+impl<S: Read + Write> CobsConnection<S>{
+    pub fn new(stream: S) -> Self{
+        Self{
+            stream,
+            decoder: Decoder::new(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            read_buf: ChunkBuf::new(CHUNK_BUF_SIZE),
+            write_buf: Vec::new(),
+        }
+    }
+    /// Longer frames are skipped up to their delimiter and reported with `FrameTooLong`
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize){
+        self.max_frame_len = max_frame_len;
+    }
+    pub fn max_frame_len(&self) -> usize{
+        self.max_frame_len
+    }
+    pub fn get_ref(&self) -> &S{
+        &self.stream
+    }
+    /// Reading from the stream directly loses the framing
+    pub fn get_mut(&mut self) -> &mut S{
+        &mut self.stream
+    }
+    /// Iterates over frames until the stream ends between frames. Damaged and overlong frames,
+    /// timeouts and `WouldBlock` are yielded as errors and iteration goes on,
+    /// any other error is yielded once and ends the iteration
+    pub fn frames(&mut self) -> CobsFrames<'_, S>{
+        CobsFrames{connection: self, done: false}
+    }
+}
+
+impl<S: Read + Write> FrameReader for CobsConnection<S>{
+    /// After `WouldBlock` or a timeout the next call resumes the same frame
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        loop {
+            if self.read_buf.fill(&mut self.stream)? == 0 {
+                let mid_frame = self.decoder.started;
+                self.decoder.reset();
+                return Err(eof_error(mid_frame))
+            }
+            let (used, done) = self.decoder.feed(self.read_buf.unread(), self.max_frame_len);
+            self.read_buf.consume(used);
+            if let Some(result) = done {
+                let result = result.map(|()| {
+                    buf.clear();
+                    buf.extend_from_slice(&self.decoder.frame);
+                    buf.len()
+                });
+                self.decoder.reset();
+                return result
+            }
+        }
+    }
+}
+
+/// Error-aware frame iterator, see `CobsConnection::frames`
+#[derive(Debug)]
+pub struct CobsFrames<'a, S: Read + Write>{
+    connection: &'a mut CobsConnection<S>,
+    done: bool,
+}
+
+impl<S: Read + Write> Iterator for CobsFrames<'_, S>{
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        match self.connection.read_frame() {
+            Ok(frame) => Some(Ok(frame)),
+            Err(err) if crate::decoder::is_closed(&err) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = !crate::is_timeout(&err) && err.kind() != io::ErrorKind::Interrupted
+                    && !CorruptFrame::is_corrupt_frame(&err) && !err.get_ref().is_some_and(|inner| inner.is::<FrameTooLong>());
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> FrameWriter for CobsConnection<S>{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.write_buf.clear();
+        encode(frame, &mut self.write_buf);
+        self.stream.write_all(&self.write_buf).map_err(WriteErr::Io)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::Cursor;
+
+    fn encoded(frames: &[Vec<u8>]) -> Vec<u8>{
+        let mut bytes = Vec::new();
+        for frame in frames {
+            encode(frame, &mut bytes);
+        }
+        bytes
+    }
+
+    /// Payloads made mostly or only of zeros, and runs around the longest block
+    fn zero_heavy() -> Vec<Vec<u8>>{
+        let mut payloads = vec![Vec::new(), vec![0], vec![0; 2], vec![0; 1000], vec![1, 0, 0, 1], vec![0, 1], vec![1, 0]];
+        for len in [253, 254, 255, 508, 509, 510] {
+            payloads.push(vec![0x55; len]);
+            let mut run = vec![0x55; len];
+            run.push(0);
+            payloads.push(run);
+        }
+        payloads.push((0..5000).map(|i| if i % 3 == 0 { (i % 251) as u8 } else { 0 }).collect());
+        payloads
+    }
+
+    #[test]
+    fn zero_heavy_payloads_round_trip(){
+        let payloads = zero_heavy();
+        let mut writer = CobsConnection::new(Cursor::new(Vec::new()));
+        for payload in &payloads {
+            writer.write_frame(payload).unwrap();
+        }
+        let bytes = writer.get_ref().get_ref().clone();
+        assert_eq!(bytes, encoded(&payloads));
+        // Only the delimiters are zero
+        assert_eq!(bytes.iter().filter(|byte| **byte == 0).count(), payloads.len());
+        let mut reader = CobsConnection::new(Cursor::new(bytes));
+        let frames: Vec<Vec<u8>> = reader.frames().collect::<io::Result<_>>().unwrap();
+        assert_eq!(frames, payloads);
+    }
+
+    #[test]
+    fn delimiter_inside_a_frame_costs_only_that_frame(){
+        let payloads = [b"first".to_vec(), vec![0x55; 300], b"last".to_vec()];
+        let mut bytes = encoded(&payloads);
+        let second = encoded(&payloads[..1]).len();
+        bytes[second + 100] = DELIMITER;
+        let mut reader = CobsConnection::new(Cursor::new(bytes));
+        let mut frames = reader.frames();
+        assert_eq!(frames.next().unwrap().unwrap(), b"first");
+        let err = frames.next().unwrap().unwrap_err();
+        assert_eq!(err.get_ref().and_then(|inner| inner.downcast_ref::<CorruptFrame>()), Some(&CorruptFrame{decoded: 99}));
+        // The rest of the broken frame up to its delimiter decodes as a frame of its own
+        assert!(frames.next().unwrap().map_or(true, |frame| frame != payloads[1]));
+        assert_eq!(frames.next().unwrap().unwrap(), b"last");
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn lost_delimiter_merges_two_frames_and_the_next_one_is_intact(){
+        let payloads = [b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+        let mut bytes = encoded(&payloads);
+        let end_of_first = encoded(&payloads[..1]).len() - 1;
+        bytes.remove(end_of_first);
+        let mut reader = CobsConnection::new(Cursor::new(bytes));
+        let merged = reader.read_frame().unwrap();
+        assert!(merged != payloads[0] && merged != payloads[1]);
+        assert_eq!(reader.read_frame().unwrap(), b"three");
+    }
+
+    #[test]
+    fn overlong_frame_is_skipped_up_to_its_delimiter(){
+        let payloads = [vec![0; 100], b"fits".to_vec()];
+        let mut reader = CobsConnection::new(Cursor::new(encoded(&payloads)));
+        reader.set_max_frame_len(10);
+        let mut frames = reader.frames();
+        let err = frames.next().unwrap().unwrap_err();
+        assert_eq!(err.get_ref().and_then(|inner| inner.downcast_ref::<FrameTooLong>()).map(|err| err.length), Some(100));
+        assert_eq!(frames.next().unwrap().unwrap(), b"fits");
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn stream_ending_inside_a_frame_ends_the_iteration(){
+        let mut bytes = encoded(&[b"whole".to_vec(), b"cut".to_vec()]);
+        bytes.pop();
+        let mut reader = CobsConnection::new(Cursor::new(bytes));
+        let mut frames = reader.frames();
+        assert_eq!(frames.next().unwrap().unwrap(), b"whole");
+        assert_eq!(frames.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(frames.next().is_none());
+    }
+}
@@ -0,0 +1,269 @@
+use std::io;
+use std::fmt;
+use std::fmt::Formatter;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use unisocket::{Stream, SocketAddr};
+
+/// Returned (wrapped into an `io::Error`) by `Connection::connect_any` when no address could be connected to.
+/// Its kind is the one all attempts failed with, `Other` if they differ
+#[derive(Debug)]
+pub struct AllAttemptsFailed{
+    /// Every address in the order tried, with its error
+    pub errors: Vec<(SocketAddr, io::Error)>,
+}
+
+impl AllAttemptsFailed{
+    pub fn is_all_attempts_failed(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<AllAttemptsFailed>())
+    }
+    /// The attempts behind `err`, if `connect_any` returned it
+    pub fn errors(err: &io::Error) -> Option<&[(SocketAddr, io::Error)]>{
+        err.get_ref().and_then(|inner| inner.downcast_ref::<AllAttemptsFailed>()).map(|failed| failed.errors.as_slice())
+    }
+}
+
+impl fmt::Display for AllAttemptsFailed{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "All {} connection attempts failed", self.errors.len())?;
+        for (addr, err) in &self.errors {
+            write!(f, ", {}: {}", addr, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AllAttemptsFailed{}
+
+pub(crate) fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<Stream>{
+    match addr {
+        SocketAddr::Inet(addr) => TcpStream::connect_timeout(addr, timeout).map(Stream::Inet),
+        #[cfg(unix)]
+        SocketAddr::Unix(path) => unix::connect_timeout(path, timeout),
+    }
+}
+
+/// Order of the addresses a host name resolves to, see `Connection::connect_str_prefer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpPreference{
+    /// As the resolver returned them
+    #[default]
+    Resolver,
+    Ipv6,
+    Ipv4,
+}
+
+/// Returned (wrapped into an `io::Error` of the same kind) by `Connection::connect_str`
+/// when the host name could not be resolved, nothing was tried
+#[derive(Debug)]
+pub struct ResolveFailed{
+    pub target: String,
+    pub error: io::Error,
+}
+
+impl ResolveFailed{
+    pub fn is_resolve_failed(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<ResolveFailed>())
+    }
+}
+
+impl fmt::Display for ResolveFailed{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not resolve {}: {}", self.target, self.error)
+    }
+}
+
+impl std::error::Error for ResolveFailed{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Unix paths and IP addresses as they are, host names through the system resolver
+pub(crate) fn resolve(target: &str, preference: IpPreference) -> io::Result<Vec<SocketAddr>>{
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(vec![addr])
+    }
+    let failed = |error: io::Error| io::Error::new(error.kind(), ResolveFailed{target: target.to_string(), error});
+    let mut addrs: Vec<std::net::SocketAddr> = target.to_socket_addrs().map_err(failed)?.collect();
+    if addrs.is_empty() {
+        return Err(failed(io::Error::new(io::ErrorKind::NotFound, "no addresses found")))
+    }
+    match preference {
+        IpPreference::Resolver => {}
+        IpPreference::Ipv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        IpPreference::Ipv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+    }
+    Ok(addrs.into_iter().map(SocketAddr::Inet).collect())
+}
+
+pub(crate) fn connect_any(addrs: &[SocketAddr], per_attempt: Duration) -> io::Result<(Stream, usize)>{
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to"))
+    }
+    let (sender, receiver) = mpsc::channel();
+    let mut errors: Vec<Option<io::Error>> = addrs.iter().map(|_| None).collect();
+    let mut started = 0;
+    let mut failed = 0;
+    // `None` once the next attempt only starts when the previous one fails
+    let mut next_start = Some(Instant::now());
+    loop {
+        if started < addrs.len() && next_start.is_some_and(|start| Instant::now() >= start) {
+            let (sender, addr) = (sender.clone(), addrs[started].clone());
+            let index = started;
+            // A loser still connecting after the winner returned finds the receiver gone and closes its stream
+            thread::spawn(move || sender.send((index, Stream::connect(&addr))));
+            started += 1;
+            next_start = Instant::now().checked_add(per_attempt);
+        }
+        let result = match next_start {
+            _ if failed == addrs.len() => break,
+            Some(start) if started < addrs.len() => match receiver.recv_timeout(start.saturating_duration_since(Instant::now())) {
+                Ok(result) => result,
+                Err(_) => continue,
+            },
+            _ => match receiver.recv() {
+                Ok(result) => result,
+                Err(_) => break,
+            },
+        };
+        match result {
+            (index, Ok(stream)) => return Ok((stream, index)),
+            (index, Err(err)) => {
+                errors[index] = Some(err);
+                failed += 1;
+                // No reason to wait for the next address once this one is out
+                next_start = Some(Instant::now());
+            }
+        }
+    }
+    let errors: Vec<(SocketAddr, io::Error)> = addrs.iter().cloned().zip(errors).filter_map(|(addr, err)| Some((addr, err?))).collect();
+    let kind = match errors[0].1.kind() {
+        kind if errors.iter().all(|(_, err)| err.kind() == kind) => kind,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(kind, AllAttemptsFailed{errors}))
+}
+
+#[cfg(unix)]
+pub(crate) fn pair() -> io::Result<(Stream, Stream)>{
+    let (a, b) = std::os::unix::net::UnixStream::pair()?;
+    Ok((Stream::Unix(a), Stream::Unix(b)))
+}
+
+/// Over loopback TCP, through a listener that only lives for this call
+#[cfg(not(unix))]
+pub(crate) fn pair() -> io::Result<(Stream, Stream)>{
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+    let a = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (b, peer) = listener.accept()?;
+        // Another local process may have raced for the port
+        if peer == a.local_addr()? {
+            return Ok((Stream::Inet(a), Stream::Inet(b)))
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix{
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use unisocket::Stream;
+    use crate::poll::{poll, Readiness};
+
+    /// Retry interval while the listener's backlog is full, Unix sockets do not queue such attempts
+    const BACKLOG_RETRY: Duration = Duration::from_millis(10);
+
+    fn timed_out() -> io::Error{
+        io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+    }
+
+    fn sockaddr(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)>{
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        let bytes = path.as_os_str().as_bytes();
+        // One byte stays for the terminating nul
+        if bytes.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "path must be shorter than SUN_LEN"))
+        }
+        for (dst, &src) in addr.sun_path.iter_mut().zip(bytes) {
+            *dst = src as libc::c_char;
+        }
+        let offset = addr.sun_path.as_ptr() as usize - &addr as *const libc::sockaddr_un as usize;
+        Ok((addr, (offset + bytes.len() + 1) as libc::socklen_t))
+    }
+
+    pub(super) fn connect_timeout(path: &Path, timeout: Duration) -> io::Result<Stream>{
+        if timeout == Duration::ZERO {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot set a 0 duration timeout"))
+        }
+        let deadline = Instant::now() + timeout;
+        let (addr, len) = sockaddr(path)?;
+        let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        // Owns the descriptor from here on, closing it on every error below
+        let stream = Stream::Unix(unsafe { UnixStream::from_raw_fd(fd) });
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        crate::stream_set_nonblocking(&stream, true)?;
+        loop {
+            if unsafe { libc::connect(fd, &addr as *const libc::sockaddr_un as *const libc::sockaddr, len) } == 0 {
+                break
+            }
+            let err = io::Error::last_os_error();
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match err.raw_os_error() {
+                Some(libc::EINTR) => {}
+                Some(libc::EAGAIN) if remaining.is_zero() => return Err(timed_out()),
+                Some(libc::EAGAIN) => std::thread::sleep(remaining.min(BACKLOG_RETRY)),
+                Some(libc::EINPROGRESS) => {
+                    if !poll(&stream, Readiness::Writable, Some(remaining))? {
+                        return Err(timed_out())
+                    }
+                    if let Some(err) = crate::stream_take_error(&stream)? {
+                        return Err(err)
+                    }
+                    break
+                }
+                _ => return Err(err),
+            }
+        }
+        crate::stream_set_nonblocking(&stream, false)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::{Duration, Instant};
+    use unisocket::SocketAddr;
+    use crate::Connection;
+
+    /// Needs an address whose SYNs are dropped. The default is unroutable on most networks,
+    /// set `RUST_SFP_BLACKHOLE` to another one where it is refused at once
+    #[test]
+    #[ignore = "needs a blackhole route, run with --ignored"]
+    fn connect_timeout_gives_up_on_a_blackhole(){
+        let target = std::env::var("RUST_SFP_BLACKHOLE").unwrap_or_else(|_| "10.255.255.1:9".to_string());
+        let addr = SocketAddr::Inet(target.parse().expect("RUST_SFP_BLACKHOLE is an IP address and port"));
+        let timeout = Duration::from_millis(300);
+        let start = Instant::now();
+        let result = Connection::connect_timeout(&addr, timeout);
+        let waited = start.elapsed();
+        match result {
+            Err(err) => assert_eq!(err.kind(), std::io::ErrorKind::TimedOut, "{} is not a blackhole here: {}", target, err),
+            Ok(_) => panic!("{} is not a blackhole here, it accepted a connection", target),
+        }
+        assert!(waited >= timeout && waited < timeout * 4, "{:?}", waited);
+    }
+}
@@ -0,0 +1,1548 @@
+use std::io;
+use std::io::Read;
+use std::fmt;
+use std::fmt::Formatter;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::time::Instant;
+use crate::source::ReadUninit;
+use crate::checksum::Hasher;
+use crate::activity::Activity;
+use crate::turn::{Turn, AbandonedFrame};
+use crate::{FrameTooLong, Desynchronized, ModeMismatch, SequenceGap, ChecksumMismatch, MalformedLength, NegativeLength, UnknownFlags, PeerClosed, FrameFlag, FrameFlags, FrameMeta, Extensions, Framing, FramingConfig, HeaderWidth, FRAME_MAGIC, PAD_PREFIX_LEN, ReadErr, DEFAULT_MAX_FRAME_LEN, DEFAULT_READ_CHUNK_SIZE, DEFAULT_READ_BUFFER_CAPACITY};
+
+/// Widest fixed length prefix
+pub(crate) const MAX_LENGTH_LEN: usize = 8;
+/// A varint holding a `u32`
+pub(crate) const VARINT_MAX_LEN: usize = 5;
+pub(crate) const HELLO_LEN: usize = 4;
+const MAGIC_LEN: usize = FRAME_MAGIC.len();
+const FLAGS_LEN: usize = 1;
+const SEQUENCE_LEN: usize = 4;
+const MAX_DIGEST_LEN: usize = 8;
+const HEADER_LEN: usize = MAGIC_LEN + MAX_LENGTH_LEN + FLAGS_LEN + SEQUENCE_LEN + MAX_DIGEST_LEN;
+
+/// Progress of the frame being decoded, kept across calls so that `WouldBlock`,
+/// timeouts and `Interrupted` can be retried without losing bytes
+#[derive(Debug)]
+enum ReadState{
+    Header{header: [u8; HEADER_LEN], filled: usize},
+    /// Payload is collected into `FrameDecoder::partial`
+    Buffered{length: usize},
+    /// Payload is handed out straight from the source
+    Streamed{length: usize, remaining: usize},
+    /// Payload is thrown away
+    Discarded{length: usize, remaining: usize},
+    /// Payload of a control frame is collected into `FrameDecoder::control`
+    Control{length: usize},
+}
+
+impl ReadState{
+    fn idle() -> Self{
+        ReadState::Header{header: [0u8; HEADER_LEN], filled: 0}
+    }
+}
+
+/// Why a decoder stopped, it is no longer at a frame boundary
+#[derive(Debug, Clone, Copy)]
+enum Failure{
+    TooLong(FrameTooLong),
+    Desynchronized(Desynchronized),
+    ModeMismatch(ModeMismatch),
+    ChecksumMismatch(ChecksumMismatch),
+    MalformedLength(MalformedLength),
+    NegativeLength(NegativeLength),
+    Abandoned(AbandonedFrame),
+}
+
+impl Failure{
+    fn error(self) -> io::Error{
+        match self {
+            Failure::TooLong(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Failure::Desynchronized(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Failure::ModeMismatch(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Failure::ChecksumMismatch(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Failure::MalformedLength(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Failure::NegativeLength(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Failure::Abandoned(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Where a decoder left the stream, taken over by the clone reading next
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReadPosition{
+    peer_hello: bool,
+    next_sequence: u32,
+    failure: Option<Failure>,
+}
+
+/// Peer closed the stream between frames
+#[derive(Debug)]
+struct Closed;
+
+impl fmt::Display for Closed{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection closed")
+    }
+}
+
+impl std::error::Error for Closed{}
+
+pub(crate) fn eof_error(mid_frame: bool) -> io::Error{
+    if mid_frame {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed in the middle of a frame")
+    } else {
+        io::Error::new(io::ErrorKind::UnexpectedEof, Closed)
+    }
+}
+
+/// Whether `err` is the end of the stream at a frame boundary, or the peer closed it with a close frame
+pub(crate) fn is_closed(err: &io::Error) -> bool{
+    err.get_ref().is_some_and(|inner| inner.is::<Closed>() || inner.is::<PeerClosed>())
+}
+
+/// First payload byte of a control frame closing the connection, the reason follows,
+/// see `Connection::close_graceful`. Control frames starting with another byte are skipped
+pub(crate) const CLOSE_FRAME: u8 = 1;
+
+/// Length prefix decoded from the start of `bytes`
+enum LengthPrefix{
+    /// Value and encoded length
+    Complete(u64, usize),
+    /// More bytes are needed, at least this many in total
+    Incomplete(usize),
+}
+
+/// `width` is the length of fixed prefixes
+fn decode_length(config: &FramingConfig, width: usize, bytes: &[u8]) -> Result<LengthPrefix, MalformedLength>{
+    match config.framing {
+        Framing::Fixed if bytes.len() < width => Ok(LengthPrefix::Incomplete(width)),
+        Framing::Fixed => {
+            let mut word = [0u8; MAX_LENGTH_LEN];
+            let value = if config.little_endian {
+                word[..width].copy_from_slice(&bytes[..width]);
+                u64::from_le_bytes(word)
+            } else {
+                word[MAX_LENGTH_LEN - width..].copy_from_slice(&bytes[..width]);
+                u64::from_be_bytes(word)
+            };
+            Ok(LengthPrefix::Complete(value, width))
+        }
+        Framing::Varint => {
+            let mut value = 0u64;
+            for (i, byte) in bytes.iter().enumerate() {
+                value |= ((byte & 0x7F) as u64) << (7 * i);
+                if byte & 0x80 == 0 {
+                    // A trailing zero group could have been left out
+                    if (i > 0 && *byte == 0) || value > u32::MAX as u64 {
+                        return Err(MalformedLength)
+                    }
+                    return Ok(LengthPrefix::Complete(value, i + 1))
+                }
+                if i + 1 == VARINT_MAX_LEN {
+                    return Err(MalformedLength)
+                }
+            }
+            Ok(LengthPrefix::Incomplete(bytes.len() + 1))
+        }
+    }
+}
+
+/// Encodes `value` as a LEB128 varint, returning the buffer and the encoded length
+pub(crate) fn encode_varint(mut value: u32) -> ([u8; VARINT_MAX_LEN], usize){
+    let mut encoded = [0u8; VARINT_MAX_LEN];
+    let mut len = 0;
+    loop {
+        encoded[len] = (value & 0x7F) as u8;
+        value >>= 7;
+        len += 1;
+        if value == 0 {
+            return (encoded, len)
+        }
+        encoded[len - 1] |= 0x80;
+    }
+}
+
+/// Encodes `value` as it is, `width` is the length of fixed prefixes
+pub(crate) fn encode_raw_length(config: &FramingConfig, width: usize, value: u64) -> ([u8; MAX_LENGTH_LEN], usize){
+    let mut encoded = [0u8; MAX_LENGTH_LEN];
+    match config.framing {
+        Framing::Fixed if config.little_endian => {
+            encoded[..width].copy_from_slice(&value.to_le_bytes()[..width]);
+            (encoded, width)
+        }
+        Framing::Fixed => {
+            encoded[..width].copy_from_slice(&value.to_be_bytes()[MAX_LENGTH_LEN - width..]);
+            (encoded, width)
+        }
+        Framing::Varint => {
+            let (varint, len) = encode_varint(value as u32);
+            encoded[..len].copy_from_slice(&varint[..len]);
+            (encoded, len)
+        }
+    }
+}
+
+/// Why a frame length can not be encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LengthOutOfRange{
+    TooLong,
+    /// The length adjustment is bigger than the frame
+    Negative,
+}
+
+/// Length prefix of a frame whose payload and header extensions take `length` bytes,
+/// after `prefix_start` bytes of magic
+pub(crate) fn encode_length(config: &FramingConfig, prefix_start: usize, length: u64) -> Result<([u8; MAX_LENGTH_LEN], usize), LengthOutOfRange>{
+    let max = match config.framing {
+        Framing::Fixed => config.width.max_length(),
+        Framing::Varint => u32::MAX as u64,
+    };
+    let raw = |prefix_len: usize| {
+        let header = if config.length_includes_header { (prefix_start + prefix_len) as i128 } else { 0 };
+        let raw = length as i128 + header - config.length_adjustment as i128;
+        match raw {
+            raw if raw < 0 => Err(LengthOutOfRange::Negative),
+            raw if raw > max as i128 => Err(LengthOutOfRange::TooLong),
+            raw => Ok(raw as u64),
+        }
+    };
+    match config.framing {
+        Framing::Fixed => Ok(encode_raw_length(config, config.width.bytes(), raw(config.width.bytes())?)),
+        Framing::Varint => {
+            // The prefix may count itself, its length has to match the value it encodes
+            for prefix_len in 1..=VARINT_MAX_LEN {
+                let value = raw(prefix_len)?;
+                if encode_varint(value as u32).1 == prefix_len {
+                    return Ok(encode_raw_length(config, prefix_len, value))
+                }
+            }
+            Err(LengthOutOfRange::TooLong)
+        }
+    }
+}
+
+/// Length of the rest of a frame out of its length prefix, `None` when it would be negative
+fn decoded_length(config: &FramingConfig, header_len: usize, raw: u64) -> Option<u64>{
+    let header = if config.length_includes_header { header_len as i128 } else { 0 };
+    let length = raw as i128 + config.length_adjustment as i128 - header;
+    match length {
+        length if length < 0 => None,
+        length => Some(length.min(u64::MAX as i128) as u64),
+    }
+}
+
+/// Source without bytes of its own, decoding runs over pushed bytes only
+struct Pushed;
+
+impl Read for Pushed{
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl ReadUninit for Pushed{}
+
+/// Bytes received but not consumed by framing yet
+#[derive(Debug)]
+struct InputBuf{
+    buf: Vec<u8>,
+    pos: usize,
+    capacity: usize,
+    /// Whether reads are timestamped into `filled_at` and `read_at`
+    timestamping: bool,
+    /// When the buffered bytes arrived
+    filled_at: Option<Instant>,
+    /// When the bytes returned by the last `read` arrived
+    read_at: Option<Instant>,
+}
+
+impl InputBuf{
+    fn available(&self) -> &[u8]{
+        &self.buf[self.pos..]
+    }
+    fn consume(&mut self, n: usize){
+        self.pos += n;
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+        }
+    }
+    /// Puts `bytes` back in front of the buffered ones
+    fn unread(&mut self, bytes: &[u8]){
+        self.compact();
+        self.buf.splice(0..0, bytes.iter().copied());
+    }
+    fn compact(&mut self){
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+    }
+    /// Serves buffered bytes first. Once they run out, reads smaller than the capacity
+    /// refill the buffer while bigger ones go to the source directly
+    fn read<R: ReadUninit + ?Sized>(&mut self, src: &mut R, dst: &mut [u8]) -> io::Result<usize>{
+        // `read_uninit` only ever writes initialized bytes
+        self.read_uninit(src, unsafe { &mut *(dst as *mut [u8] as *mut [MaybeUninit<u8>]) })
+    }
+    /// Same as `read`, `dst` does not have to be initialized
+    fn read_uninit<R: ReadUninit + ?Sized>(&mut self, src: &mut R, dst: &mut [MaybeUninit<u8>]) -> io::Result<usize>{
+        if self.available().is_empty() {
+            if dst.len() >= self.capacity {
+                let n = src.read_uninit(dst)?;
+                if self.timestamping {
+                    self.read_at = Some(Instant::now());
+                }
+                return Ok(n)
+            }
+            if self.fill(src, self.capacity)? == 0 {
+                return Ok(0)
+            }
+        }
+        let available = self.available();
+        let n = available.len().min(dst.len());
+        for (dst, src) in dst.iter_mut().zip(&available[..n]) {
+            dst.write(*src);
+        }
+        self.consume(n);
+        self.read_at = self.filled_at;
+        Ok(n)
+    }
+    /// Appends whatever a single read of at most `size` bytes returns
+    fn fill<R: ReadUninit + ?Sized>(&mut self, src: &mut R, size: usize) -> io::Result<usize>{
+        self.compact();
+        self.buf.reserve(size);
+        let result = src.read_uninit(&mut self.buf.spare_capacity_mut()[..size]);
+        if let Ok(n) = result {
+            assert!(n <= size);
+            // The first `n` spare bytes were initialized by the read
+            unsafe { self.buf.set_len(self.buf.len() + n) }
+        }
+        if self.timestamping && matches!(result, Ok(n) if n > 0) {
+            self.filled_at = Some(Instant::now());
+        }
+        result
+    }
+}
+
+/// Splits a byte stream into SFP frames without doing any I/O itself:
+/// bytes are fed with `push` and complete frames are taken with `next_frame`.
+/// `Connection` reads through the same state machine
+#[derive(Debug)]
+pub struct FrameDecoder{
+    state: ReadState,
+    input: InputBuf,
+    partial: Vec<u8>,
+    max_frame_len: usize,
+    chunk_size: usize,
+    failure: Option<Failure>,
+    /// Every header starts with `FRAME_MAGIC`
+    magic: bool,
+    config: FramingConfig,
+    completed_at: Option<Instant>,
+    /// Of the connection this decoder reads for, see `Connection::last_read_at`
+    activity: Option<Arc<Activity>>,
+    /// Reader turn shared with the clones of the connection, and the handle of this one
+    turn: Option<(Arc<Turn<ReadPosition>>, u64)>,
+    /// Frames completed or skipped so far
+    received: u64,
+    /// Zero-length frames are dropped as soon as their header is read
+    filter_empty: bool,
+    /// Bytes dropped so far by an interrupted `resync`
+    resync_skipped: Option<u64>,
+    /// Extensions expected to be announced by the hello of the peer
+    extensions: Extensions,
+    peer_hello: bool,
+    next_sequence: u32,
+    /// Checksum announced by the header of the current frame and the one of its payload so far
+    checksum: Option<(u64, Hasher)>,
+    /// Flags of the last frame whose header was read
+    flags: FrameFlags,
+    control: Vec<u8>,
+    /// The close frame of the peer, once read
+    closed: Option<PeerClosed>,
+    /// Payloads carry their true length and padding, see `Connection::set_strip_padding`
+    padded: bool,
+}
+
+impl Default for FrameDecoder{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder{
+    pub fn new() -> Self{
+        Self{
+            state: ReadState::idle(),
+            input: InputBuf{
+                buf: Vec::new(),
+                pos: 0,
+                capacity: DEFAULT_READ_BUFFER_CAPACITY,
+                timestamping: false,
+                filled_at: None,
+                read_at: None,
+            },
+            partial: Vec::new(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            chunk_size: DEFAULT_READ_CHUNK_SIZE,
+            failure: None,
+            magic: false,
+            config: FramingConfig::new(),
+            completed_at: None,
+            activity: None,
+            turn: None,
+            received: 0,
+            filter_empty: false,
+            resync_skipped: None,
+            extensions: Extensions::default(),
+            peer_hello: false,
+            next_sequence: 0,
+            checksum: None,
+            flags: FrameFlags::empty(),
+            control: Vec::new(),
+            closed: None,
+            padded: false,
+        }
+    }
+    /// Frames declaring a bigger length are rejected before any allocation.
+    /// After a rejection the input is no longer at a frame boundary,
+    /// so the decoder stays failed. Without extensions the hello of a peer that uses them is only
+    /// recognized as long as it can not be a frame: from a limit of 0x5346_4800 (about 1.3 GiB) up,
+    /// it may be taken for the length of one
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize){
+        self.max_frame_len = max_frame_len;
+    }
+    pub fn max_frame_len(&self) -> usize{
+        self.max_frame_len
+    }
+    pub fn push(&mut self, bytes: &[u8]){
+        self.input.compact();
+        self.input.buf.extend_from_slice(bytes);
+        if self.input.timestamping {
+            self.input.filled_at = Some(Instant::now());
+        }
+    }
+    /// Next complete frame out of the pushed bytes, `None` if more bytes are needed
+    /// or the decoder has failed
+    pub fn next_frame(&mut self) -> Option<Vec<u8>>{
+        self.read_buffered(&mut Pushed).ok()
+    }
+    /// Rejected frame that made the decoder fail
+    pub fn error(&self) -> Option<FrameTooLong>{
+        match self.failure {
+            Some(Failure::TooLong(err)) => Some(err),
+            _ => None,
+        }
+    }
+    /// Header without the frame magic that made the decoder fail
+    pub fn desynchronized(&self) -> Option<Desynchronized>{
+        match self.failure {
+            Some(Failure::Desynchronized(err)) => Some(err),
+            _ => None,
+        }
+    }
+    /// What made the decoder fail, as reads of a `Connection` return it: `FrameTooLong`, `Desynchronized`,
+    /// `ModeMismatch`, `ChecksumMismatch`, `MalformedLength` or `NegativeLength` wrapped into `InvalidData`
+    pub fn failure(&self) -> Option<io::Error>{
+        self.failure.map(Failure::error)
+    }
+    pub fn is_failed(&self) -> bool{
+        self.failure.is_some()
+    }
+    /// Extensions the input was written with, see `FrameEncoder::set_sequence_numbers`, `set_checksum` and
+    /// `set_frame_flags`. The input then starts with the hello announcing them.
+    /// Must only be changed before the first frame
+    pub fn set_extensions(&mut self, extensions: Extensions){
+        self.extensions = extensions;
+    }
+    pub fn extensions(&self) -> Extensions{
+        self.extensions
+    }
+    /// Strips the padding of frames written with `FrameEncoder::set_pad_to`, see `Connection::set_strip_padding`
+    pub fn set_strip_padding(&mut self, strip: bool){
+        self.padded = strip;
+    }
+    pub fn strip_padding(&self) -> bool{
+        self.padded
+    }
+    /// Expects every header to start with `FRAME_MAGIC`, see `Connection::set_magic_prefix`.
+    /// Must only be changed at a frame boundary
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.magic = magic;
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.magic
+    }
+    /// Encoding of the length prefixes, see `Connection::set_framing_config`.
+    /// Must only be changed at a frame boundary
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.config = config;
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.config
+    }
+    /// See `Connection::set_framing`
+    pub fn set_framing(&mut self, framing: Framing){
+        self.config.framing = framing;
+    }
+    pub fn framing(&self) -> Framing{
+        self.config.framing
+    }
+    /// See `Connection::set_header_width`
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.config.width = width;
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.config.width
+    }
+    /// Whether part of a frame was received: if the input ends now, that frame is truncated
+    pub fn is_mid_frame(&self) -> bool{
+        !matches!(self.state, ReadState::Header{filled: 0, ..})
+    }
+
+    pub(crate) fn set_chunk_size(&mut self, chunk_size: usize){
+        self.chunk_size = chunk_size.max(1);
+    }
+    pub(crate) fn chunk_size(&self) -> usize{
+        self.chunk_size
+    }
+    pub(crate) fn set_input_capacity(&mut self, capacity: usize){
+        self.input.capacity = capacity;
+    }
+    pub(crate) fn input_capacity(&self) -> usize{
+        self.input.capacity
+    }
+    pub(crate) fn set_timestamping(&mut self, timestamping: bool){
+        self.input.timestamping = timestamping;
+        if !timestamping {
+            self.input.filled_at = None;
+            self.input.read_at = None;
+            self.completed_at = None;
+        }
+    }
+    pub(crate) fn timestamping(&self) -> bool{
+        self.input.timestamping
+    }
+    /// When the last byte of the last completed frame arrived, if timestamping
+    pub(crate) fn completed_at(&self) -> Option<Instant>{
+        self.completed_at
+    }
+    pub(crate) fn set_activity(&mut self, activity: Arc<Activity>){
+        self.activity = Some(activity);
+    }
+    pub(crate) fn activity(&self) -> Option<&Activity>{
+        self.activity.as_deref()
+    }
+    pub(crate) fn set_turn(&mut self, turn: Arc<Turn<ReadPosition>>, handle: u64){
+        self.turn = Some((turn, handle));
+    }
+    /// Bytes another clone received and handed over with the reader turn
+    pub(crate) fn has_handed_input(&self) -> bool{
+        self.turn.as_ref().is_some_and(|(turn, _)| turn.has_input())
+    }
+    fn position(&self) -> ReadPosition{
+        ReadPosition{peer_hello: self.peer_hello, next_sequence: self.next_sequence, failure: self.failure}
+    }
+    /// Takes over where the previous holder of the reader turn left the stream, returning its failure if any
+    fn adopt_turn(&mut self) -> Option<Failure>{
+        let (turn, handle) = self.turn.as_ref()?;
+        let position = turn.adopt(*handle)?;
+        self.peer_hello = position.peer_hello;
+        self.next_sequence = position.next_sequence;
+        if position.failure.is_some() {
+            self.failure = position.failure;
+        }
+        position.failure
+    }
+    /// Lets a waiting clone read between two frames, handing it the bytes received past them
+    fn pass_turn(&mut self){
+        let Some((turn, handle)) = self.turn.clone() else { return };
+        if self.has_input() && !turn.has_waiters() {
+            return
+        }
+        if let Some(input) = self.take_input() {
+            turn.give_back(handle, self.position(), &input);
+        }
+    }
+    /// Gives the reader turn back for good, as the connection goes away partway through a frame or not
+    pub(crate) fn leave_turn(&mut self){
+        let Some((turn, handle)) = self.turn.take() else { return };
+        let input = self.take_input().unwrap_or_else(|| {
+            self.failure.get_or_insert(Failure::Abandoned(AbandonedFrame));
+            Vec::new()
+        });
+        turn.give_back(handle, self.position(), &input);
+    }
+    pub(crate) fn set_filter_empty(&mut self, filter_empty: bool){
+        self.filter_empty = filter_empty;
+    }
+    pub(crate) fn filter_empty(&self) -> bool{
+        self.filter_empty
+    }
+    /// Metadata of the last frame read
+    pub(crate) fn meta(&self) -> FrameMeta{
+        FrameMeta{
+            received_at: self.completed_at,
+            flags: self.flags,
+            sequence: if self.extensions.sequence_numbers { Some(self.next_sequence.wrapping_sub(1)) } else { None },
+        }
+    }
+    pub(crate) fn flags(&self) -> FrameFlags{
+        self.flags
+    }
+    pub(crate) fn received(&self) -> u64{
+        self.received
+    }
+    pub(crate) fn has_input(&self) -> bool{
+        !self.input.available().is_empty()
+    }
+    /// Whether `take_input` can give the received bytes back: not past a header
+    pub(crate) fn can_take_input(&self) -> bool{
+        matches!(self.state, ReadState::Header{..})
+    }
+    /// Received bytes not decoded yet, removed from the decoder, with those of a header read only in part.
+    /// `None` past a header, the frame's bytes cannot be given back
+    pub(crate) fn take_input(&mut self) -> Option<Vec<u8>>{
+        let ReadState::Header{header, filled} = &self.state else { return None };
+        let mut input = header[..*filled].to_vec();
+        input.extend_from_slice(self.input.available());
+        self.input.consume(self.input.available().len());
+        self.state = ReadState::idle();
+        Some(input)
+    }
+    /// Decoder with the same settings and no received bytes
+    pub(crate) fn fresh(&self) -> Self{
+        let mut decoder = Self::new();
+        decoder.max_frame_len = self.max_frame_len;
+        decoder.chunk_size = self.chunk_size;
+        decoder.input.capacity = self.input.capacity;
+        decoder.input.timestamping = self.input.timestamping;
+        decoder.filter_empty = self.filter_empty;
+        decoder.magic = self.magic;
+        decoder.config = self.config;
+        decoder.extensions = self.extensions;
+        decoder.padded = self.padded;
+        decoder.activity = self.activity.clone();
+        decoder
+    }
+    /// Appends whatever a single read of at most `size` bytes from `src` returns
+    pub(crate) fn fill<R: ReadUninit + ?Sized>(&mut self, src: &mut R, size: usize) -> io::Result<usize>{
+        self.input.fill(src, size)
+    }
+    /// Whether a frame is being collected, see `read_buffered`
+    pub(crate) fn is_buffering(&self) -> bool{
+        matches!(self.state, ReadState::Buffered{..})
+    }
+    /// Whether a payload is being handed out by `read_streamed` or skipped
+    pub(crate) fn is_streaming(&self) -> bool{
+        matches!(self.state, ReadState::Streamed{..} | ReadState::Discarded{..})
+    }
+    /// Completes the header, resuming from the bytes received so far,
+    /// and returns the declared length. The payload is then expected to be buffered,
+    /// streaming callers switch the state themselves.
+    /// A payload left streamed or discarded by an interrupted call is skipped first
+    pub(crate) fn read_header<R: ReadUninit + ?Sized>(&mut self, src: &mut R) -> io::Result<usize>{
+        if let Some(failure) = self.failure {
+            return Err(failure.error())
+        }
+        if let Some(closed) = &self.closed {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, closed.clone()))
+        }
+        if self.is_streaming() {
+            self.discard_pending(src)?;
+        }
+        let length_start = if self.magic { MAGIC_LEN } else { 0 };
+        let overhead = self.flags_len() + self.sequence_len() + self.checksum_len();
+        let extended = self.extensions != Extensions::default();
+        loop {
+            let (header, filled) = match &self.state {
+                ReadState::Header{header, filled} => (*header, *filled),
+                ReadState::Buffered{length} => return Ok(*length),
+                ReadState::Control{length} => {
+                    let length = *length;
+                    self.read_control_frame(src, length)?;
+                    continue
+                }
+                ReadState::Streamed{..} | ReadState::Discarded{..} => unreachable!(),
+            };
+            // The bytes came with the reader turn, they follow where its previous holder stopped
+            if filled > 0 {
+                if let Some(failure) = self.adopt_turn() {
+                    return Err(failure.error())
+                }
+            }
+            let mut prefix = [0u8; MAGIC_LEN];
+            prefix.copy_from_slice(&header[..MAGIC_LEN]);
+            if self.magic && filled >= MAGIC_LEN && prefix != FRAME_MAGIC {
+                return Err(self.fail(Failure::Desynchronized(Desynchronized{found: prefix})))
+            }
+            // A hello does not fit a 2 byte prefix and takes 4
+            let width = match self.config.width {
+                HeaderWidth::U16 if extended && !self.peer_hello => HELLO_LEN,
+                width => width.bytes(),
+            };
+            let (word, word_end) = match decode_length(&self.config, width, &header[length_start.min(filled)..filled]) {
+                Ok(LengthPrefix::Complete(word, len)) => (Some(word), length_start + len),
+                Ok(LengthPrefix::Incomplete(needed)) => (None, length_start + needed),
+                Err(err) => return Err(self.fail(Failure::MalformedLength(err))),
+            };
+            let mut header_len = word_end;
+            if let Some(word) = word {
+                // The peer sends one hello, after it a word that looks like one is the length of a frame.
+                // A plain decoder only treats a hello as such when it can not be a frame
+                let maybe_hello = word <= u32::MAX as u64
+                    && (if extended { !self.peer_hello } else { word > self.max_frame_len as u64 });
+                if let Some(peer) = Extensions::from_hello((word as u32).to_be_bytes()).filter(|_| maybe_hello) {
+                    if peer != self.extensions {
+                        return Err(self.fail(Failure::ModeMismatch(ModeMismatch{local: self.extensions, peer})))
+                    }
+                    self.peer_hello = true;
+                    self.state = ReadState::idle();
+                    continue
+                }
+                let length = match decoded_length(&self.config, word_end, word) {
+                    Some(length) => length,
+                    None => return Err(self.fail(Failure::NegativeLength(NegativeLength{prefix: word}))),
+                };
+                if extended && (!self.peer_hello || length < overhead as u64) {
+                    let mismatch = ModeMismatch{local: self.extensions, peer: Extensions::default()};
+                    return Err(self.fail(Failure::ModeMismatch(mismatch)))
+                }
+                let length = length - overhead as u64;
+                if length > self.max_frame_len as u64 {
+                    let length = length.min(usize::MAX as u64) as usize;
+                    // A plain decoder reading magic prefixed frames
+                    if !self.magic && prefix == FRAME_MAGIC {
+                        return Err(self.fail(Failure::Desynchronized(Desynchronized{found: prefix})))
+                    }
+                    return Err(self.fail(Failure::TooLong(FrameTooLong{length, max_frame_len: self.max_frame_len})))
+                }
+                let length = length as usize;
+                header_len += overhead;
+                if filled == header_len {
+                    let flags_end = word_end + self.flags_len();
+                    let flags = header[word_end..flags_end].first().copied().unwrap_or(0);
+                    let sequence_end = flags_end + self.sequence_len();
+                    let gap = self.check_sequence(&header[flags_end..sequence_end]);
+                    let expected = header[sequence_end..header_len].iter().fold(0u64, |digest, byte| digest << 8 | *byte as u64);
+                    self.checksum = Hasher::new(self.extensions.checksum).map(|hasher| (expected, hasher));
+                    self.flags = FrameFlags::from_bits_truncate(flags);
+                    if flags & !FrameFlags::KNOWN != 0 {
+                        // The frame is still delimited, it is skipped by the next read
+                        match length {
+                            0 => self.complete_frame()?,
+                            _ => self.state = ReadState::Discarded{length, remaining: length},
+                        }
+                        self.count_error();
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, UnknownFlags{flags}))
+                    }
+                    let control = self.flags.contains(FrameFlag::Control);
+                    if control {
+                        self.state = ReadState::Control{length};
+                    } else if length == 0 && self.filter_empty {
+                        self.complete_frame()?;
+                    } else {
+                        self.state = ReadState::Buffered{length};
+                    }
+                    match gap {
+                        Some(gap) => {
+                            self.count_error();
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, gap))
+                        }
+                        None if control || (length == 0 && self.filter_empty) => continue,
+                        None => return Ok(length),
+                    }
+                }
+            }
+            if let ReadState::Header{header, filled} = &mut self.state {
+                match self.input.read(src, &mut header[*filled..header_len]) {
+                    Ok(0) => return Err(eof_error(*filled > 0)),
+                    Ok(n) => *filled += n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+    /// Completes a control frame, handled here rather than returned. Fails with `PeerClosed` on a close frame
+    fn read_control_frame<R: ReadUninit + ?Sized>(&mut self, src: &mut R, length: usize) -> io::Result<()>{
+        while self.control.len() < length {
+            let mut chunk = [0u8; 256];
+            let n = (length - self.control.len()).min(chunk.len());
+            match self.input.read(src, &mut chunk[..n]) {
+                Ok(0) => return Err(eof_error(true)),
+                Ok(n) => {
+                    if let Some((_, crc)) = &mut self.checksum {
+                        crc.update(&chunk[..n]);
+                    }
+                    self.control.extend_from_slice(&chunk[..n]);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.complete_frame()?;
+        let payload = std::mem::take(&mut self.control);
+        // A padded payload shorter than its length prefix reads as empty, which is skipped
+        let payload = if self.padded {
+            payload.get(..PAD_PREFIX_LEN)
+                .map(|prefix| u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize)
+                .and_then(|len| payload.get(PAD_PREFIX_LEN..PAD_PREFIX_LEN.checked_add(len)?))
+                .unwrap_or_default()
+        } else {
+            &payload[..]
+        };
+        match payload.split_first() {
+            Some((&CLOSE_FRAME, reason)) => {
+                let closed = PeerClosed{reason: reason.to_vec()};
+                self.closed = Some(closed.clone());
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, closed))
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Reason of the close frame of the peer, once read
+    pub(crate) fn close_reason(&self) -> Option<&[u8]>{
+        self.closed.as_ref().map(|closed| &closed.reason[..])
+    }
+    /// Advances the expected sequence number past `sequence`, returning the gap if it skipped any
+    fn check_sequence(&mut self, sequence: &[u8]) -> Option<SequenceGap>{
+        if sequence.len() != SEQUENCE_LEN {
+            return None
+        }
+        let mut got = [0u8; SEQUENCE_LEN];
+        got.copy_from_slice(sequence);
+        let got = u32::from_be_bytes(got);
+        let expected = self.next_sequence;
+        self.next_sequence = got.wrapping_add(1);
+        if got != expected {
+            return Some(SequenceGap{expected, got})
+        }
+        None
+    }
+    fn sequence_len(&self) -> usize{
+        if self.extensions.sequence_numbers { SEQUENCE_LEN } else { 0 }
+    }
+    fn flags_len(&self) -> usize{
+        if self.extensions.flags { FLAGS_LEN } else { 0 }
+    }
+    fn checksum_len(&self) -> usize{
+        self.extensions.checksum.digest_len()
+    }
+    /// Goes back to the frame boundary, failing if the payload does not match its checksum
+    fn complete_frame(&mut self) -> io::Result<()>{
+        let length = match self.state {
+            ReadState::Header{..} => 0,
+            ReadState::Buffered{length} | ReadState::Streamed{length, ..} | ReadState::Discarded{length, ..}
+            | ReadState::Control{length} => length,
+        };
+        self.state = ReadState::idle();
+        self.completed_at = self.input.read_at;
+        self.received += 1;
+        if let Some((expected, crc)) = self.checksum.take() {
+            let actual = crc.finalize();
+            if actual != expected {
+                return Err(self.fail(Failure::ChecksumMismatch(ChecksumMismatch{expected, actual})))
+            }
+        }
+        if let Some(activity) = &self.activity {
+            activity.read(length);
+        }
+        self.pass_turn();
+        Ok(())
+    }
+    fn fail(&mut self, failure: Failure) -> io::Error{
+        self.failure = Some(failure);
+        self.count_error();
+        // Clones could not read on from here either
+        if let Some((turn, handle)) = &self.turn {
+            turn.give_back(*handle, self.position(), &[]);
+        }
+        failure.error()
+    }
+    fn count_error(&self){
+        if let Some(activity) = &self.activity {
+            activity.read_error();
+        }
+    }
+    /// Completes the current frame into `self.partial`, resuming from the bytes received so far
+    fn read_buffered_frame<R: ReadUninit + ?Sized>(&mut self, src: &mut R) -> io::Result<usize>{
+        let length = self.read_header(src)?;
+        while self.partial.len() < length {
+            let start = self.partial.len();
+            if self.partial.capacity() == start {
+                // Up to four times what arrived so far: memory follows the bytes the peer actually sent,
+                // and a large frame is copied over only a few times on its way to the declared length
+                let grown = (3 * start).max(self.chunk_size).min(length - start);
+                self.partial.reserve_exact(grown);
+            }
+            // Filled straight into the spare capacity, sparing a memset of the whole payload
+            let span = (self.partial.capacity() - start).min(length - start);
+            let result = self.input.read_uninit(src, &mut self.partial.spare_capacity_mut()[..span]);
+            if let Ok(n) = result {
+                assert!(n <= span);
+                // The first `n` spare bytes were initialized by the read
+                unsafe { self.partial.set_len(start + n) }
+            }
+            match result {
+                Ok(0) => return Err(eof_error(true)),
+                Ok(_) => if let Some((_, crc)) = &mut self.checksum {
+                    crc.update(&self.partial[start..]);
+                },
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.complete_frame()?;
+        if self.padded && length > 0 {
+            return self.unpad()
+        }
+        Ok(length)
+    }
+    /// Cuts the completed padded payload in `self.partial` down to the bytes it carries
+    fn unpad(&mut self) -> io::Result<usize>{
+        let length = match self.partial.get(..PAD_PREFIX_LEN) {
+            Some(prefix) => u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize,
+            None => usize::MAX,
+        };
+        if length > self.partial.len().saturating_sub(PAD_PREFIX_LEN) {
+            // The frame is over, the next one is read normally
+            self.partial.clear();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Padded frame shorter than its length prefix"))
+        }
+        self.partial.truncate(PAD_PREFIX_LEN + length);
+        self.partial.drain(..PAD_PREFIX_LEN);
+        Ok(length)
+    }
+    /// Reads the next frame as a whole
+    pub(crate) fn read_buffered<R: ReadUninit + ?Sized>(&mut self, src: &mut R) -> io::Result<Vec<u8>>{
+        self.read_buffered_frame(src)?;
+        Ok(std::mem::take(&mut self.partial))
+    }
+    /// Reads the next frame into `buf`. The caller's buffer receives the payload directly
+    /// unless an interrupted frame is pending
+    pub(crate) fn read_frame_into<R: ReadUninit + ?Sized>(&mut self, src: &mut R, buf: &mut Vec<u8>) -> io::Result<usize>{
+        let lent = self.partial.is_empty();
+        if lent {
+            buf.clear();
+            std::mem::swap(buf, &mut self.partial);
+        }
+        let result = self.read_buffered_frame(src);
+        if result.is_ok() || self.partial.is_empty() {
+            std::mem::swap(buf, &mut self.partial);
+            self.partial.clear();
+        } else if lent {
+            // Interrupted mid-frame: the caller gets its buffer back, what arrived is copied once
+            let received = self.partial.to_vec();
+            std::mem::swap(buf, &mut self.partial);
+            buf.clear();
+            self.partial = received;
+        }
+        result
+    }
+    /// Reads the next header and switches to streaming its payload,
+    /// unless an interrupted streamed payload is pending
+    pub(crate) fn start_streamed<R: ReadUninit + ?Sized>(&mut self, src: &mut R) -> io::Result<()>{
+        if let ReadState::Streamed{..} = self.state {
+            return Ok(())
+        }
+        let length = self.read_header(src)?;
+        match length {
+            0 => self.complete_frame()?,
+            _ => self.state = ReadState::Streamed{length, remaining: length},
+        }
+        Ok(())
+    }
+    pub(crate) fn streamed_remaining(&self) -> usize{
+        match self.state {
+            ReadState::Streamed{remaining, ..} => remaining,
+            _ => 0,
+        }
+    }
+    /// Reads at most `dst.len()` bytes of the streamed payload, 0 once it is over
+    pub(crate) fn read_streamed<R: ReadUninit + ?Sized>(&mut self, src: &mut R, dst: &mut [u8]) -> io::Result<usize>{
+        let (length, remaining) = match self.state {
+            ReadState::Streamed{length, remaining} => (length, remaining),
+            _ => return Ok(0),
+        };
+        if dst.is_empty() {
+            return Ok(0)
+        }
+        let limit = dst.len().min(remaining);
+        let n = self.input.read(src, &mut dst[..limit])?;
+        if n == 0 {
+            return Err(eof_error(true))
+        }
+        if let Some((_, crc)) = &mut self.checksum {
+            crc.update(&dst[..n]);
+        }
+        match remaining - n {
+            // A corrupted payload is not handed out in full
+            0 => self.complete_frame()?,
+            remaining => self.state = ReadState::Streamed{length, remaining},
+        }
+        Ok(n)
+    }
+    /// Skips the rest of a streamed or discarded payload, returning the frame length
+    pub(crate) fn discard_pending<R: ReadUninit + ?Sized>(&mut self, src: &mut R) -> io::Result<usize>{
+        let length = match self.state {
+            ReadState::Streamed{length, remaining} | ReadState::Discarded{length, remaining} => {
+                self.state = ReadState::Discarded{length, remaining};
+                length
+            }
+            _ => return Ok(0),
+        };
+        let mut scratch = [0u8; 4096];
+        while let ReadState::Discarded{remaining, ..} = &mut self.state {
+            if *remaining == 0 {
+                break
+            }
+            let chunk = (*remaining).min(scratch.len());
+            match self.input.read(src, &mut scratch[..chunk]) {
+                Ok(0) => return Err(eof_error(true)),
+                Ok(n) => {
+                    *remaining -= n;
+                    if let Some((_, crc)) = &mut self.checksum {
+                        crc.update(&scratch[..n]);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.complete_frame()?;
+        Ok(length)
+    }
+    /// Skips the next frame, or finishes skipping one left by an interrupted call
+    pub(crate) fn skip_frame<R: ReadUninit + ?Sized>(&mut self, src: &mut R) -> io::Result<usize>{
+        if self.is_streaming() {
+            return self.discard_pending(src)
+        }
+        if self.is_buffering() {
+            return self.read_buffered_frame(src)
+        }
+        let length = self.read_header(src)?;
+        self.state = ReadState::Discarded{length, remaining: length};
+        self.discard_pending(src)
+    }
+    /// Drops bytes up to the next `FRAME_MAGIC`, including the current frame, and clears the failure.
+    /// Returns how many bytes were dropped
+    pub(crate) fn resync<R: ReadUninit + ?Sized>(&mut self, src: &mut R) -> io::Result<u64>{
+        if !self.magic {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Resynchronization needs the magic prefix mode"))
+        }
+        if self.resync_skipped.is_none() {
+            let (config, overhead) = (self.config, self.flags_len() + self.sequence_len() + self.checksum_len());
+            let header_len = |length: usize| {
+                let prefix_len = encode_length(&config, MAGIC_LEN, (length + overhead) as u64).map_or(0, |(_, len)| len);
+                (MAGIC_LEN + prefix_len + overhead) as u64
+            };
+            self.checksum = None;
+            let (pending, dropped) = match std::mem::replace(&mut self.state, ReadState::idle()) {
+                // The header is not trusted, scanning resumes after its first byte
+                ReadState::Header{header, filled} if filled > 0 => (header[1..filled].to_vec(), 1),
+                ReadState::Header{..} => (Vec::new(), 0),
+                ReadState::Buffered{length} => (std::mem::take(&mut self.partial), header_len(length)),
+                ReadState::Control{length} => (std::mem::take(&mut self.control), header_len(length)),
+                ReadState::Streamed{length, remaining} | ReadState::Discarded{length, remaining} => {
+                    (Vec::new(), header_len(length) + (length - remaining) as u64)
+                }
+            };
+            self.input.unread(&pending);
+            self.resync_skipped = Some(dropped);
+        }
+        loop {
+            let available = self.input.available();
+            let found = available.windows(MAGIC_LEN).position(|window| window == FRAME_MAGIC);
+            // A trailing partial magic is kept for the next read
+            let dropped = found.unwrap_or_else(|| available.len().saturating_sub(MAGIC_LEN - 1));
+            self.input.consume(dropped);
+            let skipped = self.resync_skipped.unwrap_or(0) + dropped as u64;
+            self.resync_skipped = Some(skipped);
+            if found.is_some() {
+                self.resync_skipped = None;
+                self.failure = None;
+                return Ok(skipped)
+            }
+            match self.input.fill(src, self.input.capacity.max(DEFAULT_READ_BUFFER_CAPACITY)) {
+                Ok(0) => return Err(eof_error(true)),
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    /// Classifies a failure of the last read by the progress of the current frame
+    pub(crate) fn read_err(&self, err: io::Error) -> ReadErr{
+        if let Some(too_long) = err.get_ref().and_then(|inner| inner.downcast_ref::<FrameTooLong>()) {
+            return ReadErr::TooLongFrame{length: too_long.length, max_frame_len: too_long.max_frame_len}
+        }
+        if let Some(desync) = err.get_ref().and_then(|inner| inner.downcast_ref::<Desynchronized>()) {
+            return ReadErr::Desynchronized{found: desync.found}
+        }
+        if let Some(mismatch) = err.get_ref().and_then(|inner| inner.downcast_ref::<ModeMismatch>()) {
+            return ReadErr::ModeMismatch{local: mismatch.local, peer: mismatch.peer}
+        }
+        if let Some(gap) = err.get_ref().and_then(|inner| inner.downcast_ref::<SequenceGap>()) {
+            return ReadErr::SequenceGap{expected: gap.expected, got: gap.got}
+        }
+        if let Some(mismatch) = err.get_ref().and_then(|inner| inner.downcast_ref::<ChecksumMismatch>()) {
+            return ReadErr::ChecksumMismatch{expected: mismatch.expected, actual: mismatch.actual}
+        }
+        if err.get_ref().is_some_and(|inner| inner.is::<MalformedLength>()) {
+            return ReadErr::MalformedLength
+        }
+        if let Some(negative) = err.get_ref().and_then(|inner| inner.downcast_ref::<NegativeLength>()) {
+            return ReadErr::NegativeLength{prefix: negative.prefix}
+        }
+        if let Some(unknown) = err.get_ref().and_then(|inner| inner.downcast_ref::<UnknownFlags>()) {
+            return ReadErr::UnknownFlags{flags: unknown.flags}
+        }
+        if let Some(closed) = err.get_ref().and_then(|inner| inner.downcast_ref::<PeerClosed>()) {
+            return ReadErr::Closed{reason: closed.reason.clone()}
+        }
+        if crate::Cancelled::is_cancelled(&err) {
+            return ReadErr::Cancelled
+        }
+        if crate::is_timeout(&err) {
+            return if self.is_mid_frame() { ReadErr::TimeoutMidFrame } else { ReadErr::Timeout }
+        }
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => match self.state {
+                ReadState::Header{filled: 0, ..} => ReadErr::Disconnected,
+                ReadState::Header{filled, ..} => ReadErr::TruncatedHeader{got: filled},
+                ReadState::Buffered{length} => ReadErr::TruncatedFrame{expected: length, got: self.partial.len()},
+                ReadState::Control{length} => ReadErr::TruncatedFrame{expected: length, got: self.control.len()},
+                ReadState::Streamed{length, remaining} | ReadState::Discarded{length, remaining} => {
+                    ReadErr::TruncatedFrame{expected: length, got: length - remaining}
+                }
+            },
+            _ => ReadErr::Io(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn oversized_length_is_rejected_before_allocating(){
+        let mut decoder = FrameDecoder::new();
+        decoder.set_max_frame_len(1024);
+        decoder.push(&u32::MAX.to_be_bytes());
+        assert_eq!(decoder.next_frame(), None);
+        let err = decoder.error().expect("the frame is rejected");
+        assert_eq!((err.length, err.max_frame_len), (u32::MAX as usize, 1024));
+        assert_eq!(decoder.partial.capacity(), 0);
+        // The decoder stays failed, the payload is never collected
+        decoder.push(&[0u8; 4096]);
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(decoder.partial.capacity(), 0);
+    }
+
+    #[test]
+    fn stalled_large_frame_allocates_only_what_arrived(){
+        let mut decoder = FrameDecoder::new();
+        decoder.set_max_frame_len(200 * 1024 * 1024);
+        decoder.push(&(100u32 * 1024 * 1024).to_be_bytes());
+        decoder.push(&[7u8; 1024]);
+        let err = decoder.read_buffered(&mut Pushed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(decoder.partial.len(), 1024);
+        assert!(decoder.partial.capacity() <= 2 * (decoder.chunk_size() + 1024), "{}", decoder.partial.capacity());
+        // One chunk is reserved ahead at a time, nothing more while waiting for the rest
+        for _ in 0..8 {
+            assert!(decoder.read_buffered(&mut Pushed).is_err());
+        }
+        assert!(decoder.partial.capacity() <= 2 * (decoder.chunk_size() + 1024));
+        assert!(decoder.is_mid_frame() && !decoder.is_failed());
+    }
+
+    #[test]
+    fn large_frame_buffer_grows_geometrically_with_what_arrived(){
+        let mut decoder = FrameDecoder::new();
+        let length = 4 * 1024 * 1024;
+        decoder.set_max_frame_len(length);
+        decoder.push(&(length as u32).to_be_bytes());
+        let mut capacities = Vec::new();
+        let piece = [7u8; 16 * 1024];
+        for sent in (piece.len()..=length).step_by(piece.len()) {
+            decoder.push(&piece);
+            match decoder.read_buffered(&mut Pushed) {
+                Ok(frame) => assert_eq!(frame.len(), length),
+                Err(err) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+            }
+            let capacity = decoder.partial.capacity();
+            assert!(capacity <= 4 * sent + decoder.chunk_size(), "{} for {}", capacity, sent);
+            if capacities.last() != Some(&capacity) {
+                capacities.push(capacity);
+            }
+        }
+        // The last one is the frame taken out
+        assert!(capacities.len() <= 8, "{:?}", capacities);
+    }
+
+    /// Reads from `bytes`, counting the calls
+    struct Counting{
+        bytes: io::Cursor<Vec<u8>>,
+        reads: usize,
+    }
+
+    impl Read for Counting{
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.bytes.read(buf)
+        }
+    }
+
+    impl ReadUninit for Counting{}
+
+    fn encoded(frames: &[&[u8]]) -> Vec<u8>{
+        let mut bytes = Vec::new();
+        for frame in frames {
+            bytes.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(frame);
+        }
+        bytes
+    }
+
+    #[test]
+    fn small_frames_are_served_from_the_read_buffer(){
+        let frame = [5u8; 16];
+        let bytes = encoded(&[&frame[..]; 1000]);
+        let read_all = |capacity: usize| {
+            let mut decoder = FrameDecoder::new();
+            decoder.set_input_capacity(capacity);
+            let mut src = Counting{bytes: io::Cursor::new(bytes.clone()), reads: 0};
+            for _ in 0..1000 {
+                assert_eq!(decoder.read_buffered(&mut src).unwrap(), frame);
+            }
+            src.reads
+        };
+        // A header and a payload read per frame without the buffer
+        assert_eq!(read_all(0), 2000);
+        let buffered = read_all(DEFAULT_READ_BUFFER_CAPACITY);
+        assert!(buffered <= bytes.len() / DEFAULT_READ_BUFFER_CAPACITY + 1, "{} reads", buffered);
+    }
+
+    /// Hands out one byte per read, failing with `WouldBlock` before each
+    struct Trickle{
+        bytes: Vec<u8>,
+        pos: usize,
+        blocked: bool,
+    }
+
+    impl Trickle{
+        fn new(bytes: Vec<u8>) -> Self{
+            Self{bytes, pos: 0, blocked: false}
+        }
+    }
+
+    impl Read for Trickle{
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if buf.is_empty() {
+                return Ok(0)
+            }
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            let Some(byte) = self.bytes.get(self.pos) else { return Ok(0) };
+            buf[0] = *byte;
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    impl ReadUninit for Trickle{}
+
+    /// Reads `count` frames, retrying after every `WouldBlock`
+    fn read_resuming<R: ReadUninit>(decoder: &mut FrameDecoder, src: &mut R, count: usize) -> Vec<Vec<u8>>{
+        let mut frames = Vec::new();
+        while frames.len() < count {
+            match decoder.read_buffered(src) {
+                Ok(frame) => frames.push(frame),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => panic!("{}", err),
+            }
+        }
+        frames
+    }
+
+    const FRAMES: [&[u8]; 5] = [b"", b"a", &[9u8; 200], b"", b"hello world"];
+
+    /// Applies a header mode to the writer and to the decoder reading back
+    type Configure = fn(&mut crate::FrameEncoder<Vec<u8>>, &mut FrameDecoder);
+
+    fn header_modes() -> Vec<(&'static str, Configure)>{
+        vec![
+            ("plain", |_, _| {}),
+            ("magic", |encoder, decoder| {
+                encoder.set_magic_prefix(true);
+                decoder.set_magic_prefix(true);
+            }),
+            ("hello with a 2 byte prefix", |encoder, decoder| {
+                encoder.set_header_width(HeaderWidth::U16);
+                encoder.set_frame_flags(true);
+                decoder.set_header_width(HeaderWidth::U16);
+                decoder.set_extensions(Extensions{flags: true, ..Extensions::default()});
+            }),
+            ("sequence numbers", |encoder, decoder| {
+                encoder.set_sequence_numbers(true);
+                decoder.set_extensions(Extensions{sequence_numbers: true, ..Extensions::default()});
+            }),
+            ("checksum", |encoder, decoder| {
+                encoder.set_checksum(crate::ChecksumKind::Crc32).unwrap();
+                decoder.set_extensions(Extensions{checksum: crate::ChecksumKind::Crc32, ..Extensions::default()});
+            }),
+            ("flags", |encoder, decoder| {
+                encoder.set_frame_flags(true);
+                decoder.set_extensions(Extensions{flags: true, ..Extensions::default()});
+            }),
+            ("varint", |encoder, decoder| {
+                encoder.set_framing(Framing::Varint);
+                decoder.set_framing(Framing::Varint);
+            }),
+            ("padding", |encoder, decoder| {
+                encoder.set_pad_to(Some(256));
+                decoder.set_strip_padding(true);
+            }),
+            ("all of them", |encoder, decoder| {
+                encoder.set_magic_prefix(true);
+                encoder.set_framing(Framing::Varint);
+                encoder.set_sequence_numbers(true);
+                encoder.set_checksum(crate::ChecksumKind::Crc32).unwrap();
+                encoder.set_frame_flags(true);
+                encoder.set_pad_to(Some(256));
+                decoder.set_magic_prefix(true);
+                decoder.set_framing(Framing::Varint);
+                decoder.set_extensions(Extensions{sequence_numbers: true, checksum: crate::ChecksumKind::Crc32, flags: true});
+                decoder.set_strip_padding(true);
+            }),
+        ]
+    }
+
+    fn encode_with(configure: Configure) -> (Vec<u8>, FrameDecoder){
+        let mut encoder = crate::FrameEncoder::new(Vec::new());
+        let mut decoder = FrameDecoder::new();
+        configure(&mut encoder, &mut decoder);
+        for frame in FRAMES {
+            crate::FrameWriter::write_frame(&mut encoder, frame).unwrap();
+        }
+        (encoder.into_inner(), decoder)
+    }
+
+    /// Decodes `bytes` until the decoder fails, returning the failure
+    fn failure_of(mut decoder: FrameDecoder, bytes: &[u8]) -> io::Error{
+        decoder.push(bytes);
+        while decoder.next_frame().is_some() {}
+        assert!(decoder.is_failed());
+        decoder.failure().expect("the decoder failed")
+    }
+
+    fn is<E: std::error::Error + 'static>(err: &io::Error) -> bool{
+        err.kind() == io::ErrorKind::InvalidData && err.get_ref().is_some_and(|inner| inner.is::<E>())
+    }
+
+    #[test]
+    fn failure_tells_why_the_decoder_stopped(){
+        let (mut bytes, decoder) = encode_with(|encoder, decoder| {
+            encoder.set_checksum(crate::ChecksumKind::Crc32).unwrap();
+            decoder.set_extensions(Extensions{checksum: crate::ChecksumKind::Crc32, ..Extensions::default()});
+        });
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(ChecksumMismatch::is_checksum_mismatch(&failure_of(decoder, &bytes)));
+
+        let (bytes, mut decoder) = encode_with(|encoder, _| encoder.set_sequence_numbers(true));
+        decoder.set_extensions(Extensions{flags: true, ..Extensions::default()});
+        assert!(is::<ModeMismatch>(&failure_of(decoder, &bytes)));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.set_framing(Framing::Varint);
+        assert!(is::<MalformedLength>(&failure_of(decoder, &[0xFF; 16])));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.set_max_frame_len(10);
+        assert!(is::<FrameTooLong>(&failure_of(decoder, &11u32.to_be_bytes())));
+        assert!(FrameDecoder::new().failure().is_none());
+    }
+
+    #[test]
+    fn would_block_at_every_byte_offset_loses_nothing(){
+        for (mode, configure) in header_modes() {
+            let (bytes, mut decoder) = encode_with(configure);
+            let frames = read_resuming(&mut decoder, &mut Trickle::new(bytes), FRAMES.len());
+            assert_eq!(frames, FRAMES, "{}", mode);
+            assert!(!decoder.is_mid_frame(), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn pushed_bytes_split_at_every_offset_decode_the_same(){
+        for (mode, configure) in header_modes() {
+            let (bytes, _) = encode_with(configure);
+            for split in 0..=bytes.len() {
+                let (_, mut decoder) = encode_with(configure);
+                decoder.push(&bytes[..split]);
+                let mut frames = Vec::new();
+                frames.extend(std::iter::from_fn(|| decoder.next_frame()));
+                decoder.push(&bytes[split..]);
+                frames.extend(std::iter::from_fn(|| decoder.next_frame()));
+                assert_eq!(frames, FRAMES, "{} split at {}", mode, split);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_header_waits_for_the_rest(){
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[0, 0]);
+        assert_eq!(decoder.next_frame(), None);
+        assert!(decoder.is_mid_frame() && !decoder.is_failed());
+        decoder.push(&[0, 3, b'a']);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(b"bc\0");
+        assert_eq!(decoder.next_frame().as_deref(), Some(&b"abc"[..]));
+        assert_eq!(decoder.next_frame(), None);
+        assert!(decoder.is_mid_frame());
+        decoder.push(&[0, 0, 0]);
+        assert_eq!(decoder.next_frame().as_deref(), Some(&b""[..]));
+        assert!(!decoder.is_mid_frame());
+    }
+
+    #[test]
+    fn hello_after_the_hello_of_the_peer_is_a_length(){
+        let mut encoder = crate::FrameEncoder::new(Vec::new());
+        encoder.set_sequence_numbers(true);
+        crate::FrameWriter::write_frame(&mut encoder, b"").unwrap();
+        let hello = Extensions{sequence_numbers: true, ..Extensions::default()}.hello();
+        let mut decoder = FrameDecoder::new();
+        decoder.set_extensions(Extensions{sequence_numbers: true, ..Extensions::default()});
+        decoder.set_max_frame_len(u32::MAX as usize);
+        decoder.push(&encoder.into_inner());
+        assert_eq!(decoder.next_frame().as_deref(), Some(&b""[..]));
+        decoder.push(&hello);
+        decoder.push(&1u32.to_be_bytes());
+        decoder.push(b"payload");
+        assert_eq!(decoder.next_frame(), None);
+        assert!(decoder.is_mid_frame() && !decoder.is_failed());
+    }
+
+    /// Frames of "Hello world" and an empty one as tokio's `LengthDelimitedCodec` encodes them
+    fn length_delimited_codec_frames() -> Vec<(&'static str, FramingConfig, Vec<u8>)>{
+        let frames = |header: &dyn Fn(usize) -> Vec<u8>| {
+            let mut bytes = header(11);
+            bytes.extend_from_slice(b"Hello world");
+            bytes.extend_from_slice(&header(0));
+            bytes
+        };
+        vec![
+            ("default", FramingConfig::new(), frames(&|len| (len as u32).to_be_bytes().to_vec())),
+            ("length_field_type::<u16>()", FramingConfig::new().header_width(HeaderWidth::U16),
+                frames(&|len| (len as u16).to_be_bytes().to_vec())),
+            ("length_field_type::<u64>()", FramingConfig::new().header_width(HeaderWidth::U64),
+                frames(&|len| (len as u64).to_be_bytes().to_vec())),
+            ("little_endian()", FramingConfig::new().little_endian(),
+                frames(&|len| (len as u32).to_le_bytes().to_vec())),
+            ("length_field_type::<u16>().length_adjustment(-2)", FramingConfig::new().header_width(HeaderWidth::U16).length_adjustment(-2),
+                frames(&|len| (len as u16 + 2).to_be_bytes().to_vec())),
+            ("length_adjustment(-4), as length_includes_header", FramingConfig::new().length_includes_header(true),
+                frames(&|len| (len as u32 + 4).to_be_bytes().to_vec())),
+        ]
+    }
+
+    #[test]
+    fn length_delimited_codec_bytes_interoperate(){
+        for (name, config, bytes) in length_delimited_codec_frames() {
+            let mut encoder = crate::FrameEncoder::new(Vec::new());
+            encoder.set_framing_config(config);
+            crate::FrameWriter::write_frame(&mut encoder, b"Hello world").unwrap();
+            crate::FrameWriter::write_frame(&mut encoder, b"").unwrap();
+            assert_eq!(encoder.into_inner(), bytes, "{}", name);
+
+            let mut decoder = FrameDecoder::new();
+            decoder.set_framing_config(config);
+            decoder.push(&bytes);
+            assert_eq!(decoder.next_frame().as_deref(), Some(&b"Hello world"[..]), "{}", name);
+            assert_eq!(decoder.next_frame().as_deref(), Some(&b""[..]), "{}", name);
+            assert!(!decoder.is_mid_frame(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn control_frames_are_handled_not_returned(){
+        let mut encoder = crate::FrameEncoder::new(Vec::new());
+        encoder.set_frame_flags(true);
+        encoder.write_frame_with_flags(&[CLOSE_FRAME + 1, 1, 2, 3], FrameFlag::Control.into()).unwrap();
+        crate::FrameWriter::write_frame(&mut encoder, b"data").unwrap();
+        encoder.write_frame_with_flags(&[CLOSE_FRAME, b'b', b'y', b'e'], FrameFlag::Control.into()).unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.set_extensions(Extensions{flags: true, ..Extensions::default()});
+        decoder.push(&encoder.into_inner());
+        // The unknown control frame is skipped
+        assert_eq!(decoder.read_buffered(&mut Pushed).unwrap(), b"data");
+        let err = decoder.read_buffered(&mut Pushed).unwrap_err();
+        assert!(PeerClosed::is_peer_closed(&err));
+        assert!(is_closed(&err));
+        assert_eq!(decoder.close_reason(), Some(&b"bye"[..]));
+        assert!(PeerClosed::is_peer_closed(&decoder.read_buffered(&mut Pushed).unwrap_err()));
+    }
+
+    #[test]
+    fn resync_skips_to_the_next_magic(){
+        let mut encoder = crate::FrameEncoder::new(Vec::new());
+        encoder.set_magic_prefix(true);
+        crate::FrameWriter::write_frame(&mut encoder, b"after").unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.set_magic_prefix(true);
+        decoder.push(b"junk!");
+        decoder.push(&encoder.into_inner());
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(decoder.desynchronized().map(|err| err.found), Some(*b"junk"));
+        assert_eq!(decoder.resync(&mut Pushed).unwrap(), 5);
+        assert!(!decoder.is_failed());
+        assert_eq!(decoder.next_frame().as_deref(), Some(&b"after"[..]));
+    }
+
+    #[test]
+    fn resync_needs_the_magic_prefix_mode(){
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.resync(&mut Pushed).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn read_frame_into_keeps_the_capacity_of_the_buffer(){
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encoded(&[b"short", b"again"]));
+        let mut buf = Vec::with_capacity(4096);
+        assert_eq!(decoder.read_frame_into(&mut Pushed, &mut buf).unwrap(), 5);
+        assert_eq!(buf, b"short");
+        assert!(buf.capacity() >= 4096);
+        assert_eq!(decoder.read_frame_into(&mut Pushed, &mut buf).unwrap(), 5);
+        assert_eq!(buf, b"again");
+        assert!(buf.capacity() >= 4096);
+        // Nothing more arrived, the buffer is left empty but kept
+        assert_eq!(decoder.read_frame_into(&mut Pushed, &mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(buf.capacity() >= 4096);
+    }
+    #[test]
+    fn read_frame_into_keeps_the_capacity_of_the_buffer_mid_frame(){
+        let mut decoder = FrameDecoder::new();
+        let bytes = encoded(&[&[7; 100]]);
+        let (head, tail) = bytes.split_at(40);
+        decoder.push(head);
+        let mut buf = Vec::with_capacity(4096);
+        assert_eq!(decoder.read_frame_into(&mut Pushed, &mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 4096);
+        decoder.push(tail);
+        assert_eq!(decoder.read_frame_into(&mut Pushed, &mut buf).unwrap(), 100);
+        assert_eq!(buf, [7; 100]);
+    }
+}
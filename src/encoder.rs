@@ -0,0 +1,298 @@
+use std::io;
+use std::io::{Read, Write};
+use crate::{FrameWriter, FrameDecoder, Frame, FrameBuf, FrameFlags, Framing, FramingConfig, HeaderWidth, ChecksumKind,
+            FlushPolicy, PadOverflow, WriteErr, WriteProgress, WriterState, WriteState, WritePath, is_timeout, read_source};
+
+/// Writes frames to any `Write`, a file or a buffer, for a `FrameDecoder` or a connection to read back.
+/// Takes the same settings as `Connection` and buffers by the same flush policies,
+/// buffered frames are lost when it is dropped without `flush`
+#[derive(Debug)]
+pub struct FrameEncoder<W: Write>{
+    inner: W,
+    /// Only its framing settings are used
+    decoder: FrameDecoder,
+    state: WriteState,
+}
+
+impl<W: Write> FrameEncoder<W>{
+    pub fn new(inner: W) -> Self{
+        Self{inner, decoder: FrameDecoder::new(), state: WriteState::default()}
+    }
+    /// Carries on where another writer over the same stream stopped
+    pub(crate) fn resume(inner: W, decoder: FrameDecoder, state: WriteState) -> Self{
+        Self{inner, decoder, state}
+    }
+    pub fn get_ref(&self) -> &W{
+        &self.inner
+    }
+    /// Writing to it directly corrupts the frame stream
+    pub fn get_mut(&mut self) -> &mut W{
+        &mut self.inner
+    }
+    /// Frames still buffered are dropped, `flush` first
+    pub fn into_inner(self) -> W{
+        self.inner
+    }
+    /// See `Connection::set_magic_prefix`
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.decoder.set_magic_prefix(magic)
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.decoder.magic_prefix()
+    }
+    /// See `Connection::set_framing_config`
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.decoder.set_framing_config(config)
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.decoder.framing_config()
+    }
+    pub fn set_framing(&mut self, framing: Framing){
+        self.decoder.set_framing(framing)
+    }
+    pub fn framing(&self) -> Framing{
+        self.decoder.framing()
+    }
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.decoder.set_header_width(width)
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.decoder.header_width()
+    }
+    /// See `Connection::set_sequence_numbers`
+    pub fn set_sequence_numbers(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.sequence_numbers = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn sequence_numbers(&self) -> bool{
+        self.decoder.extensions().sequence_numbers
+    }
+    /// See `Connection::set_checksum`, fails the same way
+    pub fn set_checksum(&mut self, kind: ChecksumKind) -> io::Result<()>{
+        kind.check_available()?;
+        let mut extensions = self.decoder.extensions();
+        extensions.checksum = kind;
+        self.decoder.set_extensions(extensions);
+        Ok(())
+    }
+    pub fn checksum(&self) -> ChecksumKind{
+        self.decoder.extensions().checksum
+    }
+    /// See `Connection::set_frame_flags`
+    pub fn set_frame_flags(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.flags = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn frame_flags(&self) -> bool{
+        self.decoder.extensions().flags
+    }
+    /// See `Connection::set_flush_policy`
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy){
+        self.state.flush_policy = policy;
+    }
+    pub fn flush_policy(&self) -> FlushPolicy{
+        self.state.flush_policy
+    }
+    /// See `Connection::set_pad_to`
+    pub fn set_pad_to(&mut self, pad_to: Option<usize>){
+        self.state.pad_to = pad_to;
+    }
+    pub fn pad_to(&self) -> Option<usize>{
+        self.state.pad_to
+    }
+    pub fn set_pad_overflow(&mut self, overflow: PadOverflow){
+        self.state.pad_overflow = overflow;
+    }
+    pub fn pad_overflow(&self) -> PadOverflow{
+        self.state.pad_overflow
+    }
+    /// Bytes written but not passed to the writer yet
+    pub fn buffered_len(&self) -> usize{
+        self.state.write_buf.len()
+    }
+    pub fn write_frame_with_flags(&mut self, frame: &[u8], flags: FrameFlags) -> Result<(), WriteErr>{
+        self.path().send_frame(frame, flags, true, false)
+    }
+    /// See `Connection::resume_write`
+    pub fn resume_write(&mut self) -> Result<WriteProgress, WriteErr>{
+        match self.path().flush_buffer() {
+            Ok(()) => Ok(WriteProgress::Complete),
+            Err(err) if is_timeout(&err) => Ok(WriteProgress::Pending),
+            Err(err) => Err(WriteErr::Io(err)),
+        }
+    }
+    pub fn is_write_pending(&self) -> bool{
+        self.state.write_pending
+    }
+    /// See `Connection::is_desynced`
+    pub fn is_desynced(&self) -> bool{
+        self.state.writer_state != WriterState::Healthy
+    }
+    pub fn writer_state(&self) -> WriterState{
+        self.state.writer_state
+    }
+    fn path(&mut self) -> WritePath<'_, &mut W>{
+        WritePath{stream: &mut self.inner, decoder: &self.decoder, state: &mut self.state, turn: None}
+    }
+}
+
+impl<W: Write> FrameWriter for FrameEncoder<W>{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame, FrameFlags::empty())
+    }
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame.payload(), frame.meta.flags)
+    }
+    /// Streams the payload unless a checksum is on, its digest needs the whole payload first
+    fn write_frame_from_reader(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr>{
+        if self.checksum() == ChecksumKind::None {
+            return self.path().write_streamed(src, len, 0, false)
+        }
+        let frame = read_source(src, len, false)?;
+        self.write_frame(&frame)
+    }
+    fn write_frame_from_reader_padded(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr>{
+        if self.checksum() == ChecksumKind::None {
+            return self.path().write_streamed(src, len, 0, true)
+        }
+        let frame = read_source(src, len, true)?;
+        self.write_frame(&frame)
+    }
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr>{
+        self.path().send_vectored(parts)
+    }
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr>{
+        self.path().send_framebuf(buf)
+    }
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr>{
+        let frames: Vec<&[u8]> = frames.into_iter().collect();
+        self.path().write_frames(&frames)
+    }
+    /// Sends the buffered frames, then flushes the writer
+    fn flush(&mut self) -> io::Result<()>{
+        self.path().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::Extensions;
+
+    /// Takes `budget` bytes, then fails every write with `kind`
+    struct FailAfter{
+        wire: Vec<u8>,
+        budget: usize,
+        kind: io::ErrorKind,
+    }
+
+    impl FailAfter{
+        fn new(budget: usize, kind: io::ErrorKind) -> Self{
+            Self{wire: Vec::new(), budget, kind}
+        }
+    }
+
+    impl Write for FailAfter{
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[io::IoSlice::new(buf)])
+        }
+        fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(self.kind.into())
+            }
+            let mut n = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.budget - n);
+                self.wire.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            self.budget -= n;
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    type WriteFn = fn(&mut FrameEncoder<FailAfter>, &[u8]) -> Result<(), WriteErr>;
+
+    const WRITES: [(&str, WriteFn); 2] = [
+        ("write_frame", |encoder, frame| encoder.write_frame(frame)),
+        ("write_frame_vectored", |encoder, frame| {
+            let (head, tail) = frame.split_at(frame.len() / 2);
+            encoder.write_frame_vectored(&[io::IoSlice::new(head), io::IoSlice::new(tail)])
+        }),
+    ];
+
+    fn encoder(budget: usize, kind: io::ErrorKind, extended: bool) -> FrameEncoder<FailAfter>{
+        let mut encoder = FrameEncoder::new(FailAfter::new(budget, kind));
+        if extended {
+            // The hello goes with the first frame, its header is 13 bytes longer
+            encoder.set_sequence_numbers(true);
+            encoder.set_checksum(ChecksumKind::Crc32).unwrap();
+        }
+        encoder
+    }
+
+    fn decoded(wire: &[u8], extended: bool) -> Vec<Vec<u8>>{
+        let mut decoder = FrameDecoder::new();
+        if extended {
+            decoder.set_extensions(Extensions{sequence_numbers: true, checksum: ChecksumKind::Crc32, ..Extensions::default()});
+        }
+        decoder.push(wire);
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn failing_write_after_any_byte_sends_the_frame_whole_or_desynchronizes(){
+        let frame: Vec<u8> = (0..100u8).collect();
+        for extended in [false, true] {
+            for (name, write) in WRITES {
+                let mut whole = encoder(usize::MAX, io::ErrorKind::BrokenPipe, extended);
+                write(&mut whole, &frame).unwrap();
+                let expected = whole.into_inner().wire;
+                for budget in 0..=expected.len() {
+                    let mut encoder = encoder(budget, io::ErrorKind::BrokenPipe, extended);
+                    let result = write(&mut encoder, &frame);
+                    let case = format!("{} after {} of {} bytes", name, budget, expected.len());
+                    assert_eq!(encoder.get_ref().wire, expected[..budget], "{}", case);
+                    encoder.get_mut().budget = usize::MAX;
+                    if budget == expected.len() {
+                        assert!(result.is_ok() && !encoder.is_desynced(), "{}", case);
+                    } else if budget == 0 {
+                        // Nothing went out, the frame is lost and the stream is intact
+                        assert!(matches!(result, Err(WriteErr::Io(_))) && !encoder.is_desynced(), "{}", case);
+                        write(&mut encoder, &frame).unwrap();
+                        assert_eq!(decoded(&encoder.get_ref().wire, extended), std::slice::from_ref(&frame), "{}", case);
+                    } else {
+                        assert!(matches!(result, Err(WriteErr::Io(_))) && encoder.is_desynced(), "{}", case);
+                        assert!(matches!(write(&mut encoder, &frame), Err(WriteErr::Desynchronized)), "{}", case);
+                        assert_eq!(encoder.get_ref().wire, expected[..budget], "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn would_block_after_any_byte_resumes_the_frame(){
+        let frame: Vec<u8> = (0..100u8).collect();
+        for extended in [false, true] {
+            for (name, write) in WRITES {
+                for budget in 0..frame.len() + 4 {
+                    let mut encoder = encoder(budget, io::ErrorKind::WouldBlock, extended);
+                    let case = format!("{} after {} bytes", name, budget);
+                    assert!(matches!(write(&mut encoder, &frame), Err(WriteErr::Io(err)) if err.kind() == io::ErrorKind::WouldBlock), "{}", case);
+                    assert!(encoder.is_write_pending() && !encoder.is_desynced(), "{}", case);
+                    assert!(matches!(write(&mut encoder, b"refused"), Err(WriteErr::Io(_))), "{}", case);
+                    encoder.get_mut().budget = usize::MAX;
+                    assert!(matches!(encoder.resume_write(), Ok(WriteProgress::Complete)), "{}", case);
+                    write(&mut encoder, b"next").unwrap();
+                    assert_eq!(decoded(&encoder.get_ref().wire, extended), [frame.clone(), b"next".to_vec()], "{}", case);
+                }
+            }
+        }
+    }
+}
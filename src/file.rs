@@ -0,0 +1,58 @@
+use std::io;
+use std::io::Read;
+use std::fs::File;
+
+/// Reads a region of a file by position, leaving the file's own position alone where the platform allows
+pub(crate) struct FileRegion<'a>{
+    file: &'a File,
+    offset: u64,
+}
+
+impl<'a> FileRegion<'a>{
+    pub(crate) fn new(file: &'a File, offset: u64) -> Self{
+        Self{file, offset}
+    }
+}
+
+impl Read for FileRegion<'_>{
+    #[cfg(unix)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+    #[cfg(not(unix))]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::{Seek, SeekFrom};
+        let mut file = self.file;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let n = file.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Largest count a single `sendfile` call moves
+#[cfg(target_os = "linux")]
+const MAX_SENDFILE: u64 = 0x7fff_f000;
+
+/// Sends up to `len` bytes of `file` from `offset` straight from the page cache to the socket `fd`,
+/// returning how many. `Ok(None)` when the kernel cannot do it for this pair of descriptors
+#[cfg(target_os = "linux")]
+pub(crate) fn sendfile(fd: std::os::unix::io::RawFd, file: &File, offset: u64, len: u64) -> io::Result<Option<usize>>{
+    use std::os::unix::io::AsRawFd;
+    let mut offset = offset as libc::off_t;
+    loop {
+        let n = unsafe { libc::sendfile(fd, file.as_raw_fd(), &mut offset, len.min(MAX_SENDFILE) as usize) };
+        if n >= 0 {
+            return Ok(Some(n as usize))
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EINVAL) | Some(libc::ENOSYS) => return Ok(None),
+            _ => return Err(err),
+        }
+    }
+}
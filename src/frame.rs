@@ -0,0 +1,159 @@
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+use crate::{FrameFlags, MAX_FRAME_HEADER_LEN};
+
+/// What is known about a frame besides its payload.
+/// Fields are added as readers learn more, construct it with `Default`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct FrameMeta{
+    /// When the frame finished arriving, needs `Connection::set_timestamping`
+    pub received_at: Option<Instant>,
+    /// Flags received or to send, see `Connection::set_frame_flags`
+    pub flags: FrameFlags,
+    /// Sequence number the frame arrived with, see `Connection::set_sequence_numbers`.
+    /// Ignored when writing, the writer numbers its frames itself
+    pub sequence: Option<u32>,
+}
+
+/// Payload with its metadata, read with `read_frame_meta` and written with `write_frame_meta`.
+/// Clones share the payload
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Frame{
+    payload: Arc<Vec<u8>>,
+    pub meta: FrameMeta,
+}
+
+impl Frame{
+    pub fn new(payload: Vec<u8>, meta: FrameMeta) -> Self{
+        Self{payload: Arc::new(payload), meta}
+    }
+    pub fn payload(&self) -> &[u8]{
+        &self.payload
+    }
+    /// Copies the payload only if a clone still shares it
+    pub fn into_payload(self) -> Vec<u8>{
+        Arc::try_unwrap(self.payload).unwrap_or_else(|shared| shared.to_vec())
+    }
+    pub fn len(&self) -> usize{
+        self.payload.len()
+    }
+    pub fn is_empty(&self) -> bool{
+        self.payload.is_empty()
+    }
+}
+
+impl From<Vec<u8>> for Frame{
+    fn from(payload: Vec<u8>) -> Self {
+        Self::new(payload, FrameMeta::default())
+    }
+}
+
+impl From<&[u8]> for Frame{
+    fn from(payload: &[u8]) -> Self {
+        Self::from(payload.to_vec())
+    }
+}
+
+impl From<Frame> for Vec<u8>{
+    fn from(frame: Frame) -> Self {
+        frame.into_payload()
+    }
+}
+
+impl AsRef<[u8]> for Frame{
+    fn as_ref(&self) -> &[u8] {
+        self.payload()
+    }
+}
+
+/// Payload buffer keeping room for the header in front, so `write_framebuf` sends
+/// header and payload from one contiguous buffer without copying the payload.
+/// Reusable: `clear` keeps the capacity
+#[derive(Debug, Clone)]
+pub struct FrameBuf{
+    /// Reserved header space followed by the payload
+    buf: Vec<u8>,
+}
+
+impl FrameBuf{
+    pub fn new() -> Self{
+        Self::with_capacity(0)
+    }
+    /// Room for a payload of `capacity` bytes
+    pub fn with_capacity(capacity: usize) -> Self{
+        let mut buf = Vec::with_capacity(MAX_FRAME_HEADER_LEN + capacity);
+        buf.resize(MAX_FRAME_HEADER_LEN, 0);
+        Self{buf}
+    }
+    pub fn payload(&self) -> &[u8]{
+        &self.buf[MAX_FRAME_HEADER_LEN..]
+    }
+    pub fn payload_mut(&mut self) -> &mut [u8]{
+        &mut self.buf[MAX_FRAME_HEADER_LEN..]
+    }
+    pub fn len(&self) -> usize{
+        self.buf.len() - MAX_FRAME_HEADER_LEN
+    }
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+    pub fn capacity(&self) -> usize{
+        self.buf.capacity() - MAX_FRAME_HEADER_LEN
+    }
+    pub fn reserve(&mut self, additional: usize){
+        self.buf.reserve(additional)
+    }
+    pub fn clear(&mut self){
+        self.buf.truncate(MAX_FRAME_HEADER_LEN)
+    }
+    pub fn truncate(&mut self, len: usize){
+        self.buf.truncate(MAX_FRAME_HEADER_LEN + len)
+    }
+    pub fn push(&mut self, byte: u8){
+        self.buf.push(byte)
+    }
+    pub fn extend_from_slice(&mut self, bytes: &[u8]){
+        self.buf.extend_from_slice(bytes)
+    }
+    /// Puts `header` right before the payload, returning both
+    pub(crate) fn with_header(&mut self, header: &[u8]) -> &[u8]{
+        let start = MAX_FRAME_HEADER_LEN - header.len();
+        self.buf[start..MAX_FRAME_HEADER_LEN].copy_from_slice(header);
+        &self.buf[start..]
+    }
+}
+
+impl Default for FrameBuf{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for FrameBuf{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Extend<u8> for FrameBuf{
+    fn extend<T: IntoIterator<Item = u8>>(&mut self, iter: T) {
+        self.buf.extend(iter)
+    }
+}
+
+impl AsRef<[u8]> for FrameBuf{
+    fn as_ref(&self) -> &[u8] {
+        self.payload()
+    }
+}
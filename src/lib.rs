@@ -1,153 +1,3625 @@
-pub use unisocket::SocketAddr;
-use unisocket::{Stream, Listener};
+mod decoder;
+mod cancel;
+mod source;
+mod limit;
+mod checksum;
+pub mod cobs;
+pub mod line;
+pub mod testing;
+mod stdio;
+mod frame;
+mod file;
+mod queue;
+mod shared;
+mod encoder;
+mod adapter;
+mod chunking;
+mod message;
+mod sockopt;
+mod raw;
+mod poll;
+mod connect;
+mod retry;
+mod reconnect;
+mod session;
+mod activity;
+mod turn;
+#[cfg(test)]
+mod tests;
+
+pub use unisocket::{SocketAddr, Stream};
+pub use decoder::FrameDecoder;
+pub use cancel::{CancelToken, Cancelled};
+pub use limit::{RateLimit, WouldExceed};
+pub use stdio::StdioConnection;
+pub use frame::{Frame, FrameMeta, FrameBuf};
+pub use queue::{QueuedWriter, OverflowPolicy, Priority, DropReason, SendError};
+pub use shared::SharedWriter;
+pub use encoder::FrameEncoder;
+pub use adapter::{SfpReader, SfpWriter, GenericConnection};
+pub use chunking::{ChunkingWriter, FrameByteReader};
+pub use message::MessageAborted;
+pub use sockopt::KeepaliveConfig;
+pub use retry::RetryPolicy;
+pub use reconnect::{ReconnectingWriter, ResendPolicy};
+pub use session::{SessionWriter, SessionAcceptor, SessionReader, ReplayBuffer};
+pub use activity::ConnectionStats;
+pub use turn::{ConcurrentReads, ConcurrentReader, AbandonedFrame};
+pub use connect::{AllAttemptsFailed, IpPreference, ResolveFailed};
+use source::{Source, ReadControl, ReadTurn};
+use decoder::LengthOutOfRange;
+use sockopt::SocketBuffer;
+use activity::Activity;
+use turn::Turn;
+use poll::Readiness;
+use limit::{FrameRate, Bandwidth};
+pub use checksum::{ChecksumKind, FrameHasher, Crc32};
+#[cfg(feature = "crc32c")]
+pub use checksum::Crc32c;
+#[cfg(feature = "xxhash64")]
+pub use checksum::XxHash64;
+use unisocket::Listener;
 use std::io;
-use std::io::{Read, Write};
-use std::time::Duration;
+use std::io::{Read, Write, Seek};
+use std::time::{Duration, Instant};
 use std::fmt;
 use std::fmt::{Formatter, Debug};
+use std::fs::File;
 use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
 #[cfg(unix)]
 use std::os::unix::net as unix;
 
-#[derive(Debug, Clone)]
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+pub const DEFAULT_READ_CHUNK_SIZE: usize = 64 * 1024;
+pub const DEFAULT_FRAME_BUFFER_HIGH_WATER: usize = 64 * 1024;
+pub const DEFAULT_READ_BUFFER_CAPACITY: usize = 8 * 1024;
+/// Starts every header in magic prefix mode, see `Connection::set_magic_prefix`
+pub const FRAME_MAGIC: [u8; 4] = *b"SFP\x01";
+
+#[derive(Debug)]
 pub enum WriteErr{
-    I0(io::Error),
+    Io(io::Error),
     TooLongFrame,
+    /// `write_frame_timeout` ran out of time. If part of the frame was sent,
+    /// the writer is desynchronized, see `Connection::is_desynced`
+    Timeout,
+    /// An earlier frame was cut short after part of it reached the socket,
+    /// the peer can no longer find the frame boundaries. Nothing more can be written
+    Desynchronized,
+}
+
+#[derive(Debug)]
+pub enum ReadErr{
+    /// Nothing of the next frame arrived in time, the connection is intact
+    Timeout,
+    /// Part of the frame arrived before the timeout. Received bytes are kept,
+    /// the next read resumes the frame unless the connection is dropped
+    TimeoutMidFrame,
+    /// Peer closed the connection cleanly between frames
+    Disconnected,
+    /// Peer closed the connection with `close_graceful`, every following read fails the same way
+    Closed{reason: Vec<u8>},
+    /// Connection was lost inside a header
+    TruncatedHeader{got: usize},
+    /// Connection was lost inside a payload, the frame is lost
+    TruncatedFrame{expected: usize, got: usize},
+    /// Peer declared a frame longer than `max_frame_len`.
+    /// The stream is no longer at a frame boundary, every following read fails the same way
+    TooLongFrame{length: usize, max_frame_len: usize},
+    /// A header did not start with the frame magic, or the peer sends the magic while it is disabled.
+    /// Every following read fails the same way
+    Desynchronized{found: [u8; 4]},
+    /// The peer announced different extensions, or none. Every following read fails the same way
+    ModeMismatch{local: Extensions, peer: Extensions},
+    /// Frames were lost or replayed, the frame itself is returned by the next read
+    SequenceGap{expected: u32, got: u32},
+    /// The payload was corrupted on the way, the frame is lost. Every following read fails the same way
+    ChecksumMismatch{expected: u64, actual: u64},
+    /// A varint length prefix was too long or not in its shortest form. Every following read fails the same way
+    MalformedLength,
+    /// The length prefix is smaller than the header it should include, see `FramingConfig`.
+    /// Every following read fails the same way
+    NegativeLength{prefix: u64},
+    /// The peer set flags this version does not know, the frame is skipped
+    UnknownFlags{flags: u8},
+    /// Reading was stopped by a `CancelToken`
+    Cancelled,
+    /// The frame was read but the previous read timeout could not be put back,
+    /// the temporary one stays in effect
+    TimeoutNotRestored{frame: Vec<u8>, error: io::Error},
+    Io(io::Error),
+}
+
+#[derive(Debug)]
+pub enum ReadStringErr{
+    Io(io::Error),
+    /// The frame was read but is not UTF-8, `into_bytes` returns it unchanged
+    InvalidUtf8(std::string::FromUtf8Error),
+}
+
+/// Copyable summary of a `ReadErr`, kept by the connection when its `Iterator` stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFailure{
+    Timeout,
+    TimeoutMidFrame,
+    /// Clean end of the connection at a frame boundary
+    Disconnected,
+    /// See `ReadErr::Closed`, the reason is kept by `Connection::close_reason`
+    Closed,
+    TruncatedHeader{got: usize},
+    TruncatedFrame{expected: usize, got: usize},
+    TooLongFrame{length: usize, max_frame_len: usize},
+    Desynchronized{found: [u8; 4]},
+    ModeMismatch{local: Extensions, peer: Extensions},
+    SequenceGap{expected: u32, got: u32},
+    ChecksumMismatch{expected: u64, actual: u64},
+    MalformedLength,
+    NegativeLength{prefix: u64},
+    UnknownFlags{flags: u8},
+    Cancelled,
+    TimeoutNotRestored(io::ErrorKind),
+    Io(io::ErrorKind),
+}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by `read_frame` when the peer declares
+/// a frame longer than the connection's `max_frame_len`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTooLong{
+    pub length: usize,
+    pub max_frame_len: usize,
+}
+
+/// Frame returned by `read_frame_spilled`
+#[derive(Debug)]
+pub enum FrameData{
+    InMemory(Vec<u8>),
+    /// Payload of the given length in an anonymous temp file positioned at its start,
+    /// the file is deleted once closed
+    Spilled(File, u64),
+}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads when the framing of the peer
+/// does not match: a header misses `FRAME_MAGIC` in magic prefix mode, or has it while the mode is off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Desynchronized{
+    /// First bytes of the offending header
+    pub found: [u8; 4],
+}
+
+/// Encoding of the length prefix of every frame, see `Connection::set_framing`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing{
+    /// 4 byte big-endian length
+    #[default]
+    Fixed,
+    /// LEB128 length of 1 to 5 bytes, 1 byte for frames under 128 bytes
+    Varint,
+}
+
+/// Width of fixed length prefixes, see `Connection::set_header_width`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderWidth{
+    U16,
+    #[default]
+    U32,
+    U64,
+}
+
+impl HeaderWidth{
+    pub fn bytes(self) -> usize{
+        match self {
+            HeaderWidth::U16 => 2,
+            HeaderWidth::U32 => 4,
+            HeaderWidth::U64 => 8,
+        }
+    }
+    /// Biggest length the prefix holds, including the header extensions in use
+    pub fn max_length(self) -> u64{
+        match self {
+            HeaderWidth::U16 => u16::MAX as u64,
+            HeaderWidth::U32 => u32::MAX as u64,
+            HeaderWidth::U64 => u64::MAX,
+        }
+    }
+}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads when a varint length prefix
+/// is longer than 5 bytes, does not fit a `u32` or is not in its shortest form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MalformedLength;
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads when the length of the rest
+/// of a frame, out of its length prefix and the `FramingConfig`, is negative
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegativeLength{
+    pub prefix: u64,
+}
+
+/// Length prefix format of a connection, see `Connection::set_framing_config`.
+/// Mirrors the options of tokio's `LengthDelimitedCodec`, the default is plain SFP:
+/// a 4 byte big-endian length of the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FramingConfig{
+    pub(crate) framing: Framing,
+    pub(crate) width: HeaderWidth,
+    pub(crate) little_endian: bool,
+    pub(crate) length_includes_header: bool,
+    pub(crate) length_adjustment: i64,
+}
+
+impl FramingConfig{
+    pub fn new() -> Self{
+        Self::default()
+    }
+    pub fn framing(mut self, framing: Framing) -> Self{
+        self.framing = framing;
+        self
+    }
+    pub fn header_width(mut self, width: HeaderWidth) -> Self{
+        self.width = width;
+        self
+    }
+    pub fn big_endian(mut self) -> Self{
+        self.little_endian = false;
+        self
+    }
+    /// Fixed length prefixes only, varints are always little-endian
+    pub fn little_endian(mut self) -> Self{
+        self.little_endian = true;
+        self
+    }
+    /// The length prefix also counts the magic and itself
+    pub fn length_includes_header(mut self, includes: bool) -> Self{
+        self.length_includes_header = includes;
+        self
+    }
+    /// Added to the length prefix to get the length of the rest of the frame,
+    /// like `LengthDelimitedCodec::length_adjustment`: a length counting its own 4 bytes
+    /// takes an adjustment of -4 (or `length_includes_header`, not both)
+    pub fn length_adjustment(mut self, adjustment: i64) -> Self{
+        self.length_adjustment = adjustment;
+        self
+    }
+}
+
+/// Whether a writer still sends frames, see `Connection::writer_state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriterState{
+    Healthy,
+    /// A frame was cut short after part of it reached the socket, every write since fails
+    /// with `WriteErr::Desynchronized`. Frames queued before still go out with `flush`
+    Poisoned{
+        /// The frame cut short and every frame refused since
+        frames_lost: u64,
+    },
+}
+
+/// Outcome of `Connection::resume_write`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteProgress{
+    /// Everything written so far was sent
+    Complete,
+    /// The socket took only part of it, resume once it is writable again
+    Pending,
+}
+
+/// When buffered frames are sent, see `Connection::set_flush_policy`.
+/// The default sends every frame as it is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy{
+    pub(crate) max_bytes: Option<usize>,
+    pub(crate) max_frames: Option<usize>,
+    pub(crate) max_delay: Option<Duration>,
+}
+
+impl Default for FlushPolicy{
+    fn default() -> Self {
+        Self::immediate()
+    }
+}
+
+impl FlushPolicy{
+    pub fn immediate() -> Self{
+        Self{max_bytes: None, max_frames: Some(1), max_delay: None}
+    }
+    /// Buffers until `flush`, add limits with `max_bytes`, `max_frames` and `max_delay`
+    pub fn explicit() -> Self{
+        Self{max_bytes: None, max_frames: None, max_delay: None}
+    }
+    /// Sends the buffer once it holds this many bytes
+    pub fn max_bytes(mut self, bytes: usize) -> Self{
+        self.max_bytes = Some(bytes);
+        self
+    }
+    /// Sends the buffer once it holds this many frames
+    pub fn max_frames(mut self, frames: usize) -> Self{
+        self.max_frames = Some(frames);
+        self
+    }
+    /// Sends the buffer once its oldest frame waited this long. There is no timer:
+    /// the age is checked by writes and by `Connection::flush_expired`
+    pub fn max_delay(mut self, delay: Duration) -> Self{
+        self.max_delay = Some(delay);
+        self
+    }
+    /// Whether frames buffered since `since` waited too long
+    pub(crate) fn is_expired(&self, since: Option<Instant>, now: Instant) -> bool{
+        match (self.max_delay, since) {
+            (Some(delay), Some(since)) => now.saturating_duration_since(since) >= delay,
+            _ => false,
+        }
+    }
+    pub(crate) fn is_due(&self, bytes: usize, frames: usize) -> bool{
+        self.max_bytes.is_some_and(|max| bytes >= max) || self.max_frames.is_some_and(|max| frames >= max)
+    }
+    /// Whether every frame is due as soon as it is written
+    pub(crate) fn is_immediate(&self) -> bool{
+        self.is_due(0, 1)
+    }
+}
+
+/// Nagle-like coalescing of small frames, see `Connection::set_write_coalescing`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteCoalescing{
+    /// Longest a frame waits in the buffer, checked lazily like `FlushPolicy::max_delay`
+    pub max_delay: Duration,
+    /// The buffer is sent once it holds this many bytes, a frame reaching it goes out with the buffer right away
+    pub max_bytes: usize,
+}
+
+impl From<WriteCoalescing> for FlushPolicy{
+    fn from(coalescing: WriteCoalescing) -> Self {
+        FlushPolicy::explicit().max_bytes(coalescing.max_bytes).max_delay(coalescing.max_delay)
+    }
+}
+
+/// What a padded writer does with a frame too long for the pad size, see `Connection::set_pad_to`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PadOverflow{
+    /// Fails with `WriteErr::TooLongFrame`
+    #[default]
+    Reject,
+    /// Sends it with its length prefix and no padding, the peer still reads it
+    Unpadded,
+}
+
+/// Length of the true payload length in front of a padded payload
+const PAD_PREFIX_LEN: usize = 4;
+
+/// Registry of the flag bits of frames, see `Connection::set_frame_flags`
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameFlag{
+    /// Reserved for compressed payloads
+    Compressed = 0x01,
+    /// Control frames, handled by the connection rather than returned to the application,
+    /// see `Connection::close_graceful`
+    Control = 0x02,
+    /// Left to the application, never used by this crate
+    Application = 0x80,
+}
+
+/// Set of `FrameFlag`s sent in the flags byte of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FrameFlags(u8);
+
+impl FrameFlags{
+    /// Bits of every `FrameFlag`, a frame with any other bit is rejected
+    pub const KNOWN: u8 = FrameFlag::Compressed as u8 | FrameFlag::Control as u8 | FrameFlag::Application as u8;
+
+    pub const fn empty() -> Self{
+        FrameFlags(0)
+    }
+    /// Drops unknown bits
+    pub const fn from_bits_truncate(bits: u8) -> Self{
+        FrameFlags(bits & Self::KNOWN)
+    }
+    pub const fn bits(self) -> u8{
+        self.0
+    }
+    pub const fn is_empty(self) -> bool{
+        self.0 == 0
+    }
+    pub const fn contains(self, flag: FrameFlag) -> bool{
+        self.0 & flag as u8 != 0
+    }
+    pub const fn with(self, flag: FrameFlag) -> Self{
+        FrameFlags(self.0 | flag as u8)
+    }
+    pub const fn without(self, flag: FrameFlag) -> Self{
+        FrameFlags(self.0 & !(flag as u8))
+    }
+}
+
+impl From<FrameFlag> for FrameFlags{
+    fn from(flag: FrameFlag) -> Self {
+        FrameFlags(flag as u8)
+    }
+}
+
+impl std::ops::BitOr<FrameFlag> for FrameFlags{
+    type Output = FrameFlags;
+
+    fn bitor(self, flag: FrameFlag) -> Self::Output {
+        self.with(flag)
+    }
+}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads when a frame has flags
+/// missing from `FrameFlag`. The frame is skipped, the connection stays usable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownFlags{
+    pub flags: u8,
+}
+
+impl UnknownFlags{
+    pub fn is_unknown_flags(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<UnknownFlags>())
+    }
+}
+
+/// Returned (wrapped into `io::ErrorKind::UnexpectedEof`, as the plain end of the stream is) by reads once
+/// the peer closed the connection with `close_graceful`: it finished cleanly, with frames sent before all read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerClosed{
+    /// Empty when the peer gave none
+    pub reason: Vec<u8>,
+}
+
+impl PeerClosed{
+    pub fn is_peer_closed(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<PeerClosed>())
+    }
+}
+
+/// Framing extensions, announced to the peer by a hello before the first frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Extensions{
+    pub sequence_numbers: bool,
+    pub checksum: ChecksumKind,
+    /// A flags byte follows the length, see `Connection::set_frame_flags`
+    pub flags: bool,
+}
+
+const HELLO_PREFIX: [u8; 3] = *b"SFH";
+
+impl Extensions{
+    /// Sent in place of a length, too big to be taken for one by the default `max_frame_len`
+    fn hello(self) -> [u8; 4]{
+        let bits = self.sequence_numbers as u8 | self.checksum.code() << 1 | (self.flags as u8) << 4;
+        [HELLO_PREFIX[0], HELLO_PREFIX[1], HELLO_PREFIX[2], bits]
+    }
+    fn from_hello(word: [u8; 4]) -> Option<Self>{
+        if word[..3] != HELLO_PREFIX {
+            return None
+        }
+        if word[3] >> 5 != 0 {
+            return None
+        }
+        let checksum = ChecksumKind::from_code(word[3] >> 1 & 0b111)?;
+        Some(Self{sequence_numbers: word[3] & 1 != 0, checksum, flags: word[3] & 0x10 != 0})
+    }
+}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads when the peer does not use
+/// the same extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeMismatch{
+    pub local: Extensions,
+    pub peer: Extensions,
+}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads when the sequence number
+/// of a frame is not the expected one. The connection stays usable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap{
+    pub expected: u32,
+    pub got: u32,
+}
+
+impl SequenceGap{
+    pub fn is_sequence_gap(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<SequenceGap>())
+    }
+}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads when the checksum of a payload
+/// does not match the one sent by the peer. The connection is broken from then on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumMismatch{
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl ChecksumMismatch{
+    pub fn is_checksum_mismatch(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<ChecksumMismatch>())
+    }
+}
+
+/// Object safe, readers of different kinds can be kept as `Box<dyn FrameReader>`.
+/// Iterate with `FrameReaderExt::iter_frames`
+pub trait FrameReader{
+    /// Clears `buf` and fills it with the next frame, keeping its capacity.
+    /// Returns the frame length
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>;
+    fn read_frame(&mut self) -> io::Result<Vec<u8>>{
+        let mut frame = Vec::new();
+        self.read_frame_into(&mut frame)?;
+        Ok(frame)
+    }
+    /// Copies the payload of the next frame into `w`, returning its length.
+    /// Failures of `w` are wrapped into `SinkError`
+    fn read_frame_to_writer(&mut self, w: &mut dyn Write) -> io::Result<u64>{
+        let frame = self.read_frame()?;
+        w.write_all(&frame).map_err(SinkError::wrap)?;
+        Ok(frame.len() as u64)
+    }
+    /// Discards the next frame, returning its length
+    fn skip_frame(&mut self) -> io::Result<usize>{
+        Ok(self.read_frame()?.len())
+    }
+    fn read_frame_string(&mut self) -> Result<String, ReadStringErr>{
+        let frame = self.read_frame().map_err(ReadStringErr::Io)?;
+        String::from_utf8(frame).map_err(ReadStringErr::InvalidUtf8)
+    }
+    /// Reads the next frame along with what the reader knows about it
+    fn read_frame_meta(&mut self) -> io::Result<Frame>{
+        self.read_frame().map(Frame::from)
+    }
+}
+
+/// Iteration for every `FrameReader`, trait objects included
+pub trait FrameReaderExt: FrameReader{
+    /// Iterates over frames until the stream ends between frames, see `ReadFrames`
+    fn iter_frames(&mut self) -> ReadFrames<'_, Self>{
+        ReadFrames{reader: self, done: false}
+    }
+}
+
+impl<R: FrameReader + ?Sized> FrameReaderExt for R{}
+
+impl<R: FrameReader + ?Sized> FrameReader for &mut R{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        (**self).read_frame_into(buf)
+    }
+    fn read_frame(&mut self) -> io::Result<Vec<u8>>{
+        (**self).read_frame()
+    }
+    fn read_frame_to_writer(&mut self, w: &mut dyn Write) -> io::Result<u64>{
+        (**self).read_frame_to_writer(w)
+    }
+    fn skip_frame(&mut self) -> io::Result<usize>{
+        (**self).skip_frame()
+    }
+    fn read_frame_string(&mut self) -> Result<String, ReadStringErr>{
+        (**self).read_frame_string()
+    }
+    fn read_frame_meta(&mut self) -> io::Result<Frame>{
+        (**self).read_frame_meta()
+    }
+}
+
+impl<R: FrameReader + ?Sized> FrameReader for Box<R>{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        (**self).read_frame_into(buf)
+    }
+    fn read_frame(&mut self) -> io::Result<Vec<u8>>{
+        (**self).read_frame()
+    }
+    fn read_frame_to_writer(&mut self, w: &mut dyn Write) -> io::Result<u64>{
+        (**self).read_frame_to_writer(w)
+    }
+    fn skip_frame(&mut self) -> io::Result<usize>{
+        (**self).skip_frame()
+    }
+    fn read_frame_string(&mut self) -> Result<String, ReadStringErr>{
+        (**self).read_frame_string()
+    }
+    fn read_frame_meta(&mut self) -> io::Result<Frame>{
+        (**self).read_frame_meta()
+    }
+}
+
+/// Error-aware iterator over the frames of any `FrameReader`, see `FrameReaderExt::iter_frames`.
+/// Timeouts, `WouldBlock`, sequence gaps and unknown flags are yielded as errors and iteration may go on,
+/// any other error is yielded once and ends the iteration
+#[derive(Debug)]
+pub struct ReadFrames<'a, R: ?Sized>{
+    reader: &'a mut R,
+    done: bool,
+}
+
+impl<R: FrameReader + ?Sized> Iterator for ReadFrames<'_, R>{
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        match self.reader.read_frame() {
+            Ok(frame) => Some(Ok(frame)),
+            Err(err) if decoder::is_closed(&err) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = !is_timeout(&err) && err.kind() != io::ErrorKind::Interrupted
+                    && !SequenceGap::is_sequence_gap(&err) && !UnknownFlags::is_unknown_flags(&err);
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Reads `len` bytes of `src` into memory for writers that cannot stream a payload
+fn read_source(src: &mut (impl Read + ?Sized), len: u64, pad: bool) -> Result<Vec<u8>, WriteErr>{
+    if len > usize::MAX as u64 {
+        return Err(WriteErr::TooLongFrame)
+    }
+    let len = len as usize;
+    let mut frame = Vec::new();
+    src.take(len as u64).read_to_end(&mut frame).map_err(WriteErr::Io)?;
+    if frame.len() < len {
+        if !pad {
+            let err = SourceTruncated{expected: len as u64, got: frame.len() as u64};
+            return Err(WriteErr::Io(io::Error::new(io::ErrorKind::UnexpectedEof, err)))
+        }
+        frame.resize(len, 0);
+    }
+    Ok(frame)
+}
+
+/// Total length of a payload given in pieces, `TooLongFrame` if it overflows
+fn vectored_len(parts: &[io::IoSlice<'_>]) -> Result<u64, WriteErr>{
+    parts.iter().try_fold(0u64, |total, part| total.checked_add(part.len() as u64)).ok_or(WriteErr::TooLongFrame)
+}
+
+pub trait FrameWriter{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>;
+    fn flush(&mut self) -> io::Result<()>;
+    fn write_frame_str(&mut self, s: &str) -> Result<(), WriteErr>{
+        self.write_frame(s.as_bytes())
+    }
+    /// Sends a zero-length frame, see `Connection::set_filter_empty_frames`
+    fn write_keepalive(&mut self) -> Result<(), WriteErr>{
+        self.write_frame(&[])
+    }
+    /// Sends the concatenation of `parts` as one frame. Writers that cannot send them
+    /// as they are copy them into one buffer first
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr>{
+        let mut frame = Vec::with_capacity(vectored_len(parts)? as usize);
+        parts.iter().for_each(|part| frame.extend_from_slice(part));
+        self.write_frame(&frame)
+    }
+    /// Sends the payload built in `buf`, see `FrameBuf`
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr>{
+        self.write_frame(buf.payload())
+    }
+    /// Sends `frames` in order, returning how many.
+    /// A failure after some frames were written comes wrapped into `BatchInterrupted` with their count,
+    /// the frame it hit may be partly written
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr> where Self: Sized{
+        let mut written = 0;
+        for frame in frames {
+            self.write_frame(frame).map_err(|err| BatchInterrupted::wrap(err, written))?;
+            written += 1;
+        }
+        Ok(written)
+    }
+    /// Sends exactly `len` bytes read from `src` as one frame.
+    /// Fails with `SourceTruncated` when `src` ends early, writers that stream the payload
+    /// have sent the header by then and are desynchronized for good
+    fn write_frame_from_reader(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr> where Self: Sized{
+        let frame = read_source(src, len, false)?;
+        self.write_frame(&frame)
+    }
+    /// Same as `write_frame_from_reader`, filling up with zeros when `src` ends early
+    fn write_frame_from_reader_padded(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr> where Self: Sized{
+        let frame = read_source(src, len, true)?;
+        self.write_frame(&frame)
+    }
+    /// Sends the payload of `frame` with the metadata the writer supports,
+    /// fails with `io::ErrorKind::InvalidInput` for flags it cannot send
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr>{
+        if !frame.meta.flags.is_empty() {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "Writer does not send frame flags");
+            return Err(WriteErr::Io(err))
+        }
+        self.write_frame(frame.payload())
+    }
+}
+
+pub trait ConnectionController{
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+    fn shutdown(&self, t: Shutdown) -> io::Result<()>;
+    /// Reads and writes fail with `WouldBlock` instead of waiting, for readiness-based event loops.
+    /// Halves and clones share the mode, they share the socket
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "set_nonblocking is not supported"))
+    }
+    /// Turns Nagle's algorithm off, small frames then go out without waiting for acknowledgements
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "set_nodelay is not supported"))
+    }
+    fn nodelay(&self) -> io::Result<bool>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "nodelay is not supported"))
+    }
+    /// Probes the peer after `idle` without traffic so that a dead connection fails reads,
+    /// `None` turns probing off. See `set_keepalive_config` for the rest of the settings
+    fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()>{
+        self.set_keepalive_config(idle.map(KeepaliveConfig::new))
+    }
+    fn set_keepalive_config(&self, _config: Option<KeepaliveConfig>) -> io::Result<()>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "keepalive is not supported"))
+    }
+    /// Idle time before the first probe, `None` when keepalive is off
+    fn keepalive(&self) -> io::Result<Option<Duration>>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "keepalive is not supported"))
+    }
+    /// `IP_TTL` of the packets sent
+    fn set_ttl(&self, _ttl: u32) -> io::Result<()>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "set_ttl is not supported"))
+    }
+    fn ttl(&self) -> io::Result<u32>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "ttl is not supported"))
+    }
+    /// How long closing the socket waits for unsent data to go out, `None` returns at once and sends it
+    /// in the background. Zero drops the unsent data and resets the connection, see `Connection::close_abortive`.
+    /// Only the socket buffer is covered: frames buffered by the flush policy need `flush` or `close` first
+    fn set_linger(&self, _linger: Option<Duration>) -> io::Result<()>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "set_linger is not supported"))
+    }
+    fn linger(&self) -> io::Result<Option<Duration>>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "linger is not supported"))
+    }
+    /// Size of the kernel receive buffer, bigger ones keep fast links with long round trips busy.
+    /// The kernel may adjust it, Linux doubles it: the getter returns the size in use
+    fn set_recv_buffer_size(&self, _size: usize) -> io::Result<()>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "set_recv_buffer_size is not supported"))
+    }
+    fn recv_buffer_size(&self) -> io::Result<usize>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "recv_buffer_size is not supported"))
+    }
+    /// Size of the kernel send buffer, adjusted like `set_recv_buffer_size`
+    fn set_send_buffer_size(&self, _size: usize) -> io::Result<()>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "set_send_buffer_size is not supported"))
+    }
+    fn send_buffer_size(&self) -> io::Result<usize>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "send_buffer_size is not supported"))
+    }
+    /// Takes the error the socket got in the background (`SO_ERROR`), a reset or an unreachable peer,
+    /// which the next read or write would otherwise report. Sends nothing, cheap enough for health checks
+    fn take_error(&self) -> io::Result<Option<io::Error>>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "take_error is not supported"))
+    }
+}
+
+/// The reading direction of a connection, what a `ConnectionReader` controls on its own.
+/// The socket options shared with the writer half stay on `ConnectionController`
+pub trait ReadController{
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    /// Bounds each wait of a read for data, `None` waits for ever
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+    /// Reads see the end of the stream from then on, the peer can still be written to
+    fn shutdown_read(&self) -> io::Result<()>;
+}
+
+/// The writing direction of a connection, what a `ConnectionWriter` controls on its own, see `ReadController`
+pub trait WriteController{
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    /// Bounds each wait of a write for the socket to take data, `None` waits for ever
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+    /// Sends the end of the stream, frames buffered by the flush policy need `flush` first
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+/// `Debug` of a half: its type, then the connection id, the name when set and the addresses
+/// the socket still reports, in this order
+fn debug_half(f: &mut Formatter<'_>, half: &str, connection: &Connection) -> fmt::Result{
+    let mut s = f.debug_struct(half);
+    s.field("id", &connection.id);
+    if let Some(name) = &connection.name {
+        s.field("name", name);
+    }
+    if let Ok(addr) = connection.stream.local_addr() {
+        s.field("local_addr", &format_args!("{}", addr));
+    }
+    if let Ok(addr) = connection.stream.peer_addr() {
+        s.field("peer_addr", &format_args!("{}", addr));
+    }
+    s.finish()
 }
 
-pub trait FrameReader: Iterator{
-    fn read_frame(&mut self) -> io::Result<Vec<u8>>;
-}
+/// `Display` of a half, as in `reader of connection 3 to 127.0.0.1:4000`, without the peer once it is gone
+fn display_half(f: &mut Formatter<'_>, role: &str, connection: &Connection) -> fmt::Result{
+    write!(f, "{} of connection {}", role, connection.id)?;
+    match connection.stream.peer_addr() {
+        Ok(addr) => write!(f, " to {}", addr),
+        Err(_) => Ok(()),
+    }
+}
+
+/// A half setting the timeout of the other one, which would see it too through the shared socket
+fn other_half_error(setting: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::Unsupported, format!("{} belongs to the other half", setting))
+}
+
+impl fmt::Display for WriteErr{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self{
+            WriteErr::Io(err) => { std::fmt::Display::fmt(&err, f) }
+            WriteErr::TooLongFrame => {
+                write!(f, "Frame is too long to send by SFP")
+            }
+            WriteErr::Timeout => {
+                write!(f, "Frame could not be written in time")
+            }
+            WriteErr::Desynchronized => {
+                write!(f, "Stream is desynchronized by a partly written frame")
+            }
+        }
+    }
+}
+
+impl WriteErr{
+    /// Old name of `WriteErr::Io`, only constructs it: patterns need the new name
+    #[deprecated(note = "renamed to `WriteErr::Io`")]
+    #[allow(non_upper_case_globals)]
+    pub const I0: fn(io::Error) -> WriteErr = WriteErr::Io;
+}
+
+impl std::error::Error for WriteErr{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self{
+            WriteErr::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WriteErr{
+    fn from(err: io::Error) -> Self {
+        WriteErr::Io(err)
+    }
+}
+
+/// Keeps the kind of the failure, for code speaking `io::Write`
+impl From<WriteErr> for io::Error{
+    fn from(err: WriteErr) -> Self {
+        match err {
+            WriteErr::Io(err) => err,
+            WriteErr::TooLongFrame => io::Error::new(io::ErrorKind::InvalidInput, WriteErr::TooLongFrame.to_string()),
+            WriteErr::Timeout => io::Error::new(io::ErrorKind::TimedOut, WriteErr::Timeout.to_string()),
+            WriteErr::Desynchronized => io::Error::other(WriteErr::Desynchronized.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for FrameTooLong{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer declared a frame of {} bytes, the limit is {}", self.length, self.max_frame_len)
+    }
+}
+
+impl std::error::Error for FrameTooLong{}
+
+impl fmt::Display for Desynchronized{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.found == FRAME_MAGIC {
+            write!(f, "Peer sends magic prefixed frames, the magic prefix is disabled")
+        } else {
+            write!(f, "Expected the frame magic, found {:02x?}: out of sync or the peer does not use the magic prefix", self.found)
+        }
+    }
+}
+
+impl std::error::Error for Desynchronized{}
+
+impl fmt::Display for ModeMismatch{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer uses extensions {:?}, expected {:?}", self.peer, self.local)
+    }
+}
+
+impl std::error::Error for ModeMismatch{}
+
+impl fmt::Display for SequenceGap{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected frame number {}, got {}", self.expected, self.got)
+    }
+}
+
+impl std::error::Error for SequenceGap{}
+
+impl fmt::Display for ChecksumMismatch{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame checksum mismatch: expected {:x}, payload has {:x}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch{}
+
+impl fmt::Display for MalformedLength{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed varint length prefix: out of sync or the peer does not use varint framing")
+    }
+}
+
+impl std::error::Error for MalformedLength{}
+
+impl fmt::Display for NegativeLength{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Length prefix {} is shorter than the header: the framing config does not match the peer", self.prefix)
+    }
+}
+
+impl std::error::Error for NegativeLength{}
+
+impl fmt::Display for UnknownFlags{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame has unknown flags {:#04x}, the peer uses a newer protocol", self.flags & !FrameFlags::KNOWN)
+    }
+}
+
+impl std::error::Error for UnknownFlags{}
+
+impl fmt::Display for PeerClosed{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer closed the connection")?;
+        if self.reason.is_empty() {
+            return Ok(())
+        }
+        match std::str::from_utf8(&self.reason) {
+            Ok(reason) => write!(f, ": {}", reason),
+            Err(_) => write!(f, " with a {} byte reason", self.reason.len()),
+        }
+    }
+}
+
+impl std::error::Error for PeerClosed{}
+
+impl fmt::Display for ReadErr{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self{
+            ReadErr::Timeout => write!(f, "Timed out waiting for a frame"),
+            ReadErr::TimeoutMidFrame => write!(f, "Timed out in the middle of a frame"),
+            ReadErr::Disconnected => write!(f, "Connection closed"),
+            ReadErr::Closed{reason} => fmt::Display::fmt(&PeerClosed{reason: reason.clone()}, f),
+            ReadErr::TruncatedHeader{got} => {
+                write!(f, "Connection lost after {} bytes of a frame header", got)
+            }
+            ReadErr::TruncatedFrame{expected, got} => {
+                write!(f, "Connection lost after {} of {} bytes of a frame", got, expected)
+            }
+            ReadErr::TooLongFrame{length, max_frame_len} => {
+                fmt::Display::fmt(&FrameTooLong{length: *length, max_frame_len: *max_frame_len}, f)
+            }
+            ReadErr::Desynchronized{found} => fmt::Display::fmt(&Desynchronized{found: *found}, f),
+            ReadErr::ModeMismatch{local, peer} => fmt::Display::fmt(&ModeMismatch{local: *local, peer: *peer}, f),
+            ReadErr::SequenceGap{expected, got} => fmt::Display::fmt(&SequenceGap{expected: *expected, got: *got}, f),
+            ReadErr::ChecksumMismatch{expected, actual} => {
+                fmt::Display::fmt(&ChecksumMismatch{expected: *expected, actual: *actual}, f)
+            }
+            ReadErr::MalformedLength => fmt::Display::fmt(&MalformedLength, f),
+            ReadErr::NegativeLength{prefix} => fmt::Display::fmt(&NegativeLength{prefix: *prefix}, f),
+            ReadErr::UnknownFlags{flags} => fmt::Display::fmt(&UnknownFlags{flags: *flags}, f),
+            ReadErr::Cancelled => fmt::Display::fmt(&Cancelled, f),
+            ReadErr::TimeoutNotRestored{frame, error} => {
+                write!(f, "Read a {} byte frame but could not restore the read timeout: {}", frame.len(), error)
+            }
+            ReadErr::Io(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl fmt::Display for ReadStringErr{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self{
+            ReadStringErr::Io(err) => fmt::Display::fmt(err, f),
+            ReadStringErr::InvalidUtf8(err) => write!(f, "Frame is not valid UTF-8: {}", err.utf8_error()),
+        }
+    }
+}
+
+impl std::error::Error for ReadStringErr{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self{
+            ReadStringErr::Io(err) => Some(err),
+            ReadStringErr::InvalidUtf8(err) => Some(err),
+        }
+    }
+}
+
+impl From<&ReadErr> for ReadFailure{
+    fn from(err: &ReadErr) -> Self {
+        match err{
+            ReadErr::Timeout => ReadFailure::Timeout,
+            ReadErr::TimeoutMidFrame => ReadFailure::TimeoutMidFrame,
+            ReadErr::Disconnected => ReadFailure::Disconnected,
+            ReadErr::Closed{..} => ReadFailure::Closed,
+            ReadErr::TruncatedHeader{got} => ReadFailure::TruncatedHeader{got: *got},
+            ReadErr::TruncatedFrame{expected, got} => ReadFailure::TruncatedFrame{expected: *expected, got: *got},
+            ReadErr::TooLongFrame{length, max_frame_len} => {
+                ReadFailure::TooLongFrame{length: *length, max_frame_len: *max_frame_len}
+            }
+            ReadErr::Desynchronized{found} => ReadFailure::Desynchronized{found: *found},
+            ReadErr::ModeMismatch{local, peer} => ReadFailure::ModeMismatch{local: *local, peer: *peer},
+            ReadErr::SequenceGap{expected, got} => ReadFailure::SequenceGap{expected: *expected, got: *got},
+            ReadErr::ChecksumMismatch{expected, actual} => {
+                ReadFailure::ChecksumMismatch{expected: *expected, actual: *actual}
+            }
+            ReadErr::MalformedLength => ReadFailure::MalformedLength,
+            ReadErr::NegativeLength{prefix} => ReadFailure::NegativeLength{prefix: *prefix},
+            ReadErr::UnknownFlags{flags} => ReadFailure::UnknownFlags{flags: *flags},
+            ReadErr::Cancelled => ReadFailure::Cancelled,
+            ReadErr::TimeoutNotRestored{error, ..} => ReadFailure::TimeoutNotRestored(error.kind()),
+            ReadErr::Io(err) => ReadFailure::Io(err.kind()),
+        }
+    }
+}
+
+impl std::error::Error for ReadErr{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self{
+            ReadErr::TimeoutNotRestored{error, ..} => Some(error),
+            ReadErr::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool{
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn stream_read_timeout(stream: &Stream) -> io::Result<Option<Duration>>{
+    match stream {
+        Stream::Inet(s) => s.read_timeout(),
+        #[cfg(unix)]
+        Stream::Unix(s) => s.read_timeout(),
+    }
+}
+
+fn stream_set_nonblocking(stream: &Stream, nonblocking: bool) -> io::Result<()>{
+    match stream {
+        Stream::Inet(s) => s.set_nonblocking(nonblocking),
+        #[cfg(unix)]
+        Stream::Unix(s) => s.set_nonblocking(nonblocking),
+    }
+}
+
+/// Unix sockets have no Nagle's algorithm, they always count as nodelay
+fn stream_set_nodelay(stream: &Stream, nodelay: bool) -> io::Result<()>{
+    match stream {
+        Stream::Inet(s) => s.set_nodelay(nodelay),
+        #[cfg(unix)]
+        Stream::Unix(_) => Ok(()),
+    }
+}
+
+fn stream_nodelay(stream: &Stream) -> io::Result<bool>{
+    match stream {
+        Stream::Inet(s) => s.nodelay(),
+        #[cfg(unix)]
+        Stream::Unix(_) => Ok(true),
+    }
+}
+
+fn stream_set_ttl(stream: &Stream, ttl: u32) -> io::Result<()>{
+    match stream {
+        Stream::Inet(s) => s.set_ttl(ttl),
+        #[cfg(unix)]
+        Stream::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets have no TTL")),
+    }
+}
+
+fn stream_ttl(stream: &Stream) -> io::Result<u32>{
+    match stream {
+        Stream::Inet(s) => s.ttl(),
+        #[cfg(unix)]
+        Stream::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets have no TTL")),
+    }
+}
+
+fn stream_take_error(stream: &Stream) -> io::Result<Option<io::Error>>{
+    match stream {
+        Stream::Inet(s) => s.take_error(),
+        #[cfg(unix)]
+        Stream::Unix(s) => s.take_error(),
+    }
+}
+
+fn stream_write_timeout(stream: &Stream) -> io::Result<Option<Duration>>{
+    match stream {
+        Stream::Inet(s) => s.write_timeout(),
+        #[cfg(unix)]
+        Stream::Unix(s) => s.write_timeout(),
+    }
+}
+
+#[cfg(unix)]
+fn stream_fd(stream: &Stream) -> std::os::unix::io::RawFd{
+    use std::os::unix::io::AsRawFd;
+    match stream {
+        Stream::Inet(s) => s.as_raw_fd(),
+        Stream::Unix(s) => s.as_raw_fd(),
+    }
+}
+
+/// Error of the destination writer in `read_frame_to_writer`,
+/// wrapped into an `io::Error` of the same kind to tell it apart from socket errors
+#[derive(Debug)]
+pub struct SinkError(pub io::Error);
+
+impl SinkError{
+    fn wrap(err: io::Error) -> io::Error{
+        io::Error::new(err.kind(), SinkError(err))
+    }
+    pub fn is_sink_error(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<SinkError>())
+    }
+}
+
+impl fmt::Display for SinkError{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Destination writer failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for SinkError{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Returned by `Connection::into_inner` with the connection, left as it was
+#[derive(Debug)]
+pub struct IntoInnerError{
+    pub connection: Box<Connection>,
+    pub error: io::Error,
+}
+
+impl fmt::Display for IntoInnerError{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not take the stream out of the connection: {}", self.error)
+    }
+}
+
+impl std::error::Error for IntoInnerError{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Returned (wrapped into an `io::Error` of the same kind inside `WriteErr::Io`) by `write_frames`
+/// when a frame fails after others were written
+#[derive(Debug)]
+pub struct BatchInterrupted{
+    /// Frames of the batch completely written, in order
+    pub frames_written: usize,
+    pub error: io::Error,
+}
+
+impl BatchInterrupted{
+    /// Failures before the first frame was written are returned as they are
+    fn wrap(err: WriteErr, frames_written: usize) -> WriteErr{
+        if frames_written == 0 {
+            return err
+        }
+        let error = io::Error::from(err);
+        WriteErr::Io(io::Error::new(error.kind(), BatchInterrupted{frames_written, error}))
+    }
+    pub fn is_batch_interrupted(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<BatchInterrupted>())
+    }
+    /// Frames written before `err`, if it ended a batch
+    pub fn frames_written(err: &io::Error) -> Option<usize>{
+        err.get_ref().and_then(|inner| inner.downcast_ref::<BatchInterrupted>()).map(|batch| batch.frames_written)
+    }
+}
+
+impl fmt::Display for BatchInterrupted{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Batch failed after {} frames: {}", self.frames_written, self.error)
+    }
+}
+
+impl std::error::Error for BatchInterrupted{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Returned (wrapped into `io::ErrorKind::UnexpectedEof` inside `WriteErr::Io`) by `write_frame_from_reader`
+/// when the source ends before the promised length. The header is already sent, the writer is desynchronized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceTruncated{
+    pub expected: u64,
+    pub got: u64,
+}
+
+impl SourceTruncated{
+    pub fn is_source_truncated(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<SourceTruncated>())
+    }
+}
+
+impl fmt::Display for SourceTruncated{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Source ended after {} of {} promised bytes", self.got, self.expected)
+    }
+}
+
+impl std::error::Error for SourceTruncated{}
+
+/// Keeps the error of the flush a connection tried when dropped, see `Connection::set_drop_error_slot`.
+/// Clones share the slot, so one slot can watch several connections
+#[derive(Debug, Clone, Default)]
+pub struct DropErrorSlot{
+    error: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl DropErrorSlot{
+    pub fn new() -> Self{
+        Self::default()
+    }
+    /// The last error recorded, taking it out of the slot
+    pub fn take(&self) -> Option<io::Error>{
+        self.error.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take()
+    }
+    fn set(&self, err: io::Error){
+        *self.error.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(err);
+    }
+}
+
+const BATCH_READ_SIZE: usize = 64 * 1024;
+
+/// Source of `Connection::id`
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+pub struct Connection{
+    id: u64,
+    name: Option<String>,
+    /// Shared by the halves of `split_shared` only
+    stream: Arc<Stream>,
+    /// Also held by the decoder, which records reads and writes
+    activity: Arc<Activity>,
+    decoder: FrameDecoder,
+    frame_buf: Vec<u8>,
+    frame_buf_high_water: usize,
+    peeked: Option<Vec<u8>>,
+    spill_threshold: Option<u64>,
+    spill: Option<File>,
+    last_read_error: Option<ReadFailure>,
+    read_control: ReadControl,
+    read_rate: Option<FrameRate>,
+    /// Locked only for writes through `&Connection`
+    write: Mutex<WriteState>,
+    /// Writer turn shared with clones, and the handle of this one. The reader turn is in `read_control`
+    write_turn: Arc<Turn<WritePosition>>,
+    write_handle: u64,
+    flush_on_drop: bool,
+    drop_error: Option<DropErrorSlot>,
+}
+
+#[derive(Debug)]
+struct WriteState{
+    hello_sent: bool,
+    next_sequence: u32,
+    /// Encoded frames not sent yet, see `set_flush_policy`
+    write_buf: Vec<u8>,
+    buffered_frames: usize,
+    /// When the oldest buffered frame was written
+    buffered_since: Option<Instant>,
+    flush_policy: FlushPolicy,
+    /// A send failed and left bytes in `write_buf`, see `resume_write`
+    write_pending: bool,
+    write_bandwidth: Option<Bandwidth>,
+    writer_state: WriterState,
+    /// Payload size frames are padded to, see `set_pad_to`
+    pad_to: Option<usize>,
+    pad_overflow: PadOverflow,
+}
+
+/// Where a writer left the stream, taken over by the clone writing next
+#[derive(Debug, Clone, Copy)]
+struct WritePosition{
+    hello_sent: bool,
+    next_sequence: u32,
+    desynchronized: bool,
+}
+
+impl WriteState{
+    fn position(&self) -> WritePosition{
+        let desynchronized = self.write_pending || matches!(self.writer_state, WriterState::Poisoned{..});
+        WritePosition{hello_sent: self.hello_sent, next_sequence: self.next_sequence, desynchronized}
+    }
+}
+
+impl Default for WriteState{
+    fn default() -> Self {
+        Self{
+            hello_sent: false,
+            next_sequence: 0,
+            write_buf: Vec::new(),
+            buffered_frames: 0,
+            buffered_since: None,
+            flush_policy: FlushPolicy::default(),
+            write_pending: false,
+            write_bandwidth: None,
+            writer_state: WriterState::Healthy,
+            pad_to: None,
+            pad_overflow: PadOverflow::default(),
+        }
+    }
+}
+
+/// What a write needs: where the bytes go, the framing settings and the write state, borrowed or locked
+struct WritePath<'a, S>{
+    stream: S,
+    decoder: &'a FrameDecoder,
+    state: &'a mut WriteState,
+    /// Writer turn shared with clones and the handle of this one, taken by the first frame written
+    turn: Option<(&'a Turn<WritePosition>, u64)>,
+}
+
+/// Gives the writer turn back unless frames are left buffered, see `Turn`
+impl<S> Drop for WritePath<'_, S>{
+    fn drop(&mut self) {
+        if let Some((turn, handle)) = self.turn {
+            if self.state.write_buf.is_empty() {
+                turn.give_back(handle, self.state.position(), &[]);
+            }
+        }
+    }
+}
+
+impl From<Stream> for Connection{
+    fn from(stream: Stream) -> Self {
+        Self::with_stream(Arc::new(stream))
+    }
+}
+
+impl Connection{
+    fn with_stream(stream: Arc<Stream>) -> Self{
+        let activity = Arc::new(Activity::new());
+        let read_turn = Arc::new(Turn::new());
+        let read_handle = turn::next_handle();
+        let mut decoder = FrameDecoder::new();
+        decoder.set_activity(activity.clone());
+        decoder.set_turn(read_turn.clone(), read_handle);
+        Self{
+            stream,
+            decoder,
+            activity,
+            frame_buf: Vec::new(),
+            frame_buf_high_water: DEFAULT_FRAME_BUFFER_HIGH_WATER,
+            peeked: None,
+            spill_threshold: None,
+            spill: None,
+            last_read_error: None,
+            read_control: ReadControl{turn: Some(ReadTurn{turn: read_turn, handle: read_handle, mode: ConcurrentReads::default()}), ..ReadControl::default()},
+            read_rate: None,
+            write: Mutex::new(WriteState::default()),
+            write_turn: Arc::new(Turn::new()),
+            write_handle: turn::next_handle(),
+            flush_on_drop: true,
+            drop_error: None,
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            name: None,
+        }
+    }
+}
+
+impl Drop for Connection{
+    fn drop(&mut self) {
+        self.wind_down()
+    }
+}
+
+impl From<TcpStream> for Connection{
+    fn from(s: TcpStream) -> Self {
+        Self::from(Stream::from(s))
+    }
+}
+
+#[cfg(unix)]
+impl From<unix::UnixStream> for Connection{
+    fn from(s: unix::UnixStream) -> Self {
+        Self::from(Stream::from(s))
+    }
+}
+
+impl Connection{
+    pub fn connect(s: &SocketAddr) -> io::Result<Self> {
+        match Stream::connect(s) {
+            Ok(stream) => {
+                Ok(Self::from(stream))
+            }
+            Err(err) => {
+                Err(err)
+            }
+        }
+    }
+    /// Same as `connect`, failing with `TimedOut` if the connection is not established within `timeout`.
+    /// A zero `timeout` is rejected with `InvalidInput`
+    pub fn connect_timeout(s: &SocketAddr, timeout: Duration) -> io::Result<Self> {
+        connect::connect_timeout(s, timeout).map(Self::from)
+    }
+    /// Connects to `"host:port"`, an IP address with its port or `"unix:/path"`, trying the addresses a host name
+    /// resolves to one after the other. Fails with `ResolveFailed` if the name does not resolve,
+    /// with `AllAttemptsFailed` if no address accepts
+    pub fn connect_str(target: &str) -> io::Result<Self> {
+        Self::connect_str_prefer(target, IpPreference::Resolver)
+    }
+    /// Same as `connect_str`, trying addresses of the preferred IP version first
+    pub fn connect_str_prefer(target: &str, preference: IpPreference) -> io::Result<Self> {
+        let addrs = connect::resolve(target, preference)?;
+        Self::connect_any(&addrs, Duration::MAX).map(|(connection, _)| connection)
+    }
+    /// Connects to the first of `addrs` to accept, returning it with its index. Attempts start in order,
+    /// each `per_attempt` after the previous one or as soon as it failed, staggered as in RFC 8305.
+    /// Losers still connecting are closed once they finish in the background. Fails with `AllAttemptsFailed`
+    pub fn connect_any(addrs: &[SocketAddr], per_attempt: Duration) -> io::Result<(Self, usize)> {
+        connect::connect_any(addrs, per_attempt).map(|(stream, index)| (Self::from(stream), index))
+    }
+    /// Frames over a transport other than a socket, see `GenericConnection`
+    pub fn from_io<S: Read + Write>(io: S) -> GenericConnection<S> {
+        GenericConnection::new(io)
+    }
+    /// Same as `connect`, retrying by `policy` while the server refuses, resets, times out, is unreachable
+    /// or, for Unix sockets, has not created its socket file yet. Other errors and the last attempt's are returned
+    pub fn connect_with_retry(s: &SocketAddr, policy: &RetryPolicy) -> io::Result<Self> {
+        Self::connect_with_retry_notify(s, policy, |_, _, _| {})
+    }
+    /// Same as `connect_with_retry`, `notify` gets each failed attempt, counted from 1, its error
+    /// and the delay before the next one, to log them
+    pub fn connect_with_retry_notify(s: &SocketAddr, policy: &RetryPolicy, notify: impl FnMut(u32, &io::Error, Duration)) -> io::Result<Self> {
+        let connect = || match policy.attempt_timeout {
+            Some(timeout) => Self::connect_timeout(s, timeout),
+            None => Self::connect(s),
+        };
+        retry::retry(s, policy, connect, std::thread::sleep, notify)
+    }
+    /// Two connections to each other without binding an address: a Unix socket pair,
+    /// loopback TCP on Windows. A descriptor of one end can be inherited by a child process
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = connect::pair()?;
+        Ok((Self::from(a), Self::from(b)))
+    }
+    /// Same as `connect`, with Nagle's algorithm turned off if `nodelay`, see `set_nodelay`
+    pub fn connect_with_nodelay(s: &SocketAddr, nodelay: bool) -> io::Result<Self> {
+        let connection = Self::connect(s)?;
+        connection.set_nodelay(nodelay)?;
+        Ok(connection)
+    }
+    /// Same as `from`, with `leftover` bytes already taken from the stream, sniffing the protocol or along with
+    /// an upgrade response: reads decode them before reading the stream. The reader half of `separate` keeps them
+    pub fn from_stream_with_prefix(stream: Stream, leftover: Vec<u8>) -> Self {
+        let mut connection = Self::from(stream);
+        connection.decoder.push(&leftover);
+        connection
+    }
+    /// The clone shares the stream but not the read state:
+    /// bytes already buffered by this handle are only delivered by this handle
+    pub fn try_clone(&self) -> io::Result<Self>{
+        Ok(self.clone_with(Arc::new(self.stream.try_clone()?)))
+    }
+    /// A clone of the settings on `stream`, see `try_clone`
+    fn clone_with(&self, stream: Arc<Stream>) -> Self{
+        let mut clone = Self::with_stream(stream);
+        clone.decoder = self.decoder.fresh();
+        clone.frame_buf_high_water = self.frame_buf_high_water;
+        clone.spill_threshold = self.spill_threshold;
+        clone.read_rate = self.read_rate.as_ref().map(|rate| FrameRate::new(rate.limit, 0));
+        clone.set_read_bandwidth_limit(self.read_bandwidth_limit());
+        clone.set_write_bandwidth_limit(self.write_bandwidth_limit());
+        clone.set_flush_policy(self.flush_policy());
+        clone.flush_on_drop = self.flush_on_drop;
+        clone.drop_error = self.drop_error.clone();
+        clone.write_state().writer_state = self.writer_state();
+        clone.set_pad_to(self.pad_to());
+        clone.set_pad_overflow(self.pad_overflow());
+        clone.id = self.id;
+        clone.activity = self.activity.clone();
+        clone.name = self.name.clone();
+        if let Some(reader) = &self.read_control.turn {
+            let handle = turn::next_handle();
+            clone.decoder.set_turn(reader.turn.clone(), handle);
+            clone.read_control.turn = Some(ReadTurn{turn: reader.turn.clone(), handle, mode: reader.mode});
+        }
+        clone.write_turn = self.write_turn.clone();
+        clone
+    }
+    /// Number unique to the connection in this process, kept by `try_clone` and the halves of `separate`
+    pub fn id(&self) -> u64{
+        self.id
+    }
+    /// Label shown by `Debug`, for logs. `try_clone` and `separate` copy it
+    pub fn set_name(&mut self, name: impl Into<String>){
+        self.name = Some(name.into());
+    }
+    pub fn name(&self) -> Option<&str>{
+        self.name.as_deref()
+    }
+    /// What a read does while a clone, see `try_clone`, reads the socket. Clones take turns a frame at a time:
+    /// the one reading lets another in once it is between frames, with nothing more buffered or a clone waiting,
+    /// and hands over what it received past its frame. A clone dropped partway through a frame leaves the others
+    /// failing with `AbandonedFrame`. Writes of clones take turns the same way, holding theirs while frames
+    /// are buffered by the flush policy or left pending
+    pub fn set_concurrent_reads(&mut self, mode: ConcurrentReads){
+        if let Some(reader) = &mut self.read_control.turn {
+            reader.mode = mode;
+        }
+    }
+    pub fn concurrent_reads(&self) -> ConcurrentReads{
+        self.read_control.turn.as_ref().map_or(ConcurrentReads::default(), |reader| reader.mode)
+    }
+    /// When a frame was last read by this connection, its clones or its halves
+    pub fn last_read_at(&self) -> Option<Instant>{
+        self.activity.last_read()
+    }
+    /// When a frame was last written, buffered frames count once written
+    pub fn last_write_at(&self) -> Option<Instant>{
+        self.activity.last_write()
+    }
+    /// Time since the last frame read or written, or since the connection was made
+    pub fn idle_for(&self) -> Duration{
+        self.activity.idle_for()
+    }
+    /// Whether keepalives, empty frames, count for `last_read_at` and `last_write_at`. On by default,
+    /// shared with clones and halves
+    pub fn set_keepalives_count_as_activity(&self, count: bool){
+        self.activity.set_count_keepalives(count)
+    }
+    pub fn keepalives_count_as_activity(&self) -> bool{
+        self.activity.count_keepalives()
+    }
+    /// Counters shared with clones and halves, a snapshot
+    pub fn stats(&self) -> ConnectionStats{
+        self.activity.stats()
+    }
+    /// Frames declaring a bigger length are rejected before any allocation.
+    /// After a rejection the stream is no longer at a frame boundary,
+    /// so every following read fails. Without extensions, see `FrameDecoder::set_max_frame_len`
+    /// for limits from about 1.3 GiB up
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize){
+        self.decoder.set_max_frame_len(max_frame_len)
+    }
+    pub fn max_frame_len(&self) -> usize{
+        self.decoder.max_frame_len()
+    }
+    /// Payload buffer starts at this many bytes and grows to at most four times what arrived,
+    /// so memory is committed only as the peer actually sends data
+    pub fn set_read_chunk_size(&mut self, read_chunk_size: usize){
+        self.decoder.set_chunk_size(read_chunk_size)
+    }
+    pub fn read_chunk_size(&self) -> usize{
+        self.decoder.chunk_size()
+    }
+    /// Capacity the internal buffer of `next_frame` is shrunk back to after a bigger frame
+    pub fn set_frame_buffer_high_water(&mut self, high_water: usize){
+        self.frame_buf_high_water = high_water;
+    }
+    pub fn frame_buffer_high_water(&self) -> usize{
+        self.frame_buf_high_water
+    }
+    /// Small reads are served from a buffer of this size to save syscalls, 0 disables buffering
+    pub fn set_read_buffer_capacity(&mut self, capacity: usize){
+        self.decoder.set_input_capacity(capacity)
+    }
+    pub fn read_buffer_capacity(&self) -> usize{
+        self.decoder.input_capacity()
+    }
+    /// Frames longer than this are written to a temp file by `read_frame_spilled`
+    /// and kept in one by `frame_writer` until sent. `max_frame_len` still applies to reads
+    pub fn set_spill_threshold(&mut self, threshold: Option<u64>){
+        self.spill_threshold = threshold;
+    }
+    pub fn spill_threshold(&self) -> Option<u64>{
+        self.spill_threshold
+    }
+    /// Token bucket limiting how fast frames are read: reads wait for a token,
+    /// `try_read_frame` fails with `WouldExceed` instead
+    pub fn set_read_rate_limit(&mut self, limit: Option<RateLimit>){
+        self.read_rate = limit.map(|limit| FrameRate::new(limit, self.decoder.received()));
+    }
+    pub fn read_rate_limit(&self) -> Option<RateLimit>{
+        self.read_rate.as_ref().map(|rate| rate.limit)
+    }
+    /// Paces reads of the stream to keep the received bytes per second under the limit,
+    /// after a second worth of bytes read at full speed.
+    /// Time spent waiting does not count towards the read timeout, it does count towards deadlines
+    pub fn set_read_bandwidth_limit(&mut self, bytes_per_sec: Option<u64>){
+        self.read_control.bandwidth = bytes_per_sec.map(Bandwidth::new);
+    }
+    pub fn read_bandwidth_limit(&self) -> Option<u64>{
+        self.read_control.bandwidth.as_ref().map(|bandwidth| bandwidth.bytes_per_sec)
+    }
+    /// Paces writes to keep the sent bytes per second, headers included, under the limit,
+    /// after a second worth of bytes sent at full speed. Frames wait before they are sent or buffered,
+    /// `try_write_frame` fails with `WouldExceed` instead
+    pub fn set_write_bandwidth_limit(&mut self, bytes_per_sec: Option<u64>){
+        self.write_state().write_bandwidth = bytes_per_sec.map(Bandwidth::new);
+    }
+    pub fn write_bandwidth_limit(&self) -> Option<u64>{
+        self.lock_write().write_bandwidth.as_ref().map(|bandwidth| bandwidth.bytes_per_sec)
+    }
+    /// Prefixes every frame with `FRAME_MAGIC` and expects it on every received frame,
+    /// so that a desynchronized or plain peer fails with `Desynchronized` right away.
+    /// Both peers must enable it before the first frame
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.decoder.set_magic_prefix(magic)
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.decoder.magic_prefix()
+    }
+    /// Length prefix format of sent and received frames, for interoperability with
+    /// other length delimited protocols.
+    /// Not announced to the peer, both peers must use the same config before the first frame
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.decoder.set_framing_config(config)
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.decoder.framing_config()
+    }
+    /// Encodes the length prefixes of sent and received frames, `Framing::Varint` saves
+    /// up to 3 bytes per frame for small frames.
+    /// Not announced to the peer, both peers must pick the same framing before the first frame
+    pub fn set_framing(&mut self, framing: Framing){
+        self.decoder.set_framing(framing)
+    }
+    pub fn framing(&self) -> Framing{
+        self.decoder.framing()
+    }
+    /// Width of the length prefixes with `Framing::Fixed`: `U16` limits frames to 64 KiB,
+    /// `U64` lifts the 4 GiB limit. Frames longer than the width allows fail to write
+    /// with `WriteErr::TooLongFrame`.
+    /// Not announced to the peer, both peers must pick the same width before the first frame.
+    /// In `U16` a plain peer can not tell the hello of extensions from a frame
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.decoder.set_header_width(width)
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.decoder.header_width()
+    }
+    /// Recovers from `Desynchronized` (or any framing doubt) in magic prefix mode:
+    /// drops the current frame and every byte up to the next `FRAME_MAGIC`,
+    /// returning how many were dropped. Fails with `InvalidInput` without the magic prefix mode
+    pub fn resync(&mut self) -> io::Result<u64>{
+        self.spill = None;
+        self.decoder.resync(&mut Source::new(&self.stream, &mut self.read_control))
+    }
+    /// Numbers frames to detect lost or replayed ones: reads fail with `SequenceGap`
+    /// on a jump and return the frame with the next call.
+    /// Announced with a hello before the first frame, a peer without it fails with `ModeMismatch`.
+    /// Both peers must enable it before the first frame
+    pub fn set_sequence_numbers(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.sequence_numbers = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn sequence_numbers(&self) -> bool{
+        self.decoder.extensions().sequence_numbers
+    }
+    /// Sends a checksum of every payload in its header and verifies the ones received:
+    /// a corrupted frame fails with `ChecksumMismatch` and breaks the connection.
+    /// The algorithm is announced like `set_sequence_numbers`, both peers must pick the same one
+    /// before the first frame.
+    /// Fails with `Unsupported` if the algorithm is not available in this build, see `ChecksumKind::is_available`
+    pub fn set_checksum(&mut self, kind: ChecksumKind) -> io::Result<()>{
+        kind.check_available()?;
+        let mut extensions = self.decoder.extensions();
+        extensions.checksum = kind;
+        self.decoder.set_extensions(extensions);
+        Ok(())
+    }
+    pub fn checksum(&self) -> ChecksumKind{
+        self.decoder.extensions().checksum
+    }
+    /// Adds a flags byte after the length of every frame (protocol v2), set with
+    /// `write_frame_with_flags` and read with `read_frame_with_flags`.
+    /// Announced like `set_sequence_numbers`, both peers must enable it before the first frame
+    pub fn set_frame_flags(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.flags = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn frame_flags(&self) -> bool{
+        self.decoder.extensions().flags
+    }
+    /// Buffers written frames and sends them together once the policy says so, or on `flush`.
+    /// While frames are buffered a failed send is reported by the write that triggered it,
+    /// that frame is queued anyway and the unsent bytes stay buffered.
+    /// Writes are refused until `flush` or `resume_write` sent them
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy){
+        self.write_state().flush_policy = policy;
+    }
+    pub fn flush_policy(&self) -> FlushPolicy{
+        self.lock_write().flush_policy
+    }
+    /// Pads every payload with zeros to `pad_to` bytes so that frames do not give their lengths away.
+    /// The payload is preceded by its true length in 4 bytes, it holds up to `pad_to - 4` bytes,
+    /// longer ones are handled by `set_pad_overflow`. The peer needs `set_strip_padding`
+    pub fn set_pad_to(&mut self, pad_to: Option<usize>){
+        self.write_state().pad_to = pad_to;
+    }
+    pub fn pad_to(&self) -> Option<usize>{
+        self.lock_write().pad_to
+    }
+    pub fn set_pad_overflow(&mut self, overflow: PadOverflow){
+        self.write_state().pad_overflow = overflow;
+    }
+    pub fn pad_overflow(&self) -> PadOverflow{
+        self.lock_write().pad_overflow
+    }
+    /// Buffers small frames for up to `max_delay` or until `max_bytes` accumulate, `None` sends every frame
+    /// right away. Shorthand for the matching flush policy, `write_frame_now` bypasses it per frame
+    pub fn set_write_coalescing(&mut self, coalescing: Option<WriteCoalescing>){
+        self.set_flush_policy(coalescing.map_or_else(FlushPolicy::immediate, FlushPolicy::from))
+    }
+    /// When the buffered frames are due by `FlushPolicy::max_delay`, for event loops to wake up in time
+    pub fn flush_deadline(&self) -> Option<Instant>{
+        let state = self.lock_write();
+        let since = state.buffered_since?;
+        state.flush_policy.max_delay.and_then(|delay| since.checked_add(delay))
+    }
+    /// Sends the buffered frames if they waited past `FlushPolicy::max_delay`
+    pub fn flush_expired(&mut self) -> io::Result<()>{
+        self.write_path().flush_expired()
+    }
+    /// Whether dropping the connection sends the frames still buffered, on by default.
+    /// The send blocks unless the socket is nonblocking, its errors go to the drop error slot if any.
+    /// Use `close` to get them instead
+    pub fn set_flush_on_drop(&mut self, flush: bool){
+        self.flush_on_drop = flush;
+    }
+    pub fn flush_on_drop(&self) -> bool{
+        self.flush_on_drop
+    }
+    /// Where the flush on drop records its error, none by default
+    pub fn set_drop_error_slot(&mut self, slot: Option<DropErrorSlot>){
+        self.drop_error = slot;
+    }
+    pub fn drop_error_slot(&self) -> Option<&DropErrorSlot>{
+        self.drop_error.as_ref()
+    }
+    /// Sends the buffered frames and shuts down the write half, reporting what failed.
+    /// The read half of clones and of the `ConnectionReader` stays open.
+    /// The socket still sends what it holds after this returns, `set_linger` bounds that
+    pub fn close(mut self) -> io::Result<()>{
+        self.flush_on_drop = false;
+        self.write_path().flush_buffer()?;
+        self.stream.shutdown(Shutdown::Write)
+    }
+    /// Closes like `close` after a close frame carrying `reason`, so that the peer tells a clean end
+    /// from a crash: its reads fail with `PeerClosed` instead of the plain end of the stream, see `ReadErr::Closed`.
+    /// The close frame is a control frame, see `FrameFlag::Control`, it needs the flags extension.
+    /// Without it this is `close`, the peer then sees the plain end of the stream as from any other peer.
+    /// A peer whose version predates close frames reads it as a frame of its own with the control flag
+    pub fn close_graceful(mut self, reason: Option<&[u8]>) -> io::Result<()>{
+        if self.decoder.extensions().flags {
+            let mut frame = vec![decoder::CLOSE_FRAME];
+            frame.extend_from_slice(reason.unwrap_or_default());
+            self.write_path().send_frame(&frame, FrameFlag::Control.into(), true, false)?;
+        }
+        self.close()
+    }
+    /// What the peer gave as the reason once its close frame was read, see `close_graceful`
+    pub fn close_reason(&self) -> Option<&[u8]>{
+        self.decoder.close_reason()
+    }
+    /// What dropping does: flushes if asked to, then gives the reader and writer turns back to the clones
+    fn wind_down(&mut self){
+        if self.flush_on_drop {
+            let result = self.write_path().flush_buffer();
+            if let Err(err) = result {
+                if let Some(slot) = &self.drop_error {
+                    slot.set(err);
+                }
+            }
+        }
+        self.decoder.leave_turn();
+        let position = self.write_state().position();
+        self.write_turn.give_back(self.write_handle, position, &[]);
+    }
+    /// Gives a half of `split_shared` whose other half is alive a descriptor of its own,
+    /// leaving this connection the only owner of its stream
+    fn unshare_stream(&mut self) -> io::Result<()>{
+        if Arc::strong_count(&self.stream) > 1 {
+            self.stream = Arc::new(self.stream.try_clone()?);
+        }
+        Ok(())
+    }
+    /// The stream, after flushing the buffered frames as a drop would
+    fn into_stream(mut self) -> io::Result<Stream>{
+        self.unshare_stream()?;
+        Ok(self.take_unshared())
+    }
+    /// The stream once `unshare_stream` made this connection its only owner, the rest is dropped as a drop would
+    fn take_unshared(self) -> Stream{
+        let stream = self.stream.clone();
+        drop(self);
+        match Arc::try_unwrap(stream) {
+            Ok(stream) => stream,
+            Err(_) => unreachable!("the connection held the only other handle"),
+        }
+    }
+    /// The stream back with the bytes received past the last frame read, to switch it to another protocol.
+    /// Buffered frames are flushed first, the connection comes back with the error if that fails.
+    /// It comes back too once a frame is peeked or its header was received (`InvalidInput`):
+    /// its bytes are decoded already, read it first
+    pub fn into_inner(mut self) -> Result<(Stream, Vec<u8>), IntoInnerError>{
+        if self.peeked.is_some() || !self.decoder.can_take_input() {
+            let error = io::Error::new(io::ErrorKind::InvalidInput, "A frame is partly decoded, read it first");
+            return Err(IntoInnerError{connection: Box::new(self), error})
+        }
+        if let Err(error) = self.flush().and_then(|()| self.unshare_stream()) {
+            return Err(IntoInnerError{connection: Box::new(self), error})
+        }
+        let leftover = self.decoder.take_input().unwrap_or_default();
+        Ok((self.take_unshared(), leftover))
+    }
+    pub fn get_ref(&self) -> &Stream{
+        &self.stream
+    }
+    /// Reading from or writing to it directly corrupts the frame stream, setting options is fine.
+    /// `None` for a half of `split_shared` while the other half is alive, they share it
+    pub fn get_mut(&mut self) -> Option<&mut Stream>{
+        Arc::get_mut(&mut self.stream)
+    }
+    /// Resets the connection instead of closing it gracefully, for peers that misbehave:
+    /// buffered frames and data still in the socket buffer are dropped and the peer's reads fail
+    /// with `ConnectionReset`. The reset happens once clones and halves sharing the socket are dropped too
+    pub fn close_abortive(mut self) -> io::Result<()>{
+        self.flush_on_drop = false;
+        self.set_linger(Some(Duration::ZERO))
+    }
+    /// Bytes written but not sent yet
+    pub fn buffered_len(&self) -> usize{
+        self.lock_write().write_buf.len()
+    }
+    /// Drops zero-length frames (keepalives) instead of returning them.
+    /// A dropped keepalive still restarts the read timeout, a read only times out
+    /// once nothing at all arrived for that long. Deadlines are not extended
+    pub fn set_filter_empty_frames(&mut self, filter: bool){
+        self.decoder.set_filter_empty(filter)
+    }
+    pub fn filter_empty_frames(&self) -> bool{
+        self.decoder.filter_empty()
+    }
+    /// Reads frames padded by a peer with `set_pad_to`, returning only the bytes they carry.
+    /// Zero-length frames are taken as they are. `skip_frame` returns the length on the wire
+    pub fn set_strip_padding(&mut self, strip: bool){
+        self.decoder.set_strip_padding(strip)
+    }
+    pub fn strip_padding(&self) -> bool{
+        self.decoder.strip_padding()
+    }
+    /// Records when each frame finished arriving, as seen by `read_frame_timed`
+    pub fn set_timestamping(&mut self, timestamping: bool){
+        self.decoder.set_timestamping(timestamping)
+    }
+    pub fn timestamping(&self) -> bool{
+        self.decoder.timestamping()
+    }
+    pub fn is_poisoned(&self) -> bool{
+        self.decoder.is_failed()
+    }
+    /// Token cancelling reads of this connection (and of its reader half) from another thread.
+    /// Every call returns the same token, clones of the connection get their own
+    pub fn cancel_token(&mut self) -> io::Result<CancelToken>{
+        if self.read_control.cancel.is_none() {
+            self.read_control.cancel = Some(CancelToken::new()?);
+        }
+        Ok(self.read_control.cancel.clone().unwrap())
+    }
+    /// Why the `Iterator` impl last returned `None`, cleared when it yields a frame
+    pub fn last_read_error(&self) -> Option<ReadFailure>{
+        self.last_read_error
+    }
+    /// Reads the next frame into the internal buffer.
+    /// The returned slice is valid until the next call
+    pub fn next_frame(&mut self) -> io::Result<&[u8]>{
+        let mut buf = std::mem::take(&mut self.frame_buf);
+        if buf.capacity() > self.frame_buf_high_water {
+            buf.clear();
+            buf.shrink_to(self.frame_buf_high_water);
+        }
+        let result = self.read_frame_into(&mut buf);
+        self.frame_buf = buf;
+        result?;
+        Ok(&self.frame_buf)
+    }
+    /// Starts reading the next frame without buffering it,
+    /// the payload is consumed through the returned `Read`.
+    /// Dropping it skips whatever was left unread
+    pub fn frame_reader(&mut self) -> io::Result<FramePayload<'_>>{
+        if let Some(frame) = self.take_buffered()? {
+            return Ok(FramePayload{connection: self, buffered: Some(io::Cursor::new(frame))})
+        }
+        self.pace()?;
+        self.decoder.start_streamed(&mut Source::new(&self.stream, &mut self.read_control))?;
+        Ok(FramePayload{connection: self, buffered: None})
+    }
+    /// Reads the next frame without consuming it:
+    /// following peeks and reads return the same payload
+    pub fn peek_frame(&mut self) -> io::Result<&[u8]>{
+        if self.peeked.is_none() {
+            let mut frame = Vec::new();
+            self.read_frame_into(&mut frame)?;
+            self.peeked = Some(frame);
+        }
+        Ok(self.peeked.as_deref().unwrap_or_default())
+    }
+    /// Returns `Ok(None)` instead of failing with `WouldBlock` when the frame is not complete yet,
+    /// partially received bytes are kept for the next call.
+    /// Meant for connections whose stream is in non-blocking mode
+    pub fn try_read_frame(&mut self) -> io::Result<Option<Vec<u8>>>{
+        if let Some(frame) = self.peeked.take() {
+            return Ok(Some(frame))
+        }
+        self.try_pace()?;
+        match self.decoder.read_buffered(&mut Source::new(&self.stream, &mut self.read_control)) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+    /// As on `ConnectionController`, `ReadController` and `WriteController`, so that calls are not ambiguous
+    /// with all of them in scope
+    pub fn local_addr(&self) -> io::Result<SocketAddr>{
+        self.stream.local_addr()
+    }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr>{
+        self.stream.peer_addr()
+    }
+    pub fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.stream.set_read_timeout(t)
+    }
+    pub fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.stream.set_write_timeout(t)
+    }
+    /// Waits up to `timeout`, `None` for ever, until there is something to read, `Ok(false)` once it passed.
+    /// Only a hint: the peer closing counts as readable and the frame may not be complete yet,
+    /// pair it with `try_read_frame` on a non-blocking stream. Bytes already received here count as readable
+    pub fn poll_readable(&self, timeout: Option<Duration>) -> io::Result<bool>{
+        if self.peeked.is_some() || self.decoder.has_input() || self.decoder.has_handed_input() {
+            return Ok(true)
+        }
+        poll::poll(&self.stream, Readiness::Readable, timeout)
+    }
+    /// Cheap liveness check for pooled connections, reading nothing: `Ok(true)` once the peer closed
+    /// or reset the connection. A pending socket error, see `take_error`, is returned instead.
+    /// Received bytes waiting to be read count as open
+    pub fn is_closed(&self) -> io::Result<bool>{
+        if let Some(err) = stream_take_error(&self.stream)? {
+            return Err(err)
+        }
+        if self.peeked.is_some() || self.decoder.has_input() || self.decoder.has_handed_input() {
+            return Ok(false)
+        }
+        poll::is_closed(&self.stream)
+    }
+    /// Reads as many frames as are immediately available, up to `max`.
+    /// Blocks only until the first frame is complete, a trailing partial frame
+    /// stays buffered for the next read
+    pub fn read_frames(&mut self, max: usize) -> io::Result<Vec<Vec<u8>>>{
+        let mut frames = Vec::new();
+        if max == 0 {
+            return Ok(frames)
+        }
+        if self.peeked.is_none() && !self.decoder.is_mid_frame() && !self.decoder.has_input() {
+            self.decoder.fill(&mut Source::new(&self.stream, &mut self.read_control), BATCH_READ_SIZE)?;
+        }
+        frames.push(self.read_frame()?);
+        while frames.len() < max && self.try_pace().is_ok() {
+            match self.decoder.next_frame() {
+                Some(frame) => frames.push(frame),
+                None => break,
+            }
+        }
+        Ok(frames)
+    }
+    /// Temporarily applies `t` as the read timeout, restoring the previous one afterwards.
+    /// A frame read while the restore fails comes back in `ReadErr::TimeoutNotRestored`
+    pub fn read_frame_timeout(&mut self, t: Duration) -> Result<Vec<u8>, ReadErr>{
+        let previous = stream_read_timeout(&self.stream).map_err(ReadErr::Io)?;
+        self.stream.set_read_timeout(Some(t)).map_err(ReadErr::Io)?;
+        let result = self.read_frame();
+        let restored = self.stream.set_read_timeout(previous);
+        self.timed_read_result(result, restored)
+    }
+    /// The read error wins over a failed restore, a frame is never dropped for one
+    fn timed_read_result(&mut self, result: io::Result<Vec<u8>>, restored: io::Result<()>) -> Result<Vec<u8>, ReadErr>{
+        match (result, restored) {
+            (Ok(frame), Ok(())) => Ok(frame),
+            (Ok(frame), Err(error)) => Err(ReadErr::TimeoutNotRestored{frame, error}),
+            (Err(err), _) => Err(self.decoder.read_err(err)),
+        }
+    }
+    /// Iterates over frames until the peer closes the connection between frames.
+    /// Timeouts are yielded as errors and iteration may go on,
+    /// any other error is yielded once and ends the iteration
+    pub fn frames(&mut self) -> Frames<'_>{
+        Frames{connection: self, done: false}
+    }
+    /// Reads a message sent with `write_message` into `sink`, returning its length.
+    /// Every frame read is taken as part of the message except zero-length ones, which are skipped.
+    /// Fails with `MessageAborted` if the sender gave up. On an error the message is not resumed,
+    /// what was passed to `sink` stays there
+    pub fn read_message(&mut self, sink: &mut impl Write) -> io::Result<u64>{
+        message::read_message(self, sink)
+    }
+    /// Reads the next frame, failing with a timeout once `deadline` has passed.
+    /// The read timeout configured before is restored afterwards, see `read_frame_timeout`
+    pub fn read_frame_deadline(&mut self, deadline: Instant) -> Result<Vec<u8>, ReadErr>{
+        if Instant::now() >= deadline {
+            return Err(self.decoder.read_err(io::ErrorKind::TimedOut.into()))
+        }
+        let previous = stream_read_timeout(&self.stream).map_err(ReadErr::Io)?;
+        self.read_control.deadline = Some(deadline);
+        let result = self.read_frame();
+        self.read_control.deadline = None;
+        let restored = self.stream.set_read_timeout(previous);
+        self.timed_read_result(result, restored)
+    }
+    /// Reads the next frame along with the time its last byte was received.
+    /// Without timestamping the time is taken when the frame is returned
+    pub fn read_frame_timed(&mut self) -> io::Result<(Vec<u8>, Instant)>{
+        let frame = self.read_frame()?;
+        Ok((frame, self.decoder.completed_at().unwrap_or_else(Instant::now)))
+    }
+    /// Reads the next frame along with its flags, empty without `set_frame_flags`
+    pub fn read_frame_with_flags(&mut self) -> io::Result<(Vec<u8>, FrameFlags)>{
+        let frame = self.read_frame()?;
+        Ok((frame, self.decoder.flags()))
+    }
+    /// Same as `read_frame` with the failure classified by `ReadErr`
+    pub fn read_frame_checked(&mut self) -> Result<Vec<u8>, ReadErr>{
+        self.read_frame().map_err(|err| self.decoder.read_err(err))
+    }
+    /// Reads the next frame, writing it to an anonymous temp file instead of memory
+    /// when it is longer than the spill threshold.
+    /// Failures of the temp file are wrapped into `SinkError`
+    pub fn read_frame_spilled(&mut self) -> io::Result<FrameData>{
+        if !self.decoder.is_streaming() {
+            self.spill = None;
+        }
+        if self.spill.is_none() {
+            if let Some(frame) = self.take_buffered()? {
+                return Ok(FrameData::InMemory(frame))
+            }
+            self.pace()?;
+            let length = self.decoder.read_header(&mut Source::new(&self.stream, &mut self.read_control))?;
+            if self.spill_threshold.is_none_or(|threshold| length as u64 <= threshold) {
+                return Ok(FrameData::InMemory(self.decoder.read_buffered(&mut Source::new(&self.stream, &mut self.read_control))?))
+            }
+            self.decoder.start_streamed(&mut Source::new(&self.stream, &mut self.read_control))?;
+            match tempfile::tempfile() {
+                Ok(file) => self.spill = Some(file),
+                Err(err) => {
+                    let _ = self.decoder.discard_pending(&mut Source::new(&self.stream, &mut self.read_control));
+                    return Err(SinkError::wrap(err))
+                }
+            }
+        }
+        let mut chunk = vec![0u8; self.decoder.chunk_size().min(self.decoder.streamed_remaining())];
+        while let Some(file) = &mut self.spill {
+            let n = match self.decoder.read_streamed(&mut Source::new(&self.stream, &mut self.read_control), &mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if let Err(err) = file.write_all(&chunk[..n]) {
+                self.spill = None;
+                let _ = self.decoder.discard_pending(&mut Source::new(&self.stream, &mut self.read_control));
+                return Err(SinkError::wrap(err))
+            }
+        }
+        let mut file = self.spill.take().unwrap();
+        let length = file.stream_position().and_then(|length| {
+            file.rewind()?;
+            Ok(length)
+        }).map_err(SinkError::wrap)?;
+        Ok(FrameData::Spilled(file, length))
+    }
+    /// Waits for the read rate limit to allow the next frame
+    fn pace(&mut self) -> io::Result<()>{
+        match &mut self.read_rate {
+            Some(rate) if !self.decoder.is_mid_frame() => {
+                rate.wait_ready(self.decoder.received(), self.read_control.deadline)
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Fails with `WouldExceed` instead of waiting
+    fn try_pace(&mut self) -> io::Result<()>{
+        match &mut self.read_rate {
+            Some(rate) if !self.decoder.is_mid_frame() => {
+                rate.ready(self.decoder.received(), Instant::now()).map_err(WouldExceed::error)
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Frame completed earlier by `peek_frame` or left half-read by an interrupted read
+    fn take_buffered(&mut self) -> io::Result<Option<Vec<u8>>>{
+        if let Some(frame) = self.peeked.take() {
+            return Ok(Some(frame))
+        }
+        // Padding is only stripped from whole frames
+        if self.decoder.is_buffering() || (self.decoder.strip_padding() && !self.decoder.is_streaming()) {
+            self.pace()?;
+            return Ok(Some(self.decoder.read_buffered(&mut Source::new(&self.stream, &mut self.read_control))?))
+        }
+        Ok(None)
+    }
+    /// The reader half keeps the read state of this connection (peeked frame, poisoning)
+    pub fn separate(mut self) -> io::Result<(ConnectionReader, ConnectionWriter)>{
+        let mut writer = self.try_clone()?;
+        // The write state goes with the writer, buffered frames included, and so does the writer turn they hold
+        std::mem::swap(writer.write_state(), self.write_state());
+        std::mem::swap(&mut writer.write_handle, &mut self.write_handle);
+        Ok((ConnectionReader{connection: self}, ConnectionWriter{connection: writer}))
+    }
+    /// Splits like `separate` with both halves on this socket instead of a duplicate, which cannot fail
+    /// and suits transports that cannot be duplicated. The halves read and write it concurrently,
+    /// the socket closes once both are dropped. They report the same descriptor: register only one
+    /// with a poller that refuses it twice, as mio does. Turning one into a raw descriptor while
+    /// the other is alive duplicates it after all, and panics if that fails
+    pub fn split_shared(mut self) -> (ConnectionReader, ConnectionWriter){
+        let mut writer = self.clone_with(self.stream.clone());
+        std::mem::swap(writer.write_state(), self.write_state());
+        std::mem::swap(&mut writer.write_handle, &mut self.write_handle);
+        (ConnectionReader{connection: self}, ConnectionWriter{connection: writer})
+    }
+}
+
+/// Longest header written before a frame, the hello included
+pub(crate) const MAX_FRAME_HEADER_LEN: usize = 40;
+
+/// Header of the next frame written with the settings of `decoder`,
+/// preceded by the hello unless it was sent
+pub(crate) fn frame_header(decoder: &FrameDecoder, hello_sent: bool, sequence: u32, frame: &[u8], flags: FrameFlags) -> Result<([u8; MAX_FRAME_HEADER_LEN], usize), WriteErr>{
+    let checksum = decoder.extensions().checksum;
+    let digest = if checksum == ChecksumKind::None { 0 } else { checksum::digest_kind(checksum, frame) };
+    payload_header(decoder, hello_sent, sequence, frame.len() as u64, digest, flags)
+}
+
+/// Header of a payload of `length` bytes whose checksum digest is already known
+fn payload_header(decoder: &FrameDecoder, hello_sent: bool, sequence: u32, length: u64, digest: u64, flags: FrameFlags) -> Result<([u8; MAX_FRAME_HEADER_LEN], usize), WriteErr>{
+    let extensions = decoder.extensions();
+    if !extensions.flags && !flags.is_empty() {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "Frame flags are disabled, see set_frame_flags");
+        return Err(WriteErr::Io(err))
+    }
+    let overhead = extensions.flags as usize + 4 * extensions.sequence_numbers as usize + extensions.checksum.digest_len();
+    let config = decoder.framing_config();
+    let magic = decoder.magic_prefix();
+    let prefix_start = if magic { FRAME_MAGIC.len() } else { 0 };
+    let (prefix, prefix_len) = match decoder::encode_length(&config, prefix_start, length.saturating_add(overhead as u64)) {
+        Ok(prefix) => prefix,
+        Err(LengthOutOfRange::TooLong) => return Err(WriteErr::TooLongFrame),
+        Err(LengthOutOfRange::Negative) => {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "Frame is shorter than the length adjustment");
+            return Err(WriteErr::Io(err))
+        }
+    };
+    let mut header = [0u8; MAX_FRAME_HEADER_LEN];
+    let mut filled = 0;
+    let mut put = |bytes: &[u8]| {
+        header[filled..filled + bytes.len()].copy_from_slice(bytes);
+        filled += bytes.len();
+    };
+    // The first frame is preceded by the hello announcing the extensions in use
+    if !hello_sent && extensions != Extensions::default() {
+        if magic { put(&FRAME_MAGIC) }
+        // A hello does not fit a 2 byte prefix and takes 4
+        let width = config.width.bytes().max(decoder::HELLO_LEN);
+        let (hello, hello_len) = decoder::encode_raw_length(&config, width, u32::from_be_bytes(extensions.hello()) as u64);
+        put(&hello[..hello_len]);
+    }
+    if magic { put(&FRAME_MAGIC) }
+    put(&prefix[..prefix_len]);
+    if extensions.flags { put(&[flags.bits()]) }
+    if extensions.sequence_numbers { put(&sequence.to_be_bytes()) }
+    if extensions.checksum != ChecksumKind::None {
+        let digest = digest.to_be_bytes();
+        put(&digest[digest.len() - extensions.checksum.digest_len()..]);
+    }
+    Ok((header, filled))
+}
+
+/// Chunks a streamed payload is copied to the stream in
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Slices given to one vectored write
+const MAX_WRITE_SLICES: usize = 128;
+
+/// Writes all of `parts` with as few vectored writes as the transport allows,
+/// resuming after short writes. Fails with the number of bytes written before the error
+pub(crate) fn write_parts<W: Write + ?Sized>(w: &mut W, parts: &[&[u8]]) -> Result<(), (io::Error, usize)>{
+    let mut written = 0;
+    let (mut part, mut offset) = (0, 0);
+    loop {
+        while part < parts.len() && offset == parts[part].len() {
+            part += 1;
+            offset = 0;
+        }
+        if part == parts.len() {
+            return Ok(())
+        }
+        let mut bufs = [io::IoSlice::new(&[]); MAX_WRITE_SLICES];
+        let count = (parts.len() - part).min(MAX_WRITE_SLICES);
+        bufs[0] = io::IoSlice::new(&parts[part][offset..]);
+        for (buf, bytes) in bufs[1..count].iter_mut().zip(&parts[part + 1..]) {
+            *buf = io::IoSlice::new(bytes);
+        }
+        let mut n = match w.write_vectored(&bufs[..count]) {
+            Ok(0) => return Err((io::ErrorKind::WriteZero.into(), written)),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err((err, written)),
+        };
+        written += n;
+        while n > 0 {
+            let left = parts[part].len() - offset;
+            if n < left {
+                offset += n;
+                break
+            }
+            n -= left;
+            part += 1;
+            offset = 0;
+        }
+    }
+}
+
+
+impl Connection{
+    /// Same as `write_frame` for anything holding bytes, a `String` or a `Vec<u8>`
+    pub fn send(&mut self, payload: impl AsRef<[u8]>) -> Result<(), WriteErr>{
+        self.write_frame(payload.as_ref())
+    }
+    /// Same as `write_frame`, flags need `set_frame_flags`
+    pub fn write_frame_with_flags(&mut self, frame: &[u8], flags: FrameFlags) -> Result<(), WriteErr>{
+        self.write_path().send_frame(frame, flags, true, false)
+    }
+    /// Same as `write_frame`, failing with `WouldExceed` instead of waiting for the write bandwidth limit
+    pub fn try_write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.write_path().send_frame(frame, FrameFlags::empty(), false, false)
+    }
+    /// Sends the frame right away whatever the flush policy, the buffered frames go with it
+    pub fn write_frame_now(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.write_path().send_frame(frame, FrameFlags::empty(), true, true)
+    }
+    /// Sends what a frame the socket would not take at once left behind, along with buffered frames.
+    /// After `WouldBlock` or a timeout it reports `Pending`, call it again once the socket is writable.
+    /// Frames are refused while something is pending
+    pub fn resume_write(&mut self) -> Result<WriteProgress, WriteErr>{
+        match self.write_path().flush_buffer() {
+            Ok(()) => Ok(WriteProgress::Complete),
+            Err(err) if is_timeout(&err) => Ok(WriteProgress::Pending),
+            Err(err) => Err(WriteErr::Io(err)),
+        }
+    }
+    /// Whether a send left bytes behind, see `resume_write`
+    pub fn is_write_pending(&self) -> bool{
+        self.lock_write().write_pending
+    }
+    /// Waits up to `timeout`, `None` for ever, until the socket takes more bytes, `Ok(false)` once it passed.
+    /// Meant for `resume_write` after a non-blocking write left bytes behind
+    pub fn poll_writable(&self, timeout: Option<Duration>) -> io::Result<bool>{
+        poll::poll(&self.stream, Readiness::Writable, timeout)
+    }
+    /// Temporarily applies `t` as the write timeout, restoring the previous one afterwards.
+    /// Buffered frames are sent first under the same timeout. A timeout before any byte of the frame
+    /// went out leaves the connection intact, one in the middle of it desynchronizes the writer
+    pub fn write_frame_timeout(&mut self, frame: &[u8], t: Duration) -> Result<(), WriteErr>{
+        self.write_path().write_frame_timeout(frame, t)
+    }
+    /// Starts a frame whose payload is produced through the returned `Write`
+    /// and sent by `FrameSink::finish` once its length is known.
+    /// Payloads longer than the spill threshold are kept in an anonymous temp file meanwhile
+    pub fn frame_writer(&mut self) -> FrameSink<'_>{
+        FrameSink{connection: self, buffer: Vec::new(), spill: None, hasher: None, length: 0, failed: false}
+    }
+    /// Sends `len` bytes of `f` from `offset` as one frame, the position of `f` is left alone.
+    /// On Linux the payload goes from the page cache to the socket with `sendfile`,
+    /// elsewhere or with a checksum on it is copied in chunks.
+    /// A file shorter than the region fails with `SourceTruncated` and desynchronizes the writer
+    pub fn write_frame_from_file(&mut self, f: &File, offset: u64, len: u64) -> Result<(), WriteErr>{
+        #[cfg(target_os = "linux")]
+        if self.decoder.extensions().checksum == ChecksumKind::None && self.write_state().pad_to.is_none() {
+            return self.write_path().send_file(f, offset, len)
+        }
+        self.write_from_reader(&mut file::FileRegion::new(f, offset), len, false)
+    }
+    /// Sends `total_len` bytes of `src` as one message of frames of up to `chunk` bytes,
+    /// for payloads too long for a frame. Each frame carries one more byte marking whether the message goes on,
+    /// the peer reads them with `read_message`. Frames written through `&Connection` meanwhile end up inside the message.
+    /// When `src` fails or ends early the peer is told the message is aborted, the connection stays usable
+    pub fn write_message(&mut self, src: &mut impl Read, total_len: u64, chunk: usize) -> Result<(), WriteErr>{
+        message::write_message(self, src, total_len, chunk)
+    }
+    /// The payload goes straight from `src` to the stream unless a checksum is on:
+    /// its digest precedes the payload, which is then gathered like `frame_writer` does first
+    fn write_from_reader(&mut self, src: &mut dyn Read, len: u64, pad: bool) -> Result<(), WriteErr>{
+        if self.decoder.extensions().checksum == ChecksumKind::None {
+            return self.write_path().write_streamed(src, len, 0, pad)
+        }
+        let mut sink = self.frame_writer();
+        io::copy(&mut src.take(len), &mut sink).map_err(WriteErr::Io)?;
+        if sink.len() < len {
+            if !pad {
+                let err = SourceTruncated{expected: len, got: sink.len()};
+                return Err(WriteErr::Io(io::Error::new(io::ErrorKind::UnexpectedEof, err)))
+            }
+            io::copy(&mut io::repeat(0).take(len - sink.len()), &mut sink).map_err(WriteErr::Io)?;
+        }
+        sink.finish()
+    }
+    /// Whether a frame was cut short after part of it reached the socket: a failing send or source,
+    /// or a `write_frame_timeout`. The peer can no longer find the frame boundaries,
+    /// every following write fails with `WriteErr::Desynchronized`
+    pub fn is_desynced(&self) -> bool{
+        self.writer_state() != WriterState::Healthy
+    }
+    pub fn writer_state(&self) -> WriterState{
+        self.lock_write().writer_state
+    }
+    /// Forgets the write state of a stream a wrapper has re-established: the writer is healthy again,
+    /// unsent bytes are dropped and the hello and sequence numbers start over
+    pub fn reset_after_reconnect(&mut self){
+        let state = self.write_state();
+        state.writer_state = WriterState::Healthy;
+        state.write_buf.clear();
+        state.buffered_frames = 0;
+        state.buffered_since = None;
+        state.write_pending = false;
+        state.hello_sent = false;
+        state.next_sequence = 0;
+    }
+    /// The write state, without locking since the connection is not shared
+    fn write_state(&mut self) -> &mut WriteState{
+        self.write.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn lock_write(&self) -> MutexGuard<'_, WriteState>{
+        // Writes mark the writer desynchronized themselves when they fail halfway, a panic adds nothing to that
+        self.write.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn write_path(&mut self) -> WritePath<'_, &Stream>{
+        let state = self.write.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        WritePath{stream: &self.stream, decoder: &self.decoder, state, turn: Some((&self.write_turn, self.write_handle))}
+    }
+    /// Runs `f` on the write path with the write state locked, for writes through `&Connection`
+    fn with_write_path<R>(&self, f: impl FnOnce(&mut WritePath<'_, &Stream>) -> R) -> R{
+        let mut state = self.lock_write();
+        let mut path = WritePath{stream: &*self.stream, decoder: &self.decoder, state: &mut state, turn: Some((&self.write_turn, self.write_handle))};
+        f(&mut path)
+    }
+}
+
+impl<S: Write> WritePath<'_, S>{
+    fn send_frame(&mut self, frame: &[u8], flags: FrameFlags, wait: bool, now: bool) -> Result<(), WriteErr>{
+        self.check_writable(1)?;
+        let padded = self.pad(frame)?;
+        let frame = &padded[..];
+        let (header, header_len) = frame_header(self.decoder, self.state.hello_sent, self.state.next_sequence, frame, flags)?;
+        self.send_parts(&[&header[..header_len], frame], wait, now)
+    }
+    /// Same as `send_frame` with the payload in pieces, hashed and sent without joining them
+    fn send_vectored(&mut self, payload: &[io::IoSlice<'_>]) -> Result<(), WriteErr>{
+        self.check_writable(1)?;
+        let length = vectored_len(payload)?;
+        if self.state.pad_to.is_some() {
+            let mut frame = Vec::with_capacity(length as usize);
+            payload.iter().for_each(|part| frame.extend_from_slice(part));
+            return self.send_frame(&frame, FrameFlags::empty(), true, false)
+        }
+        let digest = match checksum::Hasher::new(self.decoder.extensions().checksum) {
+            Some(mut hasher) => {
+                payload.iter().for_each(|part| hasher.update(part));
+                hasher.finalize()
+            }
+            None => 0,
+        };
+        let (header, header_len) = payload_header(self.decoder, self.state.hello_sent, self.state.next_sequence, length, digest, FrameFlags::empty())?;
+        let mut parts = Vec::with_capacity(payload.len() + 1);
+        parts.push(&header[..header_len]);
+        parts.extend(payload.iter().map(|part| &part[..]));
+        self.send_parts(&parts, true, false)
+    }
+    /// Paces the header, first of `parts`, and the payload after it, then buffers or writes them by the flush policy.
+    /// Without `wait` the bandwidth limit fails with `WouldExceed` instead, `now` writes the buffered frames with them
+    fn send_parts(&mut self, parts: &[&[u8]], wait: bool, now: bool) -> Result<(), WriteErr>{
+        let bytes = parts.iter().map(|part| part.len() as u64).sum();
+        if wait { self.pace_write(bytes) } else { self.try_pace_write(bytes)? }
+        let header_len = parts[0].len();
+        if !self.state.write_buf.is_empty() || (!now && !self.state.flush_policy.is_immediate()) {
+            return self.buffer_frame(parts[0], &parts[1..], now)
+        }
+        let result = write_parts(&mut self.stream, parts);
+        self.settle_write(parts, header_len, result)
+    }
+    /// Writes the header into the space reserved in front of the payload,
+    /// then sends header and payload as one contiguous buffer
+    fn send_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr>{
+        if self.state.pad_to.is_some() {
+            return self.send_frame(buf.payload(), FrameFlags::empty(), true, false)
+        }
+        self.check_writable(1)?;
+        let (header, header_len) = frame_header(self.decoder, self.state.hello_sent, self.state.next_sequence, buf.payload(), FrameFlags::empty())?;
+        self.pace_write((header_len + buf.len()) as u64);
+        if !self.state.flush_policy.is_immediate() || !self.state.write_buf.is_empty() {
+            return self.buffer_frame(&header[..header_len], &[buf.payload()], false)
+        }
+        let parts = [buf.with_header(&header[..header_len])];
+        let result = write_parts(&mut self.stream, &parts);
+        self.settle_write(&parts, header_len, result)
+    }
+    /// The payload as sent: its length and zeros up to the pad size in padding mode, itself otherwise
+    fn pad<'p>(&self, frame: &'p [u8]) -> Result<std::borrow::Cow<'p, [u8]>, WriteErr>{
+        let pad_to = match self.state.pad_to {
+            Some(pad_to) => pad_to,
+            None => return Ok(frame.into()),
+        };
+        if frame.len() > u32::MAX as usize {
+            return Err(WriteErr::TooLongFrame)
+        }
+        let size = PAD_PREFIX_LEN + frame.len();
+        if size > pad_to && self.state.pad_overflow == PadOverflow::Reject {
+            return Err(WriteErr::TooLongFrame)
+        }
+        let mut padded = Vec::with_capacity(size.max(pad_to));
+        padded.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        padded.extend_from_slice(frame);
+        padded.resize(size.max(pad_to), 0);
+        Ok(padded.into())
+    }
+    /// Waits for the write bandwidth limit to allow `bytes` more
+    fn pace_write(&mut self, bytes: u64){
+        if let Some(bandwidth) = &mut self.state.write_bandwidth {
+            // Without a deadline the wait cannot fail
+            let _ = bandwidth.wait_send(bytes, None);
+        }
+    }
+    /// Fails with `WouldExceed` instead of waiting
+    fn try_pace_write(&mut self, bytes: u64) -> Result<(), WriteErr>{
+        match &mut self.state.write_bandwidth {
+            Some(bandwidth) => bandwidth.try_send(bytes, Instant::now()).map_err(|wait| WriteErr::Io(WouldExceed::error(wait))),
+            None => Ok(()),
+        }
+    }
+    /// Counts a frame sent directly. Once the header is out the peer counts the frame, even if its payload is not.
+    /// A frame the socket would not take at once is kept for `resume_write`, one cut short by another error desynchronizes
+    fn settle_write(&mut self, parts: &[&[u8]], header_len: usize, result: Result<(), (io::Error, usize)>) -> Result<(), WriteErr>{
+        let err = match result {
+            Ok(()) => None,
+            Err((err, written)) if is_timeout(&err) => {
+                self.stash_unsent(parts, written);
+                Some(err)
+            }
+            Err((err, written)) if written < header_len => {
+                self.lose_frame(written);
+                return Err(WriteErr::Io(err))
+            }
+            Err((err, _)) => {
+                self.poison();
+                Some(err)
+            }
+        };
+        self.count_frames(1, (parts.iter().map(|part| part.len()).sum::<usize>() - header_len) as u64);
+        err.map_or(Ok(()), |err| Err(WriteErr::Io(err)))
+    }
+    /// Buffers what a failed send left out of `parts`, it goes before anything written later
+    fn stash_unsent(&mut self, parts: &[&[u8]], written: usize){
+        let mut skip = written;
+        for part in parts {
+            self.state.write_buf.extend_from_slice(&part[skip.min(part.len())..]);
+            skip = skip.saturating_sub(part.len());
+        }
+        self.state.write_pending = !self.state.write_buf.is_empty();
+    }
+    /// Sends a payload spilled to `file`, bypassing the write buffer after flushing it
+    fn write_spilled(&mut self, mut file: File, length: u64, digest: u64) -> Result<(), WriteErr>{
+        file.rewind().map_err(|err| WriteErr::Io(SinkError::wrap(err)))?;
+        self.write_streamed(&mut file, length, digest, false)
+    }
+    /// Sends the header for `length`, then copies the payload from `src` in chunks.
+    /// A short or failing `src` desynchronizes the writer once the header is out, unless `pad` fills the rest with zeros
+    fn write_streamed(&mut self, src: &mut dyn Read, length: u64, digest: u64, pad: bool) -> Result<(), WriteErr>{
+        // The padded payload is built in memory
+        if self.state.pad_to.is_some() {
+            let frame = read_source(src, length, pad)?;
+            return self.send_frame(&frame, FrameFlags::empty(), true, false)
+        }
+        self.send_header(length, digest)?;
+        self.copy_payload(src, length, 0, pad)
+    }
+    /// Sends the write buffer, then the header of a payload the caller sends itself
+    fn send_header(&mut self, length: u64, digest: u64) -> Result<(), WriteErr>{
+        self.check_writable(1)?;
+        let (header, header_len) = payload_header(self.decoder, self.state.hello_sent, self.state.next_sequence, length, digest, FrameFlags::empty())?;
+        self.flush_buffer().map_err(WriteErr::Io)?;
+        self.pace_write(header_len as u64);
+        // The payload follows from elsewhere, a frame started here cannot be resumed
+        if let Err((err, written)) = write_parts(&mut self.stream, &[&header[..header_len]]) {
+            self.lose_frame(written);
+            return Err(WriteErr::Io(err))
+        }
+        self.count_frames(1, length);
+        Ok(())
+    }
+    /// Copies the payload from `src` after `sent` of its `length` bytes went out
+    fn copy_payload(&mut self, src: &mut dyn Read, length: u64, sent: u64, pad: bool) -> Result<(), WriteErr>{
+        let mut chunk = vec![0u8; COPY_CHUNK_SIZE.min((length - sent) as usize)];
+        let mut left = length - sent;
+        while left > 0 {
+            let want = left.min(chunk.len() as u64) as usize;
+            let n = match src.read(&mut chunk[..want]) {
+                Ok(0) if pad => {
+                    chunk.fill(0);
+                    want
+                }
+                Ok(0) => {
+                    self.poison();
+                    let err = SourceTruncated{expected: length, got: length - left};
+                    return Err(WriteErr::Io(io::Error::new(io::ErrorKind::UnexpectedEof, err)))
+                }
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.poison();
+                    return Err(WriteErr::Io(err))
+                }
+            };
+            self.pace_write(n as u64);
+            if let Err(err) = self.stream.write_all(&chunk[..n]) {
+                self.poison();
+                return Err(WriteErr::Io(err))
+            }
+            left -= n as u64;
+        }
+        Ok(())
+    }
+    /// Counts `frames` frames with `bytes` of payload whose headers went out
+    fn count_frames(&mut self, frames: usize, bytes: u64){
+        self.state.hello_sent = true;
+        self.state.next_sequence = self.state.next_sequence.wrapping_add(frames as u32);
+        if let Some(activity) = self.decoder.activity() {
+            activity.wrote(frames, bytes);
+        }
+    }
+    /// Counts a frame a failed send did not start, or desynchronizes the writer if `written` bytes of it went out
+    fn lose_frame(&mut self, written: usize){
+        match written {
+            0 => if let Some(activity) = self.decoder.activity() {
+                activity.write_error();
+            },
+            _ => self.poison(),
+        }
+    }
+    fn poison(&mut self){
+        if let Some(activity) = self.decoder.activity() {
+            activity.write_error();
+        }
+        if self.state.writer_state == WriterState::Healthy {
+            self.state.writer_state = WriterState::Poisoned{frames_lost: 1};
+        }
+    }
+    /// Waits for the writer turn, taking over where the clone writing before left the stream
+    fn take_turn(&mut self){
+        let Some((turn, handle)) = self.turn else { return };
+        if !turn.try_take(handle) {
+            turn.wait(handle, None);
+        }
+        if let Some(position) = turn.adopt(handle) {
+            self.state.hello_sent = position.hello_sent;
+            self.state.next_sequence = position.next_sequence;
+            if position.desynchronized && self.state.writer_state == WriterState::Healthy {
+                self.state.writer_state = WriterState::Poisoned{frames_lost: 0};
+            }
+        }
+    }
+    /// Refuses `frames` frames while poisoned or while an earlier write is pending
+    fn check_writable(&mut self, frames: usize) -> Result<(), WriteErr>{
+        self.take_turn();
+        if let WriterState::Poisoned{frames_lost} = &mut self.state.writer_state {
+            *frames_lost += frames as u64;
+            return Err(WriteErr::Desynchronized)
+        }
+        if self.state.write_pending {
+            let err = io::Error::new(io::ErrorKind::WouldBlock, "Part of an earlier write is still pending, see resume_write");
+            return Err(WriteErr::Io(err))
+        }
+        Ok(())
+    }
+    /// Queues the frame, sending the buffer along with it once the flush policy says so.
+    /// The frame counts as written either way, bytes a failed send left out stay buffered
+    fn buffer_frame(&mut self, header: &[u8], payload: &[&[u8]], now: bool) -> Result<(), WriteErr>{
+        self.count_frames(1, payload.iter().map(|part| part.len() as u64).sum());
+        self.state.buffered_frames += 1;
+        let written_at = Instant::now();
+        if self.state.write_buf.is_empty() {
+            self.state.buffered_since = Some(written_at);
+        }
+        let total = self.state.write_buf.len() + header.len() + payload.iter().map(|part| part.len()).sum::<usize>();
+        if !now && !self.state.flush_policy.is_due(total, self.state.buffered_frames) && !self.state.flush_policy.is_expired(self.state.buffered_since, written_at) {
+            self.state.write_buf.extend_from_slice(header);
+            payload.iter().for_each(|part| self.state.write_buf.extend_from_slice(part));
+            return Ok(())
+        }
+        let mut frame = Vec::with_capacity(payload.len() + 1);
+        frame.push(header);
+        frame.extend_from_slice(payload);
+        let mut parts = vec![&self.state.write_buf[..]];
+        parts.extend_from_slice(&frame);
+        // Big frames are sent along with the buffer instead of being copied into it
+        let err = match write_parts(&mut self.stream, &parts) {
+            Ok(()) => {
+                self.state.write_buf.clear();
+                self.state.buffered_frames = 0;
+                self.state.buffered_since = None;
+                return Ok(())
+            }
+            Err((err, written)) => {
+                let buffered = self.state.write_buf.len();
+                self.state.write_buf.drain(..written.min(buffered));
+                self.stash_unsent(&frame, written.saturating_sub(buffered));
+                err
+            }
+        };
+        Err(WriteErr::Io(err))
+    }
+    /// Sends the frames buffered so far, see `set_flush_policy`.
+    /// After a failure the rest stays buffered and the next flush resumes it
+    fn flush_buffer(&mut self) -> io::Result<()>{
+        if self.state.write_buf.is_empty() {
+            return Ok(())
+        }
+        match write_parts(&mut self.stream, &[&self.state.write_buf]) {
+            Ok(()) => {
+                self.state.write_buf.clear();
+                self.state.buffered_frames = 0;
+                self.state.buffered_since = None;
+                self.state.write_pending = false;
+                Ok(())
+            }
+            Err((err, written)) => {
+                self.state.write_buf.drain(..written);
+                self.state.write_pending = true;
+                Err(err)
+            }
+        }
+    }
+    fn write_frames(&mut self, frames: &[&[u8]]) -> Result<usize, WriteErr>{
+        self.check_writable(frames.len())?;
+        if self.state.pad_to.is_some() {
+            let padded = frames.iter().map(|frame| self.pad(frame)).collect::<Result<Vec<_>, _>>()?;
+            let padded: Vec<&[u8]> = padded.iter().map(|frame| &frame[..]).collect();
+            return self.send_frames(&padded)
+        }
+        self.send_frames(frames)
+    }
+    /// Sends `frames` as they are, in as few writes as the stream allows
+    fn send_frames(&mut self, frames: &[&[u8]]) -> Result<usize, WriteErr>{
+        self.flush_buffer().map_err(WriteErr::Io)?;
+        let mut headers = Vec::with_capacity(frames.len());
+        let mut failure = None;
+        for (i, frame) in frames.iter().enumerate() {
+            let sequence = self.state.next_sequence.wrapping_add(i as u32);
+            match frame_header(self.decoder, self.state.hello_sent || i > 0, sequence, frame, FrameFlags::empty()) {
+                Ok(header) => headers.push(header),
+                Err(err) => {
+                    failure = Some(err);
+                    break
+                }
+            }
+        }
+        let mut parts = Vec::with_capacity(2 * headers.len());
+        for ((header, header_len), frame) in headers.iter().zip(frames) {
+            parts.push(&header[..*header_len]);
+            parts.push(*frame);
+        }
+        self.pace_write(parts.iter().map(|part| part.len() as u64).sum());
+        let result = write_parts(&mut self.stream, &parts);
+        let written = result.as_ref().err().map_or(usize::MAX, |(_, written)| *written);
+        // Frames whose header went out count for the peer, complete ones for the caller
+        let (mut start, mut headers_out, mut complete) = (0, 0, 0);
+        for ((_, header_len), frame) in headers.iter().zip(frames) {
+            if start + header_len > written {
+                break
+            }
+            headers_out += 1;
+            if start + header_len + frame.len() > written {
+                break
+            }
+            complete += 1;
+            start += header_len + frame.len();
+        }
+        // The frame the socket would not take completely is kept for `resume_write`, the rest of the batch is not sent
+        if let Err((err, _)) = &result {
+            if is_timeout(err) && complete < headers.len() {
+                self.stash_unsent(&parts[2 * complete..2 * complete + 2], written - start);
+                complete += 1;
+                headers_out = complete;
+            } else {
+                self.lose_frame(written - start);
+            }
+        }
+        if headers_out > 0 {
+            self.count_frames(headers_out, frames[..headers_out].iter().map(|frame| frame.len() as u64).sum());
+        }
+        match (result, failure) {
+            (Err((err, _)), _) => Err(BatchInterrupted::wrap(WriteErr::Io(err), complete)),
+            (Ok(()), Some(err)) => Err(BatchInterrupted::wrap(err, complete)),
+            (Ok(()), None) => Ok(complete),
+        }
+    }
+    /// Sends the frames buffered so far, then flushes the stream
+    fn flush(&mut self) -> io::Result<()>{
+        self.flush_buffer()?;
+        self.stream.flush()
+    }
+    /// Sends the buffered frames if they waited past `FlushPolicy::max_delay`
+    fn flush_expired(&mut self) -> io::Result<()>{
+        if self.state.flush_policy.is_expired(self.state.buffered_since, Instant::now()) {
+            return self.flush_buffer()
+        }
+        Ok(())
+    }
+}
+
+/// Writes that need socket options
+impl WritePath<'_, &Stream>{
+    fn write_frame_timeout(&mut self, frame: &[u8], t: Duration) -> Result<(), WriteErr>{
+        self.check_writable(1)?;
+        let (header, header_len) = frame_header(self.decoder, self.state.hello_sent, self.state.next_sequence, frame, FrameFlags::empty())?;
+        if let Some(bandwidth) = &mut self.state.write_bandwidth {
+            // Waiting for the limit uses up the same time
+            let deadline = Instant::now().checked_add(t);
+            bandwidth.wait_send((header_len + frame.len()) as u64, deadline).map_err(|_| WriteErr::Timeout)?;
+        }
+        let previous = stream_write_timeout(self.stream).map_err(WriteErr::Io)?;
+        self.stream.set_write_timeout(Some(t)).map_err(WriteErr::Io)?;
+        let result = match self.flush_buffer() {
+            Ok(()) => write_parts(&mut self.stream, &[&header[..header_len], frame]),
+            Err(err) => Err((err, 0)),
+        };
+        self.stream.set_write_timeout(previous).map_err(WriteErr::Io)?;
+        if result.as_ref().err().is_none_or(|(_, written)| *written >= header_len) {
+            self.count_frames(1, frame.len() as u64);
+        }
+        match result {
+            Ok(()) => Ok(()),
+            Err((err, written)) => {
+                self.lose_frame(written);
+                if is_timeout(&err) { Err(WriteErr::Timeout) } else { Err(WriteErr::Io(err)) }
+            }
+        }
+    }
+    /// Sends the header, then the region of `f` with `sendfile`
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, f: &File, offset: u64, len: u64) -> Result<(), WriteErr>{
+        self.send_header(len, 0)?;
+        let fd = stream_fd(self.stream);
+        let mut sent = 0;
+        while sent < len {
+            // Paced in chunks, the limit would not see a single call
+            let count = match self.state.write_bandwidth {
+                Some(_) => (len - sent).min(COPY_CHUNK_SIZE as u64),
+                None => len - sent,
+            };
+            self.pace_write(count);
+            match file::sendfile(fd, f, offset + sent, count) {
+                Ok(Some(0)) => {
+                    self.poison();
+                    let err = SourceTruncated{expected: len, got: sent};
+                    return Err(WriteErr::Io(io::Error::new(io::ErrorKind::UnexpectedEof, err)))
+                }
+                Ok(Some(n)) => sent += n as u64,
+                Ok(None) => return self.copy_payload(&mut file::FileRegion::new(f, offset + sent), len, sent, false),
+                Err(err) => {
+                    self.poison();
+                    return Err(WriteErr::Io(err))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FrameWriter for Connection{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame, FrameFlags::empty())
+    }
+    /// Sends the flags of `frame`, they need `set_frame_flags`
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame.payload(), frame.meta.flags)
+    }
+    /// Streams the payload without holding it in memory, see `write_from_reader`
+    fn write_frame_from_reader(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr>{
+        self.write_from_reader(src, len, false)
+    }
+    fn write_frame_from_reader_padded(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr>{
+        self.write_from_reader(src, len, true)
+    }
+    /// Sends the pieces with vectored writes, without joining them
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr>{
+        self.write_path().send_vectored(parts)
+    }
+    /// Writes the header into the space reserved in front of the payload,
+    /// then sends header and payload as one contiguous buffer
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr>{
+        self.write_path().send_framebuf(buf)
+    }
+    /// Encodes the whole batch up front and sends it with as few vectored writes as possible.
+    /// A frame that cannot be encoded ends the batch, the frames before it are still sent.
+    /// Buffered frames are sent first, the batch is not buffered.
+    /// After `WouldBlock` the frame in progress counts as written, `resume_write` finishes it
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr>{
+        let frames: Vec<&[u8]> = frames.into_iter().collect();
+        self.write_path().write_frames(&frames)
+    }
+    /// Sends the buffered frames first
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_path().flush()
+    }
+}
+
+/// Writes from several places at once, each frame is written under a lock held until it is sent or buffered.
+/// Writing through `&mut Connection` takes no lock. Payloads from readers are read into memory first
+impl FrameWriter for &Connection{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.with_write_path(|path| path.send_frame(frame, FrameFlags::empty(), true, false))
+    }
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr>{
+        self.with_write_path(|path| path.send_frame(frame.payload(), frame.meta.flags, true, false))
+    }
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr>{
+        self.with_write_path(|path| path.send_vectored(parts))
+    }
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr>{
+        self.with_write_path(|path| path.send_framebuf(buf))
+    }
+    /// The batch is written under one lock, other writers wait for all of it
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr>{
+        let frames: Vec<&[u8]> = frames.into_iter().collect();
+        self.with_write_path(|path| path.write_frames(&frames))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_write_path(|path| path.flush())
+    }
+}
+
+impl FrameReader for Connection{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        if let Some(frame) = self.peeked.take() {
+            buf.clear();
+            buf.extend_from_slice(&frame);
+            return Ok(frame.len())
+        }
+        self.pace()?;
+        self.decoder.read_frame_into(&mut Source::new(&self.stream, &mut self.read_control), buf)
+    }
+    /// Returns how many bytes this call wrote into `w`. After `WouldBlock` or a timeout
+    /// the next call resumes the same payload, after a failure of `w` the rest is skipped
+    fn read_frame_to_writer(&mut self, w: &mut dyn Write) -> io::Result<u64>{
+        if let Some(frame) = self.take_buffered()? {
+            w.write_all(&frame).map_err(SinkError::wrap)?;
+            return Ok(frame.len() as u64)
+        }
+        self.pace()?;
+        self.decoder.start_streamed(&mut Source::new(&self.stream, &mut self.read_control))?;
+        let mut chunk = vec![0u8; self.decoder.chunk_size().min(self.decoder.streamed_remaining())];
+        let mut total = 0u64;
+        loop {
+            let n = match self.decoder.read_streamed(&mut Source::new(&self.stream, &mut self.read_control), &mut chunk) {
+                Ok(0) => return Ok(total),
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if let Err(err) = w.write_all(&chunk[..n]) {
+                let _ = self.decoder.discard_pending(&mut Source::new(&self.stream, &mut self.read_control));
+                return Err(SinkError::wrap(err))
+            }
+            total += n as u64;
+        }
+    }
+    /// After `WouldBlock` or a timeout the next call resumes skipping the same frame
+    fn skip_frame(&mut self) -> io::Result<usize>{
+        if let Some(frame) = self.peeked.take() {
+            return Ok(frame.len())
+        }
+        self.pace()?;
+        self.decoder.skip_frame(&mut Source::new(&self.stream, &mut self.read_control))
+    }
+    /// Fills in the flags, sequence number and receive time the connection has enabled
+    fn read_frame_meta(&mut self) -> io::Result<Frame>{
+        let frame = self.read_frame()?;
+        Ok(Frame::new(frame, self.decoder.meta()))
+    }
+}
+
+impl ConnectionController for Connection{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(t)
+    }
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(t)
+    }
+    fn shutdown(&self, t: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(t)
+    }
+    /// Frames read or written in part are resumed by the next call: reads keep the bytes received so far,
+    /// see `try_read_frame`, and written frames the socket did not take stay buffered, see `resume_write`.
+    /// Payloads streamed from elsewhere cannot be resumed, a `WouldBlock` halfway through one desynchronizes
+    /// the writer: avoid `write_frame_from_reader`, `write_frame_from_file` and spilled `frame_writer` payloads,
+    /// or gather the payload first. `read_frame_timeout` and `read_frame_deadline` do not wait either
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        stream_set_nonblocking(&self.stream, nonblocking)
+    }
+    /// A no-op on Unix sockets, which never delay, `nodelay` is true for them
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        stream_set_nodelay(&self.stream, nodelay)
+    }
+    fn nodelay(&self) -> io::Result<bool> {
+        stream_nodelay(&self.stream)
+    }
+    /// Fails with `Unsupported` on Unix sockets, and for the interval and retries where the platform lacks them
+    fn set_keepalive_config(&self, config: Option<KeepaliveConfig>) -> io::Result<()> {
+        sockopt::set_keepalive(&self.stream, config)
+    }
+    fn keepalive(&self) -> io::Result<Option<Duration>> {
+        sockopt::keepalive(&self.stream)
+    }
+    /// Fails with `Unsupported` on Unix sockets
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        stream_set_ttl(&self.stream, ttl)
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        stream_ttl(&self.stream)
+    }
+    /// Lingering applies when the last handle of the socket is dropped, `shutdown(Shutdown::Write)`
+    /// sends the end of stream right away whatever the setting. Fails with `Unsupported` on Unix sockets
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        sockopt::set_linger(&self.stream, linger)
+    }
+    fn linger(&self) -> io::Result<Option<Duration>> {
+        sockopt::linger(&self.stream)
+    }
+    fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_buffer_size(&self.stream, SocketBuffer::Recv, size)
+    }
+    fn recv_buffer_size(&self) -> io::Result<usize> {
+        sockopt::buffer_size(&self.stream, SocketBuffer::Recv)
+    }
+    fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_buffer_size(&self.stream, SocketBuffer::Send, size)
+    }
+    fn send_buffer_size(&self) -> io::Result<usize> {
+        sockopt::buffer_size(&self.stream, SocketBuffer::Send)
+    }
+    /// Halves and clones share the socket, the error is taken by the first one asking
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        stream_take_error(&self.stream)
+    }
+}
+
+impl ReadController for Connection{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(t)
+    }
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Read)
+    }
+}
+
+impl WriteController for Connection{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(t)
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Write)
+    }
+}
+
+/// Lossy: ends on the first error of any kind, see `last_read_error`.
+/// Deprecated in favour of `Connection::frames`
+impl Iterator for Connection{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.read_frame_checked();
+        self.last_read_error = result.as_ref().err().map(ReadFailure::from);
+        result.ok()
+    }
+}
+
+/// Error-aware frame iterator, see `Connection::frames`
+#[derive(Debug)]
+pub struct Frames<'a>{
+    connection: &'a mut Connection,
+    done: bool,
+}
+
+impl<'a> Frames<'a>{
+    /// Yields frames along with their receive time, see `Connection::read_frame_timed`
+    pub fn timed(self) -> TimedFrames<'a>{
+        TimedFrames{frames: self}
+    }
+    fn advance<T>(&mut self, read: fn(&mut Connection) -> io::Result<T>) -> Option<io::Result<T>>{
+        if self.done {
+            return None
+        }
+        match read(self.connection) {
+            Ok(frame) => Some(Ok(frame)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !self.connection.decoder.is_mid_frame() => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = !is_timeout(&err) && err.kind() != io::ErrorKind::Interrupted
+                    && !SequenceGap::is_sequence_gap(&err) && !UnknownFlags::is_unknown_flags(&err);
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl Iterator for Frames<'_>{
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance(Connection::read_frame)
+    }
+}
+
+/// Timestamped frame iterator, see `Frames::timed`
+#[derive(Debug)]
+pub struct TimedFrames<'a>{
+    frames: Frames<'a>,
+}
+
+impl Iterator for TimedFrames<'_>{
+    type Item = io::Result<(Vec<u8>, Instant)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.frames.advance(Connection::read_frame_timed)
+    }
+}
+
+/// Payload of a single frame, see `Connection::frame_reader`
+#[derive(Debug)]
+pub struct FramePayload<'a>{
+    connection: &'a mut Connection,
+    buffered: Option<io::Cursor<Vec<u8>>>,
+}
+
+impl FramePayload<'_>{
+    /// Bytes of the payload not read yet
+    pub fn remaining(&self) -> usize{
+        match &self.buffered {
+            Some(frame) => frame.get_ref().len() - frame.position() as usize,
+            None => self.connection.decoder.streamed_remaining(),
+        }
+    }
+}
+
+/// `WouldBlock` and timeouts can be retried, the payload resumes where it stopped
+impl Read for FramePayload<'_>{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.buffered {
+            Some(frame) => frame.read(buf),
+            None => self.connection.decoder.read_streamed(&mut Source::new(&self.connection.stream, &mut self.connection.read_control), buf),
+        }
+    }
+}
+
+/// Skips the unread rest of the payload. If that gets interrupted,
+/// the next read on the connection finishes skipping first
+impl Drop for FramePayload<'_>{
+    fn drop(&mut self) {
+        if self.buffered.is_none() {
+            let _ = self.connection.decoder.discard_pending(&mut Source::new(&self.connection.stream, &mut self.connection.read_control));
+        }
+    }
+}
+
+/// Payload of a single frame being written, see `Connection::frame_writer`.
+/// Nothing reaches the connection before `finish`, dropping the sink discards the frame
+#[derive(Debug)]
+pub struct FrameSink<'a>{
+    connection: &'a mut Connection,
+    buffer: Vec<u8>,
+    /// Payload moved to a temp file once longer than the spill threshold
+    spill: Option<File>,
+    /// Digest of the spilled payload, computed as it is written
+    hasher: Option<checksum::Hasher>,
+    length: u64,
+    /// A write to the temp file failed, the payload is incomplete
+    failed: bool,
+}
+
+impl FrameSink<'_>{
+    /// Bytes of payload written so far
+    pub fn len(&self) -> u64{
+        self.length
+    }
+    pub fn is_empty(&self) -> bool{
+        self.length == 0
+    }
+    /// Sends the header followed by the payload as one frame.
+    /// Fails with `WriteErr::TooLongFrame` when the payload does not fit the header width.
+    /// A temp file failing while it is sent desynchronizes the writer, see `Connection::is_desynced`
+    pub fn finish(self) -> Result<(), WriteErr>{
+        if self.failed {
+            let err = io::Error::other("A write to the frame sink failed, the frame is incomplete");
+            return Err(WriteErr::Io(err))
+        }
+        match self.spill {
+            None => self.connection.write_frame(&self.buffer),
+            Some(file) => {
+                let digest = self.hasher.map_or(0, checksum::Hasher::finalize);
+                self.connection.write_path().write_spilled(file, self.length, digest)
+            }
+        }
+    }
+    fn start_spill(&mut self) -> io::Result<()>{
+        let mut file = tempfile::tempfile()?;
+        file.write_all(&self.buffer)?;
+        let mut hasher = checksum::Hasher::new(self.connection.decoder.extensions().checksum);
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&self.buffer);
+        }
+        self.spill = Some(file);
+        self.hasher = hasher;
+        self.buffer = Vec::new();
+        Ok(())
+    }
+}
+
+/// Errors come from the temp file only, after one the sink can no longer `finish`
+impl Write for FrameSink<'_>{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other("A write to the frame sink failed before"))
+        }
+        let threshold = self.connection.spill_threshold;
+        if self.spill.is_none() && threshold.is_some_and(|threshold| self.length + buf.len() as u64 > threshold) {
+            if let Err(err) = self.start_spill() {
+                self.failed = true;
+                return Err(err)
+            }
+        }
+        match &mut self.spill {
+            Some(file) => {
+                if let Err(err) = file.write_all(buf) {
+                    self.failed = true;
+                    return Err(err)
+                }
+                if let Some(hasher) = &mut self.hasher {
+                    hasher.update(buf);
+                }
+            }
+            None => self.buffer.extend_from_slice(buf),
+        }
+        self.length += buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ConnectionWriter {
+    connection: Connection
+}
+
+impl ConnectionWriter{
+    pub fn send(&mut self, payload: impl AsRef<[u8]>) -> Result<(), WriteErr>{
+        self.connection.send(payload)
+    }
+    pub fn write_frame_with_flags(&mut self, frame: &[u8], flags: FrameFlags) -> Result<(), WriteErr>{
+        self.connection.write_frame_with_flags(frame, flags)
+    }
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.connection.set_magic_prefix(magic)
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.connection.magic_prefix()
+    }
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.connection.set_framing_config(config)
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.connection.framing_config()
+    }
+    pub fn set_framing(&mut self, framing: Framing){
+        self.connection.set_framing(framing)
+    }
+    pub fn framing(&self) -> Framing{
+        self.connection.framing()
+    }
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.connection.set_header_width(width)
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.connection.header_width()
+    }
+    pub fn set_sequence_numbers(&mut self, enabled: bool){
+        self.connection.set_sequence_numbers(enabled)
+    }
+    pub fn sequence_numbers(&self) -> bool{
+        self.connection.sequence_numbers()
+    }
+    pub fn set_checksum(&mut self, kind: ChecksumKind) -> io::Result<()>{
+        self.connection.set_checksum(kind)
+    }
+    pub fn checksum(&self) -> ChecksumKind{
+        self.connection.checksum()
+    }
+    pub fn set_frame_flags(&mut self, enabled: bool){
+        self.connection.set_frame_flags(enabled)
+    }
+    pub fn frame_flags(&self) -> bool{
+        self.connection.frame_flags()
+    }
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy){
+        self.connection.set_flush_policy(policy)
+    }
+    pub fn flush_policy(&self) -> FlushPolicy{
+        self.connection.flush_policy()
+    }
+    pub fn set_pad_to(&mut self, pad_to: Option<usize>){
+        self.connection.set_pad_to(pad_to)
+    }
+    pub fn pad_to(&self) -> Option<usize>{
+        self.connection.pad_to()
+    }
+    pub fn set_pad_overflow(&mut self, overflow: PadOverflow){
+        self.connection.set_pad_overflow(overflow)
+    }
+    pub fn pad_overflow(&self) -> PadOverflow{
+        self.connection.pad_overflow()
+    }
+    pub fn set_write_coalescing(&mut self, coalescing: Option<WriteCoalescing>){
+        self.connection.set_write_coalescing(coalescing)
+    }
+    pub fn flush_deadline(&self) -> Option<Instant>{
+        self.connection.flush_deadline()
+    }
+    pub fn flush_expired(&mut self) -> io::Result<()>{
+        self.connection.flush_expired()
+    }
+    pub fn write_frame_now(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.connection.write_frame_now(frame)
+    }
+    pub fn set_flush_on_drop(&mut self, flush: bool){
+        self.connection.set_flush_on_drop(flush)
+    }
+    pub fn flush_on_drop(&self) -> bool{
+        self.connection.flush_on_drop()
+    }
+    pub fn set_drop_error_slot(&mut self, slot: Option<DropErrorSlot>){
+        self.connection.set_drop_error_slot(slot)
+    }
+    pub fn drop_error_slot(&self) -> Option<&DropErrorSlot>{
+        self.connection.drop_error_slot()
+    }
+    pub fn close(self) -> io::Result<()>{
+        self.connection.close()
+    }
+    /// Half-closes the connection once done sending, as `close` does: flushes the buffered frames, then shuts down
+    /// the writing direction. The `ConnectionReader` keeps reading, the peer sees the end of the stream after
+    /// the frames sent before and can still answer. Unix stream sockets half-close like TCP ones. The socket is
+    /// shared, clones of the writer and a writer from `split_shared` fail from then on
+    pub fn finish(self) -> io::Result<()>{
+        self.connection.close()
+    }
+    pub fn close_graceful(self, reason: Option<&[u8]>) -> io::Result<()>{
+        self.connection.close_graceful(reason)
+    }
+    pub fn buffered_len(&self) -> usize{
+        self.connection.buffered_len()
+    }
+    pub fn set_spill_threshold(&mut self, threshold: Option<u64>){
+        self.connection.set_spill_threshold(threshold)
+    }
+    pub fn spill_threshold(&self) -> Option<u64>{
+        self.connection.spill_threshold()
+    }
+    pub fn frame_writer(&mut self) -> FrameSink<'_>{
+        self.connection.frame_writer()
+    }
+    pub fn is_desynced(&self) -> bool{
+        self.connection.is_desynced()
+    }
+    pub fn writer_state(&self) -> WriterState{
+        self.connection.writer_state()
+    }
+    pub fn reset_after_reconnect(&mut self){
+        self.connection.reset_after_reconnect()
+    }
+    pub fn write_frame_timeout(&mut self, frame: &[u8], t: Duration) -> Result<(), WriteErr>{
+        self.connection.write_frame_timeout(frame, t)
+    }
+    pub fn try_write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.connection.try_write_frame(frame)
+    }
+    pub fn set_write_bandwidth_limit(&mut self, bytes_per_sec: Option<u64>){
+        self.connection.set_write_bandwidth_limit(bytes_per_sec)
+    }
+    pub fn write_bandwidth_limit(&self) -> Option<u64>{
+        self.connection.write_bandwidth_limit()
+    }
+    pub fn resume_write(&mut self) -> Result<WriteProgress, WriteErr>{
+        self.connection.resume_write()
+    }
+    pub fn is_write_pending(&self) -> bool{
+        self.connection.is_write_pending()
+    }
+    /// As on `ConnectionController` and `WriteController`, so that calls are not ambiguous
+    pub fn local_addr(&self) -> io::Result<SocketAddr>{
+        self.connection.local_addr()
+    }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr>{
+        self.connection.peer_addr()
+    }
+    pub fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.connection.set_write_timeout(t)
+    }
+    pub fn poll_writable(&self, timeout: Option<Duration>) -> io::Result<bool>{
+        self.connection.poll_writable(timeout)
+    }
+    pub fn is_closed(&self) -> io::Result<bool>{
+        self.connection.is_closed()
+    }
+    /// Id of the connection the half was split from, shared with the other half
+    pub fn id(&self) -> u64{
+        self.connection.id()
+    }
+    /// Names this half only
+    pub fn set_name(&mut self, name: impl Into<String>){
+        self.connection.set_name(name)
+    }
+    pub fn name(&self) -> Option<&str>{
+        self.connection.name()
+    }
+    pub fn last_read_at(&self) -> Option<Instant>{
+        self.connection.last_read_at()
+    }
+    pub fn last_write_at(&self) -> Option<Instant>{
+        self.connection.last_write_at()
+    }
+    pub fn idle_for(&self) -> Duration{
+        self.connection.idle_for()
+    }
+    pub fn set_keepalives_count_as_activity(&self, count: bool){
+        self.connection.set_keepalives_count_as_activity(count)
+    }
+    pub fn keepalives_count_as_activity(&self) -> bool{
+        self.connection.keepalives_count_as_activity()
+    }
+    pub fn stats(&self) -> ConnectionStats{
+        self.connection.stats()
+    }
+    pub fn write_frame_from_file(&mut self, f: &File, offset: u64, len: u64) -> Result<(), WriteErr>{
+        self.connection.write_frame_from_file(f, offset, len)
+    }
+    pub fn write_message(&mut self, src: &mut impl Read, total_len: u64, chunk: usize) -> Result<(), WriteErr>{
+        self.connection.write_message(src, total_len, chunk)
+    }
+    /// Turns the writer into an `io::Write` cutting what is written into frames of `chunk_size` bytes
+    pub fn into_chunking_writer(self, chunk_size: usize) -> ChunkingWriter{
+        ChunkingWriter::new(self, chunk_size)
+    }
+}
+
+/// The read timeout fails with `Unsupported`, see `other_half_error`: set it on the reader half
+impl ConnectionController for ConnectionWriter {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.connection.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.connection.peer_addr()
+    }
+
+    fn set_read_timeout(&self, _t: Option<Duration>) -> io::Result<()> {
+        Err(other_half_error("The read timeout"))
+    }
+
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.connection.set_write_timeout(t)
+    }
+
+    fn shutdown(&self, t: Shutdown) -> io::Result<()> {
+        self.connection.shutdown(t)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.connection.set_nonblocking(nonblocking)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.connection.set_nodelay(nodelay)
+    }
+
+    fn nodelay(&self) -> io::Result<bool> {
+        self.connection.nodelay()
+    }
+
+    fn set_keepalive_config(&self, config: Option<KeepaliveConfig>) -> io::Result<()> {
+        self.connection.set_keepalive_config(config)
+    }
+
+    fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.connection.keepalive()
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.connection.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> io::Result<u32> {
+        self.connection.ttl()
+    }
 
-pub trait FrameWriter{
-    fn write_frame(&mut self, frame: &mut [u8]) -> Result<(), WriteErr>;
-    fn flush(&mut self) -> io::Result<()>;
-}
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.connection.set_linger(linger)
+    }
 
-pub trait ConnectionController{
-    fn local_addr(&self) -> io::Result<SocketAddr>;
-    fn peer_addr(&self) -> io::Result<SocketAddr>;
-    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>;
-    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>;
-    fn shutdown(&self, t: Shutdown) -> io::Result<()>;
-}
+    fn linger(&self) -> io::Result<Option<Duration>> {
+        self.connection.linger()
+    }
 
-impl fmt::Display for WriteErr{
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self{
-            WriteErr::I0(err) => { std::fmt::Display::fmt(&err, f) }
-            WriteErr::TooLongFrame => {
-                write!(f, "{}", "Frame is too long to send by SFP")
-            }
-        }
+    fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.connection.set_recv_buffer_size(size)
     }
-}
 
-#[derive(Debug)]
-pub struct Connection{
-    stream: Stream
-}
+    fn recv_buffer_size(&self) -> io::Result<usize> {
+        self.connection.recv_buffer_size()
+    }
 
-impl From<Stream> for Connection{
-    fn from(stream: Stream) -> Self {
-        Self{stream}
+    fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.connection.set_send_buffer_size(size)
     }
-}
 
-impl From<TcpStream> for Connection{
-    fn from(s: TcpStream) -> Self {
-        Self::from(Stream::from(s))
+    fn send_buffer_size(&self) -> io::Result<usize> {
+        self.connection.send_buffer_size()
     }
-}
 
-#[cfg(unix)]
-impl From<unix::UnixStream> for Connection{
-    fn from(s: unix::UnixStream) -> Self {
-        Self::from(Stream::from(s))
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.connection.take_error()
     }
 }
 
-impl Connection{
-    pub fn connect(s: &SocketAddr) -> io::Result<Self> {
-        match Stream::connect(s) {
-            Ok(stream) => {
-                Ok(Self::from(stream))
-            }
-            Err(err) => {
-                Err(err)
-            }
-        }
+impl WriteController for ConnectionWriter{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.connection.local_addr()
     }
-    pub fn try_clone(&self) -> io::Result<Self>{
-        Ok(Self::from(self.stream.try_clone()?))
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.connection.peer_addr()
     }
-    pub fn separate(self) -> io::Result<(ConnectionReader, ConnectionWriter)>{
-        let reader = self.try_clone()?;
-        Ok((ConnectionReader{connection: reader}, ConnectionWriter{connection: self}))
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.connection.set_write_timeout(t)
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.connection.shutdown_write()
     }
 }
 
-impl FrameWriter for Connection{
-    fn write_frame(&mut self, frame: &mut [u8]) -> Result<(), WriteErr>{
-        let length = frame.len();
-        if length > u32::MAX as usize {
-            return Err(WriteErr::TooLongFrame)
-        }
-        let mut header:[u8; 4] = u32::to_be_bytes(length as u32);
-        if let Err(err) = self.stream.write_all(&mut header){return Err(WriteErr::I0(err))}
-        if let Err(err) = self.stream.write_all(frame){return Err(WriteErr::I0(err))}
-        Ok(())
+/// See `FrameWriter for &Connection`
+impl FrameWriter for &ConnectionWriter {
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr> {
+        (&self.connection).write_frame(frame)
+    }
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr> {
+        (&self.connection).write_frame_meta(frame)
+    }
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr> {
+        (&self.connection).write_frame_vectored(parts)
+    }
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr> {
+        (&self.connection).write_framebuf(buf)
+    }
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr> {
+        (&self.connection).write_frames(frames)
     }
     fn flush(&mut self) -> io::Result<()> {
-        self.stream.flush()
+        (&self.connection).flush()
     }
 }
 
-impl FrameReader for Connection{
-    fn read_frame(&mut self) -> io::Result<Vec<u8>>{
-        let mut header = [0u8; 4];
-        self.stream.read_exact(&mut header)?;
-        let length = u32::from_be_bytes(header) as usize;
-        let mut frame = vec![0u8; length];
-        self.stream.read_exact(&mut *frame)?;
-        Ok(frame)
+impl FrameWriter for ConnectionWriter {
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr> {
+        self.connection.write_frame(frame)
+    }
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr> {
+        self.connection.write_frame_vectored(parts)
+    }
+    fn write_frame_from_reader(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr> {
+        self.connection.write_frame_from_reader(src, len)
+    }
+    fn write_frame_from_reader_padded(&mut self, src: &mut impl Read, len: u64) -> Result<(), WriteErr> {
+        self.connection.write_frame_from_reader_padded(src, len)
     }
-}
 
-impl ConnectionController for Connection{
-    fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.stream.local_addr()
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr> {
+        self.connection.write_frame_meta(frame)
     }
-    fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.stream.peer_addr()
+
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr> {
+        self.connection.write_frames(frames)
     }
-    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
-        self.stream.set_read_timeout(t)
+
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr> {
+        self.connection.write_framebuf(buf)
     }
-    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
-        self.stream.set_write_timeout(t)
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.flush()
     }
-    fn shutdown(&self, t: Shutdown) -> io::Result<()> {
-        self.stream.shutdown(t)
+}
+
+/// Keeps the connection for writing only: buffered frames are flushed, as far as the socket takes them,
+/// and its reading direction is shut down, which clones sharing the socket see too
+impl From<Connection> for ConnectionWriter{
+    fn from(connection: Connection) -> Self {
+        let _ = connection.stream.shutdown(Shutdown::Read);
+        Self{connection}
     }
 }
 
-impl Iterator for Connection{
-    type Item = Vec<u8>;
+impl fmt::Debug for ConnectionWriter{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        debug_half(f, "ConnectionWriter", &self.connection)
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.read_frame(){
-            Ok(frame) => {Some(frame)}
-            Err(_) => {None}
-        }
+impl fmt::Display for ConnectionWriter{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        display_half(f, "writer", &self.connection)
     }
 }
 
-pub struct ConnectionWriter {
+pub struct ConnectionReader {
     connection: Connection
 }
 
-impl ConnectionController for ConnectionWriter {
+/// The write timeout fails with `Unsupported`: set it on the writer half
+impl ConnectionController for ConnectionReader {
     fn local_addr(&self) -> io::Result<SocketAddr> {
         self.connection.local_addr()
     }
@@ -157,60 +3629,321 @@ impl ConnectionController for ConnectionWriter {
     }
 
     fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
-        self.connection.set_write_timeout(t)
+        self.connection.set_read_timeout(t)
     }
 
-    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
-        self.connection.set_write_timeout(t)
+    fn set_write_timeout(&self, _t: Option<Duration>) -> io::Result<()> {
+        Err(other_half_error("The write timeout"))
     }
 
     fn shutdown(&self, t: Shutdown) -> io::Result<()> {
         self.connection.shutdown(t)
     }
-}
 
-impl FrameWriter for ConnectionWriter {
-    fn write_frame(&mut self, frame: &mut [u8]) -> Result<(), WriteErr> {
-        self.connection.write_frame(frame)
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.connection.set_nonblocking(nonblocking)
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.connection.flush()
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.connection.set_nodelay(nodelay)
     }
-}
 
-pub struct ConnectionReader {
-    connection: Connection
+    fn nodelay(&self) -> io::Result<bool> {
+        self.connection.nodelay()
+    }
+
+    fn set_keepalive_config(&self, config: Option<KeepaliveConfig>) -> io::Result<()> {
+        self.connection.set_keepalive_config(config)
+    }
+
+    fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.connection.keepalive()
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.connection.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> io::Result<u32> {
+        self.connection.ttl()
+    }
+
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.connection.set_linger(linger)
+    }
+
+    fn linger(&self) -> io::Result<Option<Duration>> {
+        self.connection.linger()
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.connection.set_recv_buffer_size(size)
+    }
+
+    fn recv_buffer_size(&self) -> io::Result<usize> {
+        self.connection.recv_buffer_size()
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.connection.set_send_buffer_size(size)
+    }
+
+    fn send_buffer_size(&self) -> io::Result<usize> {
+        self.connection.send_buffer_size()
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.connection.take_error()
+    }
 }
 
-impl ConnectionController for ConnectionReader {
+impl ReadController for ConnectionReader{
     fn local_addr(&self) -> io::Result<SocketAddr> {
         self.connection.local_addr()
     }
-
     fn peer_addr(&self) -> io::Result<SocketAddr> {
         self.connection.peer_addr()
     }
-
     fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
-        self.connection.set_write_timeout(t)
+        self.connection.set_read_timeout(t)
     }
-
-    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
-        self.connection.set_write_timeout(t)
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.connection.shutdown_read()
     }
+}
 
-    fn shutdown(&self, t: Shutdown) -> io::Result<()> {
-        self.connection.shutdown(t)
+impl ConnectionReader{
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize){
+        self.connection.set_max_frame_len(max_frame_len)
+    }
+    pub fn max_frame_len(&self) -> usize{
+        self.connection.max_frame_len()
+    }
+    pub fn set_read_chunk_size(&mut self, read_chunk_size: usize){
+        self.connection.set_read_chunk_size(read_chunk_size)
+    }
+    pub fn read_chunk_size(&self) -> usize{
+        self.connection.read_chunk_size()
+    }
+    pub fn set_frame_buffer_high_water(&mut self, high_water: usize){
+        self.connection.set_frame_buffer_high_water(high_water)
+    }
+    pub fn frame_buffer_high_water(&self) -> usize{
+        self.connection.frame_buffer_high_water()
+    }
+    pub fn set_read_buffer_capacity(&mut self, capacity: usize){
+        self.connection.set_read_buffer_capacity(capacity)
+    }
+    pub fn read_buffer_capacity(&self) -> usize{
+        self.connection.read_buffer_capacity()
+    }
+    pub fn set_spill_threshold(&mut self, threshold: Option<u64>){
+        self.connection.set_spill_threshold(threshold)
+    }
+    pub fn spill_threshold(&self) -> Option<u64>{
+        self.connection.spill_threshold()
+    }
+    pub fn set_read_rate_limit(&mut self, limit: Option<RateLimit>){
+        self.connection.set_read_rate_limit(limit)
+    }
+    pub fn read_rate_limit(&self) -> Option<RateLimit>{
+        self.connection.read_rate_limit()
+    }
+    pub fn set_read_bandwidth_limit(&mut self, bytes_per_sec: Option<u64>){
+        self.connection.set_read_bandwidth_limit(bytes_per_sec)
+    }
+    pub fn read_bandwidth_limit(&self) -> Option<u64>{
+        self.connection.read_bandwidth_limit()
+    }
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.connection.set_magic_prefix(magic)
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.connection.magic_prefix()
+    }
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.connection.set_framing_config(config)
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.connection.framing_config()
+    }
+    pub fn set_framing(&mut self, framing: Framing){
+        self.connection.set_framing(framing)
+    }
+    pub fn framing(&self) -> Framing{
+        self.connection.framing()
+    }
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.connection.set_header_width(width)
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.connection.header_width()
+    }
+    pub fn resync(&mut self) -> io::Result<u64>{
+        self.connection.resync()
+    }
+    pub fn set_sequence_numbers(&mut self, enabled: bool){
+        self.connection.set_sequence_numbers(enabled)
+    }
+    pub fn sequence_numbers(&self) -> bool{
+        self.connection.sequence_numbers()
+    }
+    pub fn set_checksum(&mut self, kind: ChecksumKind) -> io::Result<()>{
+        self.connection.set_checksum(kind)
+    }
+    pub fn checksum(&self) -> ChecksumKind{
+        self.connection.checksum()
+    }
+    pub fn set_frame_flags(&mut self, enabled: bool){
+        self.connection.set_frame_flags(enabled)
+    }
+    pub fn frame_flags(&self) -> bool{
+        self.connection.frame_flags()
+    }
+    pub fn set_filter_empty_frames(&mut self, filter: bool){
+        self.connection.set_filter_empty_frames(filter)
+    }
+    pub fn filter_empty_frames(&self) -> bool{
+        self.connection.filter_empty_frames()
+    }
+    pub fn set_strip_padding(&mut self, strip: bool){
+        self.connection.set_strip_padding(strip)
+    }
+    pub fn strip_padding(&self) -> bool{
+        self.connection.strip_padding()
+    }
+    pub fn set_timestamping(&mut self, timestamping: bool){
+        self.connection.set_timestamping(timestamping)
+    }
+    pub fn timestamping(&self) -> bool{
+        self.connection.timestamping()
+    }
+    pub fn is_poisoned(&self) -> bool{
+        self.connection.is_poisoned()
+    }
+    pub fn last_read_error(&self) -> Option<ReadFailure>{
+        self.connection.last_read_error()
+    }
+    pub fn close_reason(&self) -> Option<&[u8]>{
+        self.connection.close_reason()
+    }
+    pub fn cancel_token(&mut self) -> io::Result<CancelToken>{
+        self.connection.cancel_token()
+    }
+    pub fn next_frame(&mut self) -> io::Result<&[u8]>{
+        self.connection.next_frame()
+    }
+    pub fn frame_reader(&mut self) -> io::Result<FramePayload<'_>>{
+        self.connection.frame_reader()
+    }
+    pub fn peek_frame(&mut self) -> io::Result<&[u8]>{
+        self.connection.peek_frame()
+    }
+    pub fn try_read_frame(&mut self) -> io::Result<Option<Vec<u8>>>{
+        self.connection.try_read_frame()
+    }
+    pub fn set_concurrent_reads(&mut self, mode: ConcurrentReads){
+        self.connection.set_concurrent_reads(mode)
+    }
+    pub fn concurrent_reads(&self) -> ConcurrentReads{
+        self.connection.concurrent_reads()
+    }
+    /// As on `ConnectionController` and `ReadController`, so that calls are not ambiguous
+    pub fn local_addr(&self) -> io::Result<SocketAddr>{
+        self.connection.local_addr()
+    }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr>{
+        self.connection.peer_addr()
+    }
+    pub fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.connection.set_read_timeout(t)
+    }
+    pub fn poll_readable(&self, timeout: Option<Duration>) -> io::Result<bool>{
+        self.connection.poll_readable(timeout)
+    }
+    pub fn is_closed(&self) -> io::Result<bool>{
+        self.connection.is_closed()
+    }
+    /// Id of the connection the half was split from, shared with the other half
+    pub fn id(&self) -> u64{
+        self.connection.id()
+    }
+    /// Names this half only
+    pub fn set_name(&mut self, name: impl Into<String>){
+        self.connection.set_name(name)
+    }
+    pub fn name(&self) -> Option<&str>{
+        self.connection.name()
+    }
+    pub fn last_read_at(&self) -> Option<Instant>{
+        self.connection.last_read_at()
+    }
+    pub fn last_write_at(&self) -> Option<Instant>{
+        self.connection.last_write_at()
+    }
+    pub fn idle_for(&self) -> Duration{
+        self.connection.idle_for()
+    }
+    pub fn set_keepalives_count_as_activity(&self, count: bool){
+        self.connection.set_keepalives_count_as_activity(count)
+    }
+    pub fn keepalives_count_as_activity(&self) -> bool{
+        self.connection.keepalives_count_as_activity()
+    }
+    pub fn stats(&self) -> ConnectionStats{
+        self.connection.stats()
+    }
+    pub fn read_frame_timeout(&mut self, t: Duration) -> Result<Vec<u8>, ReadErr>{
+        self.connection.read_frame_timeout(t)
+    }
+    pub fn read_frames(&mut self, max: usize) -> io::Result<Vec<Vec<u8>>>{
+        self.connection.read_frames(max)
+    }
+    pub fn read_frame_checked(&mut self) -> Result<Vec<u8>, ReadErr>{
+        self.connection.read_frame_checked()
+    }
+    pub fn read_frame_spilled(&mut self) -> io::Result<FrameData>{
+        self.connection.read_frame_spilled()
+    }
+    pub fn read_frame_timed(&mut self) -> io::Result<(Vec<u8>, Instant)>{
+        self.connection.read_frame_timed()
+    }
+    pub fn read_frame_with_flags(&mut self) -> io::Result<(Vec<u8>, FrameFlags)>{
+        self.connection.read_frame_with_flags()
+    }
+    pub fn read_frame_deadline(&mut self, deadline: Instant) -> Result<Vec<u8>, ReadErr>{
+        self.connection.read_frame_deadline(deadline)
+    }
+    pub fn read_message(&mut self, sink: &mut impl Write) -> io::Result<u64>{
+        self.connection.read_message(sink)
+    }
+    pub fn frames(&mut self) -> Frames<'_>{
+        self.connection.frames()
+    }
+    /// Turns the reader into an `io::Read` over the payloads of the frames that follow,
+    /// ending at a zero-length frame or when the peer closes
+    pub fn into_byte_reader(self) -> FrameByteReader{
+        FrameByteReader::new(self)
     }
 }
 
 impl FrameReader for ConnectionReader{
-    fn read_frame(&mut self) -> io::Result<Vec<u8>> {
-        self.connection.read_frame()
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.connection.read_frame_into(buf)
+    }
+    fn read_frame_to_writer(&mut self, w: &mut dyn Write) -> io::Result<u64> {
+        self.connection.read_frame_to_writer(w)
+    }
+    fn skip_frame(&mut self) -> io::Result<usize> {
+        self.connection.skip_frame()
+    }
+    fn read_frame_meta(&mut self) -> io::Result<Frame> {
+        self.connection.read_frame_meta()
     }
 }
 
+/// Lossy: ends on the first error of any kind, see `last_read_error`.
+/// Deprecated in favour of `ConnectionReader::frames`
 impl Iterator for ConnectionReader{
     type Item = Vec<u8>;
 
@@ -219,26 +3952,149 @@ impl Iterator for ConnectionReader{
     }
 }
 
+/// Keeps the connection for reading only: buffered frames are flushed first, as far as the socket takes them,
+/// then its writing direction is shut down, which clones sharing the socket see too
+impl From<Connection> for ConnectionReader{
+    fn from(mut connection: Connection) -> Self {
+        let _ = connection.flush();
+        let _ = connection.stream.shutdown(Shutdown::Write);
+        Self{connection}
+    }
+}
+
+impl fmt::Debug for ConnectionReader{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        debug_half(f, "ConnectionReader", &self.connection)
+    }
+}
+
+impl fmt::Display for ConnectionReader{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        display_half(f, "reader", &self.connection)
+    }
+}
+
 pub struct Server{
-    listener: Listener
+    listener: Listener,
+    magic_prefix: bool,
+    framing_config: FramingConfig,
+    nodelay: bool,
+    keepalive: Option<KeepaliveConfig>,
 }
 
 impl From<Listener> for Server{
     fn from(listener: Listener) -> Self {
-        Self{listener}
+        Self{listener, magic_prefix: false, framing_config: FramingConfig::new(), nodelay: false, keepalive: None}
     }
 }
 
 impl Server{
     pub fn bind(s: &SocketAddr) -> io::Result<Self> {
-        Ok(Self{listener: Listener::bind(s)?})
+        Ok(Self::from(Listener::bind(s)?))
     }
     pub fn bind_reuse(s: &SocketAddr, _mode: Option<u32>) -> io::Result<Self> {
-        Ok(Self{listener: Listener::bind_reuse(s, _mode)?})
+        Ok(Self::from(Listener::bind_reuse(s, _mode)?))
+    }
+    /// Same as `bind`, accepted connections use `framing`
+    pub fn bind_with_framing(s: &SocketAddr, framing: Framing) -> io::Result<Self> {
+        let mut server = Self::bind(s)?;
+        server.set_framing(framing);
+        Ok(server)
+    }
+    /// Accepted connections start in magic prefix mode, see `Connection::set_magic_prefix`
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.magic_prefix = magic;
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.magic_prefix
+    }
+    /// Framing config of accepted connections, see `Connection::set_framing_config`
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.framing_config = config;
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.framing_config
+    }
+    /// Framing of accepted connections, see `Connection::set_framing`
+    pub fn set_framing(&mut self, framing: Framing){
+        self.framing_config.framing = framing;
+    }
+    pub fn framing(&self) -> Framing{
+        self.framing_config.framing
+    }
+    /// Header width of accepted connections, see `Connection::set_header_width`
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.framing_config.width = width;
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.framing_config.width
+    }
+    /// Accepted connections have Nagle's algorithm turned off, see `Connection::set_nodelay`
+    pub fn set_nodelay(&mut self, nodelay: bool){
+        self.nodelay = nodelay;
+    }
+    pub fn nodelay(&self) -> bool{
+        self.nodelay
+    }
+    /// Keepalive of accepted connections, see `ConnectionController::set_keepalive_config`.
+    /// Failing to apply it fails `accept`
+    pub fn set_keepalive_config(&mut self, config: Option<KeepaliveConfig>){
+        self.keepalive = config;
+    }
+    pub fn keepalive_config(&self) -> Option<KeepaliveConfig>{
+        self.keepalive
+    }
+    /// `accept` fails with `WouldBlock` instead of waiting for a connection, the iterator then ends.
+    /// Accepted connections are blocking whatever the mode of the listener
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match &self.listener {
+            Listener::Inet(l) => l.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(l) => l.set_nonblocking(nonblocking),
+        }
+    }
+    /// `IP_TTL` of the listener, accepted connections inherit it. Fails with `Unsupported` on Unix sockets
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        match &self.listener {
+            Listener::Inet(l) => l.set_ttl(ttl),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets have no TTL")),
+        }
+    }
+    pub fn ttl(&self) -> io::Result<u32> {
+        match &self.listener {
+            Listener::Inet(l) => l.ttl(),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets have no TTL")),
+        }
+    }
+    /// Receive buffer size of the listener, accepted connections inherit it.
+    /// TCP needs it before the connection is accepted to scale its window, see `ConnectionController::set_recv_buffer_size`
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_listener_buffer_size(&self.listener, SocketBuffer::Recv, size)
+    }
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        sockopt::listener_buffer_size(&self.listener, SocketBuffer::Recv)
+    }
+    /// Send buffer size of the listener, accepted connections inherit it
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_listener_buffer_size(&self.listener, SocketBuffer::Send, size)
+    }
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        sockopt::listener_buffer_size(&self.listener, SocketBuffer::Send)
     }
     pub fn accept(&self) -> io::Result<(Connection,SocketAddr)> {
         let (stream, addr) = self.listener.accept()?;
-        Ok((Connection::from(stream), addr))
+        let mut connection = Connection::from(stream);
+        if self.nodelay {
+            connection.set_nodelay(true)?;
+        }
+        if self.keepalive.is_some() {
+            connection.set_keepalive_config(self.keepalive)?;
+        }
+        connection.set_magic_prefix(self.magic_prefix);
+        connection.set_framing_config(self.framing_config);
+        Ok((connection, addr))
     }
 }
 
@@ -0,0 +1,238 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Frame budget of a connection, see `Connection::set_read_rate_limit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit{
+    pub frames_per_sec: u32,
+    /// Frames that can be read back to back after an idle period
+    pub burst: u32,
+}
+
+/// Returned (wrapped into `io::ErrorKind::WouldBlock`) by `try_read_frame`
+/// when reading the next frame would exceed the rate limit, and by `try_write_frame`
+/// when sending it would exceed the write bandwidth limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldExceed{
+    pub retry_after: Duration,
+}
+
+impl WouldExceed{
+    pub(crate) fn error(retry_after: Duration) -> io::Error{
+        io::Error::new(io::ErrorKind::WouldBlock, WouldExceed{retry_after})
+    }
+    pub fn is_would_exceed(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<WouldExceed>())
+    }
+}
+
+impl fmt::Display for WouldExceed{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Rate limit exceeded, retry after {:?}", self.retry_after)
+    }
+}
+
+impl std::error::Error for WouldExceed{}
+
+/// Refills `rate` tokens per second up to `capacity`.
+/// Takes the current time as an argument so it can run on any clock
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket{
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket{
+    /// Starts full
+    pub(crate) fn new(rate: f64, capacity: f64, now: Instant) -> Self{
+        Self{rate, capacity, tokens: capacity, updated: now}
+    }
+    /// Removes tokens spent, the balance may go negative
+    pub(crate) fn consume(&mut self, amount: f64){
+        self.tokens -= amount;
+    }
+    /// Whether `amount` tokens are available, otherwise how long until they are.
+    /// Amounts above the capacity only need a full bucket
+    pub(crate) fn ready(&mut self, amount: f64, now: Instant) -> Result<(), Duration>{
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = self.updated.max(now);
+        let needed = amount.min(self.capacity);
+        if self.tokens >= needed {
+            return Ok(())
+        }
+        if self.rate <= 0.0 {
+            return Err(Duration::MAX)
+        }
+        Err(Duration::from_secs_f64((needed - self.tokens) / self.rate))
+    }
+    /// Sleeps until `amount` tokens are available.
+    /// A wait past `deadline` ends at the deadline with a timeout
+    pub(crate) fn wait_ready(&mut self, amount: f64, deadline: Option<Instant>) -> io::Result<()>{
+        loop {
+            let now = Instant::now();
+            let wait = match self.ready(amount, now) {
+                Ok(()) => return Ok(()),
+                Err(wait) => wait,
+            };
+            if let Some(deadline) = deadline {
+                if now.checked_add(wait).is_none_or(|ready| ready > deadline) {
+                    std::thread::sleep(deadline.saturating_duration_since(now));
+                    return Err(io::ErrorKind::TimedOut.into())
+                }
+            }
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Frame rate limiter of a connection, charged one token per frame received
+#[derive(Debug, Clone)]
+pub(crate) struct FrameRate{
+    pub(crate) limit: RateLimit,
+    bucket: TokenBucket,
+    /// Frames of the decoder already charged
+    charged: u64,
+}
+
+impl FrameRate{
+    pub(crate) fn new(limit: RateLimit, charged: u64) -> Self{
+        let bucket = TokenBucket::new(limit.frames_per_sec as f64, limit.burst.max(1) as f64, Instant::now());
+        Self{limit, bucket, charged}
+    }
+    /// Charges the frames received since the last call, then checks there is a token for the next one
+    pub(crate) fn ready(&mut self, received: u64, now: Instant) -> Result<(), Duration>{
+        self.bucket.consume(received.saturating_sub(self.charged) as f64);
+        self.charged = received;
+        self.bucket.ready(1.0, now)
+    }
+    pub(crate) fn wait_ready(&mut self, received: u64, deadline: Option<Instant>) -> io::Result<()>{
+        self.bucket.consume(received.saturating_sub(self.charged) as f64);
+        self.charged = received;
+        self.bucket.wait_ready(1.0, deadline)
+    }
+}
+
+/// Byte rate limiter of a connection, allowing a second worth of bytes as a burst
+#[derive(Debug, Clone)]
+pub(crate) struct Bandwidth{
+    pub(crate) bytes_per_sec: u64,
+    bucket: TokenBucket,
+}
+
+impl Bandwidth{
+    pub(crate) fn new(bytes_per_sec: u64) -> Self{
+        let rate = bytes_per_sec.max(1) as f64;
+        Self{bytes_per_sec, bucket: TokenBucket::new(rate, rate, Instant::now())}
+    }
+    /// Sleeps until a read can go on, returning how many bytes it may request
+    pub(crate) fn wait_ready(&mut self, len: usize, deadline: Option<Instant>) -> io::Result<usize>{
+        let len = len.min(self.bytes_per_sec.max(1).min(usize::MAX as u64) as usize);
+        self.bucket.wait_ready(len as f64, deadline)?;
+        Ok(len)
+    }
+    pub(crate) fn consume(&mut self, n: usize){
+        self.bucket.consume(n as f64)
+    }
+    /// Sleeps until `n` bytes can be sent, then charges them.
+    /// More than a second worth only waits for a full bucket and leaves it in debt
+    pub(crate) fn wait_send(&mut self, n: u64, deadline: Option<Instant>) -> io::Result<()>{
+        self.bucket.wait_ready(n as f64, deadline)?;
+        self.bucket.consume(n as f64);
+        Ok(())
+    }
+    /// Charges `n` bytes if they can be sent now, otherwise tells how long until they can
+    pub(crate) fn try_send(&mut self, n: u64, now: Instant) -> Result<(), Duration>{
+        self.bucket.ready(n as f64, now)?;
+        self.bucket.consume(n as f64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn ms(millis: u64) -> Duration{
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills_at_its_rate(){
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 3.0, start);
+        for _ in 0..3 {
+            assert_eq!(bucket.ready(1.0, start), Ok(()));
+            bucket.consume(1.0);
+        }
+        assert_eq!(bucket.ready(1.0, start), Err(ms(100)));
+        assert_eq!(bucket.ready(1.0, start + ms(50)), Err(ms(50)));
+        assert_eq!(bucket.ready(1.0, start + ms(100)), Ok(()));
+        // Refilling stops at the capacity
+        assert_eq!(bucket.ready(3.0, start + ms(10_000)), Ok(()));
+        bucket.consume(3.0);
+        assert_eq!(bucket.ready(1.0, start + ms(10_000)), Err(ms(100)));
+    }
+
+    #[test]
+    fn bucket_ignores_a_clock_going_backwards(){
+        let start = Instant::now() + ms(1000);
+        let mut bucket = TokenBucket::new(10.0, 1.0, start);
+        bucket.consume(1.0);
+        assert_eq!(bucket.ready(1.0, start - ms(500)), Err(ms(100)));
+        assert_eq!(bucket.ready(1.0, start + ms(100)), Ok(()));
+    }
+
+    #[test]
+    fn frame_rate_charges_the_frames_received(){
+        let start = Instant::now();
+        let limit = RateLimit{frames_per_sec: 100, burst: 5};
+        let mut rate = FrameRate{limit, bucket: TokenBucket::new(100.0, 5.0, start), charged: 0};
+        assert_eq!(rate.ready(0, start), Ok(()));
+        // Five frames arrived, the burst is used up
+        assert_eq!(rate.ready(5, start), Err(ms(10)));
+        assert_eq!(rate.ready(5, start + ms(10)), Ok(()));
+        // Frames already charged are not charged again
+        assert_eq!(rate.ready(5, start + ms(10)), Ok(()));
+        assert_eq!(rate.ready(6, start + ms(10)), Err(ms(10)));
+        // A steady 100 frames per second goes through, one more does not
+        let mut now = start + ms(10);
+        for received in 6..106 {
+            now += ms(10);
+            assert_eq!(rate.ready(received, now), Ok(()), "frame {}", received);
+        }
+        assert!(rate.ready(107, now).is_err());
+    }
+
+    #[test]
+    fn bandwidth_paces_the_bytes_sent(){
+        let start = Instant::now();
+        let mut bandwidth = Bandwidth{bytes_per_sec: 1000, bucket: TokenBucket::new(1000.0, 1000.0, start)};
+        assert_eq!(bandwidth.try_send(600, start), Ok(()));
+        assert_eq!(bandwidth.try_send(600, start), Err(ms(200)));
+        // A refused send is not charged
+        assert_eq!(bandwidth.try_send(600, start + ms(200)), Ok(()));
+        // A steady 1000 bytes per second goes through
+        for i in 1..=10 {
+            assert_eq!(bandwidth.try_send(100, start + ms(200 + i * 100)), Ok(()));
+        }
+        assert_eq!(bandwidth.try_send(100, start + ms(1200)), Err(ms(100)));
+    }
+
+    #[test]
+    fn bandwidth_sends_more_than_a_second_worth_into_debt(){
+        let start = Instant::now();
+        let mut bandwidth = Bandwidth{bytes_per_sec: 1000, bucket: TokenBucket::new(1000.0, 1000.0, start)};
+        assert_eq!(bandwidth.try_send(1, start), Ok(()));
+        // Waits for a full bucket only
+        assert_eq!(bandwidth.try_send(2500, start), Err(ms(1)));
+        assert_eq!(bandwidth.try_send(2500, start + ms(1)), Ok(()));
+        // Then pays the debt back before the next send
+        assert_eq!(bandwidth.try_send(500, start + ms(1)), Err(ms(2000)));
+        assert_eq!(bandwidth.try_send(500, start + ms(2001)), Ok(()));
+    }
+}
@@ -0,0 +1,142 @@
+//! Newline delimited text framing for peers that speak a line protocol
+use std::io;
+use std::io::{Read, Write};
+use std::fmt;
+use std::fmt::Formatter;
+use crate::{FrameReader, FrameWriter, WriteErr, FrameTooLong};
+use crate::decoder::eof_error;
+use crate::source::{ChunkBuf, CHUNK_BUF_SIZE};
+
+pub const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Returned (wrapped into `io::ErrorKind::InvalidInput` inside `WriteErr::Io`) by
+/// `LineConnection::write_frame` for frames that would not read back as one line:
+/// they contain `\n` or end with `\r`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineBreakInFrame{
+    pub position: usize,
+}
+
+impl LineBreakInFrame{
+    pub fn is_line_break_in_frame(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<LineBreakInFrame>())
+    }
+}
+
+impl fmt::Display for LineBreakInFrame{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame has a line break at byte {}", self.position)
+    }
+}
+
+impl std::error::Error for LineBreakInFrame{}
+
+/// Frames over any byte stream as lines: each frame is followed by `\n`,
+/// a `\r` before it is dropped on read
+#[derive(Debug)]
+pub struct LineConnection<S: Read + Write>{
+    stream: S,
+    /// Start of the current line, at most one byte more than `max_line_len` is kept
+    line: Vec<u8>,
+    /// Bytes of the current line received so far
+    line_len: usize,
+    ends_with_cr: bool,
+    max_line_len: usize,
+    read_buf: ChunkBuf,
+    write_buf: Vec<u8>,
+}
+
+impl<S: Read + Write> LineConnection<S>{
+    pub fn new(stream: S) -> Self{
+        Self{
+            stream,
+            line: Vec::new(),
+            line_len: 0,
+            ends_with_cr: false,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            read_buf: ChunkBuf::new(CHUNK_BUF_SIZE),
+            write_buf: Vec::new(),
+        }
+    }
+    /// Longer lines, not counting the line break, are skipped and reported with `FrameTooLong`
+    pub fn set_max_line_len(&mut self, max_line_len: usize){
+        self.max_line_len = max_line_len;
+    }
+    pub fn max_line_len(&self) -> usize{
+        self.max_line_len
+    }
+    pub fn get_ref(&self) -> &S{
+        &self.stream
+    }
+    /// Reading from the stream directly loses the framing
+    pub fn get_mut(&mut self) -> &mut S{
+        &mut self.stream
+    }
+    fn reset_line(&mut self){
+        self.line.clear();
+        self.line_len = 0;
+        self.ends_with_cr = false;
+    }
+}
+
+impl<S: Read + Write> FrameReader for LineConnection<S>{
+    /// After `WouldBlock` or a timeout the next call resumes the same line
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        loop {
+            if self.read_buf.fill(&mut self.stream)? == 0 {
+                let mid_line = self.line_len != 0;
+                self.reset_line();
+                return Err(eof_error(mid_line))
+            }
+            let unread = self.read_buf.unread();
+            let end = unread.iter().position(|&byte| byte == b'\n');
+            let bytes = &unread[..end.unwrap_or(unread.len())];
+            if let Some(&last) = bytes.last() {
+                let room = self.max_line_len.saturating_add(1).saturating_sub(self.line.len());
+                self.line.extend_from_slice(&bytes[..bytes.len().min(room)]);
+                self.line_len += bytes.len();
+                self.ends_with_cr = last == b'\r';
+            }
+            self.read_buf.consume(end.map_or(bytes.len(), |end| end + 1));
+            if end.is_none() {
+                continue
+            }
+            let length = self.line_len - usize::from(self.ends_with_cr);
+            let result = if length > self.max_line_len {
+                Err(io::Error::new(io::ErrorKind::InvalidData, FrameTooLong{length, max_frame_len: self.max_line_len}))
+            } else {
+                buf.clear();
+                buf.extend_from_slice(&self.line[..length]);
+                Ok(length)
+            };
+            self.reset_line();
+            return result
+        }
+    }
+}
+
+/// Lossy: ends on the first error of any kind
+impl<S: Read + Write> Iterator for LineConnection<S>{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().ok()
+    }
+}
+
+impl<S: Read + Write> FrameWriter for LineConnection<S>{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        let position = frame.iter().position(|&byte| byte == b'\n')
+            .or_else(|| frame.last().filter(|&&byte| byte == b'\r').map(|_| frame.len() - 1));
+        if let Some(position) = position {
+            return Err(WriteErr::Io(io::Error::new(io::ErrorKind::InvalidInput, LineBreakInFrame{position})))
+        }
+        self.write_buf.clear();
+        self.write_buf.extend_from_slice(frame);
+        self.write_buf.push(b'\n');
+        self.stream.write_all(&self.write_buf).map_err(WriteErr::Io)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,178 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fmt;
+use std::fmt::Formatter;
+use crate::{FrameWriter, FrameReader, WriteErr, SourceTruncated};
+
+/// First byte of every frame of a message
+const MORE: u8 = 0;
+const LAST: u8 = 1;
+const ABORTED: u8 = 2;
+
+/// Returned (wrapped into `io::ErrorKind::Other`) by `read_message` when the sender gave up on the message,
+/// its source failed. The connection stays usable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageAborted{
+    /// Bytes passed to the sink before the abort
+    pub received: u64,
+}
+
+impl MessageAborted{
+    pub fn is_message_aborted(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<MessageAborted>())
+    }
+}
+
+impl fmt::Display for MessageAborted{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Message aborted by the sender after {} bytes", self.received)
+    }
+}
+
+impl std::error::Error for MessageAborted{}
+
+pub(crate) fn write_message(writer: &mut impl FrameWriter, src: &mut impl Read, total_len: u64, chunk: usize) -> Result<(), WriteErr>{
+    let chunk = chunk.clamp(1, u32::MAX as usize - 1);
+    let mut buf = vec![0u8; 1 + (chunk as u64).min(total_len) as usize];
+    let mut sent = 0u64;
+    loop {
+        let len = (chunk as u64).min(total_len - sent) as usize;
+        if let Err(err) = read_chunk(src, &mut buf[1..1 + len], sent, total_len) {
+            // Frames are whole, so the peer can be told and the connection goes on
+            writer.write_frame(&[ABORTED])?;
+            return Err(WriteErr::Io(err))
+        }
+        sent += len as u64;
+        buf[0] = if sent == total_len { LAST } else { MORE };
+        writer.write_frame(&buf[..1 + len])?;
+        if sent == total_len {
+            return Ok(())
+        }
+    }
+}
+
+fn read_chunk(src: &mut impl Read, buf: &mut [u8], sent: u64, total_len: u64) -> io::Result<()>{
+    let mut filled = 0;
+    while filled < buf.len() {
+        match src.read(&mut buf[filled..]) {
+            Ok(0) => {
+                let got = sent + filled as u64;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, SourceTruncated{expected: total_len, got}))
+            }
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn read_message(reader: &mut impl FrameReader, sink: &mut impl Write) -> io::Result<u64>{
+    let mut frame = Vec::new();
+    let mut received = 0u64;
+    loop {
+        reader.read_frame_into(&mut frame)?;
+        // Keepalives, message frames are never empty
+        let Some((&marker, payload)) = frame.split_first() else { continue };
+        match marker {
+            MORE | LAST => {
+                sink.write_all(payload)?;
+                received += payload.len() as u64;
+                if marker == LAST {
+                    return Ok(received)
+                }
+            }
+            ABORTED => return Err(io::Error::other(MessageAborted{received})),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "frame is not part of a message")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Cursor;
+    use super::*;
+    use crate::{SfpReader, SfpWriter};
+
+    fn payload(len: usize) -> Vec<u8>{
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Frames of a message sent in `chunk` sized pieces
+    fn message_frames(total_len: usize, chunk: usize) -> Vec<Vec<u8>>{
+        let mut writer = SfpWriter::new(Vec::new());
+        write_message(&mut writer, &mut &payload(total_len)[..], total_len as u64, chunk).unwrap();
+        let mut reader = SfpReader::new(Cursor::new(writer.into_inner()));
+        std::iter::from_fn(|| reader.read_frame().ok()).collect()
+    }
+
+    fn read_back(frames: &[Vec<u8>]) -> io::Result<(u64, Vec<u8>)>{
+        let mut writer = SfpWriter::new(Vec::new());
+        for frame in frames {
+            writer.write_frame(frame).unwrap();
+        }
+        let mut reader = SfpReader::new(Cursor::new(writer.into_inner()));
+        let mut sink = Vec::new();
+        let len = read_message(&mut reader, &mut sink)?;
+        Ok((len, sink))
+    }
+
+    #[test]
+    fn exact_multiple_of_the_chunk_ends_with_a_full_chunk(){
+        let frames = message_frames(3000, 1000);
+        let markers: Vec<(u8, usize)> = frames.iter().map(|frame| (frame[0], frame.len() - 1)).collect();
+        assert_eq!(markers, [(MORE, 1000), (MORE, 1000), (LAST, 1000)]);
+        assert_eq!(read_back(&frames).unwrap(), (3000, payload(3000)));
+    }
+
+    #[test]
+    fn remainder_goes_in_a_shorter_last_chunk(){
+        let frames = message_frames(3500, 1000);
+        let markers: Vec<(u8, usize)> = frames.iter().map(|frame| (frame[0], frame.len() - 1)).collect();
+        assert_eq!(markers, [(MORE, 1000), (MORE, 1000), (MORE, 1000), (LAST, 500)]);
+        assert_eq!(read_back(&frames).unwrap(), (3500, payload(3500)));
+    }
+
+    #[test]
+    fn short_and_empty_messages_are_one_frame(){
+        assert_eq!(message_frames(10, 1000), [[&[LAST][..], &payload(10)].concat()]);
+        assert_eq!(message_frames(0, 1000), [vec![LAST]]);
+        assert_eq!(read_back(&message_frames(0, 1000)).unwrap(), (0, Vec::new()));
+    }
+
+    #[test]
+    fn keepalives_inside_a_message_are_skipped(){
+        let mut frames = message_frames(2500, 1000);
+        frames.insert(1, Vec::new());
+        assert_eq!(read_back(&frames).unwrap(), (2500, payload(2500)));
+    }
+
+    #[test]
+    fn truncated_source_aborts_the_message_and_the_connection_goes_on(){
+        let mut writer = SfpWriter::new(Vec::new());
+        let err = match write_message(&mut writer, &mut &payload(2500)[..], 4000, 1000) {
+            Err(WriteErr::Io(err)) => err,
+            other => panic!("{:?}", other),
+        };
+        assert!(SourceTruncated::is_source_truncated(&err));
+        write_message(&mut writer, &mut &payload(1500)[..], 1500, 1000).unwrap();
+
+        let mut reader = SfpReader::new(Cursor::new(writer.into_inner()));
+        let mut sink = Vec::new();
+        let err = read_message(&mut reader, &mut sink).unwrap_err();
+        assert!(MessageAborted::is_message_aborted(&err));
+        let aborted = err.get_ref().and_then(|inner| inner.downcast_ref::<MessageAborted>()).unwrap();
+        assert_eq!(aborted.received, 2000);
+        assert_eq!(sink, payload(2000));
+
+        let mut sink = Vec::new();
+        assert_eq!(read_message(&mut reader, &mut sink).unwrap(), 1500);
+        assert_eq!(sink, payload(1500));
+    }
+
+    #[test]
+    fn plain_frame_is_not_part_of_a_message(){
+        let err = read_back(&[b"\x07plain".to_vec()]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,240 @@
+use std::io;
+use std::time::{Duration, Instant};
+use unisocket::Stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Readiness{
+    Readable,
+    Writable,
+}
+
+/// Waits until the socket is ready or `timeout` passes, `None` waits forever.
+/// Errors and hang-ups count as ready, the following read or write reports them
+pub(crate) fn poll(stream: &Stream, readiness: Readiness, timeout: Option<Duration>) -> io::Result<bool>{
+    let deadline = timeout.map(|t| Instant::now() + t);
+    loop {
+        let wait = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match sys::poll(stream, readiness, wait) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+/// Whether the peer closed the stream or reset it, by looking at the next byte without taking it.
+/// Waits for nothing: a stream with nothing to read is open
+pub(crate) fn is_closed(stream: &Stream) -> io::Result<bool>{
+    if !poll(stream, Readiness::Readable, Some(Duration::ZERO))? {
+        return Ok(false)
+    }
+    match sys::peek(stream) {
+        Ok(n) => Ok(n == 0),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) if matches!(err.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+/// Rounded up, a sub-millisecond wait must not turn into a busy loop
+#[cfg(any(unix, windows))]
+fn timeout_ms(timeout: Option<Duration>) -> i32{
+    match timeout {
+        None => -1,
+        Some(t) => {
+            let ms = t.as_millis() + u128::from(t.subsec_nanos() % 1_000_000 != 0);
+            ms.min(i32::MAX as u128) as i32
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys{
+    use std::io;
+    use std::time::Duration;
+    use unisocket::Stream;
+    use super::{Readiness, timeout_ms};
+
+    pub(super) fn poll(stream: &Stream, readiness: Readiness, timeout: Option<Duration>) -> io::Result<bool>{
+        let events = match readiness {
+            Readiness::Readable => libc::POLLIN,
+            Readiness::Writable => libc::POLLOUT,
+        };
+        let mut fd = libc::pollfd{fd: crate::stream_fd(stream), events, revents: 0};
+        let ready = unsafe { libc::poll(&mut fd, 1, timeout_ms(timeout)) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(ready > 0)
+    }
+    /// Bytes available at the head of the stream, at most one, left in place
+    pub(super) fn peek(stream: &Stream) -> io::Result<usize>{
+        let mut byte = 0u8;
+        loop {
+            let n = unsafe {
+                libc::recv(crate::stream_fd(stream), &mut byte as *mut u8 as *mut libc::c_void, 1, libc::MSG_PEEK | libc::MSG_DONTWAIT)
+            };
+            if n >= 0 {
+                return Ok(n as usize)
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys{
+    use std::io;
+    use std::os::windows::io::AsRawSocket;
+    use std::time::Duration;
+    use unisocket::Stream;
+    use super::{Readiness, timeout_ms};
+
+    const POLLRDNORM: i16 = 0x0100;
+    const POLLWRNORM: i16 = 0x0010;
+
+    #[repr(C)]
+    struct WsaPollFd{
+        fd: usize,
+        events: i16,
+        revents: i16,
+    }
+
+    #[link(name = "ws2_32")]
+    extern "system" {
+        fn WSAPoll(fds: *mut WsaPollFd, count: u32, timeout: i32) -> i32;
+    }
+
+    pub(super) fn poll(stream: &Stream, readiness: Readiness, timeout: Option<Duration>) -> io::Result<bool>{
+        let events = match readiness {
+            Readiness::Readable => POLLRDNORM,
+            Readiness::Writable => POLLWRNORM,
+        };
+        let socket = match stream {
+            Stream::Inet(s) => s.as_raw_socket(),
+        };
+        let mut fd = WsaPollFd{fd: socket as usize, events, revents: 0};
+        let ready = unsafe { WSAPoll(&mut fd, 1, timeout_ms(timeout)) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(ready > 0)
+    }
+    /// Only called once the socket polled readable, so the blocking peek returns at once
+    pub(super) fn peek(stream: &Stream) -> io::Result<usize>{
+        match stream {
+            Stream::Inet(s) => s.peek(&mut [0u8]),
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod sys{
+    use std::io;
+    use std::time::Duration;
+    use unisocket::Stream;
+    use super::Readiness;
+
+    pub(super) fn poll(_: &Stream, _: Readiness, _: Option<Duration>) -> io::Result<bool>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "polling sockets is not supported on this platform"))
+    }
+    pub(super) fn peek(_: &Stream) -> io::Result<usize>{
+        Err(io::Error::new(io::ErrorKind::Unsupported, "polling sockets is not supported on this platform"))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests{
+    use std::io::Read;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::{Connection, ConnectionController, FrameReader, FrameWriter, WriteErr};
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    /// Writes to the non-blocking `connection` until its socket takes no more
+    fn fill(connection: &mut Connection){
+        connection.set_nonblocking(true).unwrap();
+        loop {
+            match connection.write_frame(&[0u8; 65_536]) {
+                Ok(()) => {}
+                Err(WriteErr::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn poll_readable_times_out_without_data(){
+        let (a, _b) = Connection::pair().unwrap();
+        let start = Instant::now();
+        assert!(!a.poll_readable(Some(WAIT)).unwrap());
+        assert!(start.elapsed() >= WAIT);
+        assert!(!a.poll_readable(Some(Duration::ZERO)).unwrap());
+    }
+
+    #[test]
+    fn poll_readable_returns_once_data_arrives(){
+        let (mut a, mut b) = Connection::pair().unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(WAIT);
+            b.write_frame(b"late").unwrap();
+            b
+        });
+        let start = Instant::now();
+        assert!(a.poll_readable(Some(Duration::from_secs(5))).unwrap());
+        let waited = start.elapsed();
+        assert!(waited >= WAIT && waited < Duration::from_secs(5), "{:?}", waited);
+        assert_eq!(a.read_frame().unwrap(), b"late");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn poll_readable_counts_bytes_already_received(){
+        let (mut a, mut b) = Connection::pair().unwrap();
+        b.write_frames([&b"one"[..], b"two"]).unwrap();
+        // Both frames come in with one read
+        assert_eq!(a.read_frame().unwrap(), b"one");
+        let mut rest = [0u8; 1];
+        a.set_nonblocking(true).unwrap();
+        assert!(a.get_ref().read(&mut rest).is_err(), "the second frame is still in the socket");
+        assert!(a.poll_readable(Some(Duration::ZERO)).unwrap());
+        assert_eq!(a.read_frame().unwrap(), b"two");
+        assert!(!a.poll_readable(Some(Duration::ZERO)).unwrap());
+    }
+
+    #[test]
+    fn closed_peer_counts_as_readable_and_writable(){
+        let (a, b) = Connection::pair().unwrap();
+        drop(b);
+        let start = Instant::now();
+        assert!(a.poll_readable(Some(Duration::from_secs(5))).unwrap());
+        assert!(a.poll_writable(Some(Duration::from_secs(5))).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(a.is_closed().unwrap());
+    }
+
+    #[test]
+    fn poll_writable_waits_for_room(){
+        let (mut a, b) = Connection::pair().unwrap();
+        assert!(a.poll_writable(Some(Duration::ZERO)).unwrap());
+        fill(&mut a);
+        let start = Instant::now();
+        assert!(!a.poll_writable(Some(WAIT)).unwrap());
+        assert!(start.elapsed() >= WAIT);
+        let reader = thread::spawn(move || {
+            thread::sleep(WAIT);
+            // Everything sent so far, the socket only reports room once a good part of it is free
+            b.set_nonblocking(true).unwrap();
+            let mut buf = vec![0u8; 1 << 20];
+            while b.get_ref().read(&mut buf).is_ok() {}
+            b
+        });
+        let start = Instant::now();
+        assert!(a.poll_writable(Some(Duration::from_secs(5))).unwrap());
+        assert!(start.elapsed() >= WAIT);
+        reader.join().unwrap();
+    }
+}
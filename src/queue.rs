@@ -0,0 +1,542 @@
+use std::collections::VecDeque;
+use std::io;
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::{ConnectionWriter, FrameWriter, WriteErr};
+
+/// What `QueuedWriter::try_send` does with a frame when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy{
+    /// Waits for room
+    #[default]
+    Block,
+    /// Drops the oldest queued frame to make room, from the lowest busy lane at or below
+    /// the priority of the frame being sent. With only higher lanes queued, that frame is dropped
+    DropOldest,
+    /// Drops the frame being sent
+    DropNewest,
+    /// Fails with `SendError::Full`
+    Error,
+}
+
+/// Lane of a frame in a `QueuedWriter`. Higher lanes go first,
+/// lower ones still get a share of every round while the higher are busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum Priority{
+    High,
+    #[default]
+    Normal,
+    Bulk,
+}
+
+/// Frames a lane sends per scheduling round, in `Priority` order
+const LANE_WEIGHTS: [u32; 3] = [8, 4, 1];
+
+/// Why a `QueuedWriter` discarded a frame, see `QueuedWriter::set_drop_hook`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason{
+    /// Dropped by `DropOldest` or `DropNewest`
+    Overflow,
+    /// Its time to live ran out before it was sent
+    Expired,
+}
+
+/// Returned by `QueuedWriter::try_send` with the frame that was not queued
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError{
+    /// The queue is full and the policy is `OverflowPolicy::Error`
+    Full(Vec<u8>),
+    /// The writer thread stopped, after `close` or a write error
+    Closed(Vec<u8>),
+}
+
+impl SendError{
+    pub fn into_frame(self) -> Vec<u8>{
+        match self {
+            SendError::Full(frame) | SendError::Closed(frame) => frame,
+        }
+    }
+}
+
+impl fmt::Display for SendError{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full(_) => write!(f, "Outbound queue is full"),
+            SendError::Closed(_) => write!(f, "Writer thread has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for SendError{}
+
+#[derive(Debug)]
+struct Queued{
+    frame: Vec<u8>,
+    /// Discarded instead of sent once it passed
+    deadline: Option<Instant>,
+}
+
+impl Queued{
+    fn is_expired(&self, now: Instant) -> bool{
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+#[derive(Debug)]
+struct State{
+    lanes: [VecDeque<Queued>; 3],
+    /// Frames each lane may still send this round
+    credits: [u32; 3],
+    /// The writer thread is sending a frame it took
+    sending: bool,
+    closed: bool,
+    dropped: u64,
+    expired: u64,
+}
+
+impl State{
+    fn len(&self) -> usize{
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+    /// Next frame by weighted round-robin, FIFO within a lane.
+    /// Expired frames met on the way go to `expired`
+    fn pop(&mut self, now: Instant, expired: &mut Vec<Vec<u8>>) -> Option<Vec<u8>>{
+        while self.len() > 0 {
+            let lane = self.lanes.iter().zip(&self.credits).position(|(lane, credit)| *credit > 0 && !lane.is_empty());
+            let lane = match lane {
+                Some(lane) => lane,
+                None => {
+                    self.credits = LANE_WEIGHTS;
+                    continue
+                }
+            };
+            let queued = self.lanes[lane].pop_front().expect("lane is not empty");
+            if queued.is_expired(now) {
+                self.expired += 1;
+                expired.push(queued.frame);
+                continue
+            }
+            self.credits[lane] -= 1;
+            return Some(queued.frame)
+        }
+        None
+    }
+    /// Drops the oldest frame of the lowest busy lane, none above `priority`
+    fn drop_oldest(&mut self, priority: Priority) -> Option<Vec<u8>>{
+        let queued = self.lanes[priority as usize..].iter_mut().rev().find(|lane| !lane.is_empty())?.pop_front()?;
+        self.dropped += 1;
+        Some(queued.frame)
+    }
+    /// Removes the expired frames of every lane into `expired`
+    fn purge_expired(&mut self, now: Instant, expired: &mut Vec<Vec<u8>>){
+        for lane in &mut self.lanes {
+            if lane.iter().any(|queued| queued.is_expired(now)) {
+                let (stale, fresh): (VecDeque<Queued>, VecDeque<Queued>) = lane.drain(..).partition(|queued| queued.is_expired(now));
+                *lane = fresh;
+                self.expired += stale.len() as u64;
+                expired.extend(stale.into_iter().map(|queued| queued.frame));
+            }
+        }
+    }
+}
+
+struct DropHook(Box<dyn FnMut(Vec<u8>, DropReason) + Send>);
+
+impl fmt::Debug for DropHook{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("DropHook")
+    }
+}
+
+#[derive(Debug)]
+struct Shared{
+    state: Mutex<State>,
+    /// Signalled when a frame is queued or the queue is closed
+    queued: Condvar,
+    /// Signalled when the writer thread takes a frame or stops
+    taken: Condvar,
+    hook: Mutex<Option<DropHook>>,
+}
+
+impl Shared{
+    fn lock(&self) -> MutexGuard<'_, State>{
+        // A panic while holding the lock leaves the queue itself consistent
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Hands discarded frames to the drop hook, called without the state locked
+    fn report(&self, frames: Vec<Vec<u8>>, reason: DropReason){
+        if frames.is_empty() {
+            return
+        }
+        let mut hook = self.hook.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(DropHook(hook)) = hook.as_mut() {
+            frames.into_iter().for_each(|frame| hook(frame, reason));
+        }
+    }
+}
+
+/// Sends frames from a dedicated thread, so that a slow peer blocks that thread
+/// instead of the caller. Frames wait in a bounded queue, sent in order within each `Priority`.
+/// Dropping it sends the queued frames first, like `close`
+#[derive(Debug)]
+pub struct QueuedWriter{
+    shared: Arc<Shared>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    thread: Option<JoinHandle<Result<ConnectionWriter, WriteErr>>>,
+}
+
+impl QueuedWriter{
+    /// Queues up to `capacity` frames, at least one
+    pub fn new(writer: ConnectionWriter, capacity: usize, policy: OverflowPolicy) -> Self{
+        let shared = Arc::new(Shared{
+            state: Mutex::new(State{
+                lanes: Default::default(),
+                credits: LANE_WEIGHTS,
+                sending: false,
+                closed: false,
+                dropped: 0,
+                expired: 0,
+            }),
+            queued: Condvar::new(),
+            taken: Condvar::new(),
+            hook: Mutex::new(None),
+        });
+        let thread_shared = shared.clone();
+        let thread = std::thread::spawn(move || run(writer, &thread_shared));
+        Self{shared, capacity: capacity.max(1), policy, thread: Some(thread)}
+    }
+    /// Queues `frame` with `Priority::Normal`, handling a full queue by the overflow policy
+    pub fn try_send(&self, frame: Vec<u8>) -> Result<(), SendError>{
+        self.try_send_with_priority(frame, Priority::Normal)
+    }
+    /// The capacity is shared by all lanes, `DropOldest` never drops a frame of a higher lane than `priority`
+    pub fn try_send_with_priority(&self, frame: Vec<u8>, priority: Priority) -> Result<(), SendError>{
+        self.push(Queued{frame, deadline: None}, priority)
+    }
+    /// The frame is discarded if it is still queued `ttl` from now, once its sending started it is always completed.
+    /// On a full queue expired frames make room before the overflow policy applies
+    pub fn try_send_with_ttl(&self, frame: Vec<u8>, priority: Priority, ttl: Duration) -> Result<(), SendError>{
+        self.push(Queued{frame, deadline: Instant::now().checked_add(ttl)}, priority)
+    }
+    fn push(&self, queued: Queued, priority: Priority) -> Result<(), SendError>{
+        let (mut expired, mut overflowed) = (Vec::new(), Vec::new());
+        let mut state = self.shared.lock();
+        let result = loop {
+            if state.closed {
+                break Err(SendError::Closed(queued.frame))
+            }
+            if state.len() < self.capacity {
+                state.lanes[priority as usize].push_back(queued);
+                self.shared.queued.notify_one();
+                break Ok(())
+            }
+            state.purge_expired(Instant::now(), &mut expired);
+            if state.len() < self.capacity {
+                continue
+            }
+            match self.policy {
+                OverflowPolicy::Block => {
+                    state = self.shared.taken.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                OverflowPolicy::DropOldest => match state.drop_oldest(priority) {
+                    Some(frame) => overflowed.push(frame),
+                    None => {
+                        state.dropped += 1;
+                        overflowed.push(queued.frame);
+                        break Ok(())
+                    }
+                },
+                OverflowPolicy::DropNewest => {
+                    state.dropped += 1;
+                    overflowed.push(queued.frame);
+                    break Ok(())
+                }
+                OverflowPolicy::Error => break Err(SendError::Full(queued.frame)),
+            }
+        };
+        drop(state);
+        self.shared.taken.notify_all();
+        self.shared.report(expired, DropReason::Expired);
+        self.shared.report(overflowed, DropReason::Overflow);
+        result
+    }
+    /// Frames queued and not taken by the writer thread yet
+    pub fn len(&self) -> usize{
+        self.shared.lock().len()
+    }
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+    pub fn capacity(&self) -> usize{
+        self.capacity
+    }
+    /// Frames dropped by `DropOldest` and `DropNewest` so far
+    pub fn dropped(&self) -> u64{
+        self.shared.lock().dropped
+    }
+    /// Frames discarded so far because their time to live ran out, see `try_send_with_ttl`
+    pub fn expired_frames(&self) -> u64{
+        self.shared.lock().expired
+    }
+    /// Called with every frame discarded from now on. Runs on the sending thread for overflows
+    /// and on the writer thread for expired frames, it must not send through this writer
+    pub fn set_drop_hook(&mut self, hook: impl FnMut(Vec<u8>, DropReason) + Send + 'static){
+        *self.shared.hook.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(DropHook(Box::new(hook)));
+    }
+    /// Whether the writer thread stopped on a write error, `close` returns it
+    pub fn is_failed(&self) -> bool{
+        self.thread.as_ref().is_some_and(|thread| thread.is_finished())
+    }
+    /// Waits until the frames of every lane are sent and flushed.
+    /// Fails once the writer thread has stopped, `close` tells why
+    pub fn flush(&self) -> io::Result<()>{
+        let mut state = self.shared.lock();
+        while !state.closed && (state.sending || state.len() > 0) {
+            state = self.shared.taken.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        if state.closed && (state.sending || state.len() > 0) {
+            return Err(io::Error::other(SendError::Closed(Vec::new())))
+        }
+        Ok(())
+    }
+    /// Sends the queued frames, then stops the thread and gives the writer back.
+    /// Fails with the error that stopped the thread, the frames still queued are lost then
+    pub fn close(mut self) -> Result<ConnectionWriter, WriteErr>{
+        self.stop()
+    }
+    fn stop(&mut self) -> Result<ConnectionWriter, WriteErr>{
+        self.shared.lock().closed = true;
+        self.shared.queued.notify_all();
+        self.shared.taken.notify_all();
+        let thread = self.thread.take().expect("writer thread is joined once");
+        thread.join().unwrap_or_else(|_| Err(WriteErr::Io(std::io::Error::other("Writer thread panicked"))))
+    }
+}
+
+impl Drop for QueuedWriter{
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let _ = self.stop();
+        }
+    }
+}
+
+/// Body of the writer thread: sends frames until the queue is closed and empty or a write fails
+fn run(mut writer: ConnectionWriter, shared: &Shared) -> Result<ConnectionWriter, WriteErr>{
+    let result = send_queued(&mut writer, shared);
+    // Senders blocked on a full queue must not wait for a thread that is gone
+    shared.lock().closed = true;
+    shared.taken.notify_all();
+    result.map(|()| writer)
+}
+
+fn send_queued(writer: &mut ConnectionWriter, shared: &Shared) -> Result<(), WriteErr>{
+    loop {
+        let mut state = shared.lock();
+        state.sending = false;
+        shared.taken.notify_all();
+        while state.len() == 0 && !state.closed {
+            state = shared.queued.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        let mut expired = Vec::new();
+        let frame = state.pop(Instant::now(), &mut expired);
+        let (drained, closed) = (state.len() == 0, state.closed);
+        // Until the write and flush below are done
+        state.sending = true;
+        drop(state);
+        shared.taken.notify_all();
+        shared.report(expired, DropReason::Expired);
+        // Without a frame everything queued had expired, or the queue is closed
+        if let Some(frame) = &frame {
+            writer.write_frame(frame)?;
+        }
+        // Buffering flush policies still apply, what they hold goes out once the queue runs dry
+        if drained {
+            writer.flush().map_err(WriteErr::Io)?;
+        }
+        if frame.is_none() && closed {
+            return Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests{
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+    use crate::{Connection, ConnectionController, FrameReader};
+
+    /// Larger than the socket buffers, so that the writer thread blocks on it until the peer reads
+    const BLOCKER: usize = 8 << 20;
+
+    /// Writer whose thread is stuck sending a frame the peer has not read yet
+    fn blocked(capacity: usize, policy: OverflowPolicy) -> (QueuedWriter, Connection){
+        let (a, b) = Connection::pair().unwrap();
+        let queue = QueuedWriter::new(a.split_shared().1, capacity, policy);
+        queue.try_send(vec![0u8; BLOCKER]).unwrap();
+        while !queue.is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        (queue, b)
+    }
+
+    /// The frames after the blocker
+    fn read_after_blocker(peer: &mut Connection, count: usize) -> Vec<Vec<u8>>{
+        assert_eq!(peer.read_frame().unwrap().len(), BLOCKER);
+        (0..count).map(|_| peer.read_frame().unwrap()).collect()
+    }
+
+    type Dropped = Arc<Mutex<Vec<(Vec<u8>, DropReason)>>>;
+
+    fn dropped_by_hook(queue: &mut QueuedWriter) -> Dropped{
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let hook_dropped = dropped.clone();
+        queue.set_drop_hook(move |frame, reason| hook_dropped.lock().unwrap().push((frame, reason)));
+        dropped
+    }
+
+    #[test]
+    fn full_queue_fails_with_error_policy(){
+        let (queue, mut peer) = blocked(2, OverflowPolicy::Error);
+        queue.try_send(b"a".to_vec()).unwrap();
+        queue.try_send(b"b".to_vec()).unwrap();
+        assert_eq!(queue.try_send(b"c".to_vec()), Err(SendError::Full(b"c".to_vec())));
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(read_after_blocker(&mut peer, 2), [b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn full_queue_drops_the_newest_frame(){
+        let (mut queue, mut peer) = blocked(2, OverflowPolicy::DropNewest);
+        let dropped = dropped_by_hook(&mut queue);
+        queue.try_send(b"a".to_vec()).unwrap();
+        queue.try_send(b"b".to_vec()).unwrap();
+        queue.try_send(b"c".to_vec()).unwrap();
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(*dropped.lock().unwrap(), [(b"c".to_vec(), DropReason::Overflow)]);
+        assert_eq!(read_after_blocker(&mut peer, 2), [b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_frame(){
+        let (mut queue, mut peer) = blocked(2, OverflowPolicy::DropOldest);
+        let dropped = dropped_by_hook(&mut queue);
+        queue.try_send(b"a".to_vec()).unwrap();
+        queue.try_send(b"b".to_vec()).unwrap();
+        queue.try_send(b"c".to_vec()).unwrap();
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(*dropped.lock().unwrap(), [(b"a".to_vec(), DropReason::Overflow)]);
+        assert_eq!(read_after_blocker(&mut peer, 2), [b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn full_queue_blocks_until_the_writer_thread_takes_a_frame(){
+        let (queue, mut peer) = blocked(1, OverflowPolicy::Block);
+        queue.try_send(b"a".to_vec()).unwrap();
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            read_after_blocker(&mut peer, 2)
+        });
+        let started = std::time::Instant::now();
+        queue.try_send(b"b".to_vec()).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(reader.join().unwrap(), [b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn drop_oldest_spares_higher_lanes(){
+        let (mut queue, mut peer) = blocked(2, OverflowPolicy::DropOldest);
+        let dropped = dropped_by_hook(&mut queue);
+        queue.try_send_with_priority(b"high".to_vec(), Priority::High).unwrap();
+        queue.try_send_with_priority(b"bulk".to_vec(), Priority::Bulk).unwrap();
+        queue.try_send_with_priority(b"normal".to_vec(), Priority::Normal).unwrap();
+        queue.try_send_with_priority(b"late bulk".to_vec(), Priority::Bulk).unwrap();
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(*dropped.lock().unwrap(), [
+            (b"bulk".to_vec(), DropReason::Overflow),
+            (b"late bulk".to_vec(), DropReason::Overflow),
+        ]);
+        assert_eq!(read_after_blocker(&mut peer, 2), [b"high".to_vec(), b"normal".to_vec()]);
+    }
+
+    #[test]
+    fn high_frames_wait_at_most_a_round_behind_a_bulk_flood(){
+        let (queue, mut peer) = blocked(1000, OverflowPolicy::Error);
+        for i in 0..500u16 {
+            queue.try_send_with_priority([b'b'].iter().chain(&i.to_be_bytes()).copied().collect(), Priority::Bulk).unwrap();
+        }
+        for i in 0..100u16 {
+            queue.try_send_with_priority([b'h'].iter().chain(&i.to_be_bytes()).copied().collect(), Priority::High).unwrap();
+        }
+        let frames = read_after_blocker(&mut peer, 600);
+        let high: Vec<usize> = (0..frames.len()).filter(|i| frames[*i][0] == b'h').collect();
+        // A bulk frame queued first delays a high one by at most one frame a round of 8 high frames
+        for (n, position) in high.iter().enumerate() {
+            assert!(*position <= n + n / 8 + 1, "high frame {} sent {}th", n, position);
+        }
+        // Bulk frames still get their share while high ones are busy
+        assert!(frames[..high[99]].iter().filter(|frame| frame[0] == b'b').count() >= 100 / 8);
+        for lane in [b'b', b'h'] {
+            let order: Vec<&[u8]> = frames.iter().filter(|frame| frame[0] == lane).map(|frame| &frame[1..]).collect();
+            assert!(order.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn flush_waits_until_every_frame_is_sent(){
+        let (queue, mut peer) = blocked(16, OverflowPolicy::Error);
+        for i in 0..10u8 {
+            queue.try_send(vec![i; 100]).unwrap();
+        }
+        let reader = thread::spawn(move || {
+            assert_eq!(peer.read_frame().unwrap().len(), BLOCKER);
+            peer
+        });
+        queue.flush().unwrap();
+        assert!(queue.is_empty());
+        // Every frame is in the socket already
+        let mut peer = reader.join().unwrap();
+        peer.set_nonblocking(true).unwrap();
+        for i in 0..10u8 {
+            assert_eq!(peer.try_read_frame().unwrap(), Some(vec![i; 100]));
+        }
+        assert_eq!(peer.try_read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn close_sends_the_queued_frames_and_gives_the_writer_back(){
+        let (queue, mut peer) = blocked(16, OverflowPolicy::Error);
+        for i in 0..10u8 {
+            queue.try_send(vec![i; 100]).unwrap();
+        }
+        let reader = thread::spawn(move || (read_after_blocker(&mut peer, 10), peer));
+        let mut writer = queue.close().unwrap();
+        let (frames, mut peer) = reader.join().unwrap();
+        assert_eq!(frames, (0..10u8).map(|i| vec![i; 100]).collect::<Vec<_>>());
+        crate::FrameWriter::write_frame(&mut writer, b"after close").unwrap();
+        assert_eq!(peer.read_frame().unwrap(), b"after close");
+    }
+
+    #[test]
+    fn write_error_stops_the_thread_and_closes_the_queue(){
+        let (queue, peer) = blocked(16, OverflowPolicy::Block);
+        drop(peer);
+        let frame = loop {
+            match queue.try_send(b"lost".to_vec()) {
+                Ok(()) => thread::sleep(Duration::from_millis(1)),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(frame, SendError::Closed(b"lost".to_vec()));
+        assert!(queue.is_failed());
+        assert!(queue.flush().is_err());
+        assert!(matches!(queue.close(), Err(WriteErr::Io(_))));
+    }
+}
@@ -0,0 +1,385 @@
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, IntoRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixStream, UnixListener};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, IntoRawSocket, FromRawSocket, RawSocket};
+use std::net::{TcpStream, TcpListener};
+use unisocket::{Stream, Listener};
+use crate::{Connection, ConnectionReader, ConnectionWriter, Server};
+
+/// Whether `fd` is bound to a Unix domain address
+#[cfg(unix)]
+fn is_unix_socket(fd: RawFd) -> bool{
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockname(fd, &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len) };
+    result == 0 && addr.ss_family as libc::c_int == libc::AF_UNIX
+}
+
+/// The descriptor stays owned by the connection. `separate` and `try_clone` duplicate it:
+/// each half or clone owns one of its own, closing or taking one leaves the others open.
+/// The halves of `split_shared` report the same one
+#[cfg(unix)]
+impl AsRawFd for Connection{
+    fn as_raw_fd(&self) -> RawFd {
+        crate::stream_fd(&self.stream)
+    }
+}
+
+/// Buffered frames are flushed first as on drop, bytes received and not read yet are lost
+#[cfg(unix)]
+impl IntoRawFd for Connection{
+    fn into_raw_fd(self) -> RawFd {
+        match self.into_stream().expect("the socket can be duplicated") {
+            Stream::Inet(s) => s.into_raw_fd(),
+            Stream::Unix(s) => s.into_raw_fd(),
+        }
+    }
+}
+
+/// `fd` must be a connected stream socket, it is taken as a Unix stream when bound to a Unix address
+/// and as TCP otherwise. To choose, build the stream itself and convert it with `Connection::from`
+#[cfg(unix)]
+impl FromRawFd for Connection{
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        if is_unix_socket(fd) {
+            Self::from(UnixStream::from_raw_fd(fd))
+        } else {
+            Self::from(TcpStream::from_raw_fd(fd))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for ConnectionReader{
+    fn as_raw_fd(&self) -> RawFd {
+        self.connection.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for ConnectionReader{
+    fn into_raw_fd(self) -> RawFd {
+        self.connection.into_raw_fd()
+    }
+}
+
+/// A reader with no writer half, see `FromRawFd for Connection`
+#[cfg(unix)]
+impl FromRawFd for ConnectionReader{
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self{connection: Connection::from_raw_fd(fd)}
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for ConnectionWriter{
+    fn as_raw_fd(&self) -> RawFd {
+        self.connection.as_raw_fd()
+    }
+}
+
+/// Buffered frames are flushed first as on drop
+#[cfg(unix)]
+impl IntoRawFd for ConnectionWriter{
+    fn into_raw_fd(self) -> RawFd {
+        self.connection.into_raw_fd()
+    }
+}
+
+/// A writer with no reader half, see `FromRawFd for Connection`
+#[cfg(unix)]
+impl FromRawFd for ConnectionWriter{
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self{connection: Connection::from_raw_fd(fd)}
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Server{
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.listener {
+            Listener::Inet(l) => l.as_raw_fd(),
+            Listener::Unix(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for Server{
+    fn into_raw_fd(self) -> RawFd {
+        match self.listener {
+            Listener::Inet(l) => l.into_raw_fd(),
+            Listener::Unix(l) => l.into_raw_fd(),
+        }
+    }
+}
+
+/// `fd` must be a listening socket, told apart like in `FromRawFd for Connection`.
+/// The server starts with the default settings
+#[cfg(unix)]
+impl FromRawFd for Server{
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        if is_unix_socket(fd) {
+            Self::from(Listener::from(UnixListener::from_raw_fd(fd)))
+        } else {
+            Self::from(Listener::from(TcpListener::from_raw_fd(fd)))
+        }
+    }
+}
+
+/// Owned like the descriptor on Unix, see `AsRawFd for Connection`
+#[cfg(windows)]
+impl AsRawSocket for Connection{
+    fn as_raw_socket(&self) -> RawSocket {
+        match &*self.stream {
+            Stream::Inet(s) => s.as_raw_socket(),
+        }
+    }
+}
+
+/// Buffered frames are flushed first as on drop, bytes received and not read yet are lost
+#[cfg(windows)]
+impl IntoRawSocket for Connection{
+    fn into_raw_socket(self) -> RawSocket {
+        match self.into_stream().expect("the socket can be duplicated") {
+            Stream::Inet(s) => s.into_raw_socket(),
+        }
+    }
+}
+
+/// `socket` must be a connected TCP socket
+#[cfg(windows)]
+impl FromRawSocket for Connection{
+    unsafe fn from_raw_socket(socket: RawSocket) -> Self {
+        Self::from(TcpStream::from_raw_socket(socket))
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for ConnectionReader{
+    fn as_raw_socket(&self) -> RawSocket {
+        self.connection.as_raw_socket()
+    }
+}
+
+#[cfg(windows)]
+impl IntoRawSocket for ConnectionReader{
+    fn into_raw_socket(self) -> RawSocket {
+        self.connection.into_raw_socket()
+    }
+}
+
+/// A reader with no writer half, see `FromRawSocket for Connection`
+#[cfg(windows)]
+impl FromRawSocket for ConnectionReader{
+    unsafe fn from_raw_socket(socket: RawSocket) -> Self {
+        Self{connection: Connection::from_raw_socket(socket)}
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for ConnectionWriter{
+    fn as_raw_socket(&self) -> RawSocket {
+        self.connection.as_raw_socket()
+    }
+}
+
+/// Buffered frames are flushed first as on drop
+#[cfg(windows)]
+impl IntoRawSocket for ConnectionWriter{
+    fn into_raw_socket(self) -> RawSocket {
+        self.connection.into_raw_socket()
+    }
+}
+
+/// A writer with no reader half, see `FromRawSocket for Connection`
+#[cfg(windows)]
+impl FromRawSocket for ConnectionWriter{
+    unsafe fn from_raw_socket(socket: RawSocket) -> Self {
+        Self{connection: Connection::from_raw_socket(socket)}
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for Server{
+    fn as_raw_socket(&self) -> RawSocket {
+        match &self.listener {
+            Listener::Inet(l) => l.as_raw_socket(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl IntoRawSocket for Server{
+    fn into_raw_socket(self) -> RawSocket {
+        match self.listener {
+            Listener::Inet(l) => l.into_raw_socket(),
+        }
+    }
+}
+
+/// `socket` must be a listening TCP socket
+#[cfg(windows)]
+impl FromRawSocket for Server{
+    unsafe fn from_raw_socket(socket: RawSocket) -> Self {
+        Self::from(Listener::from(TcpListener::from_raw_socket(socket)))
+    }
+}
+
+/// Registers the socket with a mio `Poll`, the connection has to be put in non-blocking mode
+/// with `set_nonblocking`. Readiness can be spurious: `try_read_frame` then returns `Ok(None)`,
+/// keeping what arrived of a frame. A write failing with `WouldBlock` still took the frame,
+/// `resume_write` sends the rest once the socket is writable and later writes wait for it
+#[cfg(all(unix, feature = "mio"))]
+impl mio::event::Source for Connection{
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+/// The halves have descriptors of their own, each registers separately
+#[cfg(all(unix, feature = "mio"))]
+impl mio::event::Source for ConnectionReader{
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        self.connection.register(registry, token, interests)
+    }
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        self.connection.reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        self.connection.deregister(registry)
+    }
+}
+
+#[cfg(all(unix, feature = "mio"))]
+impl mio::event::Source for ConnectionWriter{
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        self.connection.register(registry, token, interests)
+    }
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        self.connection.reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        self.connection.deregister(registry)
+    }
+}
+
+/// Readable when a connection can be accepted, the server has to be put in non-blocking mode
+/// with `set_nonblocking` and `accept` called until it fails with `WouldBlock`
+#[cfg(all(unix, feature = "mio"))]
+impl mio::event::Source for Server{
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(all(test, unix, feature = "mio"))]
+mod tests{
+    use std::io::Write;
+    use std::time::Duration;
+    use mio::{Events, Interest, Poll, Token};
+    use crate::{Connection, ConnectionController, FrameReader, FrameWriter, Server, WriteErr, WriteProgress};
+
+    /// Whether `poll` reports `token` within a second
+    fn ready(poll: &mut Poll, token: Token) -> bool{
+        let mut events = Events::with_capacity(8);
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        events.iter().any(|event| event.token() == token)
+    }
+
+    #[test]
+    fn readiness_without_a_whole_frame_keeps_what_arrived(){
+        let (mut a, b) = Connection::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        let mut poll = Poll::new().unwrap();
+        poll.registry().register(&mut a, Token(1), Interest::READABLE).unwrap();
+        // Woken up with nothing to read at all
+        assert_eq!(a.try_read_frame().unwrap(), None);
+
+        let mut frame = 10u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(b"0123456789");
+        let mut stream = b.get_ref();
+        for piece in [&frame[..2], &frame[2..7], &frame[7..]] {
+            stream.write_all(piece).unwrap();
+            assert!(ready(&mut poll, Token(1)));
+            let read = a.try_read_frame().unwrap();
+            if piece.len() == 7 {
+                assert_eq!(read.as_deref(), Some(&b"0123456789"[..]));
+            } else {
+                assert_eq!(read, None);
+            }
+            // Readiness reported again before anything new arrived
+            assert_eq!(a.try_read_frame().unwrap(), None);
+        }
+        poll.registry().deregister(&mut a).unwrap();
+    }
+
+    #[test]
+    fn writes_refused_by_a_full_socket_resume_on_writable(){
+        let (mut a, mut b) = Connection::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        let mut poll = Poll::new().unwrap();
+        poll.registry().register(&mut a, Token(1), Interest::WRITABLE).unwrap();
+        assert!(ready(&mut poll, Token(1)));
+        let frame = vec![7u8; 100_000];
+        let mut sent = 0;
+        loop {
+            sent += 1;
+            match a.write_frame(&frame) {
+                Ok(()) => {}
+                Err(WriteErr::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+        assert!(a.is_write_pending());
+        // Spurious: still full
+        assert_eq!(a.resume_write().unwrap(), WriteProgress::Pending);
+        assert!(matches!(a.write_frame(b"refused"), Err(WriteErr::Io(_))));
+
+        let reader = std::thread::spawn(move || {
+            for _ in 0..sent {
+                assert!(b.read_frame().unwrap() == [7u8; 100_000]);
+            }
+            assert_eq!(b.read_frame().unwrap(), b"after");
+        });
+        let mut resume = |a: &mut Connection| while a.resume_write().unwrap() == WriteProgress::Pending {
+            ready(&mut poll, Token(1));
+        };
+        resume(&mut a);
+        match a.write_frame(b"after") {
+            Ok(()) => {}
+            Err(WriteErr::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock => resume(&mut a),
+            Err(err) => panic!("{:?}", err),
+        }
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn server_readiness_without_a_connection_is_would_block(){
+        let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::from(unisocket::Listener::Inet(listener));
+        server.set_nonblocking(true).unwrap();
+        let mut poll = Poll::new().unwrap();
+        poll.registry().register(&mut server, Token(0), Interest::READABLE).unwrap();
+        assert_eq!(server.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        assert!(ready(&mut poll, Token(0)));
+        assert!(server.accept().is_ok());
+        assert_eq!(server.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use unisocket::SocketAddr;
+use crate::{Connection, FrameWriter, RetryPolicy, WriteErr};
+
+/// What a `ReconnectingWriter` does with a frame lost by a broken connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResendPolicy{
+    /// Writes it once more on a new connection, failing if that does not take it either
+    #[default]
+    Retry,
+    /// Fails with it, the next write reconnects
+    Drop,
+    /// Keeps it, and the frames written after it, while no connection can be made, up to this many frames.
+    /// They go first, in order, once a connection is made again. Writes succeed while their frame is kept,
+    /// past the limit they fail without keeping it
+    Buffer(usize),
+}
+
+type DisconnectHook = Box<dyn FnMut(&WriteErr) + Send>;
+pub(crate) type ReconnectHook = Box<dyn FnMut(&mut Connection) + Send>;
+
+/// Writer to `target` that survives server restarts: once a write fails on a broken connection,
+/// the connection is dropped and the next one is made by `policy`, the frame then goes by `ResendPolicy`.
+/// The first connection is made by the first write. Writes block while connecting, for the delays of `policy`.
+///
+/// A frame is written whole on one connection or not at all: a connection broken partway through a frame
+/// is not written to again, its peer drops the part it got. A frame the socket took whole counts as sent,
+/// it may still be lost with the connection as nothing confirms it arrived. Frames held back by
+/// a flush policy, set by `on_reconnect`, are lost with the connection too
+pub struct ReconnectingWriter{
+    target: SocketAddr,
+    policy: RetryPolicy,
+    resend: ResendPolicy,
+    connection: Option<Connection>,
+    /// Frames kept by `ResendPolicy::Buffer`, oldest first
+    kept: VecDeque<Vec<u8>>,
+    on_disconnect: Option<DisconnectHook>,
+    on_reconnect: Option<ReconnectHook>,
+}
+
+impl ReconnectingWriter{
+    pub fn new(target: SocketAddr, policy: RetryPolicy) -> Self{
+        Self{
+            target,
+            policy,
+            resend: ResendPolicy::default(),
+            connection: None,
+            kept: VecDeque::new(),
+            on_disconnect: None,
+            on_reconnect: None,
+        }
+    }
+    /// Kept frames past a smaller `ResendPolicy::Buffer` limit stay until sent
+    pub fn set_resend_policy(&mut self, resend: ResendPolicy){
+        self.resend = resend;
+    }
+    pub fn resend_policy(&self) -> ResendPolicy{
+        self.resend
+    }
+    /// Called with the error of each write that broke the connection, before it is dropped
+    pub fn on_disconnect(&mut self, hook: impl FnMut(&WriteErr) + Send + 'static){
+        self.on_disconnect = Some(Box::new(hook));
+    }
+    /// Called with each connection made, the first one included, before anything is written on it.
+    /// Settings are not carried over from the previous connection, set them here
+    pub fn on_reconnect(&mut self, hook: impl FnMut(&mut Connection) + Send + 'static){
+        self.on_reconnect = Some(Box::new(hook));
+    }
+    pub fn target(&self) -> &SocketAddr{
+        &self.target
+    }
+    /// Whether a connection is up, as far as writes tell: a broken one is noticed by the next write
+    pub fn is_connected(&self) -> bool{
+        self.connection.is_some()
+    }
+    /// Frames kept by `ResendPolicy::Buffer` until a connection is made
+    pub fn kept_frames(&self) -> usize{
+        self.kept.len()
+    }
+    /// The current connection, made first if there is none
+    fn connection(&mut self) -> io::Result<&mut Connection>{
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => {
+                let mut connection = Connection::connect_with_retry(&self.target, &self.policy)?;
+                if let Some(hook) = &mut self.on_reconnect {
+                    hook(&mut connection);
+                }
+                connection
+            }
+        };
+        Ok(self.connection.insert(connection))
+    }
+    /// Writes `frame` on the current connection, dropping it if the write broke it
+    fn send(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        let connection = self.connection()?;
+        let err = match connection.write_frame(frame) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if is_broken(connection, &err) {
+            self.disconnect(&err);
+        }
+        Err(err)
+    }
+    fn disconnect(&mut self, err: &WriteErr){
+        if let Some(hook) = &mut self.on_disconnect {
+            hook(err);
+        }
+        self.connection = None;
+    }
+    /// Sends the kept frames, failing once no connection can be made or it breaks. A frame refused on
+    /// a working connection, which it would always be, is dropped
+    fn send_kept(&mut self) -> Result<(), WriteErr>{
+        while let Some(frame) = self.kept.pop_front() {
+            if let Err(err) = self.send(&frame) {
+                if self.connection.is_none() {
+                    self.kept.push_front(frame);
+                    return Err(err)
+                }
+            }
+        }
+        Ok(())
+    }
+    fn write_kept(&mut self, frame: &[u8], limit: usize) -> Result<(), WriteErr>{
+        let result = match self.send_kept() {
+            Ok(()) => self.send(frame),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) if self.connection.is_some() => Err(err),
+            Err(_) if self.kept.len() < limit => {
+                self.kept.push_back(frame.to_vec());
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Whether the connection can not be written to after `err`. A timeout leaves it usable unless
+/// part of the frame was sent
+fn is_broken(connection: &Connection, err: &WriteErr) -> bool{
+    match err {
+        WriteErr::TooLongFrame => false,
+        WriteErr::Timeout => connection.is_desynced(),
+        WriteErr::Desynchronized => true,
+        WriteErr::Io(err) => !matches!(err.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported),
+    }
+}
+
+/// The other writes of `FrameWriter` go through `write_frame` one frame at a time
+impl FrameWriter for ReconnectingWriter{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        if let ResendPolicy::Buffer(limit) = self.resend {
+            return self.write_kept(frame, limit)
+        }
+        // A connection that could not be made went through the attempts of the policy already
+        self.connection()?;
+        match self.send(frame) {
+            Err(_) if self.resend == ResendPolicy::Retry && self.connection.is_none() => self.send(frame),
+            result => result,
+        }
+    }
+    /// Sends the kept frames first, failing if some are still kept
+    fn flush(&mut self) -> io::Result<()>{
+        self.send_kept()?;
+        let Some(connection) = &mut self.connection else { return Ok(()) };
+        if let Err(err) = connection.flush() {
+            let err = WriteErr::Io(err);
+            self.disconnect(&err);
+            return Err(err.into())
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ReconnectingWriter{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingWriter")
+            .field("target", &self.target)
+            .field("policy", &self.policy)
+            .field("resend", &self.resend)
+            .field("connection", &self.connection)
+            .field("kept", &self.kept.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::{FrameReader, Server};
+    use super::*;
+
+    fn listen(port: u16) -> (Server, SocketAddr){
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)).unwrap();
+        let addr = SocketAddr::Inet(listener.local_addr().unwrap());
+        (Server::from(unisocket::Listener::Inet(listener)), addr)
+    }
+
+    /// A writer to a new listener, the connection it made on its first write, and its hook counts
+    struct Setup{
+        server: Server,
+        target: SocketAddr,
+        writer: ReconnectingWriter,
+        accepted: Connection,
+        disconnects: Arc<AtomicUsize>,
+        reconnects: Arc<AtomicUsize>,
+    }
+
+    fn setup(resend: ResendPolicy) -> Setup{
+        let (server, target) = listen(0);
+        let policy = RetryPolicy::default().max_attempts(1);
+        let mut writer = ReconnectingWriter::new(target.clone(), policy);
+        writer.set_resend_policy(resend);
+        let (disconnects, reconnects) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let counted = disconnects.clone();
+        writer.on_disconnect(move |_| { counted.fetch_add(1, Ordering::SeqCst); });
+        let counted = reconnects.clone();
+        writer.on_reconnect(move |_| { counted.fetch_add(1, Ordering::SeqCst); });
+        writer.write_frame(b"first").unwrap();
+        let (mut accepted, _) = server.accept().unwrap();
+        assert_eq!(accepted.read_frame().unwrap(), b"first");
+        Setup{server, target, writer, accepted, disconnects, reconnects}
+    }
+
+    /// Resets the connection, so that the next write on it fails
+    fn kill(accepted: Connection){
+        accepted.close_abortive().unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    fn port(target: &SocketAddr) -> u16{
+        match target {
+            SocketAddr::Inet(addr) => addr.port(),
+            _ => unreachable!("the listener is TCP"),
+        }
+    }
+
+    #[test]
+    fn retry_writes_the_lost_frame_on_a_new_connection(){
+        let Setup{server, mut writer, accepted, disconnects, reconnects, ..} = setup(ResendPolicy::Retry);
+        kill(accepted);
+        writer.write_frame(b"lost once").unwrap();
+        let (mut accepted, _) = server.accept().unwrap();
+        assert_eq!(accepted.read_frame().unwrap(), b"lost once");
+        assert_eq!((disconnects.load(Ordering::SeqCst), reconnects.load(Ordering::SeqCst)), (1, 2));
+        assert!(writer.is_connected());
+    }
+
+    #[test]
+    fn drop_fails_with_the_lost_frame_and_the_next_write_reconnects(){
+        let Setup{server, mut writer, accepted, disconnects, reconnects, ..} = setup(ResendPolicy::Drop);
+        kill(accepted);
+        assert!(writer.write_frame(b"lost").is_err());
+        assert!(!writer.is_connected());
+        writer.write_frame(b"next").unwrap();
+        let (mut accepted, _) = server.accept().unwrap();
+        assert_eq!(accepted.read_frame().unwrap(), b"next");
+        assert_eq!((disconnects.load(Ordering::SeqCst), reconnects.load(Ordering::SeqCst)), (1, 2));
+    }
+
+    #[test]
+    fn buffer_keeps_frames_while_the_server_is_down_up_to_its_limit(){
+        let Setup{server, target, mut writer, accepted, disconnects, reconnects} = setup(ResendPolicy::Buffer(3));
+        drop(server);
+        kill(accepted);
+        for frame in [&b"one"[..], b"two", b"three"] {
+            writer.write_frame(frame).unwrap();
+        }
+        assert_eq!(writer.kept_frames(), 3);
+        // Past the limit a write fails without keeping its frame
+        assert!(writer.write_frame(b"refused").is_err());
+        assert_eq!(writer.kept_frames(), 3);
+        assert!(writer.flush().is_err());
+
+        let (server, _) = listen(port(&target));
+        writer.write_frame(b"four").unwrap();
+        assert_eq!(writer.kept_frames(), 0);
+        let (mut accepted, _) = server.accept().unwrap();
+        for frame in [&b"one"[..], b"two", b"three", b"four"] {
+            assert_eq!(accepted.read_frame().unwrap(), frame);
+        }
+        assert_eq!((disconnects.load(Ordering::SeqCst), reconnects.load(Ordering::SeqCst)), (1, 2));
+    }
+
+    #[test]
+    fn retry_goes_through_the_connection_attempts_once_while_the_server_is_down(){
+        let Setup{server, mut writer, accepted, ..} = setup(ResendPolicy::Retry);
+        drop(server);
+        kill(accepted);
+        // The write that finds the connection broken, then one that connects first
+        assert!(writer.write_frame(b"lost").is_err());
+        writer.policy = RetryPolicy::default().max_attempts(3).initial_delay(Duration::from_millis(200)).multiplier(1.0);
+        let started = Instant::now();
+        assert!(writer.write_frame(b"no server").is_err());
+        let took = started.elapsed();
+        assert!(took >= Duration::from_millis(350) && took < Duration::from_millis(700), "{:?}", took);
+    }
+}
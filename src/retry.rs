@@ -0,0 +1,201 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::Duration;
+use unisocket::SocketAddr;
+
+/// How `Connection::connect_with_retry` retries, for the window where the server is not up yet.
+/// The default makes 5 attempts, 100 ms apart at first, doubling up to 5 s, without jitter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy{
+    pub(crate) max_attempts: u32,
+    pub(crate) initial_delay: Duration,
+    pub(crate) multiplier: f64,
+    pub(crate) max_delay: Duration,
+    pub(crate) jitter: Option<f64>,
+    pub(crate) attempt_timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy{
+    fn default() -> Self {
+        Self{
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(5),
+            jitter: None,
+            attempt_timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy{
+    /// Attempts in total, the first one included. 0 counts as 1
+    pub fn max_attempts(mut self, attempts: u32) -> Self{
+        self.max_attempts = attempts;
+        self
+    }
+    /// Delay before the first retry
+    pub fn initial_delay(mut self, delay: Duration) -> Self{
+        self.initial_delay = delay;
+        self
+    }
+    /// Each delay is the previous one times `multiplier`, 1 keeps them constant
+    pub fn multiplier(mut self, multiplier: f64) -> Self{
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+    pub fn max_delay(mut self, delay: Duration) -> Self{
+        self.max_delay = delay;
+        self
+    }
+    /// Shortens each delay by a random part of up to `fraction` of it, so clients started together
+    /// do not retry in lockstep. Clamped to 0..=1
+    pub fn jitter(mut self, fraction: f64) -> Self{
+        self.jitter = Some(fraction.clamp(0.0, 1.0));
+        self
+    }
+    /// Bounds each attempt with `Connection::connect_timeout`
+    pub fn attempt_timeout(mut self, timeout: Duration) -> Self{
+        self.attempt_timeout = Some(timeout);
+        self
+    }
+    /// Delay before retry `retry`, counted from 0, without jitter
+    pub fn delay(&self, retry: u32) -> Duration{
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(retry.min(i32::MAX as u32) as i32);
+        match Duration::try_from_secs_f64(delay) {
+            Ok(delay) => delay.min(self.max_delay),
+            Err(_) => self.max_delay,
+        }
+    }
+    fn jittered(&self, delay: Duration) -> Duration{
+        match self.jitter {
+            Some(fraction) if fraction > 0.0 => delay.mul_f64(1.0 - fraction * random_fraction()),
+            _ => delay,
+        }
+    }
+}
+
+/// In 0..1, from the random keys std seeds its hash maps with
+fn random_fraction() -> f64{
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Errors of a server that is not accepting yet. A missing socket file is one for Unix sockets only
+fn is_retryable(addr: &SocketAddr, err: &io::Error) -> bool{
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        | io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => true,
+        io::ErrorKind::NotFound => !matches!(addr, SocketAddr::Inet(_)),
+        _ => false,
+    }
+}
+
+/// Runs `connect` until it succeeds, fails with an error not worth retrying or runs out of attempts,
+/// returning the last error then. `notify` gets each failed attempt, counted from 1, and the delay before the next
+pub(crate) fn retry<T>(addr: &SocketAddr, policy: &RetryPolicy, mut connect: impl FnMut() -> io::Result<T>,
+                       mut sleep: impl FnMut(Duration), mut notify: impl FnMut(u32, &io::Error, Duration)) -> io::Result<T>{
+    let mut attempt = 1;
+    loop {
+        match connect() {
+            Ok(connected) => return Ok(connected),
+            Err(err) if attempt >= policy.max_attempts || !is_retryable(addr, &err) => return Err(err),
+            Err(err) => {
+                let delay = policy.jittered(policy.delay(attempt - 1));
+                notify(attempt, &err, delay);
+                sleep(delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::cell::RefCell;
+    use super::*;
+
+    fn tcp() -> SocketAddr{
+        SocketAddr::Inet((std::net::Ipv4Addr::LOCALHOST, 9).into())
+    }
+
+    fn refused() -> io::Error{
+        io::ErrorKind::ConnectionRefused.into()
+    }
+
+    /// Runs `retry` with `connect` failing `failures` times with errors from `error`,
+    /// returning whether it connected, the sleeps and the notifications
+    fn schedule(addr: &SocketAddr, policy: &RetryPolicy, failures: u32, error: fn() -> io::Error) -> (bool, Vec<Duration>, Vec<(u32, Duration)>){
+        let (attempts, sleeps, notified) = (RefCell::new(0), RefCell::new(Vec::new()), RefCell::new(Vec::new()));
+        let result = retry(addr, policy, || {
+            *attempts.borrow_mut() += 1;
+            if *attempts.borrow() > failures { Ok(()) } else { Err(error()) }
+        }, |delay| sleeps.borrow_mut().push(delay), |attempt, err, delay| {
+            assert_eq!(err.kind(), error().kind());
+            notified.borrow_mut().push((attempt, delay));
+        });
+        let sleeps = sleeps.into_inner();
+        assert_eq!(*attempts.borrow() as usize, sleeps.len() + 1);
+        (result.is_ok(), sleeps, notified.into_inner())
+    }
+
+    fn millis(delays: &[u64]) -> Vec<Duration>{
+        delays.iter().map(|ms| Duration::from_millis(*ms)).collect()
+    }
+
+    #[test]
+    fn default_policy_doubles_the_delay_over_five_attempts(){
+        let (connected, sleeps, notified) = schedule(&tcp(), &RetryPolicy::default(), u32::MAX, refused);
+        assert!(!connected);
+        assert_eq!(sleeps, millis(&[100, 200, 400, 800]));
+        assert_eq!(notified, (1..).zip(sleeps).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delays_stop_growing_at_the_max_delay(){
+        let policy = RetryPolicy::default().max_attempts(7).initial_delay(Duration::from_secs(1)).multiplier(3.0).max_delay(Duration::from_secs(5));
+        let (connected, sleeps, _) = schedule(&tcp(), &policy, u32::MAX, refused);
+        assert!(!connected);
+        assert_eq!(sleeps, millis(&[1000, 3000, 5000, 5000, 5000, 5000]));
+        assert_eq!(policy.delay(1000), Duration::from_secs(5));
+        assert_eq!(RetryPolicy::default().multiplier(0.5).delay(3), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn success_ends_the_retries(){
+        let (connected, sleeps, _) = schedule(&tcp(), &RetryPolicy::default(), 2, refused);
+        assert!(connected);
+        assert_eq!(sleeps, millis(&[100, 200]));
+        assert_eq!(schedule(&tcp(), &RetryPolicy::default(), 0, refused).1, []);
+    }
+
+    #[test]
+    fn zero_attempts_still_tries_once(){
+        let (connected, sleeps, _) = schedule(&tcp(), &RetryPolicy::default().max_attempts(0), u32::MAX, refused);
+        assert!(!connected && sleeps.is_empty());
+    }
+
+    #[test]
+    fn errors_not_worth_retrying_fail_at_once(){
+        let (connected, sleeps, _) = schedule(&tcp(), &RetryPolicy::default(), u32::MAX, || io::ErrorKind::PermissionDenied.into());
+        assert!(!connected && sleeps.is_empty());
+        // A missing socket file is a server not up yet, a missing host is not
+        assert!(schedule(&tcp(), &RetryPolicy::default(), u32::MAX, || io::ErrorKind::NotFound.into()).1.is_empty());
+        #[cfg(unix)]
+        {
+            let unix = SocketAddr::Unix("/nonexistent/rust_sfp.sock".into());
+            assert_eq!(schedule(&unix, &RetryPolicy::default(), u32::MAX, || io::ErrorKind::NotFound.into()).1.len(), 4);
+        }
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delays(){
+        let policy = RetryPolicy::default().max_attempts(50).multiplier(1.0).jitter(0.5);
+        let (_, sleeps, notified) = schedule(&tcp(), &policy, u32::MAX, refused);
+        assert!(sleeps.iter().all(|delay| *delay >= Duration::from_millis(50) && *delay <= Duration::from_millis(100)));
+        assert!(sleeps.iter().any(|delay| *delay != sleeps[0]), "{:?}", sleeps);
+        assert_eq!(notified.iter().map(|(_, delay)| *delay).collect::<Vec<_>>(), sleeps);
+    }
+}
@@ -0,0 +1,619 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::fmt;
+use std::fmt::Formatter;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use unisocket::SocketAddr;
+use crate::{Connection, FrameReader, FrameWriter, RetryPolicy, Server, WriteErr};
+use crate::reconnect::ReconnectHook;
+
+// First byte of each frame of a session. The client opens with a hello, the server answers with a welcome,
+// then the client sends data and the server acknowledgements
+const HELLO: u8 = 0;
+const WELCOME: u8 = 1;
+const DATA: u8 = 2;
+const ACK: u8 = 3;
+const TOKEN_LEN: usize = 16;
+const SEQUENCE_LEN: usize = 8;
+const DEFAULT_ACK_INTERVAL: usize = 32;
+const DEFAULT_MAX_SESSIONS: usize = 1024;
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn malformed() -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed session message")
+}
+
+fn token_at(bytes: &[u8]) -> io::Result<u128>{
+    let token = bytes.get(..TOKEN_LEN).and_then(|token| token.try_into().ok()).ok_or_else(malformed)?;
+    Ok(u128::from_be_bytes(token))
+}
+
+fn sequence_at(bytes: &[u8]) -> io::Result<u64>{
+    let sequence = bytes.get(..SEQUENCE_LEN).and_then(|sequence| sequence.try_into().ok()).ok_or_else(malformed)?;
+    Ok(u64::from_be_bytes(sequence))
+}
+
+/// Unique enough for the sessions of one acceptor, from the random keys std seeds its hash maps with.
+/// Not a secret: anyone who can connect may present any token, or open sessions by the thousand,
+/// which `SessionAcceptor::set_max_sessions` bounds
+fn new_token() -> u128{
+    let half = || RandomState::new().build_hasher().finish() as u128;
+    half() << 64 | half()
+}
+
+/// Where a `SessionWriter` keeps the frames the server did not acknowledge yet, oldest first,
+/// see `SessionWriter::with_replay_buffer`. In memory by default, as a `VecDeque`
+pub trait ReplayBuffer: Send{
+    /// Keeps `frame` after the others. A failure fails the write of the frame
+    fn push(&mut self, frame: &[u8]) -> io::Result<()>;
+    /// Drops the `count` oldest frames, which the server processed
+    fn acknowledge(&mut self, count: usize);
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+    /// The frame `index` places after the oldest, for sending it again
+    fn get(&mut self, index: usize) -> io::Result<Cow<'_, [u8]>>;
+}
+
+impl ReplayBuffer for VecDeque<Vec<u8>>{
+    fn push(&mut self, frame: &[u8]) -> io::Result<()>{
+        self.push_back(frame.to_vec());
+        Ok(())
+    }
+    fn acknowledge(&mut self, count: usize){
+        self.drain(..count.min(VecDeque::len(self)));
+    }
+    fn len(&self) -> usize{
+        VecDeque::len(self)
+    }
+    fn get(&mut self, index: usize) -> io::Result<Cow<'_, [u8]>>{
+        let frame = VecDeque::get(self, index).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such frame kept"))?;
+        Ok(Cow::Borrowed(frame))
+    }
+}
+
+/// Frames sent and not acknowledged yet, numbered from `first`
+struct Replay{
+    first: u64,
+    frames: Box<dyn ReplayBuffer>,
+    capacity: usize,
+}
+
+impl Replay{
+    fn is_full(&self) -> bool{
+        self.frames.len() >= self.capacity
+    }
+    /// Sequence number the next frame gets
+    fn next(&self) -> u64{
+        self.first + self.frames.len() as u64
+    }
+    /// Drops the frames before `next`, which the server processed
+    fn acknowledge(&mut self, next: u64) -> io::Result<()>{
+        if next < self.first || next > self.next() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Server acknowledged frames that were not sent"))
+        }
+        self.frames.acknowledge((next - self.first) as usize);
+        self.first = next;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Replay{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Replay")
+            .field("first", &self.first)
+            .field("frames", &self.frames.len())
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+fn send_data(connection: &mut Connection, sequence: u64, frame: &[u8]) -> Result<(), WriteErr>{
+    let mut header = [0u8; 1 + SEQUENCE_LEN];
+    header[0] = DATA;
+    header[1..].copy_from_slice(&sequence.to_be_bytes());
+    connection.write_frame_vectored(&[io::IoSlice::new(&header), io::IoSlice::new(frame)])
+}
+
+/// Writer of a session with a `SessionAcceptor` at `target`: frames are kept until the server acknowledges
+/// them and the ones it did not are sent again on the next connection, which resumes the session.
+/// The server reads each frame once, in order, across connections, as long as the acceptor keeps the session.
+///
+/// A write keeps its frame and sends it, connecting first by `policy` when needed. Failing to send is no error:
+/// the frame goes with the others once a connection is made, by a later write or `flush`. A write fails only when
+/// `capacity` frames are kept and no acknowledgement can be got, or when the `ReplayBuffer` cannot keep its frame,
+/// without keeping it.
+/// Writes block while connecting and while waiting for acknowledgements
+pub struct SessionWriter{
+    target: SocketAddr,
+    policy: RetryPolicy,
+    connection: Option<Connection>,
+    token: Option<u128>,
+    replay: Replay,
+    on_reconnect: Option<ReconnectHook>,
+}
+
+impl SessionWriter{
+    /// Keeps up to `capacity` frames not acknowledged in memory, at least 1
+    pub fn new(target: SocketAddr, policy: RetryPolicy, capacity: usize) -> Self{
+        Self::with_replay_buffer(target, policy, capacity, VecDeque::new())
+    }
+    /// Keeps the frames not acknowledged in `buffer` instead, one that outlives the process for instance.
+    /// Frames it holds already are sent first, as the start of a new session
+    pub fn with_replay_buffer(target: SocketAddr, policy: RetryPolicy, capacity: usize, buffer: impl ReplayBuffer + 'static) -> Self{
+        let replay = Replay{first: 0, frames: Box::new(buffer), capacity: capacity.max(1)};
+        Self{target, policy, connection: None, token: None, replay, on_reconnect: None}
+    }
+    /// Called with each connection made, the first one included, before the session is opened on it.
+    /// Settings are not carried over from the previous connection, set them here
+    pub fn on_reconnect(&mut self, hook: impl FnMut(&mut Connection) + Send + 'static){
+        self.on_reconnect = Some(Box::new(hook));
+    }
+    /// Given by the server on the first connection, `None` before
+    pub fn token(&self) -> Option<u128>{
+        self.token
+    }
+    pub fn is_connected(&self) -> bool{
+        self.connection.is_some()
+    }
+    /// Frames acknowledged by the server so far, in this session
+    pub fn acknowledged(&self) -> u64{
+        self.replay.first
+    }
+    /// Frames kept until the server acknowledges them
+    pub fn unacknowledged(&self) -> usize{
+        self.replay.frames.len()
+    }
+    /// Opens or resumes the session on a new connection, then sends the frames not acknowledged.
+    /// A server that does not know the token opens a new session, the frames are numbered again from its start
+    fn connect(&mut self) -> io::Result<()>{
+        let mut connection = Connection::connect_with_retry(&self.target, &self.policy)?;
+        if let Some(hook) = &mut self.on_reconnect {
+            hook(&mut connection);
+        }
+        let mut hello = [0u8; 2 + TOKEN_LEN];
+        hello[0] = HELLO;
+        if let Some(token) = self.token {
+            hello[1] = 1;
+            hello[2..].copy_from_slice(&token.to_be_bytes());
+        }
+        connection.write_frame(&hello)?;
+        connection.set_read_timeout(self.policy.attempt_timeout)?;
+        let welcome = connection.read_frame()?;
+        connection.set_read_timeout(None)?;
+        if welcome.len() != 1 + TOKEN_LEN + SEQUENCE_LEN || welcome[0] != WELCOME {
+            return Err(malformed())
+        }
+        let token = token_at(&welcome[1..])?;
+        let next = sequence_at(&welcome[1 + TOKEN_LEN..])?;
+        if self.token == Some(token) {
+            self.replay.acknowledge(next)?;
+        } else {
+            self.replay.first = next;
+        }
+        self.token = Some(token);
+        for i in 0..self.replay.frames.len() {
+            let frame = self.replay.frames.get(i)?;
+            send_data(&mut connection, self.replay.first + i as u64, &frame)?;
+        }
+        self.connection = Some(connection);
+        Ok(())
+    }
+    /// Takes the acknowledgements received, waiting for one first if `wait`
+    fn read_acks(&mut self, mut wait: bool) -> io::Result<()>{
+        let Some(connection) = &mut self.connection else { return Ok(()) };
+        // Acknowledgements are small, one that started arriving is complete soon
+        while connection.poll_readable(if wait { None } else { Some(Duration::ZERO) })? {
+            let ack = connection.read_frame()?;
+            if ack.len() != 1 + SEQUENCE_LEN || ack[0] != ACK {
+                return Err(malformed())
+            }
+            self.replay.acknowledge(sequence_at(&ack[1..])?)?;
+            wait = false;
+        }
+        Ok(())
+    }
+    /// Waits for an acknowledgement, on a new connection if there is none or it breaks.
+    /// Fails once no connection can be made
+    fn wait_ack(&mut self) -> io::Result<()>{
+        if self.connection.is_none() {
+            return self.connect()
+        }
+        if self.read_acks(true).is_err() {
+            self.connection = None;
+        }
+        Ok(())
+    }
+}
+
+impl FrameWriter for SessionWriter{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        if self.read_acks(false).is_err() {
+            self.connection = None;
+        }
+        while self.replay.is_full() {
+            self.wait_ack()?;
+        }
+        let sequence = self.replay.next();
+        self.replay.frames.push(frame)?;
+        let sent = match &mut self.connection {
+            Some(connection) => send_data(connection, sequence, frame).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.connection = None;
+            // The frame is sent with the others not acknowledged, now or on a later connection
+            let _ = self.connect();
+        }
+        Ok(())
+    }
+    /// Waits until the server acknowledged every frame, failing once no connection can be made.
+    /// The server acknowledges when asked for the next frame, or by `SessionReader::acknowledge`
+    fn flush(&mut self) -> io::Result<()>{
+        while !self.replay.frames.is_empty() {
+            self.wait_ack()?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for SessionWriter{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionWriter")
+            .field("target", &self.target)
+            .field("policy", &self.policy)
+            .field("connection", &self.connection)
+            .field("token", &self.token)
+            .field("replay", &self.replay)
+            .finish()
+    }
+}
+
+/// Where a session is at on the server, shared by the readers of its connections
+#[derive(Debug)]
+struct SessionState{
+    /// Sequence number of the next frame to read
+    next: u64,
+    /// Counts the connections the session was opened on, only the reader of the last one reads
+    epoch: u64,
+    /// When the last of them was accepted
+    opened: Instant,
+}
+
+fn lock(state: &Mutex<SessionState>) -> MutexGuard<'_, SessionState>{
+    state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Server side of the sessions of `SessionWriter`s: each accepted connection opens a session or resumes one.
+/// Sessions are kept in memory until `end_session` or until too many are open, see `set_max_sessions`,
+/// a restarted server knows none
+pub struct SessionAcceptor{
+    server: Server,
+    sessions: HashMap<u128, Arc<Mutex<SessionState>>>,
+    max_sessions: usize,
+    ack_interval: usize,
+    handshake_timeout: Option<Duration>,
+}
+
+impl SessionAcceptor{
+    pub fn new(server: Server) -> Self{
+        Self{
+            server,
+            sessions: HashMap::new(),
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            ack_interval: DEFAULT_ACK_INTERVAL,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+    pub fn server(&self) -> &Server{
+        &self.server
+    }
+    /// Frames read before the readers acknowledge them, 32 by default. A reader acknowledges earlier
+    /// when it has read everything received, so writers waiting for room are not stuck. 0 counts as 1
+    pub fn set_ack_interval(&mut self, frames: usize){
+        self.ack_interval = frames.max(1);
+    }
+    pub fn ack_interval(&self) -> usize{
+        self.ack_interval
+    }
+    /// How long `accept` waits for the client to open the session, 10 seconds by default, `None` for ever.
+    /// A client that connects and sends nothing holds up the later accepts until then
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>){
+        self.handshake_timeout = timeout;
+    }
+    pub fn handshake_timeout(&self) -> Option<Duration>{
+        self.handshake_timeout
+    }
+    /// Sessions kept at most, 1024 by default, 0 counts as 1. Opening one more forgets one first, as `end_session`
+    /// does: of those whose reader was dropped if any, the one accepted the longest ago
+    pub fn set_max_sessions(&mut self, sessions: usize){
+        self.max_sessions = sessions.max(1);
+    }
+    pub fn max_sessions(&self) -> usize{
+        self.max_sessions
+    }
+    /// Forgets sessions until one more can be opened, see `set_max_sessions`
+    fn make_room(&mut self){
+        while self.sessions.len() >= self.max_sessions {
+            // The acceptor holds one handle, a reader the other
+            let oldest = self.sessions.iter()
+                .min_by_key(|(_, state)| (Arc::strong_count(state) > 1, lock(state).opened))
+                .map(|(token, _)| *token);
+            match oldest {
+                Some(token) => self.sessions.remove(&token),
+                None => break,
+            };
+        }
+    }
+    /// Accepts a connection and opens its session, or resumes it if the client presents a known token.
+    /// The reader of a connection the session was on before fails from then on.
+    /// Fails if the client does not open a session, the caller accepts the next one
+    pub fn accept(&mut self) -> io::Result<SessionReader>{
+        let (mut connection, _) = self.server.accept()?;
+        connection.set_read_timeout(self.handshake_timeout)?;
+        let hello = connection.read_frame()?;
+        connection.set_read_timeout(None)?;
+        if hello.len() != 2 + TOKEN_LEN || hello[0] != HELLO || hello[1] > 1 {
+            return Err(malformed())
+        }
+        let known = match hello[1] {
+            1 => token_at(&hello[2..]).map(|token| self.sessions.get(&token).map(|state| (token, state.clone())))?,
+            _ => None,
+        };
+        let resumed = known.is_some();
+        let (token, state) = known.unwrap_or_else(|| {
+            self.make_room();
+            let token = new_token();
+            let state = Arc::new(Mutex::new(SessionState{next: 0, epoch: 0, opened: Instant::now()}));
+            self.sessions.insert(token, state.clone());
+            (token, state)
+        });
+        let (next, epoch) = {
+            let mut state = lock(&state);
+            state.epoch += 1;
+            state.opened = Instant::now();
+            (state.next, state.epoch)
+        };
+        let mut welcome = [0u8; 1 + TOKEN_LEN + SEQUENCE_LEN];
+        welcome[0] = WELCOME;
+        welcome[1..1 + TOKEN_LEN].copy_from_slice(&token.to_be_bytes());
+        welcome[1 + TOKEN_LEN..].copy_from_slice(&next.to_be_bytes());
+        connection.write_frame(&welcome)?;
+        Ok(SessionReader{connection, token, resumed, state, epoch, unacknowledged: 0, ack_interval: self.ack_interval})
+    }
+    /// Forgets the session, a client resuming it opens a new one. `false` if it was not known
+    pub fn end_session(&mut self, token: u128) -> bool{
+        self.sessions.remove(&token).is_some()
+    }
+    /// Sessions kept
+    pub fn sessions(&self) -> usize{
+        self.sessions.len()
+    }
+}
+
+/// Frames of a session on one of its connections, see `SessionAcceptor::accept`. Frames already read
+/// on an earlier connection are skipped. Reading acknowledges the frames returned before,
+/// `read_frame` returning a frame means the previous ones were processed
+#[derive(Debug)]
+pub struct SessionReader{
+    connection: Connection,
+    token: u128,
+    resumed: bool,
+    state: Arc<Mutex<SessionState>>,
+    epoch: u64,
+    /// Frames returned and not acknowledged yet
+    unacknowledged: usize,
+    ack_interval: usize,
+}
+
+impl SessionReader{
+    pub fn token(&self) -> u128{
+        self.token
+    }
+    /// Whether the client resumed a session known to the acceptor
+    pub fn is_resumed(&self) -> bool{
+        self.resumed
+    }
+    pub fn connection(&self) -> &Connection{
+        &self.connection
+    }
+    /// Acknowledges the frames returned so far, which reads do by themselves before reading the next one.
+    /// For a server done with the session, so that the writer's `flush` returns
+    pub fn acknowledge(&mut self) -> io::Result<()>{
+        let next = lock(&self.state).next;
+        let mut ack = [0u8; 1 + SEQUENCE_LEN];
+        ack[0] = ACK;
+        ack[1..].copy_from_slice(&next.to_be_bytes());
+        self.connection.write_frame(&ack)?;
+        self.unacknowledged = 0;
+        Ok(())
+    }
+}
+
+/// Fails with `ConnectionAborted` once the session was resumed on another connection
+impl FrameReader for SessionReader{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        loop {
+            if self.unacknowledged > 0
+                && (self.unacknowledged >= self.ack_interval || !self.connection.poll_readable(Some(Duration::ZERO))?) {
+                self.acknowledge()?;
+            }
+            self.connection.read_frame_into(buf)?;
+            if buf.first() != Some(&DATA) {
+                return Err(malformed())
+            }
+            let sequence = sequence_at(&buf[1..])?;
+            let mut state = lock(&self.state);
+            if state.epoch != self.epoch {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "The session was resumed on another connection"))
+            }
+            if sequence < state.next {
+                // Sent again by the client, which missed the acknowledgement
+                continue
+            }
+            if sequence > state.next {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Frames of the session were lost"))
+            }
+            state.next += 1;
+            drop(state);
+            self.unacknowledged += 1;
+            buf.drain(..1 + SEQUENCE_LEN);
+            return Ok(buf.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+    use unisocket::Listener;
+    use crate::ConnectionController;
+    use super::*;
+
+    fn acceptor() -> (SessionAcceptor, SocketAddr){
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = SocketAddr::Inet(listener.local_addr().unwrap());
+        (SessionAcceptor::new(Server::from(Listener::Inet(listener))), addr)
+    }
+
+    /// Frame `i` of a session, its number first
+    fn frame(i: u32) -> Vec<u8>{
+        let mut frame = i.to_be_bytes().to_vec();
+        frame.resize(4 + (i as usize * 31) % 5_000, i as u8);
+        frame
+    }
+
+    /// Reads `frames` frames across connections, resetting each one partway through the stream
+    /// with frames read and not acknowledged yet. Returns them with the number of connections
+    fn read_resetting(mut acceptor: SessionAcceptor, frames: usize) -> thread::JoinHandle<(Vec<Vec<u8>>, usize)>{
+        thread::spawn(move || {
+            let mut got = Vec::new();
+            let mut connections = 0;
+            loop {
+                let mut reader = acceptor.accept().unwrap();
+                connections += 1;
+                let kill_at = got.len() + 250 + connections * 17;
+                while got.len() < kill_at {
+                    got.push(reader.read_frame().unwrap());
+                    if got.len() == frames {
+                        reader.acknowledge().unwrap();
+                        return (got, connections)
+                    }
+                }
+                reader.connection().set_linger(Some(Duration::ZERO)).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn frames_arrive_once_and_in_order_across_killed_connections(){
+        const FRAMES: usize = 2_000;
+        let (acceptor, addr) = acceptor();
+        let server = read_resetting(acceptor, FRAMES);
+        let mut writer = SessionWriter::new(addr, RetryPolicy::default(), 64);
+        for i in 0..FRAMES {
+            writer.write_frame(&frame(i as u32)).unwrap();
+        }
+        writer.flush().unwrap();
+        let (got, connections) = server.join().unwrap();
+        assert!(connections > 1);
+        assert_eq!(got.len(), FRAMES);
+        for (i, received) in got.iter().enumerate() {
+            assert!(*received == frame(i as u32), "frame {}", i);
+        }
+        assert_eq!(writer.acknowledged(), FRAMES as u64);
+    }
+
+    /// Frames kept in memory, the most ever kept counted
+    #[derive(Default)]
+    struct Counted{
+        frames: VecDeque<Vec<u8>>,
+        most: Arc<Mutex<usize>>,
+    }
+
+    impl ReplayBuffer for Counted{
+        fn push(&mut self, frame: &[u8]) -> io::Result<()>{
+            self.frames.push(frame)?;
+            let mut most = self.most.lock().unwrap();
+            *most = (*most).max(self.frames.len());
+            Ok(())
+        }
+        fn acknowledge(&mut self, count: usize){
+            ReplayBuffer::acknowledge(&mut self.frames, count)
+        }
+        fn len(&self) -> usize{
+            self.frames.len()
+        }
+        fn get(&mut self, index: usize) -> io::Result<Cow<'_, [u8]>>{
+            ReplayBuffer::get(&mut self.frames, index)
+        }
+    }
+
+    #[test]
+    fn a_replay_buffer_of_the_user_keeps_the_frames_and_sends_its_own_first(){
+        const FRAMES: usize = 1_000;
+        let (acceptor, addr) = acceptor();
+        let server = read_resetting(acceptor, FRAMES);
+        // Left over by an earlier run, they start the session
+        let buffer = Counted{frames: (0..10).map(frame).collect(), most: Arc::default()};
+        let most = buffer.most.clone();
+        let mut writer = SessionWriter::with_replay_buffer(addr, RetryPolicy::default(), 64, buffer);
+        assert_eq!(writer.unacknowledged(), 10);
+        for i in 10..FRAMES {
+            writer.write_frame(&frame(i as u32)).unwrap();
+        }
+        writer.flush().unwrap();
+        let (got, connections) = server.join().unwrap();
+        assert!(connections > 1);
+        assert!(got.iter().enumerate().all(|(i, received)| *received == frame(i as u32)));
+        assert_eq!(got.len(), FRAMES);
+        assert!(*most.lock().unwrap() <= 64);
+    }
+
+    #[test]
+    fn a_client_that_sends_nothing_holds_up_accept_only_until_the_handshake_timeout(){
+        let (mut acceptor, addr) = acceptor();
+        assert_eq!(acceptor.handshake_timeout(), Some(Duration::from_secs(10)));
+        acceptor.set_handshake_timeout(Some(Duration::from_millis(200)));
+        let _silent = Connection::connect(&addr).unwrap();
+        let _client = hello(&addr);
+        let started = Instant::now();
+        let err = acceptor.accept().unwrap_err();
+        assert!(matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{:?}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!acceptor.accept().unwrap().is_resumed());
+    }
+
+    /// Opens a session without a token, as a client that never resumes it
+    fn hello(addr: &SocketAddr) -> Connection{
+        let mut connection = Connection::connect(addr).unwrap();
+        let mut hello = [0u8; 2 + TOKEN_LEN];
+        hello[0] = HELLO;
+        connection.write_frame(&hello).unwrap();
+        connection
+    }
+
+    #[test]
+    fn sessions_past_the_limit_forget_those_without_a_reader_first(){
+        let (mut acceptor, addr) = acceptor();
+        acceptor.set_max_sessions(3);
+        let _client = hello(&addr);
+        let kept = acceptor.accept().unwrap();
+        let mut flood = Vec::new();
+        for _ in 0..10 {
+            let _client = hello(&addr);
+            flood.push(acceptor.accept().unwrap().token());
+        }
+        assert_eq!(acceptor.sessions(), 3);
+        assert!(acceptor.sessions.contains_key(&kept.token()));
+        // The latest ones, their readers were dropped
+        assert!(acceptor.sessions.contains_key(&flood[9]) && acceptor.sessions.contains_key(&flood[8]));
+    }
+}
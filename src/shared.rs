@@ -0,0 +1,201 @@
+use std::io;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use crate::{ConnectionWriter, FrameWriter, ConnectionController, WriteController, Frame, FrameBuf, KeepaliveConfig, SocketAddr, WriteErr};
+
+/// Writer handle for several threads, clones write to the same connection.
+/// Each frame is written under a lock held for that frame only, so frames never interleave.
+/// Waiting writers are not served in any particular order, a thread writing in a loop can starve others
+#[derive(Clone)]
+pub struct SharedWriter{
+    writer: Arc<Mutex<ConnectionWriter>>,
+}
+
+impl SharedWriter{
+    pub fn new(writer: ConnectionWriter) -> Self{
+        Self{writer: Arc::new(Mutex::new(writer))}
+    }
+    /// The writer itself, to change its settings. Writes from other clones wait until the guard is dropped
+    pub fn lock(&self) -> MutexGuard<'_, ConnectionWriter>{
+        // Writes mark the writer desynchronized themselves when they fail halfway, a panic adds nothing to that
+        self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// The writer back if this is the last clone
+    pub fn into_inner(self) -> Result<ConnectionWriter, Self>{
+        match Arc::try_unwrap(self.writer) {
+            Ok(writer) => Ok(writer.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())),
+            Err(writer) => Err(Self{writer}),
+        }
+    }
+    /// As on `ConnectionController` and `WriteController`, so that calls are not ambiguous
+    pub fn local_addr(&self) -> io::Result<SocketAddr>{
+        self.lock().local_addr()
+    }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr>{
+        self.lock().peer_addr()
+    }
+    pub fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.lock().set_write_timeout(t)
+    }
+}
+
+impl From<ConnectionWriter> for SharedWriter{
+    fn from(writer: ConnectionWriter) -> Self {
+        Self::new(writer)
+    }
+}
+
+/// `write_frame_from_reader` reads the whole payload before taking the lock,
+/// `write_frames` holds it for the whole batch
+impl FrameWriter for SharedWriter{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.lock().write_frame(frame)
+    }
+    fn write_frame_vectored(&mut self, parts: &[io::IoSlice<'_>]) -> Result<(), WriteErr>{
+        self.lock().write_frame_vectored(parts)
+    }
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr>{
+        self.lock().write_frame_meta(frame)
+    }
+    fn write_frames<'a>(&mut self, frames: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, WriteErr>{
+        self.lock().write_frames(frames)
+    }
+    fn write_framebuf(&mut self, buf: &mut FrameBuf) -> Result<(), WriteErr>{
+        self.lock().write_framebuf(buf)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        self.lock().flush()
+    }
+}
+
+impl ConnectionController for SharedWriter{
+    fn local_addr(&self) -> io::Result<SocketAddr>{
+        self.lock().local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr>{
+        self.lock().peer_addr()
+    }
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.lock().set_read_timeout(t)
+    }
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.lock().set_write_timeout(t)
+    }
+    fn shutdown(&self, t: Shutdown) -> io::Result<()>{
+        self.lock().shutdown(t)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>{
+        self.lock().set_nonblocking(nonblocking)
+    }
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()>{
+        self.lock().set_nodelay(nodelay)
+    }
+    fn nodelay(&self) -> io::Result<bool>{
+        self.lock().nodelay()
+    }
+    fn set_keepalive_config(&self, config: Option<KeepaliveConfig>) -> io::Result<()>{
+        self.lock().set_keepalive_config(config)
+    }
+    fn keepalive(&self) -> io::Result<Option<Duration>>{
+        self.lock().keepalive()
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()>{
+        self.lock().set_ttl(ttl)
+    }
+    fn ttl(&self) -> io::Result<u32>{
+        self.lock().ttl()
+    }
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()>{
+        self.lock().set_linger(linger)
+    }
+    fn linger(&self) -> io::Result<Option<Duration>>{
+        self.lock().linger()
+    }
+    fn set_recv_buffer_size(&self, size: usize) -> io::Result<()>{
+        self.lock().set_recv_buffer_size(size)
+    }
+    fn recv_buffer_size(&self) -> io::Result<usize>{
+        self.lock().recv_buffer_size()
+    }
+    fn set_send_buffer_size(&self, size: usize) -> io::Result<()>{
+        self.lock().set_send_buffer_size(size)
+    }
+    fn send_buffer_size(&self) -> io::Result<usize>{
+        self.lock().send_buffer_size()
+    }
+    fn take_error(&self) -> io::Result<Option<io::Error>>{
+        self.lock().take_error()
+    }
+}
+
+impl WriteController for SharedWriter{
+    fn local_addr(&self) -> io::Result<SocketAddr>{
+        self.lock().local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr>{
+        self.lock().peer_addr()
+    }
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.lock().set_write_timeout(t)
+    }
+    fn shutdown_write(&self) -> io::Result<()>{
+        self.lock().shutdown_write()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests{
+    use std::io::IoSlice;
+    use std::thread;
+    use crate::{Connection, FrameReader, FrameWriter};
+    use super::SharedWriter;
+
+    const THREADS: u8 = 8;
+    const FRAMES: u32 = 500;
+
+    /// Frame `n` of `thread`: both of them, then bytes that depend on them, of a length that varies from 8 to about 20 KB
+    fn frame(thread: u8, n: u32) -> Vec<u8>{
+        let len = 8 + (n as usize * 7_919 + thread as usize * 104_729) % 20_000;
+        let mut frame = vec![thread];
+        frame.extend_from_slice(&n.to_be_bytes());
+        frame.extend((5..len).map(|i| (i as u32 ^ n).wrapping_mul(thread as u32 + 1) as u8));
+        frame
+    }
+
+    #[test]
+    fn frames_of_eight_threads_never_interleave(){
+        let (a, mut b) = Connection::pair().unwrap();
+        let shared = SharedWriter::new(a.split_shared().1);
+        let writers: Vec<_> = (0..THREADS).map(|thread| {
+            let mut shared = shared.clone();
+            thread::spawn(move || {
+                for n in 0..FRAMES {
+                    let frame = frame(thread, n);
+                    match n % 3 {
+                        0 => shared.write_frame(&frame).unwrap(),
+                        1 => {
+                            let (head, tail) = frame.split_at(frame.len() / 2);
+                            shared.write_frame_vectored(&[IoSlice::new(head), IoSlice::new(tail)]).unwrap()
+                        }
+                        _ => assert_eq!(shared.write_frames([&frame[..]]).unwrap(), 1),
+                    }
+                }
+            })
+        }).collect();
+        drop(shared);
+        let mut next = [0u32; THREADS as usize];
+        for _ in 0..THREADS as u32 * FRAMES {
+            let received = b.read_frame().unwrap();
+            let thread = received[0];
+            let n = u32::from_be_bytes([received[1], received[2], received[3], received[4]]);
+            assert_eq!(n, next[thread as usize], "frames of thread {} out of order", thread);
+            assert!(received == frame(thread, n), "frame {} of thread {} corrupted", n, thread);
+            next[thread as usize] += 1;
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(b.read_frame().is_err());
+    }
+}
@@ -0,0 +1,425 @@
+use std::io;
+use std::time::Duration;
+use unisocket::{Stream, Listener};
+#[cfg(unix)]
+use crate::stream_fd;
+
+/// TCP keepalive probing of an idle connection, see `ConnectionController::set_keepalive_config`.
+/// Times are in whole seconds, rounded up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig{
+    /// Idle time before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes, `None` keeps the system default
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped, `None` keeps the system default
+    pub retries: Option<u32>,
+}
+
+impl KeepaliveConfig{
+    pub fn new(idle: Duration) -> Self{
+        Self{idle, interval: None, retries: None}
+    }
+    pub fn interval(mut self, interval: Duration) -> Self{
+        self.interval = Some(interval);
+        self
+    }
+    pub fn retries(mut self, retries: u32) -> Self{
+        self.retries = Some(retries);
+        self
+    }
+}
+
+fn unsupported(what: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::Unsupported, format!("{} is only supported on TCP connections", what))
+}
+
+/// `None` turns keepalive off
+pub(crate) fn set_keepalive(stream: &Stream, config: Option<KeepaliveConfig>) -> io::Result<()>{
+    match stream {
+        #[cfg(unix)]
+        Stream::Inet(s) => {
+            use std::os::unix::io::AsRawFd;
+            sys::set_keepalive(s.as_raw_fd(), config)
+        }
+        #[cfg(not(unix))]
+        Stream::Inet(_) => {
+            let _ = config;
+            Err(unsupported("keepalive"))
+        }
+        #[cfg(unix)]
+        Stream::Unix(_) => Err(unsupported("keepalive")),
+    }
+}
+
+/// Idle time before the first probe, `None` if keepalive is off
+pub(crate) fn keepalive(stream: &Stream) -> io::Result<Option<Duration>>{
+    match stream {
+        #[cfg(unix)]
+        Stream::Inet(s) => {
+            use std::os::unix::io::AsRawFd;
+            sys::keepalive(s.as_raw_fd())
+        }
+        #[cfg(not(unix))]
+        Stream::Inet(_) => Err(unsupported("keepalive")),
+        #[cfg(unix)]
+        Stream::Unix(_) => Err(unsupported("keepalive")),
+    }
+}
+
+/// `None` closes in the background, `Some(Duration::ZERO)` resets the connection on close
+pub(crate) fn set_linger(stream: &Stream, linger: Option<Duration>) -> io::Result<()>{
+    match stream {
+        #[cfg(unix)]
+        Stream::Inet(s) => {
+            use std::os::unix::io::AsRawFd;
+            sys::set_linger(s.as_raw_fd(), linger)
+        }
+        #[cfg(not(unix))]
+        Stream::Inet(_) => {
+            let _ = linger;
+            Err(unsupported("linger"))
+        }
+        #[cfg(unix)]
+        Stream::Unix(_) => Err(unsupported("linger")),
+    }
+}
+
+pub(crate) fn linger(stream: &Stream) -> io::Result<Option<Duration>>{
+    match stream {
+        #[cfg(unix)]
+        Stream::Inet(s) => {
+            use std::os::unix::io::AsRawFd;
+            sys::linger(s.as_raw_fd())
+        }
+        #[cfg(not(unix))]
+        Stream::Inet(_) => Err(unsupported("linger")),
+        #[cfg(unix)]
+        Stream::Unix(_) => Err(unsupported("linger")),
+    }
+}
+
+/// Kernel buffer of a socket, `SO_RCVBUF` or `SO_SNDBUF`
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SocketBuffer{
+    Recv,
+    Send,
+}
+
+/// The kernel may round the size, Linux doubles it for its bookkeeping
+pub(crate) fn set_buffer_size(stream: &Stream, buffer: SocketBuffer, size: usize) -> io::Result<()>{
+    #[cfg(unix)]
+    return sys::set_buffer_size(stream_fd(stream), buffer, size);
+    #[cfg(not(unix))]
+    {
+        let _ = (stream, buffer, size);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "socket buffer sizes are not supported on this platform"))
+    }
+}
+
+/// The size the kernel actually uses
+pub(crate) fn buffer_size(stream: &Stream, buffer: SocketBuffer) -> io::Result<usize>{
+    #[cfg(unix)]
+    return sys::buffer_size(stream_fd(stream), buffer);
+    #[cfg(not(unix))]
+    {
+        let _ = (stream, buffer);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "socket buffer sizes are not supported on this platform"))
+    }
+}
+
+/// Accepted sockets inherit the buffer sizes of the listener
+pub(crate) fn set_listener_buffer_size(listener: &Listener, buffer: SocketBuffer, size: usize) -> io::Result<()>{
+    #[cfg(unix)]
+    return sys::set_buffer_size(listener_fd(listener), buffer, size);
+    #[cfg(not(unix))]
+    {
+        let _ = (listener, buffer, size);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "socket buffer sizes are not supported on this platform"))
+    }
+}
+
+pub(crate) fn listener_buffer_size(listener: &Listener, buffer: SocketBuffer) -> io::Result<usize>{
+    #[cfg(unix)]
+    return sys::buffer_size(listener_fd(listener), buffer);
+    #[cfg(not(unix))]
+    {
+        let _ = (listener, buffer);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "socket buffer sizes are not supported on this platform"))
+    }
+}
+
+#[cfg(unix)]
+fn listener_fd(listener: &Listener) -> std::os::unix::io::RawFd{
+    use std::os::unix::io::AsRawFd;
+    match listener {
+        Listener::Inet(l) => l.as_raw_fd(),
+        Listener::Unix(l) => l.as_raw_fd(),
+    }
+}
+
+#[cfg(unix)]
+mod sys{
+    use std::io;
+    use std::time::Duration;
+    use std::os::unix::io::RawFd;
+    use super::{KeepaliveConfig, SocketBuffer};
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const KEEPIDLE: Option<libc::c_int> = Some(libc::TCP_KEEPIDLE);
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const KEEPIDLE: Option<libc::c_int> = Some(libc::TCP_KEEPALIVE);
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+    const KEEPIDLE: Option<libc::c_int> = None;
+
+    fn unsupported(what: &str) -> io::Error{
+        io::Error::new(io::ErrorKind::Unsupported, format!("keepalive {} is not supported on this platform", what))
+    }
+
+    fn seconds(t: Duration) -> libc::c_int{
+        t.as_nanos().div_ceil(1_000_000_000).clamp(1, libc::c_int::MAX as u128) as libc::c_int
+    }
+
+    /// `T` is the plain C type the option takes
+    fn set<T: Copy>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: T) -> io::Result<()>{
+        let len = std::mem::size_of::<T>() as libc::socklen_t;
+        match unsafe { libc::setsockopt(fd, level, name, &value as *const T as *const libc::c_void, len) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn get<T: Copy>(fd: RawFd, level: libc::c_int, name: libc::c_int, mut value: T) -> io::Result<T>{
+        let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+        match unsafe { libc::getsockopt(fd, level, name, &mut value as *mut T as *mut libc::c_void, &mut len) } {
+            0 => Ok(value),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) fn set_keepalive(fd: RawFd, config: Option<KeepaliveConfig>) -> io::Result<()>{
+        let config = match config {
+            Some(config) => config,
+            None => return set(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 0 as libc::c_int),
+        };
+        set(fd, libc::IPPROTO_TCP, KEEPIDLE.ok_or_else(|| unsupported("idle time"))?, seconds(config.idle))?;
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+        {
+            if let Some(interval) = config.interval {
+                set(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, seconds(interval))?;
+            }
+            if let Some(retries) = config.retries {
+                set(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, retries.min(libc::c_int::MAX as u32) as libc::c_int)?;
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+        if config.interval.is_some() || config.retries.is_some() {
+            return Err(unsupported("interval and retries"))
+        }
+        set(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1 as libc::c_int)
+    }
+
+    pub(super) fn keepalive(fd: RawFd) -> io::Result<Option<Duration>>{
+        if get(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 0 as libc::c_int)? == 0 {
+            return Ok(None)
+        }
+        let idle = get(fd, libc::IPPROTO_TCP, KEEPIDLE.ok_or_else(|| unsupported("idle time"))?, 0 as libc::c_int)?;
+        Ok(Some(Duration::from_secs(idle.max(0) as u64)))
+    }
+
+    pub(super) fn set_linger(fd: RawFd, linger: Option<Duration>) -> io::Result<()>{
+        let linger = libc::linger{
+            l_onoff: linger.is_some() as libc::c_int,
+            // Zero stays zero, anything else rounds up to a second
+            l_linger: linger.map_or(0, |t| if t.is_zero() { 0 } else { seconds(t) }),
+        };
+        set(fd, libc::SOL_SOCKET, libc::SO_LINGER, linger)
+    }
+
+    pub(super) fn linger(fd: RawFd) -> io::Result<Option<Duration>>{
+        let linger = get(fd, libc::SOL_SOCKET, libc::SO_LINGER, libc::linger{l_onoff: 0, l_linger: 0})?;
+        Ok(if linger.l_onoff != 0 { Some(Duration::from_secs(linger.l_linger.max(0) as u64)) } else { None })
+    }
+
+    fn buffer_option(buffer: SocketBuffer) -> libc::c_int{
+        match buffer {
+            SocketBuffer::Recv => libc::SO_RCVBUF,
+            SocketBuffer::Send => libc::SO_SNDBUF,
+        }
+    }
+
+    pub(super) fn set_buffer_size(fd: RawFd, buffer: SocketBuffer, size: usize) -> io::Result<()>{
+        set(fd, libc::SOL_SOCKET, buffer_option(buffer), size.min(libc::c_int::MAX as usize) as libc::c_int)
+    }
+
+    pub(super) fn buffer_size(fd: RawFd, buffer: SocketBuffer) -> io::Result<usize>{
+        Ok(get(fd, libc::SOL_SOCKET, buffer_option(buffer), 0 as libc::c_int)?.max(0) as usize)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests{
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use unisocket::Listener;
+    use crate::{Connection, ConnectionController, FrameReader, FrameWriter, ReadErr, Server};
+
+    /// Connection over loopback TCP and the server side of it
+    fn tcp_pair() -> (Connection, Connection){
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Connection::from(client), Connection::from(server))
+    }
+
+    fn listener() -> (Server, std::net::SocketAddr){
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        (Server::from(Listener::Inet(listener)), addr)
+    }
+
+    #[test]
+    fn ttl_round_trips_on_tcp(){
+        let (a, b) = tcp_pair();
+        for ttl in [1, 17, 255] {
+            a.set_ttl(ttl).unwrap();
+            assert_eq!(a.ttl().unwrap(), ttl);
+        }
+        assert_ne!(b.ttl().unwrap(), 1);
+        let (_reader, writer) = b.split_shared();
+        writer.set_ttl(33).unwrap();
+        assert_eq!(writer.ttl().unwrap(), 33);
+    }
+
+    #[test]
+    fn accepted_connections_inherit_the_ttl_of_the_server(){
+        let (server, addr) = listener();
+        server.set_ttl(42).unwrap();
+        assert_eq!(server.ttl().unwrap(), 42);
+        let client = thread::spawn(move || TcpStream::connect(addr).unwrap());
+        let (accepted, _) = server.accept().unwrap();
+        assert_eq!(accepted.ttl().unwrap(), 42);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn unix_sockets_have_no_ttl(){
+        let (a, _b) = Connection::pair().unwrap();
+        assert_eq!(a.set_ttl(17).unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(a.ttl().unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn linger_round_trips_on_tcp(){
+        let (a, _b) = tcp_pair();
+        assert_eq!(a.linger().unwrap(), None);
+        for linger in [Some(Duration::from_secs(5)), Some(Duration::ZERO), None] {
+            a.set_linger(linger).unwrap();
+            assert_eq!(a.linger().unwrap(), linger);
+        }
+        // Rounded up to whole seconds
+        a.set_linger(Some(Duration::from_millis(1500))).unwrap();
+        assert_eq!(a.linger().unwrap(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn abortive_close_resets_the_peer(){
+        let (mut a, b) = tcp_pair();
+        a.write_frame(b"before").unwrap();
+        let reader = thread::spawn(move || {
+            let mut b = b;
+            let first = b.read_frame().unwrap();
+            (first, b.read_frame())
+        });
+        thread::sleep(Duration::from_millis(50));
+        a.close_abortive().unwrap();
+        let (first, result) = reader.join().unwrap();
+        assert_eq!(first, b"before");
+        // `read_frame_checked` counts it as a disconnection between frames
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn graceful_close_ends_the_stream_between_frames(){
+        let (mut a, mut b) = tcp_pair();
+        a.set_linger(Some(Duration::from_secs(1))).unwrap();
+        a.write_frame(b"last").unwrap();
+        drop(a);
+        assert_eq!(b.read_frame().unwrap(), b"last");
+        assert!(matches!(b.read_frame_checked(), Err(ReadErr::Disconnected)));
+    }
+
+    /// Sets both buffers of `connection` and returns what the kernel reports for them
+    fn buffer_sizes(connection: &Connection, size: usize) -> (usize, usize){
+        connection.set_recv_buffer_size(size).unwrap();
+        connection.set_send_buffer_size(size).unwrap();
+        (connection.recv_buffer_size().unwrap(), connection.send_buffer_size().unwrap())
+    }
+
+    /// The kernel may round sizes up, Linux doubles them, but enlarging and shrinking show
+    fn check_buffer_sizes(connection: &Connection){
+        let (large_recv, large_send) = buffer_sizes(connection, 1 << 20);
+        let (small_recv, small_send) = buffer_sizes(connection, 8192);
+        if cfg!(target_os = "linux") {
+            assert_eq!((small_recv, small_send), (2 * 8192, 2 * 8192));
+        }
+        assert!(small_recv >= 8192 && small_send >= 8192);
+        assert!(large_recv > small_recv && large_send > small_send, "{:?}", (large_recv, large_send, small_recv, small_send));
+    }
+
+    #[test]
+    fn buffer_sizes_round_trip_on_tcp(){
+        let (a, _b) = tcp_pair();
+        check_buffer_sizes(&a);
+    }
+
+    #[test]
+    fn buffer_sizes_round_trip_on_unix_sockets(){
+        let (a, _b) = Connection::pair().unwrap();
+        check_buffer_sizes(&a);
+    }
+
+    #[test]
+    fn accepted_connections_inherit_the_buffer_sizes_of_the_server(){
+        let (server, addr) = listener();
+        server.set_recv_buffer_size(12_288).unwrap();
+        server.set_send_buffer_size(20_480).unwrap();
+        let client = thread::spawn(move || TcpStream::connect(addr).unwrap());
+        let (accepted, _) = server.accept().unwrap();
+        assert_eq!(accepted.recv_buffer_size().unwrap(), server.recv_buffer_size().unwrap());
+        assert_eq!(accepted.send_buffer_size().unwrap(), server.send_buffer_size().unwrap());
+        assert!(server.recv_buffer_size().unwrap() >= 12_288);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn take_error_reports_a_reset_without_reading(){
+        let (a, b) = tcp_pair();
+        assert!(a.take_error().unwrap().is_none());
+        b.close_abortive().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let err = a.take_error().unwrap().expect("pending reset");
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        // Taking it clears it
+        assert!(a.take_error().unwrap().is_none());
+    }
+
+    #[test]
+    fn take_error_works_on_both_halves(){
+        for take_from_reader in [true, false] {
+            let (a, b) = tcp_pair();
+            let (reader, writer) = a.separate().unwrap();
+            b.close_abortive().unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let err = if take_from_reader { reader.take_error() } else { writer.take_error() };
+            assert_eq!(err.unwrap().expect("pending reset").kind(), std::io::ErrorKind::ConnectionReset);
+        }
+    }
+
+    #[test]
+    fn take_error_is_empty_on_a_healthy_unix_socket(){
+        let (a, _b) = Connection::pair().unwrap();
+        assert!(a.take_error().unwrap().is_none());
+    }
+}
@@ -0,0 +1,230 @@
+use std::io;
+use std::io::Read;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
+use unisocket::Stream;
+use crate::{CancelToken, ConcurrentReads, ConcurrentReader};
+use crate::decoder::ReadPosition;
+use crate::limit::Bandwidth;
+use crate::turn::Turn;
+
+/// Read side settings applied around every read of the stream
+#[derive(Debug, Default)]
+pub(crate) struct ReadControl{
+    pub(crate) cancel: Option<CancelToken>,
+    /// Each read gets the time left as its timeout
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) bandwidth: Option<Bandwidth>,
+    pub(crate) turn: Option<ReadTurn>,
+}
+
+/// Reader turn shared by the clones of a connection, see `Connection::set_concurrent_reads`
+#[derive(Debug)]
+pub(crate) struct ReadTurn{
+    pub(crate) turn: Arc<Turn<ReadPosition>>,
+    pub(crate) handle: u64,
+    pub(crate) mode: ConcurrentReads,
+}
+
+/// Stream of a connection as read by the decoder
+pub(crate) struct Source<'a>{
+    stream: &'a Stream,
+    control: &'a mut ReadControl,
+}
+
+impl<'a> Source<'a>{
+    pub(crate) fn new(stream: &'a Stream, control: &'a mut ReadControl) -> Self{
+        Self{stream, control}
+    }
+}
+
+/// `Read` that can fill memory without zeroing it first
+pub(crate) trait ReadUninit: Read{
+    /// Reads into `buf`, returning how many of its leading bytes are now initialized
+    fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize>{
+        for byte in buf.iter_mut() {
+            byte.write(0);
+        }
+        // Every byte was just initialized
+        let buf = unsafe { &mut *(buf as *mut [MaybeUninit<u8>] as *mut [u8]) };
+        self.read(buf)
+    }
+}
+
+impl Source<'_>{
+    /// Takes the reader turn, then moves what its previous holder handed over to `buf`, returning how much
+    fn take_turn(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize>{
+        let Some(reader) = &self.control.turn else { return Ok(0) };
+        if !reader.turn.try_take(reader.handle) {
+            if reader.mode == ConcurrentReads::Fail {
+                return Err(io::Error::new(io::ErrorKind::ResourceBusy, ConcurrentReader))
+            }
+            let timeout = crate::stream_read_timeout(self.stream)?.and_then(|t| Instant::now().checked_add(t));
+            let deadline = match (self.control.deadline, timeout) {
+                (Some(deadline), Some(timeout)) => Some(deadline.min(timeout)),
+                (deadline, timeout) => deadline.or(timeout),
+            };
+            if !reader.turn.wait(reader.handle, deadline) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Another clone of the connection is still reading"))
+            }
+        }
+        Ok(reader.turn.read_input(buf))
+    }
+    /// Applies the read controls, returning how many bytes the read may request
+    fn before_read(&mut self, len: usize) -> io::Result<usize>{
+        let mut len = len;
+        if let Some(bandwidth) = &mut self.control.bandwidth {
+            // Waiting happens before the socket timeout is applied, so it does not count towards it
+            len = bandwidth.wait_ready(len, self.control.deadline)?;
+        }
+        if let Some(deadline) = self.control.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into())
+            }
+            self.stream.set_read_timeout(Some(left))?;
+        }
+        if let Some(cancel) = &self.control.cancel {
+            cancel.wait_readable(self.stream)?;
+        }
+        Ok(len)
+    }
+    fn after_read(&mut self, n: usize){
+        if let Some(bandwidth) = &mut self.control.bandwidth {
+            bandwidth.consume(n);
+        }
+    }
+}
+
+impl Read for Source<'_>{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // `take_turn` only writes initialized bytes
+        let handed = self.take_turn(unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) })?;
+        if handed > 0 {
+            return Ok(handed)
+        }
+        let len = self.before_read(buf.len())?;
+        let mut stream = self.stream;
+        let n = stream.read(&mut buf[..len])?;
+        self.after_read(n);
+        Ok(n)
+    }
+}
+
+impl ReadUninit for Source<'_>{
+    #[cfg(unix)]
+    fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize>{
+        let handed = self.take_turn(buf)?;
+        if handed > 0 {
+            return Ok(handed)
+        }
+        let len = self.before_read(buf.len())?;
+        let fd = crate::stream_fd(self.stream);
+        let n = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, len, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        self.after_read(n as usize);
+        Ok(n as usize)
+    }
+}
+
+pub(crate) const CHUNK_BUF_SIZE: usize = 4096;
+
+/// Bytes read from a stream in chunks and handed out as they are consumed,
+/// for codecs that scan for delimiters
+#[derive(Debug)]
+pub(crate) struct ChunkBuf{
+    buf: Box<[u8]>,
+    unread: Range<usize>,
+}
+
+impl ChunkBuf{
+    pub(crate) fn new(size: usize) -> Self{
+        Self{buf: vec![0u8; size].into_boxed_slice(), unread: 0..0}
+    }
+    /// Reads the next chunk once everything was consumed, returning how many bytes are unread.
+    /// 0 means the stream ended
+    pub(crate) fn fill<R: Read + ?Sized>(&mut self, src: &mut R) -> io::Result<usize>{
+        while self.unread.is_empty() {
+            match src.read(&mut self.buf) {
+                Ok(0) => return Ok(0),
+                Ok(n) => self.unread = 0..n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.unread.len())
+    }
+    pub(crate) fn unread(&self) -> &[u8]{
+        &self.buf[self.unread.clone()]
+    }
+    pub(crate) fn consume(&mut self, n: usize){
+        self.unread.start += n;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests{
+    use std::io::{Read, Write};
+    use std::thread;
+    use crate::{Connection, FrameReader, SfpReader};
+
+    /// Deterministic bytes that differ from frame to frame
+    fn payload(len: usize, seed: u32) -> Vec<u8>{
+        let mut state = seed.wrapping_mul(2_654_435_761).wrapping_add(1);
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    /// Frames as read before uninitialized reads: the header, then a zeroed payload filled by `read_exact`
+    fn read_zeroed(mut bytes: &[u8]) -> Vec<Vec<u8>>{
+        let mut frames = Vec::new();
+        let mut header = [0u8; 4];
+        while bytes.read_exact(&mut header).is_ok() {
+            let mut frame = vec![0u8; u32::from_be_bytes(header) as usize];
+            bytes.read_exact(&mut frame).unwrap();
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn uninitialized_reads_match_zeroed_reads_byte_for_byte(){
+        let lens = [0, 1, 7, 4095, 4096, 8191, 8192, 8193, 65_535, 65_536, 65_537, 300_001, 3 * 1024 * 1024 + 5];
+        let mut bytes = Vec::new();
+        for (i, len) in lens.iter().enumerate() {
+            bytes.extend_from_slice(&(*len as u32).to_be_bytes());
+            bytes.extend_from_slice(&payload(*len, i as u32));
+        }
+        let expected = read_zeroed(&bytes);
+        assert_eq!(expected.len(), lens.len());
+
+        let (a, mut b) = Connection::pair().unwrap();
+        let sent = bytes.clone();
+        let writer = thread::spawn(move || {
+            // Uneven writes, so that reads end anywhere in headers and payloads
+            let mut stream = a.get_ref();
+            for (i, piece) in sent.chunks(9_973).enumerate() {
+                let (head, tail) = piece.split_at(i % piece.len().max(1));
+                stream.write_all(head).unwrap();
+                stream.write_all(tail).unwrap();
+            }
+            a
+        });
+        for frame in &expected {
+            assert!(b.read_frame().unwrap() == *frame);
+        }
+        writer.join().unwrap();
+
+        // Readers of plain `Read` types zero the memory first
+        let mut reader = SfpReader::new(&bytes[..]);
+        for frame in &expected {
+            assert!(reader.read_frame().unwrap() == *frame);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::io;
+use std::io::{Write, StdinLock, StdoutLock};
+use std::net::Shutdown;
+use std::time::Duration;
+use crate::{FrameReader, FrameWriter, ConnectionController, KeepaliveConfig, FrameDecoder, Frame, FrameFlags, Framing, FramingConfig,
+            HeaderWidth, ChecksumKind, SocketAddr, WriteErr, ReadErr, frame_header, write_parts};
+use crate::source::ReadUninit;
+
+impl ReadUninit for StdinLock<'_>{}
+
+fn unsupported(what: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::Unsupported, format!("{} is not supported over stdio", what))
+}
+
+/// Frames over the stdin and stdout of the process, for filters in a pipe or peers behind ssh.
+/// Reads and writes behave like `Connection` and talk to it across the pipe,
+/// every frame is flushed once written.
+/// Holds both handles locked, other output to stdout would corrupt the stream
+#[derive(Debug)]
+pub struct StdioConnection{
+    stdin: StdinLock<'static>,
+    stdout: StdoutLock<'static>,
+    decoder: FrameDecoder,
+    hello_sent: bool,
+    next_sequence: u32,
+}
+
+impl StdioConnection{
+    pub fn new() -> Self{
+        Self{
+            stdin: io::stdin().lock(),
+            stdout: io::stdout().lock(),
+            decoder: FrameDecoder::new(),
+            hello_sent: false,
+            next_sequence: 0,
+        }
+    }
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize){
+        self.decoder.set_max_frame_len(max_frame_len)
+    }
+    pub fn max_frame_len(&self) -> usize{
+        self.decoder.max_frame_len()
+    }
+    /// See `Connection::set_magic_prefix`
+    pub fn set_magic_prefix(&mut self, magic: bool){
+        self.decoder.set_magic_prefix(magic)
+    }
+    pub fn magic_prefix(&self) -> bool{
+        self.decoder.magic_prefix()
+    }
+    /// See `Connection::set_framing_config`
+    pub fn set_framing_config(&mut self, config: FramingConfig){
+        self.decoder.set_framing_config(config)
+    }
+    pub fn framing_config(&self) -> FramingConfig{
+        self.decoder.framing_config()
+    }
+    pub fn set_framing(&mut self, framing: Framing){
+        self.decoder.set_framing(framing)
+    }
+    pub fn framing(&self) -> Framing{
+        self.decoder.framing()
+    }
+    pub fn set_header_width(&mut self, width: HeaderWidth){
+        self.decoder.set_header_width(width)
+    }
+    pub fn header_width(&self) -> HeaderWidth{
+        self.decoder.header_width()
+    }
+    /// See `Connection::set_sequence_numbers`
+    pub fn set_sequence_numbers(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.sequence_numbers = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn sequence_numbers(&self) -> bool{
+        self.decoder.extensions().sequence_numbers
+    }
+    /// See `Connection::set_checksum`, fails the same way
+    pub fn set_checksum(&mut self, kind: ChecksumKind) -> io::Result<()>{
+        kind.check_available()?;
+        let mut extensions = self.decoder.extensions();
+        extensions.checksum = kind;
+        self.decoder.set_extensions(extensions);
+        Ok(())
+    }
+    pub fn checksum(&self) -> ChecksumKind{
+        self.decoder.extensions().checksum
+    }
+    /// See `Connection::set_frame_flags`
+    pub fn set_frame_flags(&mut self, enabled: bool){
+        let mut extensions = self.decoder.extensions();
+        extensions.flags = enabled;
+        self.decoder.set_extensions(extensions)
+    }
+    pub fn frame_flags(&self) -> bool{
+        self.decoder.extensions().flags
+    }
+    pub fn read_frame_with_flags(&mut self) -> io::Result<(Vec<u8>, FrameFlags)>{
+        let frame = self.read_frame()?;
+        Ok((frame, self.decoder.flags()))
+    }
+    pub fn read_frame_checked(&mut self) -> Result<Vec<u8>, ReadErr>{
+        self.read_frame().map_err(|err| self.decoder.read_err(err))
+    }
+    pub fn write_frame_with_flags(&mut self, frame: &[u8], flags: FrameFlags) -> Result<(), WriteErr>{
+        let (header, header_len) = frame_header(&self.decoder, self.hello_sent, self.next_sequence, frame, flags)?;
+        let result = write_parts(&mut self.stdout, &[&header[..header_len], frame]);
+        if result.as_ref().err().is_none_or(|(_, written)| *written >= header_len) {
+            self.hello_sent = true;
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+        }
+        result.map_err(|(err, _)| WriteErr::Io(err))?;
+        self.stdout.flush().map_err(WriteErr::Io)
+    }
+}
+
+impl Default for StdioConnection{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader for StdioConnection{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        self.decoder.read_frame_into(&mut self.stdin, buf)
+    }
+    fn skip_frame(&mut self) -> io::Result<usize>{
+        self.decoder.skip_frame(&mut self.stdin)
+    }
+    fn read_frame_meta(&mut self) -> io::Result<Frame>{
+        let frame = self.read_frame()?;
+        Ok(Frame::new(frame, self.decoder.meta()))
+    }
+}
+
+/// Lossy: ends on the first error of any kind
+impl Iterator for StdioConnection{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().ok()
+    }
+}
+
+impl FrameWriter for StdioConnection{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame, FrameFlags::empty())
+    }
+    fn write_frame_meta(&mut self, frame: &Frame) -> Result<(), WriteErr>{
+        self.write_frame_with_flags(frame.payload(), frame.meta.flags)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        self.stdout.flush()
+    }
+}
+
+/// Stdio has no addresses or timeouts, those fail with `io::ErrorKind::Unsupported`.
+/// `shutdown` too: stdin and stdout stay open until the process exits
+impl ConnectionController for StdioConnection{
+    fn local_addr(&self) -> io::Result<SocketAddr>{
+        Err(unsupported("local_addr"))
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr>{
+        Err(unsupported("peer_addr"))
+    }
+    fn set_read_timeout(&self, _t: Option<Duration>) -> io::Result<()>{
+        Err(unsupported("set_read_timeout"))
+    }
+    fn set_write_timeout(&self, _t: Option<Duration>) -> io::Result<()>{
+        Err(unsupported("set_write_timeout"))
+    }
+    fn shutdown(&self, _t: Shutdown) -> io::Result<()>{
+        Err(unsupported("shutdown"))
+    }
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()>{
+        Err(unsupported("set_nonblocking"))
+    }
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()>{
+        Err(unsupported("set_nodelay"))
+    }
+    fn nodelay(&self) -> io::Result<bool>{
+        Err(unsupported("nodelay"))
+    }
+    fn set_keepalive_config(&self, _config: Option<KeepaliveConfig>) -> io::Result<()>{
+        Err(unsupported("keepalive"))
+    }
+    fn keepalive(&self) -> io::Result<Option<Duration>>{
+        Err(unsupported("keepalive"))
+    }
+    fn set_ttl(&self, _ttl: u32) -> io::Result<()>{
+        Err(unsupported("set_ttl"))
+    }
+    fn ttl(&self) -> io::Result<u32>{
+        Err(unsupported("ttl"))
+    }
+    fn set_linger(&self, _linger: Option<Duration>) -> io::Result<()>{
+        Err(unsupported("set_linger"))
+    }
+    fn linger(&self) -> io::Result<Option<Duration>>{
+        Err(unsupported("linger"))
+    }
+    fn set_recv_buffer_size(&self, _size: usize) -> io::Result<()>{
+        Err(unsupported("set_recv_buffer_size"))
+    }
+    fn recv_buffer_size(&self) -> io::Result<usize>{
+        Err(unsupported("recv_buffer_size"))
+    }
+    fn set_send_buffer_size(&self, _size: usize) -> io::Result<()>{
+        Err(unsupported("set_send_buffer_size"))
+    }
+    fn send_buffer_size(&self) -> io::Result<usize>{
+        Err(unsupported("send_buffer_size"))
+    }
+    fn take_error(&self) -> io::Result<Option<io::Error>>{
+        Err(unsupported("take_error"))
+    }
+}
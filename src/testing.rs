@@ -0,0 +1,339 @@
+//! In-memory connections for unit tests: frames are handed over through a queue,
+//! no socket and no system call is involved
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, Shutdown};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::{FrameReader, FrameWriter, ConnectionController, SocketAddr, WriteErr};
+use crate::decoder::eof_error;
+
+/// What a write does when the peer has `capacity` bytes of frames waiting, see `duplex`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FullPolicy{
+    /// Waits until the peer reads, up to the write timeout
+    #[default]
+    Block,
+    /// Fails with `WouldBlock` at once, as a non-blocking socket does
+    Fail,
+}
+
+/// Two connected ends, each reads what the other writes. Up to `capacity` bytes of payload wait in each direction,
+/// a frame is taken whatever its size when nothing waits. Writes past it behave by `FullPolicy`
+pub fn duplex(capacity: usize) -> (MockConnection, MockConnection){
+    let a = Arc::new(Pipe::new(capacity));
+    let b = Arc::new(Pipe::new(capacity));
+    (MockConnection::new(b.clone(), a.clone(), 1), MockConnection::new(a, b, 2))
+}
+
+#[derive(Debug)]
+struct PipeState{
+    frames: VecDeque<Vec<u8>>,
+    len: usize,
+    capacity: usize,
+    /// The writing end shut down or was dropped, reads end once the queue is empty
+    write_closed: bool,
+    /// The reading end shut down or was dropped, writes fail
+    read_closed: bool,
+}
+
+#[derive(Debug)]
+struct Pipe{
+    state: Mutex<PipeState>,
+    changed: Condvar,
+}
+
+impl Pipe{
+    fn new(capacity: usize) -> Self{
+        let state = PipeState{frames: VecDeque::new(), len: 0, capacity, write_closed: false, read_closed: false};
+        Self{state: Mutex::new(state), changed: Condvar::new()}
+    }
+    fn lock(&self) -> MutexGuard<'_, PipeState>{
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn update(&self, f: impl FnOnce(&mut PipeState)){
+        f(&mut self.lock());
+        self.changed.notify_all();
+    }
+    /// Waits for the other end to change the state, `WouldBlock` once `deadline` passed like a socket timeout
+    fn wait<'a>(&self, state: MutexGuard<'a, PipeState>, deadline: Option<Instant>) -> io::Result<MutexGuard<'a, PipeState>>{
+        let Some(deadline) = deadline else {
+            return Ok(self.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner()))
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+        }
+        Ok(self.changed.wait_timeout(state, left).unwrap_or_else(|poisoned| poisoned.into_inner()).0)
+    }
+    fn push(&self, frame: &[u8], settings: Settings) -> io::Result<()>{
+        let deadline = settings.write_timeout.and_then(|t| Instant::now().checked_add(t));
+        let mut state = self.lock();
+        loop {
+            if state.read_closed || state.write_closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "mock connection closed"))
+            }
+            if state.frames.is_empty() || state.len + frame.len() <= state.capacity {
+                state.len += frame.len();
+                state.frames.push_back(frame.to_vec());
+                self.changed.notify_all();
+                return Ok(())
+            }
+            if settings.nonblocking || settings.full_policy == FullPolicy::Fail {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "mock connection is full"))
+            }
+            state = self.wait(state, deadline)?;
+        }
+    }
+    fn pop(&self, settings: Settings) -> io::Result<Vec<u8>>{
+        let deadline = settings.read_timeout.and_then(|t| Instant::now().checked_add(t));
+        let mut state = self.lock();
+        loop {
+            if state.read_closed {
+                return Err(eof_error(false))
+            }
+            if let Some(frame) = state.frames.pop_front() {
+                state.len -= frame.len();
+                self.changed.notify_all();
+                return Ok(frame)
+            }
+            if state.write_closed {
+                return Err(eof_error(false))
+            }
+            if settings.nonblocking {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no frame waiting"))
+            }
+            state = self.wait(state, deadline)?;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Settings{
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nonblocking: bool,
+    full_policy: FullPolicy,
+}
+
+/// One end of `duplex`. Timeouts run on the real clock and expire with `WouldBlock`,
+/// the peer's end of the stream reads as `UnexpectedEof` as on `Connection`.
+/// Addresses are made up: 127.0.0.1 with port 1 for the first end and 2 for the second
+#[derive(Debug)]
+pub struct MockConnection{
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    settings: Mutex<Settings>,
+    port: u16,
+}
+
+impl MockConnection{
+    fn new(incoming: Arc<Pipe>, outgoing: Arc<Pipe>, port: u16) -> Self{
+        Self{incoming, outgoing, settings: Mutex::new(Settings::default()), port}
+    }
+    fn settings(&self) -> MutexGuard<'_, Settings>{
+        self.settings.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    pub fn set_full_policy(&self, policy: FullPolicy){
+        self.settings().full_policy = policy;
+    }
+    pub fn full_policy(&self) -> FullPolicy{
+        self.settings().full_policy
+    }
+    /// Frames written and not read by the peer yet
+    pub fn queued_frames(&self) -> usize{
+        self.outgoing.lock().frames.len()
+    }
+}
+
+impl Drop for MockConnection{
+    fn drop(&mut self) {
+        self.outgoing.update(|state| state.write_closed = true);
+        self.incoming.update(|state| state.read_closed = true);
+    }
+}
+
+impl FrameReader for MockConnection{
+    fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>{
+        let frame = self.incoming.pop(*self.settings())?;
+        buf.clear();
+        buf.extend_from_slice(&frame);
+        Ok(frame.len())
+    }
+    fn read_frame(&mut self) -> io::Result<Vec<u8>>{
+        self.incoming.pop(*self.settings())
+    }
+}
+
+impl FrameWriter for MockConnection{
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), WriteErr>{
+        self.outgoing.push(frame, *self.settings()).map_err(WriteErr::Io)
+    }
+    /// Frames are handed over as they are written
+    fn flush(&mut self) -> io::Result<()>{
+        Ok(())
+    }
+}
+
+/// The buffer sizes are the capacities of the two directions
+impl ConnectionController for MockConnection{
+    fn local_addr(&self) -> io::Result<SocketAddr>{
+        Ok(SocketAddr::Inet((Ipv4Addr::LOCALHOST, self.port).into()))
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr>{
+        Ok(SocketAddr::Inet((Ipv4Addr::LOCALHOST, 3 - self.port).into()))
+    }
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.settings().read_timeout = t;
+        Ok(())
+    }
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>{
+        self.settings().write_timeout = t;
+        Ok(())
+    }
+    fn shutdown(&self, t: Shutdown) -> io::Result<()>{
+        if t != Shutdown::Write {
+            self.incoming.update(|state| state.read_closed = true);
+        }
+        if t != Shutdown::Read {
+            self.outgoing.update(|state| state.write_closed = true);
+        }
+        Ok(())
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>{
+        self.settings().nonblocking = nonblocking;
+        Ok(())
+    }
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()>{
+        Ok(())
+    }
+    fn nodelay(&self) -> io::Result<bool>{
+        Ok(true)
+    }
+    fn set_recv_buffer_size(&self, size: usize) -> io::Result<()>{
+        self.incoming.update(|state| state.capacity = size);
+        Ok(())
+    }
+    fn recv_buffer_size(&self) -> io::Result<usize>{
+        Ok(self.incoming.lock().capacity)
+    }
+    fn set_send_buffer_size(&self, size: usize) -> io::Result<()>{
+        self.outgoing.update(|state| state.capacity = size);
+        Ok(())
+    }
+    fn send_buffer_size(&self) -> io::Result<usize>{
+        Ok(self.outgoing.lock().capacity)
+    }
+    fn take_error(&self) -> io::Result<Option<io::Error>>{
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::thread;
+    use super::*;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    fn write_err(connection: &mut MockConnection, frame: &[u8]) -> io::ErrorKind{
+        match connection.write_frame(frame) {
+            Err(WriteErr::Io(err)) => err.kind(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn frames_cross_in_both_directions(){
+        let (mut a, mut b) = duplex(1024);
+        a.write_frame(b"to b").unwrap();
+        b.write_frame(b"to a").unwrap();
+        a.write_frame(b"").unwrap();
+        assert_eq!(a.queued_frames(), 2);
+        assert_eq!(b.read_frame().unwrap(), b"to b");
+        assert_eq!(b.read_frame().unwrap(), b"");
+        assert_eq!(a.read_frame().unwrap(), b"to a");
+        assert_eq!(a.local_addr().unwrap(), b.peer_addr().unwrap());
+    }
+
+    #[test]
+    fn full_direction_blocks_until_the_peer_reads(){
+        let (mut a, mut b) = duplex(10);
+        a.write_frame(&[1u8; 6]).unwrap();
+        let reader = thread::spawn(move || {
+            thread::sleep(WAIT);
+            (b.read_frame().unwrap(), b.read_frame().unwrap())
+        });
+        let start = Instant::now();
+        a.write_frame(&[2u8; 6]).unwrap();
+        assert!(start.elapsed() >= WAIT);
+        assert_eq!(reader.join().unwrap(), (vec![1u8; 6], vec![2u8; 6]));
+    }
+
+    #[test]
+    fn full_direction_times_out_or_fails_at_once(){
+        let (mut a, _b) = duplex(10);
+        a.write_frame(&[1u8; 10]).unwrap();
+        a.set_write_timeout(Some(WAIT)).unwrap();
+        let start = Instant::now();
+        assert_eq!(write_err(&mut a, b"x"), io::ErrorKind::WouldBlock);
+        assert!(start.elapsed() >= WAIT);
+
+        a.set_write_timeout(None).unwrap();
+        a.set_full_policy(FullPolicy::Fail);
+        let start = Instant::now();
+        assert_eq!(write_err(&mut a, b"x"), io::ErrorKind::WouldBlock);
+        assert!(start.elapsed() < WAIT);
+        assert_eq!(a.queued_frames(), 1);
+    }
+
+    #[test]
+    fn frame_over_the_capacity_goes_through_an_empty_direction(){
+        let (mut a, mut b) = duplex(10);
+        a.set_full_policy(FullPolicy::Fail);
+        a.write_frame(&[1u8; 100]).unwrap();
+        assert_eq!(write_err(&mut a, b"x"), io::ErrorKind::WouldBlock);
+        assert_eq!(b.read_frame().unwrap(), [1u8; 100]);
+        a.write_frame(b"x").unwrap();
+    }
+
+    #[test]
+    fn reads_time_out_and_fail_when_nonblocking(){
+        let (_a, mut b) = duplex(10);
+        b.set_read_timeout(Some(WAIT)).unwrap();
+        let start = Instant::now();
+        assert_eq!(b.read_frame().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(start.elapsed() >= WAIT);
+        b.set_read_timeout(None).unwrap();
+        b.set_nonblocking(true).unwrap();
+        assert_eq!(b.read_frame().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn dropped_end_is_eof_after_its_queued_frames(){
+        let (mut a, mut b) = duplex(1024);
+        a.write_frame(b"last").unwrap();
+        drop(a);
+        assert_eq!(b.read_frame().unwrap(), b"last");
+        assert_eq!(b.read_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(write_err(&mut b, b"nobody"), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn drop_wakes_up_a_blocked_reader(){
+        let (a, mut b) = duplex(1024);
+        let reader = thread::spawn(move || b.read_frame().unwrap_err().kind());
+        thread::sleep(WAIT);
+        drop(a);
+        assert_eq!(reader.join().unwrap(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn shutdown_write_ends_the_stream_of_the_peer_only(){
+        let (mut a, mut b) = duplex(1024);
+        a.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(b.read_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        b.write_frame(b"still open").unwrap();
+        assert_eq!(a.read_frame().unwrap(), b"still open");
+    }
+}
@@ -0,0 +1,988 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+use crate::*;
+
+fn pair() -> (Connection, Connection){
+    Connection::pair().expect("socket pair")
+}
+
+/// Hands out at most one byte per read
+struct OneByte<R>(R);
+
+impl<R: Read> Read for OneByte<R>{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+/// Bytes written to the socket of `connection` as they are, bypassing the framing
+fn write_raw(connection: &Connection, bytes: &[u8]){
+    let mut stream = connection.get_ref();
+    stream.write_all(bytes).expect("raw write");
+}
+
+#[test]
+fn partly_read_frame_reader_leaves_the_next_frame_intact(){
+    let (mut a, mut b) = pair();
+    a.write_frame(&[1u8; 10_000]).unwrap();
+    a.write_frame(b"next").unwrap();
+    {
+        let mut payload = b.frame_reader().unwrap();
+        let mut start = [0u8; 100];
+        payload.read_exact(&mut start).unwrap();
+        assert_eq!(start, [1u8; 100]);
+        assert_eq!(payload.remaining(), 9_900);
+    }
+    assert_eq!(b.read_frame().unwrap(), b"next");
+}
+
+#[test]
+fn frame_reader_dropped_before_the_payload_arrived_is_skipped_by_the_next_read(){
+    let (a, mut b) = pair();
+    write_raw(&a, &8u32.to_be_bytes());
+    write_raw(&a, b"abc");
+    b.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    {
+        let mut payload = b.frame_reader().unwrap();
+        let mut start = [0u8; 2];
+        payload.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"ab");
+    }
+    write_raw(&a, b"defgh");
+    write_raw(&a, &2u32.to_be_bytes());
+    write_raw(&a, b"ok");
+    b.set_read_timeout(None).unwrap();
+    assert_eq!(b.read_frame().unwrap(), b"ok");
+}
+
+#[test]
+fn nonblocking_reads_resume_after_every_byte(){
+    let (a, mut b) = pair();
+    b.set_nonblocking(true).unwrap();
+    let mut bytes = Vec::new();
+    for frame in [&b"first"[..], b"", b"third frame"] {
+        bytes.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(frame);
+    }
+    let mut frames = Vec::new();
+    for byte in &bytes {
+        assert_eq!(b.try_read_frame().unwrap(), None);
+        write_raw(&a, &[*byte]);
+        while let Some(frame) = b.try_read_frame().unwrap() {
+            frames.push(frame);
+        }
+    }
+    assert_eq!(frames, [&b"first"[..], b"", b"third frame"]);
+}
+
+#[test]
+fn multi_byte_strings_split_across_reads_round_trip(){
+    let texts = ["", "h\u{e9}llo", "\u{65e5}\u{672c}\u{8a9e}", "\u{1f980} crab", "mixed \u{e9}\u{1f980}\u{65e5}"];
+    let mut encoder = FrameEncoder::new(Vec::new());
+    for text in texts {
+        encoder.write_frame_str(text).unwrap();
+    }
+    let mut reader = SfpReader::new(OneByte(std::io::Cursor::new(encoder.into_inner())));
+    for text in texts {
+        assert_eq!(reader.read_frame_string().unwrap(), text);
+    }
+}
+
+#[test]
+fn multi_byte_strings_split_at_the_header_round_trip(){
+    let (a, mut b) = pair();
+    let text = "\u{1f980}\u{65e5}\u{e9}";
+    let mut frame = (text.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(text.as_bytes());
+    let writer = std::thread::spawn(move || {
+        // The header ends partway through the first write, a character partway through every other
+        for piece in [&frame[..2], &frame[2..6], &frame[6..9], &frame[9..]] {
+            write_raw(&a, piece);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        a
+    });
+    assert_eq!(b.read_frame_string().unwrap(), text);
+    writer.join().unwrap();
+}
+
+#[test]
+fn invalid_utf8_hands_back_the_frame(){
+    let (mut a, mut b) = pair();
+    a.write_frame(&[b'o', b'k', 0xE6, 0x97]).unwrap();
+    a.write_frame_str("next").unwrap();
+    match b.read_frame_string() {
+        Err(ReadStringErr::InvalidUtf8(err)) => assert_eq!(err.into_bytes(), [b'o', b'k', 0xE6, 0x97]),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(b.read_frame_string().unwrap(), "next");
+}
+
+#[test]
+fn filtered_keepalives_restart_the_read_timeout(){
+    let (mut a, mut b) = pair();
+    b.set_filter_empty_frames(true);
+    b.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let start = std::time::Instant::now();
+    let writer = std::thread::spawn(move || {
+        // Keepalives for three times the timeout, each well within it
+        for _ in 0..12 {
+            std::thread::sleep(Duration::from_millis(50));
+            a.write_keepalive().unwrap();
+        }
+        a.write_frame(b"data").unwrap();
+        a
+    });
+    assert_eq!(b.read_frame().unwrap(), b"data");
+    assert!(start.elapsed() >= Duration::from_millis(600));
+    assert!(b.last_read_at().unwrap() >= start + Duration::from_millis(600));
+    // Kept open, a closed peer would end the read instead
+    let _a = writer.join().unwrap();
+
+    // Nothing at all for the whole timeout still times out
+    let err = b.read_frame().unwrap_err();
+    assert!(matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut), "{:?}", err);
+}
+
+#[test]
+fn frames_after_random_junk_arrive_intact_after_resync(){
+    let (mut a, mut b) = pair();
+    a.set_magic_prefix(true);
+    b.set_magic_prefix(true);
+    let mut state = 0x2545_f491u32;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    let mut sent = Vec::new();
+    let mut wire = Vec::new();
+    for i in 0..50usize {
+        let frame: Vec<u8> = (0..random() % 2000).map(|_| random() as u8).collect();
+        sent.push(frame);
+        // Junk in between, never holding the magic
+        let junk: Vec<u8> = (0..1 + random() % 300).map(|_| random() as u8)
+            .filter(|byte| *byte != FRAME_MAGIC[0]).collect();
+        wire.push((sent[i].clone(), if i % 3 != 2 && i < 49 { junk } else { Vec::new() }));
+    }
+    std::thread::spawn(move || {
+        for (frame, junk) in wire {
+            a.write_frame(&frame).unwrap();
+            write_raw(&a, &junk);
+        }
+    });
+    let mut received = Vec::new();
+    loop {
+        match b.read_frame_checked() {
+            Ok(frame) => received.push(frame),
+            Err(ReadErr::Desynchronized{..}) => { b.resync().unwrap(); }
+            Err(ReadErr::Disconnected) => break,
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn checksum_mismatch_fails_every_following_read(){
+    let (a, mut b) = pair();
+    b.set_checksum(ChecksumKind::Crc32).unwrap();
+    let mut encoder = FrameEncoder::new(Vec::new());
+    encoder.set_checksum(ChecksumKind::Crc32).unwrap();
+    encoder.write_frame(b"intact").unwrap();
+    encoder.write_frame(b"corrupted").unwrap();
+    encoder.write_frame(b"after").unwrap();
+    let mut bytes = encoder.into_inner();
+    let at = bytes.windows(9).position(|window| window == b"corrupted").unwrap();
+    bytes[at + 4] ^= 0x10;
+    write_raw(&a, &bytes);
+    assert_eq!(b.read_frame().unwrap(), b"intact");
+    assert!(matches!(b.read_frame_checked(), Err(ReadErr::ChecksumMismatch{..})));
+    assert!(matches!(b.read_frame_checked(), Err(ReadErr::ChecksumMismatch{..})));
+}
+
+#[test]
+fn plain_peer_of_a_checksumming_reader_is_a_mode_mismatch(){
+    let (mut a, mut b) = pair();
+    b.set_checksum(ChecksumKind::Crc32).unwrap();
+    a.write_frame(b"plain").unwrap();
+    match b.read_frame_checked() {
+        Err(ReadErr::ModeMismatch{local, peer}) => {
+            assert_eq!(local.checksum, ChecksumKind::Crc32);
+            assert_eq!(peer, Extensions::default());
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn u16_prefix_takes_65535_bytes_and_rejects_65536(){
+    let (mut a, mut b) = pair();
+    a.set_header_width(HeaderWidth::U16);
+    b.set_header_width(HeaderWidth::U16);
+    let writer = std::thread::spawn(move || {
+        a.write_frame(&[7u8; 65_535]).unwrap();
+        assert!(matches!(a.write_frame(&[7u8; 65_536]), Err(WriteErr::TooLongFrame)));
+        a.write_frame(b"after").unwrap();
+        a
+    });
+    assert_eq!(b.read_frame().unwrap(), [7u8; 65_535]);
+    assert_eq!(b.read_frame().unwrap(), b"after");
+    writer.join().unwrap();
+}
+
+#[test]
+fn wider_prefixes_take_65536_bytes(){
+    for width in [HeaderWidth::U32, HeaderWidth::U64] {
+        let (mut a, mut b) = pair();
+        a.set_header_width(width);
+        b.set_header_width(width);
+        let writer = std::thread::spawn(move || {
+            a.write_frame(&[7u8; 65_535]).unwrap();
+            a.write_frame(&[8u8; 65_536]).unwrap();
+            a
+        });
+        assert_eq!(b.read_frame().unwrap(), [7u8; 65_535]);
+        assert_eq!(b.read_frame().unwrap(), [8u8; 65_536]);
+        writer.join().unwrap();
+    }
+}
+
+/// Sparse file of `len` bytes whose first and last bytes are 1 and 2, zeros in between
+fn sparse_file(len: u64) -> std::fs::File{
+    use std::io::{Seek, SeekFrom};
+    let mut file = tempfile::tempfile().unwrap();
+    file.set_len(len).unwrap();
+    file.write_all(&[1]).unwrap();
+    file.seek(SeekFrom::Start(len - 1)).unwrap();
+    file.write_all(&[2]).unwrap();
+    file
+}
+
+/// Reads a frame of `len` bytes written from `sparse_file` without holding it in memory
+fn read_sparse_frame(connection: &mut Connection, len: u64){
+    let mut payload = connection.frame_reader().unwrap();
+    assert_eq!(payload.remaining() as u64, len);
+    let mut buf = vec![0u8; 1 << 20];
+    let (mut total, mut last) = (0u64, 0u8);
+    loop {
+        let n = payload.read(&mut buf).unwrap();
+        if n == 0 {
+            break
+        }
+        if total == 0 {
+            assert_eq!(buf[0], 1);
+        }
+        total += n as u64;
+        last = buf[n - 1];
+    }
+    assert_eq!((total, last), (len, 2));
+}
+
+#[test]
+fn four_gib_boundaries_round_trip(){
+    const FOUR_GIB: u64 = 1 << 32;
+    let (mut a, mut b) = pair();
+    b.set_max_frame_len(usize::MAX);
+    let writer = std::thread::spawn(move || {
+        let file = sparse_file(FOUR_GIB + 1);
+        a.write_frame_from_file(&file, 0, FOUR_GIB - 1).unwrap();
+        assert!(matches!(a.write_frame_from_file(&file, 0, FOUR_GIB), Err(WriteErr::TooLongFrame)));
+        a.write_frame(b"fits a u32").unwrap();
+        a
+    });
+    // The frame ends one byte short of the last byte of the file
+    let mut payload = b.frame_reader().unwrap();
+    assert_eq!(payload.remaining() as u64, FOUR_GIB - 1);
+    assert_eq!(std::io::copy(&mut payload, &mut std::io::sink()).unwrap(), FOUR_GIB - 1);
+    drop(payload);
+    assert_eq!(b.read_frame().unwrap(), b"fits a u32");
+    let mut a = writer.join().unwrap();
+
+    a.set_header_width(HeaderWidth::U64);
+    b.set_header_width(HeaderWidth::U64);
+    let writer = std::thread::spawn(move || {
+        for len in [FOUR_GIB, FOUR_GIB + 1] {
+            a.write_frame_from_file(&sparse_file(len), 0, len).unwrap();
+        }
+    });
+    read_sparse_frame(&mut b, FOUR_GIB);
+    read_sparse_frame(&mut b, FOUR_GIB + 1);
+    writer.join().unwrap();
+}
+
+#[test]
+fn broadcast_payload_is_left_untouched(){
+    let payload: Vec<u8> = (0..=255u8).cycle().take(3000).collect();
+    let original = payload.clone();
+    let (mut plain, mut plain_peer) = pair();
+    let (mut extended, mut extended_peer) = pair();
+    extended.set_checksum(ChecksumKind::Crc32).unwrap();
+    extended.set_sequence_numbers(true);
+    extended.set_magic_prefix(true);
+    extended.set_pad_to(Some(4096));
+    extended_peer.set_checksum(ChecksumKind::Crc32).unwrap();
+    extended_peer.set_sequence_numbers(true);
+    extended_peer.set_magic_prefix(true);
+    extended_peer.set_strip_padding(true);
+    let (a, mut half_peer) = pair();
+    let (_reader, mut half) = a.separate().unwrap();
+    // The same buffer goes to every peer
+    for _ in 0..3 {
+        plain.write_frame(&payload).unwrap();
+        extended.write_frame(&payload).unwrap();
+        half.write_frame(&payload).unwrap();
+        assert_eq!(payload, original);
+    }
+    for _ in 0..3 {
+        assert_eq!(plain_peer.read_frame().unwrap(), original);
+        assert_eq!(extended_peer.read_frame().unwrap(), original);
+        assert_eq!(half_peer.read_frame().unwrap(), original);
+    }
+}
+
+/// Reads what arrived without blocking, checking that it ends at a frame boundary, and returns the frames
+/// completed so far
+fn frames_so_far(connection: &Connection, received: &mut Vec<u8>) -> Vec<Vec<u8>>{
+    connection.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 65_536];
+    loop {
+        match connection.get_ref().read(&mut buf) {
+            Ok(n) if n > 0 => received.extend_from_slice(&buf[..n]),
+            Ok(_) => break,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+    connection.set_nonblocking(false).unwrap();
+    let (mut frames, mut rest) = (Vec::new(), &received[..]);
+    while !rest.is_empty() {
+        assert!(rest.len() >= 4, "partial header on the wire");
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        assert!(rest.len() >= 4 + len, "partial frame on the wire");
+        frames.push(rest[4..4 + len].to_vec());
+        rest = &rest[4 + len..];
+    }
+    frames
+}
+
+#[test]
+fn buffered_writes_only_ever_send_whole_frames(){
+    let policies = [
+        FlushPolicy::explicit(),
+        FlushPolicy::explicit().max_bytes(1000),
+        FlushPolicy::explicit().max_frames(3),
+        FlushPolicy::explicit().max_bytes(700).max_frames(5),
+    ];
+    for policy in policies {
+        let (mut a, b) = pair();
+        a.set_flush_policy(policy);
+        let mut received = Vec::new();
+        let mut written = Vec::new();
+        for i in 0..60usize {
+            // Frames both smaller and larger than the byte limit
+            let frame = vec![i as u8; (i * 37) % 1500];
+            a.write_frame(&frame).unwrap();
+            written.push(frame);
+            let frames = frames_so_far(&b, &mut received);
+            assert!(written.starts_with(&frames));
+        }
+        a.flush().unwrap();
+        assert_eq!(frames_so_far(&b, &mut received), written);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn frame_from_file_is_exactly_the_region(){
+    use std::io::{Seek, SeekFrom};
+    let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&contents).unwrap();
+    file.seek(SeekFrom::Start(10)).unwrap();
+    let regions = [(0, 0), (0, 1), (12_345, 500_000), (999_999, 1), (1, 999_999), (0, 1_000_000)];
+    let (mut a, mut b) = pair();
+    let sender = std::thread::spawn(move || {
+        for (offset, len) in regions {
+            a.write_frame_from_file(&file, offset, len).unwrap();
+        }
+        // The position of the file is left alone
+        assert_eq!(file.stream_position().unwrap(), 10);
+    });
+    for (offset, len) in regions {
+        let region = &contents[offset as usize..(offset + len) as usize];
+        assert!(b.read_frame().unwrap() == region, "{} bytes from {}", len, offset);
+    }
+    sender.join().unwrap();
+}
+
+#[test]
+fn write_timeout_before_any_byte_leaves_the_connection_usable(){
+    const DECLARED: usize = 4 * 1024 * 1024;
+    let (mut a, mut b) = pair();
+    b.set_max_frame_len(DECLARED);
+    // A frame far longer than the socket buffer, sent raw until nothing more fits
+    write_raw(&a, &(DECLARED as u32).to_be_bytes());
+    a.set_nonblocking(true).unwrap();
+    let mut sent = 0;
+    let chunk = [3u8; 4096];
+    loop {
+        match a.get_ref().write(&chunk) {
+            Ok(n) => sent += n,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+    a.set_nonblocking(false).unwrap();
+    assert!(matches!(a.write_frame_timeout(b"clean", Duration::from_millis(100)), Err(WriteErr::Timeout)));
+    assert!(!a.is_desynced());
+
+    let reader = std::thread::spawn(move || (b.read_frame().unwrap().len(), b.read_frame().unwrap()));
+    write_raw(&a, &vec![3u8; DECLARED - sent]);
+    a.write_frame(b"after").unwrap();
+    assert_eq!(reader.join().unwrap(), (DECLARED, b"after".to_vec()));
+}
+
+#[test]
+fn write_timeout_partway_through_a_frame_desynchronizes_the_writer(){
+    let (mut a, mut b) = pair();
+    b.set_max_frame_len(8 * 1024 * 1024);
+    assert!(matches!(a.write_frame_timeout(&vec![1u8; 8 * 1024 * 1024], Duration::from_millis(100)), Err(WriteErr::Timeout)));
+    assert!(a.is_desynced());
+    assert!(matches!(a.write_frame(b"after"), Err(WriteErr::Desynchronized)));
+    // The peer is left with the start of a frame that never ends
+    b.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(matches!(b.read_frame_checked(), Err(ReadErr::TimeoutMidFrame)));
+}
+
+/// Hands out `ok` bytes, then fails
+struct FailingSource{
+    ok: usize,
+}
+
+impl Read for FailingSource{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.ok == 0 {
+            return Err(std::io::Error::other("source failed"))
+        }
+        let n = buf.len().min(self.ok);
+        buf[..n].fill(9);
+        self.ok -= n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn poisoned_writer_refuses_frames_and_flush_still_works(){
+    let (mut a, mut b) = pair();
+    a.set_flush_policy(FlushPolicy::explicit());
+    a.write_frame(b"queued one").unwrap();
+    a.write_frame(b"queued two").unwrap();
+    assert!(matches!(a.write_frame_from_reader(&mut FailingSource{ok: 10}, 100), Err(WriteErr::Io(_))));
+    assert_eq!(a.writer_state(), WriterState::Poisoned{frames_lost: 1});
+    assert!(matches!(a.write_frame(b"refused"), Err(WriteErr::Desynchronized)));
+    assert!(matches!(a.write_frames([&b"refused"[..], b"too"]), Err(WriteErr::Desynchronized)));
+    assert_eq!(a.writer_state(), WriterState::Poisoned{frames_lost: 4});
+    a.flush().unwrap();
+
+    // The frames queued before the poisoned one are whole, then the stream stops inside it
+    assert_eq!(b.read_frame().unwrap(), b"queued one");
+    assert_eq!(b.read_frame().unwrap(), b"queued two");
+    b.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert!(matches!(b.read_frame_checked(), Err(ReadErr::TimeoutMidFrame)));
+
+    a.reset_after_reconnect();
+    assert_eq!(a.writer_state(), WriterState::Healthy);
+    a.write_frame(b"after a reset").unwrap();
+}
+
+#[test]
+fn write_bandwidth_limit_paces_frames(){
+    let (mut a, mut b) = pair();
+    a.set_write_bandwidth_limit(Some(10_000));
+    let reader = std::thread::spawn(move || (0..3).map(|_| b.read_frame().unwrap().len()).collect::<Vec<_>>());
+    let start = std::time::Instant::now();
+    // A second worth of bytes, headers included, goes at once
+    a.write_frame(&[0u8; 9_996]).unwrap();
+    let err = match a.try_write_frame(&[0u8; 996]) {
+        Err(WriteErr::Io(err)) => err,
+        other => panic!("{:?}", other),
+    };
+    let retry_after = err.get_ref().and_then(|inner| inner.downcast_ref::<WouldExceed>()).unwrap().retry_after;
+    assert!(retry_after > Duration::from_millis(50) && retry_after <= Duration::from_millis(100), "{:?}", retry_after);
+    a.write_frame(&[0u8; 996]).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(90));
+    a.write_frame(&[0u8; 996]).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(190));
+    assert_eq!(reader.join().unwrap(), [9_996, 996, 996]);
+}
+
+#[test]
+fn coalescing_batches_small_frames_and_lets_large_ones_through(){
+    let (mut a, b) = pair();
+    a.set_write_coalescing(Some(WriteCoalescing{max_delay: Duration::from_secs(60), max_bytes: 1000}));
+    let mut received = Vec::new();
+    let mut written = Vec::new();
+    // 54 bytes a frame with its header, the 19th reaches the limit
+    for i in 0..18u8 {
+        written.push(vec![i; 50]);
+        a.write_frame(&written[i as usize]).unwrap();
+        assert!(frames_so_far(&b, &mut received).is_empty());
+    }
+    written.push(vec![18; 50]);
+    a.write_frame(&written[18]).unwrap();
+    assert_eq!(frames_so_far(&b, &mut received), written);
+
+    // A frame past the limit on its own goes out at once, with what was buffered before it
+    a.write_frame(b"small").unwrap();
+    a.write_frame(&[1u8; 5000]).unwrap();
+    written.extend([b"small".to_vec(), vec![1u8; 5000]]);
+    assert_eq!(frames_so_far(&b, &mut received), written);
+
+    a.write_frame(b"urgent").unwrap();
+    assert_eq!(frames_so_far(&b, &mut received), written);
+    a.write_frame_now(b"now").unwrap();
+    written.extend([b"urgent".to_vec(), b"now".to_vec()]);
+    assert_eq!(frames_so_far(&b, &mut received), written);
+    a.write_frame(b"flushed").unwrap();
+    a.flush().unwrap();
+    written.push(b"flushed".to_vec());
+    assert_eq!(frames_so_far(&b, &mut received), written);
+}
+
+#[test]
+fn coalesced_frames_wait_at_most_the_delay(){
+    let max_delay = Duration::from_millis(50);
+    let (mut a, b) = pair();
+    a.set_write_coalescing(Some(WriteCoalescing{max_delay, max_bytes: 1 << 20}));
+    let mut received = Vec::new();
+    let written_at = std::time::Instant::now();
+    a.write_frame(b"first").unwrap();
+    let deadline = a.flush_deadline().unwrap();
+    assert!(deadline >= written_at + max_delay && deadline <= std::time::Instant::now() + max_delay);
+    // Nothing is due before the deadline
+    a.flush_expired().unwrap();
+    a.write_frame(b"second").unwrap();
+    assert!(frames_so_far(&b, &mut received).is_empty());
+    // An event loop waking up at the deadline sends both
+    std::thread::sleep(deadline.saturating_duration_since(std::time::Instant::now()));
+    a.flush_expired().unwrap();
+    assert_eq!(frames_so_far(&b, &mut received), [b"first".to_vec(), b"second".to_vec()]);
+    assert!(written_at.elapsed() < max_delay * 3);
+    assert_eq!(a.flush_deadline(), None);
+
+    // Without the event loop, the next write sends what waited too long
+    a.write_frame(b"third").unwrap();
+    std::thread::sleep(max_delay);
+    a.write_frame(b"fourth").unwrap();
+    assert_eq!(frames_so_far(&b, &mut received).len(), 4);
+}
+
+#[test]
+fn vectored_frames_with_empty_parts_round_trip(){
+    use std::io::IoSlice;
+    let (mut a, mut b) = pair();
+    a.set_checksum(ChecksumKind::Crc32).unwrap();
+    b.set_checksum(ChecksumKind::Crc32).unwrap();
+    let empty = IoSlice::new(b"");
+    let frames: [&[IoSlice<'_>]; 5] = [
+        &[],
+        &[empty],
+        &[empty, empty, empty],
+        &[empty, IoSlice::new(b"ab"), empty, IoSlice::new(b"c"), empty],
+        &[IoSlice::new(b"end"), empty],
+    ];
+    for parts in frames {
+        a.write_frame_vectored(parts).unwrap();
+    }
+    for expected in [&b""[..], b"", b"", b"abc", b"end"] {
+        assert_eq!(b.read_frame().unwrap(), expected);
+    }
+}
+
+#[test]
+fn write_bandwidth_limit_paces_vectored_frames(){
+    use std::io::IoSlice;
+    let (mut a, mut b) = pair();
+    a.set_write_bandwidth_limit(Some(10_000));
+    let reader = std::thread::spawn(move || (0..3).map(|_| b.read_frame().unwrap().len()).collect::<Vec<_>>());
+    let start = std::time::Instant::now();
+    let (head, tail) = ([0u8; 496], [0u8; 500]);
+    a.write_frame_vectored(&[IoSlice::new(&[0u8; 4_996]), IoSlice::new(&[0u8; 5_000])]).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(50));
+    a.write_frame_vectored(&[IoSlice::new(&head), IoSlice::new(&tail)]).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(90));
+    a.write_frame_vectored(&[IoSlice::new(&head), IoSlice::new(b""), IoSlice::new(&tail)]).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(190));
+    assert_eq!(reader.join().unwrap(), [9_996, 996, 996]);
+}
+
+#[test]
+fn padded_frames_round_trip_at_one_size_on_the_wire(){
+    use std::io::IoSlice;
+    let (mut a, wire) = pair();
+    a.set_pad_to(Some(256));
+    let frames: [&[u8]; 5] = [b"", b"x", &[7u8; 100], &[8u8; 252], b"vectored"];
+    for frame in &frames[..4] {
+        a.write_frame(frame).unwrap();
+    }
+    a.write_frame_vectored(&[IoSlice::new(b"vec"), IoSlice::new(b"tored")]).unwrap();
+    let mut received = Vec::new();
+    let on_wire = frames_so_far(&wire, &mut received);
+    assert!(on_wire.iter().all(|frame| frame.len() == 256), "{:?}", on_wire.iter().map(Vec::len).collect::<Vec<_>>());
+
+    let mut reader = SfpReader::new(std::io::Cursor::new(received));
+    reader.set_strip_padding(true);
+    for frame in frames {
+        assert_eq!(reader.read_frame().unwrap(), frame);
+    }
+}
+
+#[test]
+fn frames_too_long_for_the_pad_size_follow_the_overflow_policy(){
+    let (mut a, mut b) = pair();
+    a.set_pad_to(Some(256));
+    b.set_strip_padding(true);
+    assert!(matches!(a.write_frame(&[1u8; 253]), Err(WriteErr::TooLongFrame)));
+    a.write_frame(b"still padded").unwrap();
+    a.set_pad_overflow(PadOverflow::Unpadded);
+    a.write_frame(&[2u8; 1000]).unwrap();
+    a.write_frame(b"padded again").unwrap();
+    assert_eq!(b.read_frame().unwrap(), b"still padded");
+    assert_eq!(b.read_frame().unwrap(), [2u8; 1000]);
+    assert_eq!(b.read_frame().unwrap(), b"padded again");
+}
+
+#[test]
+fn pair_round_trips_both_ways(){
+    let (mut a, mut b) = Connection::pair().unwrap();
+    a.write_frame(b"to b").unwrap();
+    b.write_frame(b"to a").unwrap();
+    assert_eq!(b.read_frame().unwrap(), b"to b");
+    assert_eq!(a.read_frame().unwrap(), b"to a");
+    // More than the socket buffers hold
+    let writer = std::thread::spawn(move || {
+        a.write_frame(&[9u8; 1 << 20]).unwrap();
+        a
+    });
+    assert!(b.read_frame().unwrap() == [9u8; 1 << 20]);
+    drop(writer.join().unwrap());
+    assert!(matches!(b.read_frame_checked(), Err(ReadErr::Disconnected)));
+}
+
+#[test]
+fn into_inner_gives_back_the_bytes_past_the_last_frame(){
+    let (mut a, mut b) = pair();
+    a.write_frame(b"frame").unwrap();
+    write_raw(&a, b"another protocol");
+    assert_eq!(b.read_frame().unwrap(), b"frame");
+    let (mut stream, mut rest) = b.into_inner().unwrap();
+    while rest.len() < b"another protocol".len() {
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).unwrap();
+        rest.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(rest, b"another protocol");
+}
+
+#[test]
+fn into_inner_gives_the_connection_back_with_a_peeked_frame(){
+    let (mut a, mut b) = pair();
+    a.write_frame(b"peeked").unwrap();
+    assert_eq!(b.peek_frame().unwrap(), b"peeked");
+    let err = b.into_inner().unwrap_err();
+    assert_eq!(err.error.kind(), std::io::ErrorKind::InvalidInput);
+    let mut b = *err.connection;
+    assert_eq!(b.read_frame().unwrap(), b"peeked");
+}
+
+#[test]
+fn into_inner_flushes_first_and_returns_the_error(){
+    let (mut a, mut b) = pair();
+    b.set_flush_policy(FlushPolicy::explicit());
+    b.write_frame(b"buffered").unwrap();
+    let (_stream, rest) = b.into_inner().unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(a.read_frame().unwrap(), b"buffered");
+
+    // Nothing reads from `b`, the socket cannot take the whole frame
+    a.set_nonblocking(true).unwrap();
+    a.set_flush_policy(FlushPolicy::explicit());
+    a.write_frame(&vec![7u8; 16 * 1024 * 1024]).unwrap();
+    let err = a.into_inner().unwrap_err();
+    assert_eq!(err.error.kind(), std::io::ErrorKind::WouldBlock);
+    assert!(err.connection.buffered_len() > 0);
+}
+
+#[test]
+fn stats_match_on_both_ends_of_a_loopback_connection(){
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = unisocket::SocketAddr::Inet(listener.local_addr().unwrap());
+    let server = Server::from(unisocket::Listener::Inet(listener));
+    let mut client = Connection::connect(&addr).unwrap();
+    let (mut accepted, _) = server.accept().unwrap();
+
+    let lens = [0usize, 1, 100, 70_000];
+    for len in lens {
+        client.write_frame(&vec![3u8; len]).unwrap();
+    }
+    client.write_keepalive().unwrap();
+    for len in lens {
+        assert_eq!(accepted.read_frame().unwrap().len(), len);
+    }
+    assert!(accepted.read_frame().unwrap().is_empty());
+    accepted.write_frames([&b"one"[..], b"two"]).unwrap();
+    for _ in 0..2 {
+        client.read_frame().unwrap();
+    }
+
+    let (sent, received) = (client.stats(), accepted.stats());
+    assert_eq!(sent.frames_written, 5);
+    assert_eq!(sent.bytes_written, 70_101);
+    assert_eq!((sent.frames_written, sent.bytes_written), (received.frames_read, received.bytes_read));
+    assert_eq!((sent.frames_read, sent.bytes_read), (received.frames_written, received.bytes_written));
+    assert_eq!((sent.frames_read, sent.bytes_read), (2, 6));
+    assert_eq!((sent.read_errors, sent.write_errors, received.read_errors, received.write_errors), (0, 0, 0, 0));
+    assert_eq!(accepted.try_clone().unwrap().stats(), received);
+
+    accepted.set_max_frame_len(10);
+    client.write_frame(&[0u8; 11]).unwrap();
+    assert!(accepted.read_frame().is_err());
+    assert_eq!(accepted.stats().read_errors, 1);
+    assert_eq!(client.stats().write_errors, 0);
+}
+
+/// How long a read of `reader` waits for a frame that never comes
+fn read_wait(reader: &mut ConnectionReader) -> Duration{
+    let started = std::time::Instant::now();
+    let err = reader.read_frame().unwrap_err();
+    assert!(matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut), "{:?}", err);
+    started.elapsed()
+}
+
+#[test]
+fn the_writer_half_cannot_clobber_the_read_timeout(){
+    for shared in [false, true] {
+        let (_a, b) = pair();
+        let (mut reader, writer) = if shared { b.split_shared() } else { b.separate().unwrap() };
+        reader.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let waited = read_wait(&mut reader);
+        assert!(waited >= Duration::from_millis(90) && waited < Duration::from_secs(2), "{:?}", waited);
+
+        let refused = ConnectionController::set_read_timeout(&writer, None).unwrap_err();
+        assert_eq!(refused.kind(), std::io::ErrorKind::Unsupported);
+        let refused = ConnectionController::set_write_timeout(&reader, None).unwrap_err();
+        assert_eq!(refused.kind(), std::io::ErrorKind::Unsupported);
+        // The write timeout is another socket option, the reads keep theirs
+        writer.set_write_timeout(Some(Duration::from_secs(30))).unwrap();
+        let waited = read_wait(&mut reader);
+        assert!(waited >= Duration::from_millis(90) && waited < Duration::from_secs(2), "{:?}", waited);
+
+        ConnectionController::set_read_timeout(&reader, Some(Duration::from_millis(300))).unwrap();
+        assert!(read_wait(&mut reader) >= Duration::from_millis(290));
+    }
+}
+
+/// Frame `i` of the concurrency tests, its length and bytes tell it apart
+fn numbered_frame(i: usize) -> Vec<u8>{
+    let len = (i * 7_919) % 40_000;
+    (0..len).map(|j| (i + j) as u8).collect()
+}
+
+#[test]
+fn split_shared_halves_read_and_write_concurrently(){
+    const FRAMES: usize = 500;
+    let (a, b) = pair();
+    let (mut a_reader, mut a_writer) = a.separate().unwrap();
+    let (mut b_reader, mut b_writer) = b.split_shared();
+    // Each direction carries more than the socket buffers hold, so neither side finishes alone
+    let threads = [
+        std::thread::spawn(move || (0..FRAMES).for_each(|i| a_writer.write_frame(&numbered_frame(i)).unwrap())),
+        std::thread::spawn(move || (0..FRAMES).for_each(|i| b_writer.write_frame(&numbered_frame(i)).unwrap())),
+        std::thread::spawn(move || (0..FRAMES).for_each(|i| assert!(a_reader.read_frame().unwrap() == numbered_frame(i), "frame {}", i))),
+        std::thread::spawn(move || (0..FRAMES).for_each(|i| assert!(b_reader.read_frame().unwrap() == numbered_frame(i), "frame {}", i))),
+    ];
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+/// Frame `seq` of writer `id`: the two of them, then bytes and a length that follow from them
+fn tagged_frame(id: u8, seq: u32) -> Vec<u8>{
+    let mut frame = vec![id];
+    frame.extend_from_slice(&seq.to_be_bytes());
+    let len = (seq as usize * 4_099 + id as usize * 977) % 30_000;
+    frame.extend((0..len).map(|j| (j as u32 ^ seq) as u8 ^ id));
+    frame
+}
+
+#[test]
+fn clones_hammered_from_several_threads_never_corrupt_a_frame(){
+    const THREADS: u8 = 4;
+    const FRAMES: u32 = 400;
+    let (a, b) = pair();
+    let writers: Vec<_> = (0..THREADS).map(|id| {
+        let mut writer = a.try_clone().unwrap();
+        std::thread::spawn(move || {
+            for seq in 0..FRAMES {
+                if seq % 3 == 0 {
+                    writer.write_frames([&tagged_frame(id, seq)[..]]).unwrap();
+                } else {
+                    writer.write_frame(&tagged_frame(id, seq)).unwrap();
+                }
+            }
+        })
+    }).collect();
+    let readers: Vec<_> = (0..THREADS).map(|_| {
+        let mut reader = b.try_clone().unwrap();
+        std::thread::spawn(move || {
+            let mut got = Vec::new();
+            // Each reader stops at an empty frame, one is sent for each
+            loop {
+                let frame = reader.read_frame().unwrap();
+                if frame.is_empty() {
+                    return got
+                }
+                let seq = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+                assert!(frame == tagged_frame(frame[0], seq), "corrupted frame {} of writer {}", seq, frame[0]);
+                got.push((frame[0], seq));
+            }
+        })
+    }).collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let mut a = a;
+    for _ in 0..THREADS {
+        a.write_frame(&[]).unwrap();
+    }
+    let mut got: Vec<_> = readers.into_iter().flat_map(|reader| reader.join().unwrap()).collect();
+    got.sort_unstable();
+    let expected: Vec<_> = (0..THREADS).flat_map(|id| (0..FRAMES).map(move |seq| (id, seq))).collect();
+    assert!(got == expected);
+}
+
+#[test]
+fn halves_show_their_id_name_and_addresses(){
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let client = std::net::TcpStream::connect(server_addr).unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let mut connection = Connection::from(unisocket::Stream::Inet(client));
+    let id = connection.id();
+    let (reader, writer) = connection.try_clone().unwrap().separate().unwrap();
+    assert_eq!(format!("{:?}", reader), format!("ConnectionReader {{ id: {}, local_addr: {}, peer_addr: {} }}", reader.id(), client_addr, server_addr));
+    assert_eq!(format!("{}", writer), format!("writer of connection {} to {}", writer.id(), server_addr));
+
+    connection.set_name("upstream");
+    let (reader, writer) = connection.separate().unwrap();
+    assert_eq!((reader.id(), writer.id()), (id, id));
+    assert_eq!(format!("{:?}", writer), format!("ConnectionWriter {{ id: {}, name: \"upstream\", local_addr: {}, peer_addr: {} }}", id, client_addr, server_addr));
+    assert_eq!(format!("{}", reader), format!("reader of connection {} to {}", id, server_addr));
+
+    // The peer is gone once it reset the connection, as the listener does with one it never accepted
+    ConnectionController::shutdown(&writer, std::net::Shutdown::Both).unwrap();
+    drop(listener);
+    assert_eq!(format!("{}", reader), format!("reader of connection {}", id));
+    assert_eq!(format!("{:?}", writer), format!("ConnectionWriter {{ id: {}, name: \"upstream\", local_addr: {} }}", id, client_addr));
+}
+
+#[test]
+fn the_reader_half_keeps_receiving_after_the_writer_half_finishes(){
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = unisocket::SocketAddr::Inet(listener.local_addr().unwrap());
+    let server = Server::from(unisocket::Listener::Inet(listener));
+    let tcp = (Connection::connect(&addr).unwrap(), server.accept().unwrap().0);
+    for (mut a, b) in [pair(), tcp] {
+        let (mut reader, mut writer) = b.separate().unwrap();
+        writer.set_flush_policy(FlushPolicy::explicit());
+        for i in 0..5 {
+            writer.write_frame(&numbered_frame(i)).unwrap();
+        }
+        writer.finish().unwrap();
+        for i in 0..5 {
+            assert!(a.read_frame().unwrap() == numbered_frame(i));
+        }
+        assert!(matches!(a.read_frame_checked(), Err(ReadErr::Disconnected)));
+        for i in 0..7 {
+            a.write_frame(&numbered_frame(i)).unwrap();
+        }
+        for i in 0..7 {
+            assert!(reader.read_frame().unwrap() == numbered_frame(i));
+        }
+    }
+}
+
+#[test]
+fn a_failed_timeout_restore_hands_back_the_frame(){
+    let (mut a, mut b) = pair();
+    b.set_read_timeout(Some(Duration::from_secs(7))).unwrap();
+    a.write_frame(b"first").unwrap();
+    assert!(b.read_frame_timeout(Duration::from_secs(1)).unwrap() == b"first");
+    assert_eq!(stream_read_timeout(&b.stream).unwrap(), Some(Duration::from_secs(7)));
+    let restore = || Err(std::io::Error::other("restore failed"));
+    match b.timed_read_result(Ok(b"second".to_vec()), restore()) {
+        Err(ReadErr::TimeoutNotRestored{frame, error}) => {
+            assert!(frame == b"second");
+            assert_eq!(error.to_string(), "restore failed");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    let timeout = std::io::Error::from(std::io::ErrorKind::WouldBlock);
+    assert!(matches!(b.timed_read_result(Err(timeout), restore()), Err(ReadErr::Timeout)));
+    assert!(!b.is_poisoned());
+}
+
+/// Median round trip over loopback of two 16-byte frames written one by one and answered with one:
+/// under Nagle's algorithm the second waits for the delayed acknowledgement of the first
+fn median_round_trip(nodelay: bool) -> Duration{
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = unisocket::SocketAddr::Inet(listener.local_addr().unwrap());
+    let server = Server::from(unisocket::Listener::Inet(listener));
+    let (mut reader, mut writer) = Connection::connect_with_nodelay(&addr, nodelay).unwrap().separate().unwrap();
+    let accepted = server.accept().unwrap().0;
+    accepted.set_nodelay(nodelay).unwrap();
+    let (mut peer_reader, mut peer_writer) = accepted.separate().unwrap();
+    for half in [&reader as &dyn ConnectionController, &writer, &peer_reader, &peer_writer] {
+        assert_eq!(half.nodelay().unwrap(), nodelay);
+    }
+    let echo = std::thread::spawn(move || {
+        while let (Ok(_), Ok(frame)) = (peer_reader.read_frame(), peer_reader.read_frame()) {
+            peer_writer.write_frame(&frame).unwrap();
+        }
+    });
+    let mut times = Vec::new();
+    for i in 0..20u8 {
+        let frame = [i; 16];
+        let start = std::time::Instant::now();
+        writer.write_frame(&frame).unwrap();
+        writer.write_frame(&frame).unwrap();
+        assert_eq!(reader.read_frame().unwrap(), frame);
+        times.push(start.elapsed());
+    }
+    drop((reader, writer));
+    echo.join().unwrap();
+    times.sort();
+    times[times.len() / 2]
+}
+
+#[test]
+fn nodelay_cuts_the_latency_of_small_frames(){
+    let delayed = median_round_trip(false);
+    let immediate = median_round_trip(true);
+    assert!(immediate * 4 < delayed, "nodelay {:?}, Nagle {:?}", immediate, delayed);
+}
+
+#[test]
+fn nodelay_set_on_a_half_applies_to_both(){
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = unisocket::SocketAddr::Inet(listener.local_addr().unwrap());
+    let (reader, writer) = Connection::connect(&addr).unwrap().separate().unwrap();
+    assert!(!reader.nodelay().unwrap());
+    writer.set_nodelay(true).unwrap();
+    assert!(reader.nodelay().unwrap());
+    reader.set_nodelay(false).unwrap();
+    assert!(!writer.nodelay().unwrap());
+}
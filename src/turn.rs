@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+/// Tells apart the clones and halves of a connection, which share its id
+pub(crate) fn next_handle() -> u64{
+    NEXT_HANDLE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What a read does while a clone of the connection is reading, see `Connection::set_concurrent_reads`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConcurrentReads{
+    /// Waits for the other clone to get to the end of a frame, up to the read timeout or deadline
+    #[default]
+    Block,
+    /// Fails with `ConcurrentReader` (wrapped into `io::ErrorKind::ResourceBusy`) at once
+    Fail,
+}
+
+/// Returned by reads while another clone of the connection is reading, see `ConcurrentReads::Fail`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConcurrentReader;
+
+impl ConcurrentReader{
+    pub fn is_concurrent_reader(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<ConcurrentReader>())
+    }
+}
+
+impl fmt::Display for ConcurrentReader{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Another clone of the connection is reading")
+    }
+}
+
+impl std::error::Error for ConcurrentReader{}
+
+/// Returned (wrapped into `io::ErrorKind::InvalidData`) by reads once a clone of the connection
+/// was dropped partway through a frame: where the next one starts is lost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbandonedFrame;
+
+impl AbandonedFrame{
+    pub fn is_abandoned_frame(err: &io::Error) -> bool{
+        err.get_ref().is_some_and(|inner| inner.is::<AbandonedFrame>())
+    }
+}
+
+impl fmt::Display for AbandonedFrame{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "A clone of the connection was dropped partway through a frame")
+    }
+}
+
+impl std::error::Error for AbandonedFrame{}
+
+#[derive(Debug)]
+struct TurnState<T>{
+    holder: Option<u64>,
+    waiting: usize,
+    /// Where the last holder left the stream, with the handle that took it over last
+    left: Option<(u64, T)>,
+    /// Bytes the last holder received past its frames, the next one reads them before the socket
+    input: VecDeque<u8>,
+}
+
+/// Which handle of a socket, its clones and halves, reads it or writes it. The holder gives the turn back
+/// between frames with nothing left buffered, so frames of different handles never interleave
+#[derive(Debug)]
+pub(crate) struct Turn<T>{
+    state: Mutex<TurnState<T>>,
+    changed: Condvar,
+}
+
+impl<T: Clone> Turn<T>{
+    pub(crate) fn new() -> Self{
+        let state = TurnState{holder: None, waiting: 0, left: None, input: VecDeque::new()};
+        Self{state: Mutex::new(state), changed: Condvar::new()}
+    }
+    fn lock(&self) -> MutexGuard<'_, TurnState<T>>{
+        // Nothing is left half updated under the lock
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Takes the turn for `handle` unless another handle holds it
+    pub(crate) fn try_take(&self, handle: u64) -> bool{
+        let mut state = self.lock();
+        match state.holder {
+            Some(holder) => holder == handle,
+            None => {
+                state.holder = Some(handle);
+                true
+            }
+        }
+    }
+    /// Waits for the turn up to `deadline`, `false` once it passed
+    pub(crate) fn wait(&self, handle: u64, deadline: Option<Instant>) -> bool{
+        let mut state = self.lock();
+        state.waiting += 1;
+        let taken = loop {
+            match state.holder {
+                Some(holder) if holder != handle => {}
+                _ => break true,
+            }
+            state = match deadline {
+                None => self.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner()),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        break false
+                    }
+                    self.changed.wait_timeout(state, left).unwrap_or_else(|poisoned| poisoned.into_inner()).0
+                }
+            };
+        };
+        state.waiting -= 1;
+        if taken {
+            state.holder = Some(handle);
+        }
+        taken
+    }
+    /// Where the previous holder left the stream, once, if it was another handle
+    pub(crate) fn adopt(&self, handle: u64) -> Option<T>{
+        match &mut self.lock().left {
+            Some((seen, left)) if *seen != handle => {
+                *seen = handle;
+                Some(left.clone())
+            }
+            _ => None,
+        }
+    }
+    /// Lets the next handle in if `handle` holds the turn, leaving it where the stream is at
+    /// and the bytes received past that
+    pub(crate) fn give_back(&self, handle: u64, left: T, input: &[u8]){
+        let mut state = self.lock();
+        if state.holder != Some(handle) {
+            return
+        }
+        state.holder = None;
+        state.left = Some((handle, left));
+        state.input.extend(input);
+        drop(state);
+        self.changed.notify_all();
+    }
+    pub(crate) fn has_waiters(&self) -> bool{
+        self.lock().waiting > 0
+    }
+    pub(crate) fn has_input(&self) -> bool{
+        !self.lock().input.is_empty()
+    }
+    /// Moves bytes handed over by the previous holder to `buf`, returning how many
+    pub(crate) fn read_input(&self, buf: &mut [MaybeUninit<u8>]) -> usize{
+        let mut state = self.lock();
+        let n = buf.len().min(state.input.len());
+        for (dst, byte) in buf.iter_mut().zip(state.input.drain(..n)) {
+            dst.write(byte);
+        }
+        n
+    }
+}
@@ -0,0 +1,67 @@
+//! `StdioConnection` across the pipes of a child process. The test binary runs itself as the child,
+//! which is why it has no test harness: the harness would take over stdout
+use std::env;
+use std::io;
+use std::process::{Command, Stdio};
+use std::thread;
+use rust_sfp::{FrameReader, FrameWriter, SfpReader, SfpWriter, StdioConnection};
+
+const CHILD: &str = "RUST_SFP_STDIO_CHILD";
+
+/// Sends every frame back with its bytes reversed until stdin ends
+fn echo_child(){
+    let mut connection = StdioConnection::new();
+    connection.set_sequence_numbers(true);
+    loop {
+        let mut frame = match connection.read_frame() {
+            Ok(frame) => frame,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(err) => panic!("child read: {}", err),
+        };
+        frame.reverse();
+        connection.write_frame(&frame).expect("child write");
+    }
+}
+
+fn frames() -> Vec<Vec<u8>>{
+    let mut frames = vec![Vec::new(), b"hello".to_vec(), vec![0; 1]];
+    // Longer than a pipe holds, both sides have to stream
+    frames.push((0..1_000_000u32).map(|i| (i % 253) as u8).collect());
+    frames.extend((0..100u8).map(|i| vec![i; i as usize * 31]));
+    frames
+}
+
+fn frames_round_trip_through_a_child(){
+    let mut child = Command::new(env::current_exe().unwrap())
+        .env(CHILD, "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("spawn the child");
+    let mut writer = SfpWriter::new(child.stdin.take().unwrap());
+    writer.set_sequence_numbers(true);
+    let mut reader = SfpReader::new(child.stdout.take().unwrap());
+    reader.set_sequence_numbers(true);
+    let sender = thread::spawn(move || {
+        for frame in frames() {
+            writer.write_frame(&frame).unwrap();
+        }
+        // Dropping stdin ends the child
+    });
+    for mut frame in frames() {
+        frame.reverse();
+        assert_eq!(reader.read_frame().unwrap(), frame);
+    }
+    sender.join().unwrap();
+    assert!(reader.read_frame().is_err());
+    assert!(child.wait().unwrap().success());
+}
+
+fn main(){
+    if env::var_os(CHILD).is_some() {
+        echo_child();
+        return
+    }
+    frames_round_trip_through_a_child();
+    println!("test frames_round_trip_through_a_child ... ok");
+}
@@ -0,0 +1,97 @@
+//! `GenericConnection` over rustls streams on loopback, run with `--features tls-tests`
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rust_sfp::{ChecksumKind, FlushPolicy, FrameReader, FrameWriter, GenericConnection, ReadErr};
+
+type Tls<C> = GenericConnection<StreamOwned<C, TcpStream>>;
+
+fn frames() -> Vec<Vec<u8>>{
+    let mut frames = vec![Vec::new(), b"hello".to_vec()];
+    // Spans many TLS records
+    frames.push((0..1_000_000u32).map(|i| (i % 251) as u8).collect());
+    frames.extend((0..50u8).map(|i| vec![i; i as usize * 97]));
+    frames
+}
+
+fn configure<S: io::Read + io::Write>(connection: &mut GenericConnection<S>){
+    connection.set_sequence_numbers(true);
+    connection.set_checksum(ChecksumKind::Crc32).unwrap();
+}
+
+fn tls_pair() -> (Tls<ClientConnection>, thread::JoinHandle<Tls<ServerConnection>>){
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    let server_config = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions().unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client_config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions().unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (socket, _) = listener.accept().unwrap();
+        let session = ServerConnection::new(Arc::new(server_config)).unwrap();
+        GenericConnection::new(StreamOwned::new(session, socket))
+    });
+    let name = ServerName::try_from("localhost").unwrap();
+    let session = ClientConnection::new(Arc::new(client_config), name).unwrap();
+    (GenericConnection::new(StreamOwned::new(session, TcpStream::connect(addr).unwrap())), server)
+}
+
+#[test]
+fn frames_round_trip_over_tls(){
+    let (mut client, server) = tls_pair();
+    configure(&mut client);
+    let echo = thread::spawn(move || {
+        let mut server = server.join().unwrap();
+        configure(&mut server);
+        for _ in frames() {
+            let mut frame = server.read_frame().unwrap();
+            frame.reverse();
+            server.write_frame(&frame).unwrap();
+        }
+        // The client sent close_notify, a clean end between frames
+        assert!(matches!(server.read_frame_checked(), Err(ReadErr::Disconnected)));
+    });
+    client.set_flush_policy(FlushPolicy::explicit());
+    for frame in frames() {
+        client.write_frame(&frame).unwrap();
+        client.flush().unwrap();
+        let mut echoed = client.read_frame().unwrap();
+        echoed.reverse();
+        assert!(echoed == frame);
+    }
+    client.get_mut().conn.send_close_notify();
+    client.get_mut().flush().unwrap();
+    echo.join().unwrap();
+}
+
+#[test]
+fn streamed_and_batched_writes_round_trip_over_tls(){
+    let (mut client, server) = tls_pair();
+    let sink = thread::spawn(move || {
+        let mut server = server.join().unwrap();
+        let received: Vec<Vec<u8>> = (0..frames().len() + 1).map(|_| server.read_frame().unwrap()).collect();
+        received
+    });
+    let frames = frames();
+    client.write_frames(frames.iter().map(|frame| frame.as_slice())).unwrap();
+    let streamed = &frames[2];
+    client.write_frame_from_reader(&mut streamed.as_slice(), streamed.len() as u64).unwrap();
+    client.flush().unwrap();
+    let received = sink.join().unwrap();
+    assert!(received[..frames.len()] == frames[..]);
+    assert!(received[frames.len()] == *streamed);
+}